@@ -2,11 +2,17 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use boringtun::device::drop_privileges::drop_privileges;
+use boringtun::device::metrics::MetricsAddr;
+#[cfg(target_os = "linux")]
+use boringtun::device::netlink::NetworkConfig;
 use boringtun::device::{DeviceConfig, DeviceHandle};
+use boringtun::noise::obfuscation::Obfuscation;
+use boringtun::noise::rate_limiter::SourceLimit;
 use clap::{Arg, Command};
 use daemonize::Daemonize;
 use std::fs::File;
 use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
 use std::process::exit;
 use tracing::Level;
 
@@ -46,6 +52,24 @@ fn main() {
                 .env("WG_THREADS")
                 .help("Number of OS threads to use")
                 .default_value("4"),
+            Arg::new("handshake-threads")
+                .takes_value(true)
+                .long("handshake-threads")
+                .env("WG_HANDSHAKE_THREADS")
+                .help("Number of OS threads to process handshakes on, to keep floods of them off the data path. 0 processes them along with data packets")
+                .default_value("0"),
+            Arg::new("handshake-rate-limit")
+                .takes_value(true)
+                .long("handshake-rate-limit")
+                .env("WG_HANDSHAKE_RATE_LIMIT")
+                .help("Handshake messages per second to take before asking for cookies")
+                .default_value("100"),
+            Arg::new("peer-handshake-rate-limit")
+                .takes_value(true)
+                .long("peer-handshake-rate-limit")
+                .env("WG_PEER_HANDSHAKE_RATE_LIMIT")
+                .help("Handshake messages per second each peer takes before asking for cookies, while the device has no private key")
+                .default_value("10"),
             Arg::new("verbosity")
                 .takes_value(true)
                 .long("verbosity")
@@ -75,6 +99,9 @@ fn main() {
                 .long("disable-drop-privileges")
                 .env("WG_SUDO")
                 .help("Do not drop sudo privileges"),
+            Arg::new("limit-sources")
+                .long("limit-sources")
+                .help("Also limit the handshake messages of each source address while under load"),
             Arg::new("disable-connected-udp")
                 .long("disable-connected-udp")
                 .help("Disable connected UDP sockets to each peer"),
@@ -82,6 +109,55 @@ fn main() {
             Arg::new("disable-multi-queue")
                 .long("disable-multi-queue")
                 .help("Disable using multiple queues for the tunnel interface"),
+            #[cfg(target_os = "linux")]
+            Arg::new("disable-mmsg").long("disable-mmsg").help(
+                "Disable sending and receiving batches of UDP datagrams with a single syscall",
+            ),
+            #[cfg(target_os = "linux")]
+            Arg::new("disable-udp-offload")
+                .long("disable-udp-offload")
+                .help("Disable UDP GSO/GRO segmentation offload"),
+            #[cfg(target_os = "linux")]
+            Arg::new("tun-offload")
+                .long("tun-offload")
+                .help("Enable TCP segmentation and checksum offloads on the tunnel interface"),
+            Arg::new("obfuscation")
+                .takes_value(true)
+                .long("obfuscation")
+                .env("WG_OBFUSCATION")
+                .help("Disguise WireGuard messages, e.g. jc=4,jmin=40,jmax=70,s1=15,s2=40,h1=..,h4=.."),
+            Arg::new("metrics")
+                .takes_value(true)
+                .long("metrics")
+                .env("WG_METRICS")
+                .help("Serve OpenMetrics over HTTP on this address, e.g. 127.0.0.1:9586 or unix:/path"),
+            #[cfg(target_os = "linux")]
+            Arg::new("address")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .long("address")
+                .short('a')
+                .help("Assign this address to the interface, e.g. 10.0.0.1/24, may be repeated"),
+            #[cfg(target_os = "linux")]
+            Arg::new("mtu")
+                .takes_value(true)
+                .long("mtu")
+                .help("Set the MTU of the interface"),
+            #[cfg(target_os = "linux")]
+            Arg::new("routes")
+                .long("routes")
+                .help("Route the allowed IPs of peers to the interface"),
+            #[cfg(target_os = "linux")]
+            Arg::new("table")
+                .takes_value(true)
+                .long("table")
+                .help("Install routes in this table, with policy rules like wg-quick, and use it as the fwmark"),
+            Arg::new("config")
+                .takes_value(true)
+                .long("config")
+                .short('c')
+                .env("WG_CONFIG_FILE")
+                .help("Configure the interface from this file in the WireGuard format, reloaded on SIGHUP"),
         ])
         .get_matches();
 
@@ -94,7 +170,47 @@ fn main() {
         tun_name = matches.value_of("tun-fd").unwrap();
     }
     let n_threads: usize = matches.value_of_t("threads").unwrap_or_else(|e| e.exit());
+    let handshake_threads: usize = matches
+        .value_of_t("handshake-threads")
+        .unwrap_or_else(|e| e.exit());
+    let handshake_rate_limit: u64 = matches
+        .value_of_t("handshake-rate-limit")
+        .unwrap_or_else(|e| e.exit());
+    let peer_handshake_rate_limit: u64 = matches
+        .value_of_t("peer-handshake-rate-limit")
+        .unwrap_or_else(|e| e.exit());
     let log_level: Level = matches.value_of_t("verbosity").unwrap_or_else(|e| e.exit());
+    let obfuscation: Option<Obfuscation> = matches.is_present("obfuscation").then(|| {
+        matches
+            .value_of_t("obfuscation")
+            .unwrap_or_else(|e| e.exit())
+    });
+    let metrics: Option<MetricsAddr> = matches
+        .is_present("metrics")
+        .then(|| matches.value_of_t("metrics").unwrap_or_else(|e| e.exit()));
+    #[cfg(target_os = "linux")]
+    let network = ["address", "mtu", "routes", "table"]
+        .iter()
+        .any(|arg| matches.is_present(arg))
+        .then(|| NetworkConfig {
+            addresses: if matches.is_present("address") {
+                matches.values_of_t("address").unwrap_or_else(|e| e.exit())
+            } else {
+                vec![]
+            },
+            mtu: matches
+                .is_present("mtu")
+                .then(|| matches.value_of_t("mtu").unwrap_or_else(|e| e.exit())),
+            routes: matches.is_present("routes"),
+            table: matches
+                .is_present("table")
+                .then(|| matches.value_of_t("table").unwrap_or_else(|e| e.exit())),
+        });
+
+    // Resolved before daemonizing changes the working directory
+    let config_file: Option<PathBuf> = matches
+        .value_of("config")
+        .map(|path| std::env::current_dir().unwrap_or_default().join(path));
 
     // Create a socketpair to communicate between forked processes
     let (sock1, sock2) = UnixDatagram::pair().unwrap();
@@ -146,11 +262,29 @@ fn main() {
 
     let config = DeviceConfig {
         n_threads,
+        handshake_threads,
+        handshake_rate_limit,
+        peer_handshake_rate_limit,
+        source_limit: matches
+            .is_present("limit-sources")
+            .then(SourceLimit::default),
         #[cfg(target_os = "linux")]
         uapi_fd,
         use_connected_socket: !matches.is_present("disable-connected-udp"),
         #[cfg(target_os = "linux")]
         use_multi_queue: !matches.is_present("disable-multi-queue"),
+        #[cfg(target_os = "linux")]
+        use_mmsg: !matches.is_present("disable-mmsg"),
+        #[cfg(target_os = "linux")]
+        use_udp_offload: !matches.is_present("disable-udp-offload"),
+        #[cfg(target_os = "linux")]
+        use_tun_offload: matches.is_present("tun-offload"),
+        obfuscation,
+        metrics,
+        config_file,
+        #[cfg(target_os = "linux")]
+        network,
+        resolver: None,
     };
 
     let mut device_handle: DeviceHandle = match DeviceHandle::new(tun_name, config) {
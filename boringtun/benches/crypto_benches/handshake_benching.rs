@@ -0,0 +1,96 @@
+use boringtun::noise::handshake::{parse_handshake_anon, ResponderKey};
+use boringtun::noise::rate_limiter::RateLimiter;
+use boringtun::noise::{Packet, Tunn, TunnResult};
+use boringtun::x25519;
+use criterion::{BatchSize, Criterion, Throughput};
+use rand_core::OsRng;
+use std::sync::Arc;
+
+/// An initiator and a responder, the responder never asks for cookies
+fn create_tunnels(responder_key: &ResponderKey) -> (Tunn, Tunn, Arc<RateLimiter>) {
+    let initiator_private = x25519::StaticSecret::random_from_rng(OsRng);
+    let initiator_public = x25519::PublicKey::from(&initiator_private);
+    let responder_public = responder_key.static_public();
+    let rate_limiter = Arc::new(RateLimiter::new(&responder_public, u64::MAX));
+
+    let initiator = Tunn::new(initiator_private, responder_public, None, None, 1, None);
+    let responder = Tunn::new(
+        responder_key.static_private().clone(),
+        initiator_public,
+        None,
+        None,
+        2,
+        Some(Arc::clone(&rate_limiter)),
+    );
+    (initiator, responder, rate_limiter)
+}
+
+fn handshake_init(initiator: &mut Tunn) -> Vec<u8> {
+    let mut dst = vec![0u8; 256];
+    match initiator.force_handshake(&mut dst) {
+        TunnResult::WriteToNetwork(packet) => packet.to_vec(),
+        _ => unreachable!(),
+    }
+}
+
+/// Handshakes per second of a responder that finds the peer of each initiation, then has that
+/// peer respond to it, as the device does
+pub fn bench_handshake_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake_response");
+
+    group.sample_size(1000);
+    group.throughput(Throughput::Elements(1));
+
+    let responder_key = ResponderKey::new(x25519::StaticSecret::random_from_rng(OsRng));
+    let responder_public = responder_key.static_public();
+
+    group.bench_function("decrypted_twice", |b| {
+        let (mut initiator, mut responder, _) = create_tunnels(&responder_key);
+        let mut dst = vec![0u8; 256];
+
+        b.iter_batched(
+            || handshake_init(&mut initiator),
+            |init| {
+                let half = match Tunn::parse_incoming_packet(&init) {
+                    Ok(Packet::HandshakeInit(packet)) => parse_handshake_anon(
+                        responder_key.static_private(),
+                        &responder_public,
+                        &packet,
+                    )
+                    .unwrap(),
+                    _ => unreachable!(),
+                };
+                assert!(matches!(
+                    responder.decapsulate(None, &init, &mut dst),
+                    TunnResult::WriteToNetwork(_)
+                ));
+                half
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.bench_function("resumed", |b| {
+        let (mut initiator, mut responder, rate_limiter) = create_tunnels(&responder_key);
+        let mut dst = vec![0u8; 256];
+        let mut cookie = [0u8; 64];
+
+        b.iter_batched(
+            || handshake_init(&mut initiator),
+            |init| {
+                let packet = match rate_limiter.verify_packet(None, &init, &mut cookie) {
+                    Ok(Packet::HandshakeInit(packet)) => packet,
+                    _ => unreachable!(),
+                };
+                let half = responder_key.parse_handshake_anon(&packet).unwrap();
+                assert!(matches!(
+                    responder.resume_handshake_init(half, packet, &mut dst),
+                    TunnResult::WriteToNetwork(_)
+                ));
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
@@ -0,0 +1,96 @@
+use boringtun::device::udp_batch::{UdpBatching, MAX_BATCH, MAX_DATAGRAM_SIZE};
+use criterion::{BenchmarkId, Criterion, Throughput};
+use socket2::{Domain, Protocol, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// The size of a WireGuard data packet carrying a full 1420 bytes MTU IP packet
+const PACKET_SIZE: usize = 1420 + 32;
+
+fn loopback_socket(batching: &UdpBatching) -> socket2::Socket {
+    let udp = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+    udp.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+        .unwrap();
+    udp.set_nonblocking(true).unwrap();
+    udp.set_recv_buffer_size(1 << 24).unwrap();
+    batching.configure(&udp);
+    udp
+}
+
+/// Send MAX_BATCH packets over loopback, then receive all of them
+fn send_recv_batch(
+    batching: &UdpBatching,
+    sender: &socket2::Socket,
+    receiver: &socket2::Socket,
+    receiver_addr: SocketAddr,
+    packets: &[&[u8]],
+    batch: &mut [u8],
+) {
+    batching.send(sender, packets, Some(receiver_addr)).unwrap();
+
+    let mut datagrams = Vec::with_capacity(MAX_BATCH);
+    let mut n_received = 0;
+    while n_received < packets.len() {
+        datagrams.clear();
+        n_received += batching.recv(receiver, batch, MAX_BATCH, &mut datagrams);
+    }
+}
+
+pub fn bench_udp_loopback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_loopback");
+
+    group.throughput(Throughput::Bytes((MAX_BATCH * PACKET_SIZE) as u64));
+
+    for (name, batching) in [
+        (
+            "one_by_one",
+            UdpBatching {
+                mmsg: false,
+                offload: false,
+            },
+        ),
+        (
+            "mmsg",
+            UdpBatching {
+                mmsg: true,
+                offload: false,
+            },
+        ),
+        (
+            "mmsg_offload",
+            UdpBatching {
+                mmsg: true,
+                offload: true,
+            },
+        ),
+    ] {
+        group.bench_with_input(
+            BenchmarkId::new(name, MAX_BATCH),
+            &batching,
+            |b, batching| {
+                let sender = loopback_socket(batching);
+                let receiver = loopback_socket(batching);
+                let receiver_addr = receiver.local_addr().unwrap().as_socket().unwrap();
+
+                let packet = [0u8; PACKET_SIZE];
+                let packets = [&packet[..]; MAX_BATCH];
+                let mut batch = vec![0u8; MAX_BATCH * MAX_DATAGRAM_SIZE];
+
+                b.iter(|| {
+                    send_recv_batch(
+                        batching,
+                        &sender,
+                        &receiver,
+                        receiver_addr,
+                        &packets,
+                        &mut batch,
+                    )
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion::criterion_group!(udp_benches, bench_udp_loopback);
+criterion::criterion_main!(udp_benches);
@@ -6,10 +6,17 @@ use crate::device::peer::AllowedIP;
 use ip_network::IpNetwork;
 use ip_network_table::IpNetworkTable;
 
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 use std::iter::FromIterator;
 use std::net::IpAddr;
 
+/// The allowed IPs to add and remove to turn one set into another
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedIpsDiff {
+    pub add: Vec<AllowedIP>,
+    pub remove: Vec<AllowedIP>,
+}
+
 /// A trie of IP/cidr addresses
 #[derive(Default)]
 pub struct AllowedIps<D> {
@@ -40,12 +47,7 @@ impl<D> AllowedIps<D> {
     }
 
     pub fn insert(&mut self, key: IpAddr, cidr: u32, data: D) -> Option<D> {
-        // These are networks, it doesn't make sense for host bits to be set, so
-        // use new_truncate().
-        self.ips.insert(
-            IpNetwork::new_truncate(key, cidr as u8).expect("cidr is valid length"),
-            data,
-        )
+        self.ips.insert(network(key, cidr), data)
     }
 
     pub fn find(&self, key: IpAddr) -> Option<&D> {
@@ -56,7 +58,68 @@ impl<D> AllowedIps<D> {
         self.ips.retain(|_, v| !predicate(v));
     }
 
-    pub fn iter(&self) -> Iter<D> {
+    /// The data of exactly `key/cidr`, rather than of the longest prefix that contains `key`
+    pub fn find_exact(&self, key: IpAddr, cidr: u32) -> Option<&D> {
+        self.ips.exact_match(network(key, cidr))
+    }
+
+    /// Remove exactly `key/cidr`, the prefixes within it are kept
+    pub fn remove_exact(&mut self, key: IpAddr, cidr: u32) -> Option<D> {
+        self.ips.remove(network(key, cidr))
+    }
+
+    /// The prefixes whose data matches `predicate`, in the order of `iter`
+    pub fn list(&self, predicate: &dyn Fn(&D) -> bool) -> Vec<AllowedIP> {
+        self.ips
+            .iter()
+            .filter(|(_, data)| predicate(data))
+            .map(|(ipa, _)| allowed_ip(ipa))
+            .collect()
+    }
+
+    /// What to add and remove for the prefixes whose data matches `owner` to be exactly `wanted`
+    pub fn diff(&self, owner: &dyn Fn(&D) -> bool, wanted: &[AllowedIP]) -> AllowedIpsDiff {
+        let current: BTreeSet<_> = self.list(owner).into_iter().collect();
+        let wanted: BTreeSet<_> = wanted
+            .iter()
+            .map(|ip| allowed_ip(network(ip.addr, ip.cidr as u32)))
+            .collect();
+        AllowedIpsDiff {
+            add: wanted.difference(&current).copied().collect(),
+            remove: current.difference(&wanted).copied().collect(),
+        }
+    }
+
+    /// Remove the prefixes of `diff.remove` that belong to `owner`, then insert those of
+    /// `diff.add` with `data`. Returns the prefixes that were taken over from other owners, with
+    /// the data they had.
+    pub fn apply_diff(
+        &mut self,
+        diff: &AllowedIpsDiff,
+        owner: &dyn Fn(&D) -> bool,
+        data: D,
+    ) -> Vec<(AllowedIP, D)>
+    where
+        D: Clone,
+    {
+        for ip in &diff.remove {
+            if self.find_exact(ip.addr, ip.cidr as u32).is_some_and(owner) {
+                self.remove_exact(ip.addr, ip.cidr as u32);
+            }
+        }
+
+        let mut taken = vec![];
+        for ip in &diff.add {
+            if let Some(previous) = self.insert(ip.addr, ip.cidr as u32, data.clone()) {
+                if !owner(&previous) {
+                    taken.push((allowed_ip(network(ip.addr, ip.cidr as u32)), previous));
+                }
+            }
+        }
+        taken
+    }
+
+    pub fn iter(&self) -> Iter<'_, D> {
         Iter(
             self.ips
                 .iter()
@@ -66,6 +129,18 @@ impl<D> AllowedIps<D> {
     }
 }
 
+// These are networks, it doesn't make sense for host bits to be set
+fn network(key: IpAddr, cidr: u32) -> IpNetwork {
+    IpNetwork::new_truncate(key, cidr as u8).expect("cidr is valid length")
+}
+
+fn allowed_ip(network: IpNetwork) -> AllowedIP {
+    AllowedIP {
+        addr: network.network_address(),
+        cidr: network.netmask(),
+    }
+}
+
 pub struct Iter<'a, D: 'a>(VecDeque<(&'a D, IpAddr, u8)>);
 
 impl<'a, D> Iterator for Iter<'a, D> {
@@ -137,6 +212,71 @@ mod tests {
         assert_eq!(map_iter.next(), None);
     }
 
+    #[test]
+    fn test_allowed_ips_remove_exact() {
+        let mut map = build_allowed_ips();
+        assert_eq!(map.remove_exact(IpAddr::from([127, 0, 0, 0]), 8), None);
+        // Host bits are ignored, as on insert
+        assert_eq!(map.find_exact(IpAddr::from([127, 0, 1, 1]), 16), Some(&'2'));
+        assert_eq!(
+            map.remove_exact(IpAddr::from([127, 0, 1, 1]), 16),
+            Some('2')
+        );
+
+        // Prefixes within the one removed are kept
+        assert_eq!(map.find(IpAddr::from([127, 0, 0, 1])), Some(&'1'));
+        assert_eq!(map.find(IpAddr::from([127, 0, 0, 2])), None);
+        assert_eq!(map.find(IpAddr::from([127, 1, 15, 1])), Some(&'3'));
+    }
+
+    #[test]
+    fn test_allowed_ips_diff() {
+        let ip = |s: &str| s.parse::<AllowedIP>().unwrap();
+        let mut map: AllowedIps<char> = Default::default();
+        map.insert(IpAddr::from([10, 0, 0, 0]), 24, 'a');
+        map.insert(IpAddr::from([10, 0, 1, 0]), 24, 'a');
+        map.insert(IpAddr::from([10, 0, 2, 0]), 24, 'b');
+        assert_eq!(
+            map.list(&|c| *c == 'a'),
+            [ip("10.0.0.0/24"), ip("10.0.1.0/24")]
+        );
+
+        let diff = map.diff(
+            &|c| *c == 'a',
+            &[ip("10.0.1.1/24"), ip("10.0.2.0/24"), ip("fd00::/64")],
+        );
+        assert_eq!(
+            diff,
+            AllowedIpsDiff {
+                add: vec![ip("10.0.2.0/24"), ip("fd00::/64")],
+                remove: vec![ip("10.0.0.0/24")],
+            }
+        );
+
+        // 10.0.2.0/24 is taken over from 'b'
+        let taken = map.apply_diff(&diff, &|c| *c == 'a', 'a');
+        assert_eq!(taken, [(ip("10.0.2.0/24"), 'b')]);
+        assert_eq!(
+            map.list(&|c| *c == 'a'),
+            [ip("10.0.1.0/24"), ip("10.0.2.0/24"), ip("fd00::/64")]
+        );
+        assert!(map.list(&|c| *c == 'b').is_empty());
+        assert_eq!(
+            map.diff(&|c| *c == 'a', &map.list(&|c| *c == 'a')),
+            Default::default()
+        );
+
+        // Only the prefixes of the owner are removed
+        map.insert(IpAddr::from([10, 0, 3, 0]), 24, 'b');
+        let diff = AllowedIpsDiff {
+            add: vec![],
+            remove: vec![ip("10.0.1.0/24"), ip("10.0.3.0/24")],
+        };
+        assert!(map.apply_diff(&diff, &|c| *c == 'a', 'a').is_empty());
+        assert_eq!(map.find(IpAddr::from([10, 0, 1, 1])), None);
+        assert_eq!(map.find(IpAddr::from([10, 0, 3, 1])), Some(&'b'));
+    }
+
     #[test]
     fn test_allowed_ips_iter() {
         let map = build_allowed_ips();
@@ -1,10 +1,12 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use super::config::{DeviceUpdate, PeerChange, PeerConfig};
 use super::dev_lock::LockReadGuard;
 use super::drop_privileges::get_saved_ids;
-use super::{AllowedIP, Device, Error, SocketAddr};
+use super::{AllowedIP, Device, EndpointProtocol, Error, PeerEndpoint};
 use crate::device::Action;
+use crate::noise::errors::WireGuardError;
 use crate::serialization::KeyBytes;
 use crate::x25519;
 use hex::encode as encode_hex;
@@ -155,214 +157,306 @@ impl Device {
 
 #[allow(unused_must_use)]
 fn api_get(writer: &mut BufWriter<&UnixStream>, d: &Device) -> i32 {
+    let state = d.state();
     // get command requires an empty line, but there is no reason to be religious about it
-    if let Some(ref k) = d.key_pair {
-        writeln!(writer, "own_public_key={}", encode_hex(k.1.as_bytes()));
+    if let Some(ref k) = state.public_key {
+        writeln!(writer, "own_public_key={}", encode_hex(k.as_bytes()));
     }
 
-    if d.listen_port != 0 {
-        writeln!(writer, "listen_port={}", d.listen_port);
+    if state.listen_port != 0 {
+        writeln!(writer, "listen_port={}", state.listen_port);
     }
 
-    if let Some(fwmark) = d.fwmark {
+    if let Some(fwmark) = state.fwmark {
         writeln!(writer, "fwmark={}", fwmark);
     }
 
-    for (k, p) in d.peers.iter() {
-        let p = p.lock();
-        writeln!(writer, "public_key={}", encode_hex(k.as_bytes()));
+    writeln!(
+        writer,
+        "handshake_rate_limit={}",
+        state.handshake_rate_limit
+    );
+    writeln!(
+        writer,
+        "peer_handshake_rate_limit={}",
+        state.peer_handshake_rate_limit
+    );
+    if let Some(rate_limiter) = state.rate_limiter {
+        writeln!(writer, "handshake_rate={}", rate_limiter.handshake_rate);
+        writeln!(writer, "cookie_mode={}", rate_limiter.cookie_mode);
+        writeln!(
+            writer,
+            "cookie_replies={}",
+            rate_limiter.stats.cookie_replies
+        );
+        writeln!(writer, "invalid_mac1={}", rate_limiter.stats.invalid_mac);
+        writeln!(writer, "invalid_mac2={}", rate_limiter.stats.invalid_mac2);
+    }
+
+    for p in state.peers {
+        writeln!(writer, "public_key={}", encode_hex(p.public_key.as_bytes()));
 
-        if let Some(ref key) = p.preshared_key() {
+        if let Some(ref key) = p.preshared_key {
             writeln!(writer, "preshared_key={}", encode_hex(key));
         }
 
-        if let Some(keepalive) = p.persistent_keepalive() {
+        if let Some(keepalive) = p.persistent_keepalive {
             writeln!(writer, "persistent_keepalive_interval={}", keepalive);
         }
 
-        if let Some(ref addr) = p.endpoint().addr {
+        if let Some(ref addr) = p.endpoint {
             writeln!(writer, "endpoint={}", addr);
         }
 
-        for (ip, cidr) in p.allowed_ips() {
-            writeln!(writer, "allowed_ip={}/{}", ip, cidr);
+        if let Some(ref host) = p.endpoint_host {
+            writeln!(writer, "endpoint_host={}", host);
+        }
+
+        if p.protocol != EndpointProtocol::Udp {
+            writeln!(writer, "endpoint_protocol={}", p.protocol);
         }
 
-        if let Some(time) = p.time_since_last_handshake() {
+        for AllowedIP { addr, cidr } in p.allowed_ips {
+            writeln!(writer, "allowed_ip={}/{}", addr, cidr);
+        }
+
+        let stats = p.stats;
+        if let Some(time) = stats.time_since_last_handshake {
             writeln!(writer, "last_handshake_time_sec={}", time.as_secs());
             writeln!(writer, "last_handshake_time_nsec={}", time.subsec_nanos());
         }
 
-        let (_, tx_bytes, rx_bytes, ..) = p.tunnel.stats();
-
-        writeln!(writer, "rx_bytes={}", rx_bytes);
-        writeln!(writer, "tx_bytes={}", tx_bytes);
+        writeln!(writer, "rx_bytes={}", stats.rx_bytes);
+        writeln!(writer, "tx_bytes={}", stats.tx_bytes);
+        writeln!(
+            writer,
+            "handshakes_initiated={}",
+            stats.handshakes_initiated
+        );
+        writeln!(
+            writer,
+            "handshakes_completed={}",
+            stats.handshakes_completed
+        );
+        writeln!(writer, "handshakes_failed={}", stats.handshakes_failed);
+        writeln!(writer, "cookies_sent={}", stats.cookies_sent);
+        writeln!(writer, "cookies_received={}", stats.cookies_received);
+        // A line per reason that packets were dropped for, `dropped_packets=<name>:<count>`
+        for (reason, count) in WireGuardError::ALL.iter().zip(stats.dropped_packets) {
+            if count > 0 {
+                writeln!(writer, "dropped_packets={}:{}", reason.name(), count);
+            }
+        }
+        writeln!(writer, "replayed_packets={}", stats.replayed_packets);
+        if let Some(index) = stats.session_index {
+            writeln!(writer, "session_index={}", index);
+        }
+        if let Some(age) = stats.session_age {
+            writeln!(writer, "session_age_sec={}", age.as_secs());
+        }
+        writeln!(writer, "queued_packets={}", stats.queued_packets);
     }
     0
 }
 
+/// Read the whole set request, then apply it at once. A request with `dry_run=true` is only
+/// validated.
 fn api_set(reader: &mut BufReader<&UnixStream>, d: &mut LockReadGuard<Device>) -> i32 {
+    let (update, dry_run) = match parse_set(reader) {
+        Ok(parsed) => parsed,
+        Err(errno) => return errno,
+    };
+
+    if dry_run {
+        return set_status(d.validate(&update));
+    }
+
     d.try_writeable(
         |device| device.trigger_yield(),
         |device| {
             device.cancel_yield();
+            set_status(device.apply(update))
+        },
+    )
+    .unwrap_or(EIO)
+}
 
-            let mut cmd = String::new();
+/// The errno to answer a set request with
+fn set_status(result: Result<(), Error>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(Error::NoPrivateKey) => EINVAL,
+        Err(Error::Bind(_)) => EADDRINUSE,
+        Err(Error::SetSockOpt(_)) => EPERM, // Setting the fwmark takes CAP_NET_ADMIN
+        Err(_) => EIO,
+    }
+}
 
-            while reader.read_line(&mut cmd).is_ok() {
-                cmd.pop(); // remove newline if any
-                if cmd.is_empty() {
-                    return 0; // Done
-                }
-                {
-                    let parsed_cmd: Vec<&str> = cmd.split('=').collect();
-                    if parsed_cmd.len() != 2 {
-                        return EPROTO;
-                    }
+/// Parse a set request up to its empty line, into the update and whether it is a dry run, or
+/// the errno to answer with
+fn parse_set(reader: &mut BufReader<&UnixStream>) -> Result<(DeviceUpdate, bool), i32> {
+    let mut update = DeviceUpdate::default();
+    let mut dry_run = false;
+    let mut cmd = String::new();
 
-                    let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
-
-                    match key {
-                        "private_key" => match val.parse::<KeyBytes>() {
-                            Ok(key_bytes) => {
-                                device.set_key(x25519::StaticSecret::from(key_bytes.0))
-                            }
-                            Err(_) => return EINVAL,
-                        },
-                        "listen_port" => match val.parse::<u16>() {
-                            Ok(port) => match device.open_listen_socket(port) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        #[cfg(any(
-                            target_os = "android",
-                            target_os = "fuchsia",
-                            target_os = "linux"
-                        ))]
-                        "fwmark" => match val.parse::<u32>() {
-                            Ok(mark) => match device.set_fwmark(mark) {
-                                Ok(()) => {}
-                                Err(_) => return EADDRINUSE,
-                            },
-                            Err(_) => return EINVAL,
-                        },
-                        "replace_peers" => match val.parse::<bool>() {
-                            Ok(true) => device.clear_peers(),
-                            Ok(false) => {}
-                            Err(_) => return EINVAL,
-                        },
-                        "public_key" => match val.parse::<KeyBytes>() {
-                            // Indicates a new peer section
-                            Ok(key_bytes) => {
-                                return api_set_peer(
-                                    reader,
-                                    device,
-                                    x25519::PublicKey::from(key_bytes.0),
-                                )
-                            }
-                            Err(_) => return EINVAL,
-                        },
-                        _ => return EINVAL,
-                    }
-                }
-                cmd.clear();
+    loop {
+        if reader.read_line(&mut cmd).is_err() {
+            return Err(EIO);
+        }
+        cmd.pop(); // remove newline if any
+        if cmd.is_empty() {
+            return Ok((update, dry_run)); // Done
+        }
+        {
+            let parsed_cmd: Vec<&str> = cmd.split('=').collect();
+            if parsed_cmd.len() != 2 {
+                return Err(EPROTO);
             }
 
-            0
-        },
-    )
-    .unwrap_or(EIO)
+            let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
+
+            match key {
+                "private_key" => match val.parse::<KeyBytes>() {
+                    Ok(key_bytes) => {
+                        update.private_key = Some(x25519::StaticSecret::from(key_bytes.0))
+                    }
+                    Err(_) => return Err(EINVAL),
+                },
+                "listen_port" => match val.parse::<u16>() {
+                    Ok(port) => update.listen_port = Some(port),
+                    Err(_) => return Err(EINVAL),
+                },
+                #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+                "fwmark" => match val.parse::<u32>() {
+                    Ok(mark) => update.fwmark = Some(mark),
+                    Err(_) => return Err(EINVAL),
+                },
+                "handshake_rate_limit" => match val.parse::<u64>() {
+                    Ok(limit) => update.handshake_rate_limit = Some(limit),
+                    Err(_) => return Err(EINVAL),
+                },
+                "peer_handshake_rate_limit" => match val.parse::<u64>() {
+                    Ok(limit) => update.peer_handshake_rate_limit = Some(limit),
+                    Err(_) => return Err(EINVAL),
+                },
+                "replace_peers" => match val.parse::<bool>() {
+                    Ok(replace) => update.replace_peers = replace,
+                    Err(_) => return Err(EINVAL),
+                },
+                "dry_run" => match val.parse::<bool>() {
+                    Ok(dry) => dry_run = dry,
+                    Err(_) => return Err(EINVAL),
+                },
+                "public_key" => match val.parse::<KeyBytes>() {
+                    // Indicates a new peer section
+                    Ok(key_bytes) => {
+                        parse_set_peer(reader, &mut update, x25519::PublicKey::from(key_bytes.0))?;
+                        return Ok((update, dry_run));
+                    }
+                    Err(_) => return Err(EINVAL),
+                },
+                _ => return Err(EINVAL),
+            }
+        }
+        cmd.clear();
+    }
 }
 
-fn api_set_peer(
+/// Parse the peer sections of a set request, the first one for `pub_key`, into changes to
+/// `update`
+fn parse_set_peer(
     reader: &mut BufReader<&UnixStream>,
-    d: &mut Device,
+    update: &mut DeviceUpdate,
     pub_key: x25519::PublicKey,
-) -> i32 {
+) -> Result<(), i32> {
     let mut cmd = String::new();
 
     let mut remove = false;
-    let mut replace_ips = false;
-    let mut endpoint = None;
-    let mut keepalive = None;
-    let mut public_key = pub_key;
-    let mut preshared_key = None;
-    let mut allowed_ips: Vec<AllowedIP> = vec![];
-    while reader.read_line(&mut cmd).is_ok() {
+    let mut config = PeerConfig::new(pub_key);
+    loop {
+        if reader.read_line(&mut cmd).is_err() {
+            return Err(EIO);
+        }
         cmd.pop(); // remove newline if any
         if cmd.is_empty() {
-            d.update_peer(
-                public_key,
-                remove,
-                replace_ips,
-                endpoint,
-                allowed_ips.as_slice(),
-                keepalive,
-                preshared_key,
-            );
-            allowed_ips.clear(); //clear the vector content after update
-            return 0; // Done
+            update.peers.push(peer_change(remove, config));
+            return Ok(()); // Done
         }
         {
             let parsed_cmd: Vec<&str> = cmd.splitn(2, '=').collect();
             if parsed_cmd.len() != 2 {
-                return EPROTO;
+                return Err(EPROTO);
             }
             let (key, val) = (parsed_cmd[0], parsed_cmd[1]);
             match key {
                 "remove" => match val.parse::<bool>() {
                     Ok(true) => remove = true,
                     Ok(false) => remove = false,
-                    Err(_) => return EINVAL,
+                    Err(_) => return Err(EINVAL),
                 },
                 "preshared_key" => match val.parse::<KeyBytes>() {
-                    Ok(key_bytes) => preshared_key = Some(key_bytes.0),
-                    Err(_) => return EINVAL,
+                    Ok(key_bytes) => config.preshared_key = Some(key_bytes.0),
+                    Err(_) => return Err(EINVAL),
                 },
-                "endpoint" => match val.parse::<SocketAddr>() {
-                    Ok(addr) => endpoint = Some(addr),
-                    Err(_) => return EINVAL,
+                "endpoint" => match val.parse::<PeerEndpoint>() {
+                    Ok(endpoint) => config.endpoint = Some(endpoint),
+                    Err(_) => return Err(EINVAL),
+                },
+                "endpoint_protocol" => match val.parse::<EndpointProtocol>() {
+                    Ok(p) => config.protocol = Some(p),
+                    Err(_) => return Err(EINVAL),
                 },
                 "persistent_keepalive_interval" => match val.parse::<u16>() {
-                    Ok(interval) => keepalive = Some(interval),
-                    Err(_) => return EINVAL,
+                    Ok(interval) => config.persistent_keepalive = Some(interval),
+                    Err(_) => return Err(EINVAL),
                 },
                 "replace_allowed_ips" => match val.parse::<bool>() {
-                    Ok(true) => replace_ips = true,
-                    Ok(false) => replace_ips = false,
-                    Err(_) => return EINVAL,
+                    Ok(replace) => config.replace_allowed_ips = replace,
+                    Err(_) => return Err(EINVAL),
                 },
-                "allowed_ip" => match val.parse::<AllowedIP>() {
-                    Ok(ip) => allowed_ips.push(ip),
-                    Err(_) => return EINVAL,
+                "allowed_ip" => match val.strip_prefix('-') {
+                    // A later line wins over an earlier one for the same prefix
+                    Some(val) => match val.parse::<AllowedIP>() {
+                        Ok(ip) => {
+                            config.allowed_ips.retain(|other| *other != ip);
+                            config.remove_allowed_ips.push(ip);
+                        }
+                        Err(_) => return Err(EINVAL),
+                    },
+                    None => match val.parse::<AllowedIP>() {
+                        Ok(ip) => {
+                            config.remove_allowed_ips.retain(|other| *other != ip);
+                            config.allowed_ips.push(ip);
+                        }
+                        Err(_) => return Err(EINVAL),
+                    },
                 },
                 "public_key" => {
-                    // Indicates a new peer section. Commit changes for current peer, and continue to next peer
-                    d.update_peer(
-                        public_key,
-                        remove,
-                        replace_ips,
-                        endpoint,
-                        allowed_ips.as_slice(),
-                        keepalive,
-                        preshared_key,
-                    );
-                    allowed_ips.clear(); //clear the vector content after update
-                    match val.parse::<KeyBytes>() {
-                        Ok(key_bytes) => public_key = key_bytes.0.into(),
-                        Err(_) => return EINVAL,
-                    }
+                    // Indicates a new peer section. Record changes for current peer, and continue to next peer
+                    let next_key = match val.parse::<KeyBytes>() {
+                        Ok(key_bytes) => x25519::PublicKey::from(key_bytes.0),
+                        Err(_) => return Err(EINVAL),
+                    };
+                    update.peers.push(peer_change(remove, config));
+                    remove = false;
+                    config = PeerConfig::new(next_key);
                 }
                 "protocol_version" => match val.parse::<u32>() {
                     Ok(1) => {} // Only version 1 is legal
-                    _ => return EINVAL,
+                    _ => return Err(EINVAL),
                 },
-                _ => return EINVAL,
+                _ => return Err(EINVAL),
             }
         }
         cmd.clear();
     }
-    0
+}
+
+/// Remove the peer, or add or update it with `config`
+fn peer_change(remove: bool, config: PeerConfig) -> PeerChange {
+    if remove {
+        PeerChange::Remove(config.public_key)
+    } else {
+        PeerChange::AddOrUpdate(config)
+    }
 }
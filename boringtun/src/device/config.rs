@@ -0,0 +1,111 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Typed configuration of a running device, see `DeviceHandle::apply`,
+//! `DeviceHandle::add_or_update_peer` and `DeviceHandle::get_config`. The UAPI `get` and `set`
+//! commands are translated to these.
+
+use super::peer::{AllowedIP, EndpointProtocol, PeerEndpoint};
+use crate::noise::rate_limiter::RateLimiterStats;
+use crate::noise::TunnStats;
+use crate::x25519;
+use std::net::SocketAddr;
+
+/// Changes to a peer. Adds the peer if the device does not have it yet, fields left at None
+/// keep their current value when updating an existing peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerConfig {
+    pub public_key: x25519::PublicKey,
+    pub endpoint: Option<PeerEndpoint>,
+    pub protocol: Option<EndpointProtocol>,
+    /// Allowed IPs to add to those of the peer
+    pub allowed_ips: Vec<AllowedIP>,
+    /// Allowed IPs to remove from those of the peer, exactly these prefixes and not those within
+    /// them. Ignored when replacing the allowed IPs.
+    pub remove_allowed_ips: Vec<AllowedIP>,
+    /// Make `allowed_ips` the only allowed IPs of the peer
+    pub replace_allowed_ips: bool,
+    /// Zero disables the persistent keepalive
+    pub persistent_keepalive: Option<u16>,
+    pub preshared_key: Option<[u8; 32]>,
+}
+
+impl PeerConfig {
+    /// A config that leaves everything about the peer unchanged
+    pub fn new(public_key: x25519::PublicKey) -> PeerConfig {
+        PeerConfig {
+            public_key,
+            endpoint: None,
+            protocol: None,
+            allowed_ips: vec![],
+            remove_allowed_ips: vec![],
+            replace_allowed_ips: false,
+            persistent_keepalive: None,
+            preshared_key: None,
+        }
+    }
+}
+
+/// Changes to a device, validated as a whole before any of them is applied, see
+/// `DeviceHandle::apply`. Fields left at None keep their current value.
+#[derive(Default)]
+pub struct DeviceUpdate {
+    pub private_key: Option<x25519::StaticSecret>,
+    /// Zero picks a random port
+    pub listen_port: Option<u16>,
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fwmark: Option<u32>,
+    /// See `DeviceConfig::handshake_rate_limit`
+    pub handshake_rate_limit: Option<u64>,
+    /// See `DeviceConfig::peer_handshake_rate_limit`
+    pub peer_handshake_rate_limit: Option<u64>,
+    /// Remove every peer before applying `peers`
+    pub replace_peers: bool,
+    /// Applied in order
+    pub peers: Vec<PeerChange>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerChange {
+    AddOrUpdate(PeerConfig),
+    Remove(x25519::PublicKey),
+}
+
+/// A snapshot of the configuration of a device and the state of its peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceState {
+    pub public_key: Option<x25519::PublicKey>,
+    /// Zero until the device listens on a port
+    pub listen_port: u16,
+    pub fwmark: Option<u32>,
+    pub handshake_rate_limit: u64,
+    pub peer_handshake_rate_limit: u64,
+    /// None until the device has a private key
+    pub rate_limiter: Option<RateLimiterState>,
+    pub peers: Vec<PeerState>,
+}
+
+/// Whether the device is under load, and what its rate limiter did about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimiterState {
+    /// Handshake messages received in the current second
+    pub handshake_rate: u64,
+    /// Whether handshake messages without a valid cookie get a cookie reply instead of being
+    /// processed
+    pub cookie_mode: bool,
+    pub stats: RateLimiterStats,
+}
+
+/// A snapshot of the configuration and the stats of a peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerState {
+    pub public_key: x25519::PublicKey,
+    pub endpoint: Option<SocketAddr>,
+    /// The `host:port` the endpoint is resolved from, when configured as a hostname
+    pub endpoint_host: Option<String>,
+    pub protocol: EndpointProtocol,
+    pub allowed_ips: Vec<AllowedIP>,
+    pub persistent_keepalive: Option<u16>,
+    pub preshared_key: Option<[u8; 32]>,
+    pub stats: TunnStats,
+}
@@ -0,0 +1,280 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Configures the device from a file in the WireGuard INI format, as `wg setconf` reads it, at
+//! startup and again on SIGHUP. Keys only wg-quick understands, such as Address and DNS, are
+//! ignored.
+
+use super::config::{DeviceUpdate, PeerChange, PeerConfig};
+use super::peer::{AllowedIP, PeerEndpoint};
+use super::{Action, Device, Error};
+use crate::serialization::KeyBytes;
+use crate::x25519;
+use libc::SIGHUP;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Keys of the [Interface] section that configure the system rather than the device
+const WG_QUICK_KEYS: [&str; 9] = [
+    "address",
+    "dns",
+    "mtu",
+    "table",
+    "preup",
+    "postup",
+    "predown",
+    "postdown",
+    "saveconfig",
+];
+
+enum Section {
+    None,
+    Interface,
+    Peer(Option<x25519::PublicKey>, PeerConfig),
+}
+
+/// Parse a configuration in the WireGuard INI format. The allowed IPs of each peer replace those
+/// it has, what happens to peers of the device that are not in it is up to the caller.
+pub fn parse_config(s: &str) -> Result<DeviceUpdate, String> {
+    let mut update = DeviceUpdate::default();
+    let mut section = Section::None;
+
+    for (i, line) in s.lines().enumerate() {
+        let at_line = |e: String| format!("line {}: {}", i + 1, e);
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            finish_section(&mut update, section).map_err(at_line)?;
+            section = match name.trim().to_ascii_lowercase().as_str() {
+                "interface" => Section::Interface,
+                "peer" => Section::Peer(None, new_peer_config()),
+                _ => return Err(at_line(format!("Unknown section [{}]", name))),
+            };
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at_line("Expected Key = Value".to_owned()))?;
+        let (key, value) = (key.trim().to_ascii_lowercase(), value.trim());
+        match &mut section {
+            Section::None => Err(format!("{} outside of a section", key)),
+            Section::Interface => parse_interface_key(&mut update, &key, value),
+            Section::Peer(public_key, config) => parse_peer_key(public_key, config, &key, value),
+        }
+        .map_err(at_line)?;
+    }
+
+    finish_section(&mut update, section)?;
+    Ok(update)
+}
+
+/// A peer config with every key of the file unset, the allowed IPs and keepalive are replaced
+/// even when the file leaves them out
+fn new_peer_config() -> PeerConfig {
+    PeerConfig {
+        replace_allowed_ips: true,
+        persistent_keepalive: Some(0),
+        ..PeerConfig::new(x25519::PublicKey::from([0; 32]))
+    }
+}
+
+fn finish_section(update: &mut DeviceUpdate, section: Section) -> Result<(), String> {
+    if let Section::Peer(public_key, config) = section {
+        let public_key = public_key.ok_or("[Peer] without a PublicKey")?;
+        update.peers.push(PeerChange::AddOrUpdate(PeerConfig {
+            public_key,
+            ..config
+        }));
+    }
+    Ok(())
+}
+
+fn parse_interface_key(update: &mut DeviceUpdate, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "privatekey" => update.private_key = Some(x25519::StaticSecret::from(parse_key(value)?)),
+        "listenport" => {
+            update.listen_port = Some(value.parse().map_err(|_| "Invalid ListenPort")?);
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        "fwmark" => update.fwmark = Some(parse_fwmark(value)?),
+        _ if WG_QUICK_KEYS.contains(&key) => {}
+        _ => return Err(format!("Unknown key {} in [Interface]", key)),
+    }
+    Ok(())
+}
+
+fn parse_peer_key(
+    public_key: &mut Option<x25519::PublicKey>,
+    config: &mut PeerConfig,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    match key {
+        "publickey" => *public_key = Some(x25519::PublicKey::from(parse_key(value)?)),
+        "presharedkey" => config.preshared_key = Some(parse_key(value)?),
+        "allowedips" => {
+            for ip in value.split(',').map(str::trim).filter(|ip| !ip.is_empty()) {
+                config.allowed_ips.push(ip.parse::<AllowedIP>()?);
+            }
+        }
+        "endpoint" => {
+            let endpoint = value.parse::<PeerEndpoint>();
+            config.endpoint = Some(endpoint.map_err(|_| format!("Invalid Endpoint {}", value))?);
+        }
+        "persistentkeepalive" => {
+            config.persistent_keepalive = Some(match value {
+                "off" => 0,
+                _ => value.parse().map_err(|_| "Invalid PersistentKeepalive")?,
+            });
+        }
+        _ => return Err(format!("Unknown key {} in [Peer]", key)),
+    }
+    Ok(())
+}
+
+fn parse_key(value: &str) -> Result<[u8; 32], String> {
+    value
+        .parse::<KeyBytes>()
+        .map(|key| key.0)
+        .map_err(|e| e.to_owned())
+}
+
+/// `off`, or a mark in decimal or in hex with a 0x prefix
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn parse_fwmark(value: &str) -> Result<u32, String> {
+    let mark = match value.strip_prefix("0x") {
+        _ if value == "off" => Ok(0),
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    mark.map_err(|_| format!("Invalid FwMark {}", value))
+}
+
+/// Read and parse the configuration file at `path`
+pub(super) fn read_config_file(path: &Path) -> Result<DeviceUpdate, Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| Error::ConfigFile(format!("{}: {}", path.display(), e)))?;
+    parse_config(&contents).map_err(|e| Error::ConfigFile(format!("{}: {}", path.display(), e)))
+}
+
+impl Device {
+    /// Apply a configuration read from a file. Peers that are not in it are removed, those that
+    /// stay keep their sessions.
+    pub(super) fn apply_config_file(&mut self, mut update: DeviceUpdate) -> Result<(), Error> {
+        let listed: HashSet<_> = update
+            .peers
+            .iter()
+            .filter_map(|change| match change {
+                PeerChange::AddOrUpdate(config) => Some(config.public_key),
+                PeerChange::Remove(_) => None,
+            })
+            .collect();
+        for public_key in self.peers.keys() {
+            if !listed.contains(public_key) {
+                update.peers.push(PeerChange::Remove(*public_key));
+            }
+        }
+        self.apply(update)
+    }
+
+    /// Apply the configuration file at `path` again on every SIGHUP
+    pub(super) fn register_config_reload(&self, path: PathBuf) -> Result<(), Error> {
+        self.queue.new_signal_event(
+            SIGHUP,
+            Box::new(move |d, _| {
+                // The file is read before taking write access to the device
+                let update = match read_config_file(&path) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        tracing::error!(message = "Failed to reload configuration", error = ?e);
+                        return Action::Continue;
+                    }
+                };
+
+                let result = d.try_writeable(
+                    |device| device.trigger_yield(),
+                    |device| {
+                        device.cancel_yield();
+                        device.apply_config_file(update)
+                    },
+                );
+                match result {
+                    Some(Ok(())) => tracing::info!("Configuration reloaded"),
+                    Some(Err(e)) => {
+                        tracing::error!(message = "Failed to apply configuration", error = ?e)
+                    }
+                    None => {}
+                }
+                Action::Continue
+            }),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    #[test]
+    fn parse_wg_quick_config() {
+        let config = format!(
+            "# A comment\n\
+             [Interface]\n\
+             PrivateKey = {}\n\
+             ListenPort = 51820\n\
+             Address = 10.0.0.1/24\n\
+             \n\
+             [Peer]\n\
+             AllowedIPs = 10.0.0.2/32, fd00::2/128 # Inline comment\n\
+             PublicKey = {}\n\
+             Endpoint = vpn.example.com:51820\n\
+             PersistentKeepalive = 25\n",
+            PRIVATE_KEY, PUBLIC_KEY
+        );
+        let update = parse_config(&config).unwrap();
+
+        assert!(update.private_key.is_some());
+        assert_eq!(update.listen_port, Some(51820));
+        assert!(!update.replace_peers);
+        let public_key = x25519::PublicKey::from(PUBLIC_KEY.parse::<KeyBytes>().unwrap().0);
+        let expected = PeerConfig {
+            endpoint: Some("vpn.example.com:51820".parse().unwrap()),
+            allowed_ips: vec![
+                "10.0.0.2/32".parse().unwrap(),
+                "fd00::2/128".parse().unwrap(),
+            ],
+            replace_allowed_ips: true,
+            persistent_keepalive: Some(25),
+            ..PeerConfig::new(public_key)
+        };
+        assert_eq!(update.peers, [PeerChange::AddOrUpdate(expected)]);
+    }
+
+    #[test]
+    fn parse_config_errors() {
+        for (config, error) in [
+            ("ListenPort = 1", "line 1: listenport outside of a section"),
+            ("[Interface]\nListenPort = x", "line 2: Invalid ListenPort"),
+            (
+                "[Interface]\nFoo = 1",
+                "line 2: Unknown key foo in [Interface]",
+            ),
+            ("[Peers]", "line 1: Unknown section [Peers]"),
+            (
+                "[Peer]\nAllowedIPs = 10.0.0.2/32",
+                "[Peer] without a PublicKey",
+            ),
+            ("[Peer]\nEndpoint", "line 2: Expected Key = Value"),
+        ] {
+            assert_eq!(parse_config(config).err().as_deref(), Some(error));
+        }
+    }
+}
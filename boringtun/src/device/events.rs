@@ -0,0 +1,60 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::noise::TunnEvent;
+use crate::x25519;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Something that happened to a device or one of its peers, see `DeviceHandle::subscribe`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// A handshake with the peer established a new session
+    HandshakeCompleted { public_key: x25519::PublicKey },
+    /// No session with the peer could be established in time, or the last one grew too old
+    ConnectionExpired { public_key: x25519::PublicKey },
+    /// The peer was heard from a new endpoint, which is used from now on
+    EndpointChanged {
+        public_key: x25519::PublicKey,
+        endpoint: SocketAddr,
+    },
+    /// The peer is under load, and demanded a cookie with our next handshake message
+    CookieReceived { public_key: x25519::PublicKey },
+    /// The device is under load, and demanded a cookie from the sender of a handshake message,
+    /// before knowing which peer it is
+    CookieSent { endpoint: SocketAddr },
+}
+
+impl DeviceEvent {
+    /// The device event for an event of the tunnel of the peer with `public_key`
+    pub(crate) fn from_tunn(event: TunnEvent, public_key: x25519::PublicKey) -> Option<Self> {
+        match event {
+            TunnEvent::HandshakeCompleted => Some(DeviceEvent::HandshakeCompleted { public_key }),
+            TunnEvent::ConnectionExpired => Some(DeviceEvent::ConnectionExpired { public_key }),
+            TunnEvent::CookieReceived => Some(DeviceEvent::CookieReceived { public_key }),
+            // The device answers with cookies itself, before packets reach a tunnel
+            TunnEvent::CookieSent => None,
+        }
+    }
+}
+
+/// The channels events are delivered to, subscribers that hung up are dropped on the next event
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    senders: Mutex<Vec<Sender<DeviceEvent>>>,
+}
+
+impl Subscribers {
+    pub(crate) fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = channel();
+        self.senders.lock().push(sender);
+        receiver
+    }
+
+    pub(crate) fn emit(&self, event: DeviceEvent) {
+        self.senders
+            .lock()
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
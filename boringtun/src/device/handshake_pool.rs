@@ -0,0 +1,177 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Processes handshake messages on worker threads of their own, so that a flood of them leaves
+//! the event loop threads free to move the data packets of established sessions. Under load,
+//! messages with a valid mac2 cookie go ahead of the others, those that find the queue full are
+//! dropped.
+
+use super::dev_lock::Lock;
+use super::transport::TransportSocket;
+use super::{Device, MAX_UDP_SIZE};
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// How many handshake messages may wait for a worker
+pub(crate) const HANDSHAKE_QUEUE_SIZE: usize = 1024;
+
+/// A handshake message that passed the rate limiter, and where it came from
+pub(crate) struct HandshakeJob {
+    pub(crate) datagram: Vec<u8>,
+    pub(crate) addr: SocketAddr,
+    pub(crate) udp: Arc<dyn TransportSocket>,
+}
+
+/// Totals since the device started, and the current depth of the queue
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct HandshakeQueueStats {
+    pub(crate) queued: usize,
+    pub(crate) processed: u64,
+    /// Messages dropped because the queue was full
+    pub(crate) dropped: u64,
+}
+
+#[derive(Default)]
+struct Queues {
+    /// Messages with a valid mac2
+    cookie: VecDeque<HandshakeJob>,
+    other: VecDeque<HandshakeJob>,
+    shutdown: bool,
+}
+
+/// A bounded queue of handshake messages, shared by the workers
+pub(crate) struct HandshakeQueue {
+    queues: Mutex<Queues>,
+    available: Condvar,
+    capacity: usize,
+    processed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl HandshakeQueue {
+    pub(crate) fn new(capacity: usize) -> HandshakeQueue {
+        HandshakeQueue {
+            queues: Default::default(),
+            available: Condvar::new(),
+            capacity,
+            processed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a message. When the queue is full a message with a valid mac2 takes the place of the
+    /// oldest one without, and any other message is dropped.
+    pub(crate) fn push(&self, job: HandshakeJob, valid_mac2: bool) {
+        let mut queues = self.queues.lock();
+        if queues.cookie.len() + queues.other.len() >= self.capacity {
+            // Either this message or the oldest one without a cookie is dropped
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            if !valid_mac2 || queues.other.pop_front().is_none() {
+                return;
+            }
+        }
+
+        match valid_mac2 {
+            true => queues.cookie.push_back(job),
+            false => queues.other.push_back(job),
+        }
+        self.available.notify_one();
+    }
+
+    /// Wait for the next message, None once the queue is shut down
+    pub(crate) fn pop(&self) -> Option<HandshakeJob> {
+        let mut queues = self.queues.lock();
+        loop {
+            if queues.shutdown {
+                return None;
+            }
+            if let Some(job) = queues
+                .cookie
+                .pop_front()
+                .or_else(|| queues.other.pop_front())
+            {
+                self.processed.fetch_add(1, Ordering::Relaxed);
+                return Some(job);
+            }
+            self.available.wait(&mut queues);
+        }
+    }
+
+    /// Wake the workers up to exit, dropping the messages still queued
+    pub(crate) fn shutdown(&self) {
+        let mut queues = self.queues.lock();
+        queues.shutdown = true;
+        queues.cookie.clear();
+        queues.other.clear();
+        self.available.notify_all();
+    }
+
+    pub(crate) fn stats(&self) -> HandshakeQueueStats {
+        let queues = self.queues.lock();
+        HandshakeQueueStats {
+            queued: queues.cookie.len() + queues.other.len(),
+            processed: self.processed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Process queued handshake messages until the device shuts the queue down. Read access to the
+/// device is only held for one message at a time, so that writers are not kept waiting.
+pub(crate) fn handshake_worker(device: &Lock<Device>) {
+    let queue = match device.read().handshakes.as_ref() {
+        Some(queue) => Arc::clone(queue),
+        None => return,
+    };
+    let mut dst = vec![0u8; MAX_UDP_SIZE];
+
+    while let Some(job) = queue.pop() {
+        device.read().process_handshake(job, &mut dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::transport::{Transport, UdpTransport};
+
+    fn job(udp: &Arc<dyn TransportSocket>, n: u8) -> HandshakeJob {
+        HandshakeJob {
+            datagram: vec![n],
+            addr: SocketAddr::from(([127, 0, 0, 1], 51820)),
+            udp: Arc::clone(udp),
+        }
+    }
+
+    #[test]
+    fn cookie_messages_go_first_and_take_the_place_of_others() {
+        let udp = UdpTransport::default()
+            .bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        let queue = HandshakeQueue::new(2);
+
+        queue.push(job(&udp, 1), false);
+        queue.push(job(&udp, 2), true);
+        queue.push(job(&udp, 3), false); // Dropped
+        queue.push(job(&udp, 4), true); // Drops 1
+        assert_eq!(
+            queue.stats(),
+            HandshakeQueueStats {
+                queued: 2,
+                processed: 0,
+                dropped: 2,
+            }
+        );
+
+        let order: Vec<_> = (0..2).map(|_| queue.pop().unwrap().datagram[0]).collect();
+        assert_eq!(order, [2, 4]);
+
+        queue.push(job(&udp, 5), false);
+        queue.shutdown();
+        assert!(queue.pop().is_none());
+        assert_eq!(queue.stats().processed, 2);
+    }
+}
@@ -261,11 +261,27 @@ mod tests {
                 addr_v6,
                 DeviceConfig {
                     n_threads: 2,
+                    handshake_threads: 2,
+                    handshake_rate_limit: 100,
+                    peer_handshake_rate_limit: 10,
+                    source_limit: None,
                     use_connected_socket: true,
                     #[cfg(target_os = "linux")]
                     use_multi_queue: true,
                     #[cfg(target_os = "linux")]
                     uapi_fd: -1,
+                    #[cfg(target_os = "linux")]
+                    use_mmsg: true,
+                    #[cfg(target_os = "linux")]
+                    use_udp_offload: true,
+                    #[cfg(target_os = "linux")]
+                    use_tun_offload: false,
+                    obfuscation: None,
+                    metrics: None,
+                    config_file: None,
+                    #[cfg(target_os = "linux")]
+                    network: None,
+                    resolver: None,
                 },
             )
         }
@@ -553,11 +569,27 @@ mod tests {
             addr_v6,
             DeviceConfig {
                 n_threads: 2,
+                handshake_threads: 2,
+                handshake_rate_limit: 100,
+                peer_handshake_rate_limit: 10,
+                source_limit: None,
                 use_connected_socket: false,
                 #[cfg(target_os = "linux")]
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                #[cfg(target_os = "linux")]
+                use_mmsg: true,
+                #[cfg(target_os = "linux")]
+                use_udp_offload: true,
+                #[cfg(target_os = "linux")]
+                use_tun_offload: false,
+                obfuscation: None,
+                metrics: None,
+                config_file: None,
+                #[cfg(target_os = "linux")]
+                network: None,
+                resolver: None,
             },
         );
 
@@ -711,11 +743,27 @@ mod tests {
             addr_v6,
             DeviceConfig {
                 n_threads: 2,
+                handshake_threads: 2,
+                handshake_rate_limit: 100,
+                peer_handshake_rate_limit: 10,
+                source_limit: None,
                 use_connected_socket: false,
                 #[cfg(target_os = "linux")]
                 use_multi_queue: true,
                 #[cfg(target_os = "linux")]
                 uapi_fd: -1,
+                #[cfg(target_os = "linux")]
+                use_mmsg: true,
+                #[cfg(target_os = "linux")]
+                use_udp_offload: true,
+                #[cfg(target_os = "linux")]
+                use_tun_offload: false,
+                obfuscation: None,
+                metrics: None,
+                config_file: None,
+                #[cfg(target_os = "linux")]
+                network: None,
+                resolver: None,
             },
         );
 
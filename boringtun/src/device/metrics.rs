@@ -0,0 +1,332 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Serves the device's metrics in the OpenMetrics text format, over HTTP on a TCP or a Unix
+//! socket. Every request is answered with the metrics, whatever its method and path.
+
+use super::{Action, Device, Error};
+use crate::noise::errors::WireGuardError;
+use crate::noise::TunnStats;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Reads one value of a peer's stats, None leaves the peer out
+type PeerMetric = fn(&TunnStats) -> Option<f64>;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// How long a client gets to send its request and read the response, the worker thread serving
+/// it is blocked meanwhile
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where to serve metrics, `unix:` followed by a path for a Unix socket, or a TCP socket address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for MetricsAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(MetricsAddr::Unix(path.into())),
+            Some(_) => Err("Expected a path after unix:".to_owned()),
+            None => s
+                .parse()
+                .map(MetricsAddr::Tcp)
+                .map_err(|_| format!("Invalid metrics address {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MetricsAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetricsAddr::Tcp(addr) => write!(f, "{}", addr),
+            MetricsAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Device {
+    /// Register the metrics handler for this Device, which answers HTTP requests on `addr`
+    pub fn register_metrics_handler(&mut self, addr: &MetricsAddr) -> Result<(), Error> {
+        match addr {
+            MetricsAddr::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).map_err(Error::MetricsSocket)?;
+                self.queue.new_event(
+                    listener.as_raw_fd(),
+                    Box::new(move |d, _| {
+                        if let Ok((conn, _)) = listener.accept() {
+                            let _ = conn.set_read_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = conn.set_write_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = serve_metrics(&conn, d);
+                        }
+                        Action::Continue
+                    }),
+                )?;
+            }
+            MetricsAddr::Unix(path) => {
+                let _ = std::fs::remove_file(path); // Attempt to remove the socket if already exists
+                let listener = UnixListener::bind(path).map_err(Error::MetricsSocket)?;
+                self.cleanup_paths.push(path.display().to_string());
+                self.queue.new_event(
+                    listener.as_raw_fd(),
+                    Box::new(move |d, _| {
+                        if let Ok((conn, _)) = listener.accept() {
+                            let _ = conn.set_read_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = conn.set_write_timeout(Some(CLIENT_TIMEOUT));
+                            let _ = serve_metrics(&conn, d);
+                        }
+                        Action::Continue
+                    }),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The metrics of the device and its peers, in the OpenMetrics text format
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        let iface = self.iface.name().unwrap_or_default();
+        let mut families = Families::new(&mut out);
+
+        families.header("boringtun_peers", "gauge", "Number of peers");
+        families.sample(
+            "boringtun_peers",
+            &[("interface", &iface)],
+            self.peers.len(),
+        );
+
+        let peer_families: [(&str, &str, &str, PeerMetric); 8] = [
+            (
+                "boringtun_peer_rx_bytes",
+                "counter",
+                "Data bytes received",
+                |s| Some(s.rx_bytes as f64),
+            ),
+            (
+                "boringtun_peer_tx_bytes",
+                "counter",
+                "Data bytes sent",
+                |s| Some(s.tx_bytes as f64),
+            ),
+            (
+                "boringtun_peer_last_handshake_seconds",
+                "gauge",
+                "Seconds since the last handshake",
+                |s| s.time_since_last_handshake.map(|t| t.as_secs_f64()),
+            ),
+            (
+                "boringtun_peer_estimated_loss_ratio",
+                "gauge",
+                "Estimated fraction of packets lost",
+                |s| Some(f64::from(s.estimated_loss)),
+            ),
+            (
+                "boringtun_peer_rtt_seconds",
+                "gauge",
+                "Time it took to complete the latest initiated handshake",
+                |s| s.estimated_rtt.map(|rtt| f64::from(rtt) / 1000.0),
+            ),
+            (
+                "boringtun_peer_handshakes_initiated",
+                "counter",
+                "Handshake initiations sent",
+                |s| Some(s.handshakes_initiated as f64),
+            ),
+            (
+                "boringtun_peer_handshakes_completed",
+                "counter",
+                "Handshakes that established a session",
+                |s| Some(s.handshakes_completed as f64),
+            ),
+            (
+                "boringtun_peer_handshakes_failed",
+                "counter",
+                "Handshake initiations that got no response in time",
+                |s| Some(s.handshakes_failed as f64),
+            ),
+        ];
+        let stats: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(key, peer)| (base64::encode(key.as_bytes()), peer.lock().tunnel.stats()))
+            .collect();
+
+        for (name, kind, help, metric) in peer_families {
+            families.header(name, kind, help);
+            for (key, s) in &stats {
+                if let Some(value) = metric(s) {
+                    let labels = [("interface", iface.as_str()), ("public_key", key.as_str())];
+                    families.sample(name, &labels, value);
+                }
+            }
+        }
+
+        let name = "boringtun_peer_dropped_packets";
+        families.header(
+            name,
+            "counter",
+            "Received packets that were dropped, by reason",
+        );
+        for (key, s) in &stats {
+            for (reason, count) in WireGuardError::ALL.iter().zip(s.dropped_packets) {
+                if count > 0 {
+                    let labels = [
+                        ("interface", iface.as_str()),
+                        ("public_key", key.as_str()),
+                        ("reason", reason.name()),
+                    ];
+                    families.sample(name, &labels, count);
+                }
+            }
+        }
+
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            let s = rate_limiter.stats();
+            let labels = [("interface", iface.as_str())];
+            for (name, help, value) in [
+                (
+                    "boringtun_rate_limiter_handshakes",
+                    "Handshake messages with a valid mac1",
+                    s.handshakes,
+                ),
+                (
+                    "boringtun_rate_limiter_under_load",
+                    "Handshake messages that arrived while over the limit",
+                    s.under_load,
+                ),
+                (
+                    "boringtun_rate_limiter_cookie_replies",
+                    "Cookie replies sent",
+                    s.cookie_replies,
+                ),
+                (
+                    "boringtun_rate_limiter_invalid_mac",
+                    "Handshake messages dropped for an invalid mac1",
+                    s.invalid_mac,
+                ),
+                (
+                    "boringtun_rate_limiter_invalid_mac2",
+                    "Handshake messages with a mac2 that is not the cookie of their sender",
+                    s.invalid_mac2,
+                ),
+                (
+                    "boringtun_rate_limiter_rate_limited",
+                    "Handshake messages dropped under load for exceeding the rate of their source",
+                    s.rate_limited,
+                ),
+            ] {
+                families.header(name, "counter", help);
+                families.sample(name, &labels, value);
+            }
+        }
+
+        if let Some(handshakes) = self.handshakes.as_ref() {
+            let s = handshakes.stats();
+            let labels = [("interface", iface.as_str())];
+            let name = "boringtun_handshake_queue_depth";
+            families.header(name, "gauge", "Handshake messages waiting for a worker");
+            families.sample(name, &labels, s.queued);
+            for (name, help, value) in [
+                (
+                    "boringtun_handshake_queue_processed",
+                    "Handshake messages taken off the queue by a worker",
+                    s.processed,
+                ),
+                (
+                    "boringtun_handshake_queue_dropped",
+                    "Handshake messages dropped because the queue was full",
+                    s.dropped,
+                ),
+            ] {
+                families.header(name, "counter", help);
+                families.sample(name, &labels, value);
+            }
+        }
+
+        let name = "boringtun_thread_busy_seconds";
+        families.header(
+            name,
+            "counter",
+            "Time event loop threads spent handling events, its rate is their utilisation",
+        );
+        for (thread, busy) in self.thread_busy.iter().enumerate() {
+            let busy = Duration::from_nanos(busy.load(Ordering::Relaxed));
+            let thread = thread.to_string();
+            let labels = [("interface", iface.as_str()), ("thread", thread.as_str())];
+            families.sample(name, &labels, busy.as_secs_f64());
+        }
+
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Writes metric families, with the `_total` suffix on counter samples
+struct Families<'a> {
+    out: &'a mut String,
+    counter: bool,
+}
+
+impl<'a> Families<'a> {
+    fn new(out: &'a mut String) -> Self {
+        Families {
+            out,
+            counter: false,
+        }
+    }
+
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        self.counter = kind == "counter";
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        let suffix = if self.counter { "_total" } else { "" };
+        let _ = write!(self.out, "{}{}{{", name, suffix);
+        for (i, (label, value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            let _ = write!(self.out, "{}{}=\"{}\"", separator, label, value);
+        }
+        let _ = writeln!(self.out, "}} {}", value);
+    }
+}
+
+/// Read the request up to its empty line, and answer with the metrics
+fn serve_metrics<S>(conn: S, d: &Device) -> io::Result<()>
+where
+    S: Read + Write,
+{
+    let mut reader = BufReader::new(conn);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line.trim_end() != "" {
+        line.clear();
+    }
+
+    let body = d.render_metrics();
+    let mut conn = reader.into_inner();
+    write!(
+        conn,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+    conn.flush()
+}
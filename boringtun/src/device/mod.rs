@@ -3,11 +3,28 @@
 
 pub mod allowed_ips;
 pub mod api;
+pub mod config;
+pub mod config_file;
 mod dev_lock;
 pub mod drop_privileges;
+pub mod events;
+mod handshake_pool;
 #[cfg(test)]
 mod integration_tests;
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+#[cfg(feature = "netstack")]
+pub mod netstack;
 pub mod peer;
+mod pollable_queue;
+pub mod resolver;
+#[cfg(all(test, target_os = "linux"))]
+mod tests;
+mod timer_wheel;
+pub mod transport;
+pub mod udp_batch;
+pub mod virtual_interface;
 
 #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
 #[path = "kqueue.rs"]
@@ -25,28 +42,45 @@ pub mod tun;
 #[path = "tun_linux.rs"]
 pub mod tun;
 
+#[cfg(target_os = "linux")]
+mod tun_offload;
+
 use std::collections::HashMap;
-use std::io::{self, Write as _};
-use std::mem::MaybeUninit;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::os::unix::io::AsRawFd;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use crate::noise::errors::WireGuardError;
-use crate::noise::handshake::parse_handshake_anon;
-use crate::noise::rate_limiter::RateLimiter;
-use crate::noise::{Packet, Tunn, TunnResult};
+use crate::noise::handshake::{HalfHandshake, ResponderKey};
+use crate::noise::obfuscation::Obfuscation;
+use crate::noise::rate_limiter::{RateLimiter, SourceLimit};
+use crate::noise::{
+    Packet, PresharedKeyHandle, Tunn, TunnResult, DATA_OVERHEAD_SZ, HANDSHAKE_INIT_SZ,
+    PEER_HANDSHAKE_RATE_LIMIT,
+};
 use crate::x25519;
-use allowed_ips::AllowedIps;
+use allowed_ips::{AllowedIps, AllowedIpsDiff};
+use config::{DeviceState, DeviceUpdate, PeerChange, PeerConfig, PeerState, RateLimiterState};
+use events::{DeviceEvent, Subscribers};
+use handshake_pool::{HandshakeJob, HandshakeQueue, HANDSHAKE_QUEUE_SIZE};
+use metrics::MetricsAddr;
+#[cfg(target_os = "linux")]
+use netlink::{NetworkConfig, NetworkSetup};
 use parking_lot::Mutex;
-use peer::{AllowedIP, Peer};
+use peer::{AllowedIP, EndpointProtocol, Peer, PeerEndpoint};
 use poll::{EventPoll, EventRef, WaitResult};
 use rand_core::{OsRng, RngCore};
-use socket2::{Domain, Protocol, Type};
+use resolver::Resolver;
+use timer_wheel::TimerWheel;
+use transport::{Datagram, ObfuscatedTransport, Transport, TransportSocket, UdpTransport};
 use tun::TunSocket;
+use udp_batch::MAX_BATCH;
+use virtual_interface::VirtualInterface;
 
 use dev_lock::{Lock, LockReadGuard};
 
@@ -55,6 +89,9 @@ const HANDSHAKE_RATE_LIMIT: u64 = 100; // The number of handshakes per second we
 const MAX_UDP_SIZE: usize = (1 << 16) - 1;
 const MAX_ITR: usize = 100; // Number of packets to handle per handler call
 
+const TIMER_TICK: Duration = Duration::from_millis(250);
+const TIMER_SLOTS: usize = 1024;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("i/o error: {0}")]
@@ -87,8 +124,19 @@ pub enum Error {
     IfaceRead(io::Error),
     #[error("{0}")]
     DropPrivileges(String),
+    #[error("Invalid obfuscation: {0}")]
+    Obfuscation(String),
     #[error("API socket error: {0}")]
     ApiSocket(io::Error),
+    #[error("Metrics socket error: {0}")]
+    MetricsSocket(io::Error),
+    #[cfg(target_os = "linux")]
+    #[error("Netlink error: {0}")]
+    Netlink(io::Error),
+    #[error("Invalid configuration file {0}")]
+    ConfigFile(String),
+    #[error("The private key must be set before adding peers")]
+    NoPrivateKey,
 }
 
 // What the event loop should do after a handler returns
@@ -101,44 +149,96 @@ enum Action {
 // Event handler function
 type Handler = Box<dyn Fn(&mut LockReadGuard<Device>, &mut ThreadData) -> Action + Send + Sync>;
 
+// A peer whose timers are due at the given time, unless they were rescheduled since
+type PeerTimer = (Weak<Mutex<Peer>>, Instant);
+
 pub struct DeviceHandle {
     device: Arc<Lock<Device>>, // The interface this handle owns
     threads: Vec<JoinHandle<()>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DeviceConfig {
     pub n_threads: usize,
+    /// Threads that process handshake messages off the event loop, to keep floods of them from
+    /// holding up data packets. Zero, the default, processes them inline.
+    pub handshake_threads: usize,
+    /// Handshake messages per second the device takes before asking for cookies
+    pub handshake_rate_limit: u64,
+    /// Handshake messages per second a peer takes before asking for cookies, while the device
+    /// has no private key to rate limit them all at once
+    pub peer_handshake_rate_limit: u64,
+    /// How many handshake messages each source address may send while over
+    /// `handshake_rate_limit`, or None, the default, to not limit sources on their own
+    pub source_limit: Option<SourceLimit>,
     pub use_connected_socket: bool,
     #[cfg(target_os = "linux")]
     pub use_multi_queue: bool,
     #[cfg(target_os = "linux")]
     pub uapi_fd: i32,
+    /// Move batches of datagrams with recvmmsg/sendmmsg
+    #[cfg(target_os = "linux")]
+    pub use_mmsg: bool,
+    /// Let the kernel coalesce and segment datagrams with UDP GRO/GSO, requires use_mmsg
+    #[cfg(target_os = "linux")]
+    pub use_udp_offload: bool,
+    /// Open the tunnel interface with IFF_VNET_HDR, and segment and coalesce TCP packets ourselves
+    #[cfg(target_os = "linux")]
+    pub use_tun_offload: bool,
+    /// Disguise the datagrams exchanged with peers, which must use the same obfuscation
+    pub obfuscation: Option<Obfuscation>,
+    /// Serve OpenMetrics over HTTP on this address
+    pub metrics: Option<MetricsAddr>,
+    /// Configure the device from this file in the WireGuard INI format, at startup and again on
+    /// SIGHUP
+    pub config_file: Option<PathBuf>,
+    /// Set up the addresses, MTU and routes of the interface
+    #[cfg(target_os = "linux")]
+    pub network: Option<NetworkConfig>,
+    /// Resolves the hostnames of peer endpoints, the system resolver when None
+    pub resolver: Option<Arc<dyn Resolver>>,
 }
 
 impl Default for DeviceConfig {
     fn default() -> Self {
         DeviceConfig {
             n_threads: 4,
+            handshake_threads: 0,
+            handshake_rate_limit: HANDSHAKE_RATE_LIMIT,
+            peer_handshake_rate_limit: PEER_HANDSHAKE_RATE_LIMIT,
+            source_limit: None,
             use_connected_socket: true,
             #[cfg(target_os = "linux")]
             use_multi_queue: true,
             #[cfg(target_os = "linux")]
             uapi_fd: -1,
+            #[cfg(target_os = "linux")]
+            use_mmsg: true,
+            #[cfg(target_os = "linux")]
+            use_udp_offload: true,
+            #[cfg(target_os = "linux")]
+            use_tun_offload: false,
+            obfuscation: None,
+            metrics: None,
+            config_file: None,
+            #[cfg(target_os = "linux")]
+            network: None,
+            resolver: None,
         }
     }
 }
 
 pub struct Device {
-    key_pair: Option<(x25519::StaticSecret, x25519::PublicKey)>,
+    key_pair: Option<ResponderKey>,
     queue: Arc<EventPoll<Handler>>,
 
     listen_port: u16,
     fwmark: Option<u32>,
 
-    iface: Arc<TunSocket>,
-    udp4: Option<socket2::Socket>,
-    udp6: Option<socket2::Socket>,
+    iface: Arc<dyn VirtualInterface>,
+    transport: Arc<dyn Transport>,
+    sock4: Option<Arc<dyn TransportSocket>>,
+    sock6: Option<Arc<dyn TransportSocket>>,
 
     yield_notice: Option<EventRef>,
     exit_notice: Option<EventRef>,
@@ -155,22 +255,92 @@ pub struct Device {
     mtu: AtomicUsize,
 
     rate_limiter: Option<Arc<RateLimiter>>,
+    /// Handshake messages waiting for the workers, None when processed inline
+    handshakes: Option<Arc<HandshakeQueue>>,
+
+    /// The peers, by when their timers are due
+    timers: Arc<Mutex<TimerWheel<PeerTimer>>>,
+    /// Nanoseconds each event loop thread spent in handlers
+    thread_busy: Vec<AtomicU64>,
+    events: Arc<Subscribers>,
+    /// The addresses, routes and rules set up on the interface
+    #[cfg(target_os = "linux")]
+    network: Option<NetworkSetup>,
 
     #[cfg(target_os = "linux")]
     uapi_fd: i32,
 }
 
+/// Sockets bound on the listen port, before the device uses them
+struct ListenSockets {
+    sock4: Arc<dyn TransportSocket>,
+    sock6: Arc<dyn TransportSocket>,
+    port: u16,
+}
+
 struct ThreadData {
-    iface: Arc<TunSocket>,
-    src_buf: [u8; MAX_UDP_SIZE],
+    iface: Arc<dyn VirtualInterface>,
     dst_buf: [u8; MAX_UDP_SIZE],
+    /// MAX_BATCH slots of MAX_UDP_SIZE bytes, for packets read from the network or the interface
+    src_batch: Vec<u8>,
+    /// MAX_BATCH slots of MAX_UDP_SIZE bytes, for the results of processing src_batch
+    dst_batch: Vec<u8>,
+    /// The datagrams received into src_batch
+    datagrams: Vec<Datagram>,
+    /// Room for a single super-packet read from the interface with offloads enabled
+    iface_scratch: Vec<u8>,
 }
 
 impl DeviceHandle {
     pub fn new(name: &str, config: DeviceConfig) -> Result<DeviceHandle, Error> {
-        let n_threads = config.n_threads;
-        let mut wg_interface = Device::new(name, config)?;
+        let transport = Arc::new(Device::default_transport(&config));
+        DeviceHandle::new_with_transport(name, config, transport)
+    }
+
+    /// Create a device that exchanges datagrams with its peers over the given transport, rather
+    /// than kernel UDP sockets
+    pub fn new_with_transport(
+        name: &str,
+        config: DeviceConfig,
+        transport: Arc<dyn Transport>,
+    ) -> Result<DeviceHandle, Error> {
+        // Create a tunnel device
+        let iface = Arc::new(Device::open_tun(name, &config)?.set_non_blocking()?);
+        #[allow(unused_mut)]
+        let mut device = Device::new(iface, config, transport)?;
+
+        #[cfg(target_os = "macos")]
+        {
+            // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
+            if let Ok(name_file) = std::env::var("WG_TUN_NAME_FILE") {
+                if name == "utun" {
+                    std::fs::write(&name_file, device.iface.name().unwrap().as_bytes()).unwrap();
+                    device.cleanup_paths.push(name_file);
+                }
+            }
+        }
+
+        DeviceHandle::start(device)
+    }
+
+    /// Create a device that exchanges packets with the system over the given interface rather than
+    /// a TUN device, and datagrams with its peers over the given transport
+    pub fn new_with_interface(
+        iface: Arc<dyn VirtualInterface>,
+        config: DeviceConfig,
+        transport: Arc<dyn Transport>,
+    ) -> Result<DeviceHandle, Error> {
+        DeviceHandle::start(Device::new(iface, config, transport)?)
+    }
+
+    fn start(mut wg_interface: Device) -> Result<DeviceHandle, Error> {
+        let n_threads = wg_interface.config.n_threads;
+        let handshake_threads = wg_interface.config.handshake_threads;
         wg_interface.open_listen_socket(0)?; // Start listening on a random port
+        if let Some(path) = wg_interface.config.config_file.clone() {
+            let update = config_file::read_config_file(&path)?;
+            wg_interface.apply_config_file(update)?;
+        }
 
         let interface_lock = Arc::new(Lock::new(wg_interface));
 
@@ -182,6 +352,12 @@ impl DeviceHandle {
                 thread::spawn(move || DeviceHandle::event_loop(i, &dev))
             });
         }
+        for _ in 0..handshake_threads {
+            threads.push({
+                let dev = Arc::clone(&interface_lock);
+                thread::spawn(move || handshake_pool::handshake_worker(&dev))
+            });
+        }
 
         Ok(DeviceHandle {
             device: interface_lock,
@@ -202,36 +378,129 @@ impl DeviceHandle {
         }
     }
 
-    fn event_loop(_i: usize, device: &Lock<Device>) {
+    /// Run `f` with write access to the device, the event loop threads yield their read access
+    /// meanwhile
+    fn write<U>(&self, f: impl FnOnce(&mut Device) -> U) -> U {
+        self.device
+            .read()
+            .try_writeable(
+                |device| device.trigger_yield(),
+                |device| {
+                    device.cancel_yield();
+                    f(device)
+                },
+            )
+            .expect("try_writeable always gains write access")
+    }
+
+    /// Apply all of `update`, or none of it when it fails validation or its listen port or fwmark
+    /// cannot be set
+    pub fn apply(&self, update: DeviceUpdate) -> Result<(), Error> {
+        self.write(|device| device.apply(update))
+    }
+
+    /// Check `update` without applying it. A new listen port is bound to find out whether it
+    /// can be, and closed again.
+    pub fn validate(&self, update: &DeviceUpdate) -> Result<(), Error> {
+        self.device.read().validate(update)
+    }
+
+    /// Replace the private key of the device, and of the tunnels of its peers
+    pub fn set_private_key(&self, private_key: x25519::StaticSecret) {
+        self.write(|device| device.set_key(private_key))
+    }
+
+    /// Listen on `port`, zero picks a random port
+    pub fn set_listen_port(&self, port: u16) -> Result<(), Error> {
+        self.write(|device| device.open_listen_socket(port))
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn set_fwmark(&self, mark: u32) -> Result<(), Error> {
+        self.write(|device| device.set_fwmark(mark))
+    }
+
+    /// Add a peer, or change the configuration of an existing one
+    pub fn add_or_update_peer(&self, config: PeerConfig) -> Result<(), Error> {
+        self.write(|device| device.add_or_update_peer(config))
+    }
+
+    pub fn remove_peer(&self, public_key: &x25519::PublicKey) {
+        self.write(|device| device.remove_peer(public_key))
+    }
+
+    /// Remove every peer
+    pub fn clear_peers(&self) {
+        self.write(|device| device.clear_peers())
+    }
+
+    /// A snapshot of the configuration of the device and the state of its peers
+    pub fn get_config(&self) -> DeviceState {
+        self.device.read().state()
+    }
+
+    /// A handle to deliver new preshared keys to the tunnel of the peer with `public_key`, from
+    /// an external key exchange. None if there is no such peer.
+    pub fn preshared_key_handle(
+        &self,
+        public_key: &x25519::PublicKey,
+    ) -> Option<PresharedKeyHandle> {
+        let device = self.device.read();
+        let peer = device.peers.get(public_key)?;
+        let (timers, weak_peer) = (Arc::clone(&device.timers), Arc::downgrade(peer));
+        // Deliveries that ask for a handshake are acted upon right away
+        let handle = peer
+            .lock()
+            .tunnel
+            .preshared_key_handle()
+            .on_deliver(move || {
+                if let Some(peer) = weak_peer.upgrade() {
+                    schedule_timers(&timers, &peer, &mut peer.lock());
+                }
+            });
+        Some(handle)
+    }
+
+    /// Receive the events of the device and its peers from now on
+    pub fn subscribe(&self) -> std::sync::mpsc::Receiver<DeviceEvent> {
+        self.device.read().events.subscribe()
+    }
+
+    fn event_loop(i: usize, device: &Lock<Device>) {
         #[cfg(target_os = "linux")]
         let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
             dst_buf: [0u8; MAX_UDP_SIZE],
-            iface: if _i == 0 || !device.read().config.use_multi_queue {
+            src_batch: vec![0u8; MAX_BATCH * MAX_UDP_SIZE],
+            dst_batch: vec![0u8; MAX_BATCH * MAX_UDP_SIZE],
+            datagrams: Vec::new(),
+            iface_scratch: vec![0u8; MAX_UDP_SIZE + tun_offload::VIRTIO_NET_HDR_LEN],
+            iface: if i == 0 || !device.read().config.use_multi_queue {
                 // For the first thread use the original iface
                 Arc::clone(&device.read().iface)
             } else {
-                // For for the rest create a new iface queue
-                let iface_local = Arc::new(
-                    TunSocket::new(&device.read().iface.name().unwrap())
-                        .unwrap()
-                        .set_non_blocking()
-                        .unwrap(),
-                );
-
-                device
-                    .read()
-                    .register_iface_handler(Arc::clone(&iface_local))
-                    .ok();
-
-                iface_local
+                // For for the rest create a new iface queue, if the interface supports it
+                let iface_local = device.read().iface.open_queue().unwrap();
+                match iface_local {
+                    Some(iface_local) => {
+                        device
+                            .read()
+                            .register_iface_handler(Arc::clone(&iface_local))
+                            .ok();
+
+                        iface_local
+                    }
+                    None => Arc::clone(&device.read().iface),
+                }
             },
         };
 
         #[cfg(not(target_os = "linux"))]
         let mut thread_local = ThreadData {
-            src_buf: [0u8; MAX_UDP_SIZE],
             dst_buf: [0u8; MAX_UDP_SIZE],
+            src_batch: vec![0u8; MAX_BATCH * MAX_UDP_SIZE],
+            dst_batch: vec![0u8; MAX_BATCH * MAX_UDP_SIZE],
+            datagrams: Vec::new(),
+            iface_scratch: Vec::new(),
             iface: Arc::clone(&device.read().iface),
         };
 
@@ -248,7 +517,10 @@ impl DeviceHandle {
             loop {
                 match queue.wait() {
                     WaitResult::Ok(handler) => {
+                        let started = Instant::now();
                         let action = (*handler)(&mut device_lock, &mut thread_local);
+                        device_lock.thread_busy[i]
+                            .fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
                         match action {
                             Action::Continue => {}
                             Action::Yield => break,
@@ -294,67 +566,272 @@ impl Device {
             }
             self.peers_by_ip
                 .remove(&|p: &Arc<Mutex<Peer>>| Arc::ptr_eq(&peer, p));
+            self.sync_routes();
 
             tracing::info!("Peer removed");
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn update_peer(
-        &mut self,
-        pub_key: x25519::PublicKey,
-        remove: bool,
-        _replace_ips: bool,
-        endpoint: Option<SocketAddr>,
-        allowed_ips: &[AllowedIP],
-        keepalive: Option<u16>,
-        preshared_key: Option<[u8; 32]>,
-    ) {
-        if remove {
-            // Completely remove a peer
-            return self.remove_peer(&pub_key);
+    /// Route the allowed IPs of peers to the interface, if the device sets up its routes
+    fn sync_routes(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Some(network) = self.network.as_mut() {
+            let allowed_ips = self.peers_by_ip.iter();
+            network.sync_routes(allowed_ips.map(|(_, addr, cidr)| AllowedIP { addr, cidr }));
         }
+    }
 
-        // Update an existing peer
-        if self.peers.get(&pub_key).is_some() {
-            // We already have a peer, we need to merge the existing config into the newly created one
-            panic!("Modifying existing peers is not yet supported. Remove and add again instead.");
+    fn add_or_update_peer(&mut self, config: PeerConfig) -> Result<(), Error> {
+        let PeerConfig {
+            public_key,
+            endpoint,
+            protocol,
+            allowed_ips,
+            remove_allowed_ips,
+            replace_allowed_ips,
+            persistent_keepalive,
+            preshared_key,
+        } = config;
+
+        let peer = match self.peers.get(&public_key) {
+            Some(peer) => Arc::clone(peer),
+            None => {
+                let device_key_pair = self.key_pair.as_ref().ok_or(Error::NoPrivateKey)?;
+                let static_private = device_key_pair.static_private().clone();
+                let next_index = self.next_index();
+
+                let mut tunn = Tunn::new(static_private, public_key, None, None, next_index, None);
+                tunn.set_handshake_rate_limit(self.config.peer_handshake_rate_limit);
+                let events = Arc::clone(&self.events);
+                tunn.set_event_handler(Some(Box::new(move |event| {
+                    if let Some(event) = DeviceEvent::from_tunn(event, public_key) {
+                        events.emit(event);
+                    }
+                })));
+
+                let peer = Arc::new(Mutex::new(Peer::new(tunn, next_index, None, &[])));
+                self.peers.insert(public_key, Arc::clone(&peer));
+                self.peers_by_idx.insert(next_index, Arc::clone(&peer));
+                tracing::info!("Peer added");
+                peer
+            }
+        };
+
+        let mut p = peer.lock();
+        match endpoint {
+            Some(PeerEndpoint::Addr(addr)) => {
+                p.set_host_endpoint(None);
+                p.set_endpoint(addr);
+            }
+            Some(PeerEndpoint::Host { host, port }) => {
+                p.set_host_endpoint(Some((host, port)));
+            }
+            None => {}
+        }
+        if let Some((host, port)) = p.host_resolve_due(Instant::now(), false) {
+            self.resolve_endpoint(&peer, host, port);
+        }
+        if let Some(protocol) = protocol {
+            p.set_protocol(protocol);
+        }
+        if let Some(keepalive) = persistent_keepalive {
+            p.tunnel.set_persistent_keepalive(Some(keepalive));
+        }
+        if let Some(key) = preshared_key {
+            p.tunnel.set_preshared_key(Some(key));
         }
 
-        let next_index = self.next_index();
-        let device_key_pair = self
-            .key_pair
-            .as_ref()
-            .expect("Private key must be set first");
+        // Only the allowed IPs that change are touched, however many the peer has
+        let diff = match replace_allowed_ips {
+            true => p.allowed_ips_diff(&allowed_ips),
+            false => AllowedIpsDiff {
+                add: allowed_ips,
+                remove: remove_allowed_ips,
+            },
+        };
+        p.update_allowed_ips(&diff);
+        let owner = |other: &Arc<Mutex<Peer>>| Arc::ptr_eq(&peer, other);
+        for (ip, previous) in self
+            .peers_by_ip
+            .apply_diff(&diff, &owner, Arc::clone(&peer))
+        {
+            // The allowed IP moves over from the peer that had it
+            previous.lock().update_allowed_ips(&AllowedIpsDiff {
+                add: vec![],
+                remove: vec![ip],
+            });
+        }
 
-        let tunn = Tunn::new(
-            device_key_pair.0.clone(),
-            pub_key,
-            preshared_key,
-            keepalive,
-            next_index,
-            None,
-        );
+        self.reconnect_tcp_endpoint(&peer, &mut p);
+        schedule_timers(&self.timers, &peer, &mut p);
+        self.sync_routes();
+        Ok(())
+    }
 
-        let peer = Peer::new(tunn, next_index, endpoint, allowed_ips, preshared_key);
+    /// Check that `update` can be applied, without changing anything. A new listen port is
+    /// bound and the fwmark is tried on a spare socket, and both are closed again.
+    fn validate(&self, update: &DeviceUpdate) -> Result<(), Error> {
+        let _sockets = self.prepare(update)?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let (None, Some(mark)) = (&_sockets, update.fwmark) {
+            let spare = self.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())?;
+            set_mark(&*spare, mark)?;
+        }
+        Ok(())
+    }
 
-        let peer = Arc::new(Mutex::new(peer));
-        self.peers.insert(pub_key, Arc::clone(&peer));
-        self.peers_by_idx.insert(next_index, Arc::clone(&peer));
+    /// Check everything about `update` that does not depend on changing the device, and bind
+    /// the listen sockets it asks for, with its fwmark
+    fn prepare(&self, update: &DeviceUpdate) -> Result<Option<ListenSockets>, Error> {
+        let adds_peers = update
+            .peers
+            .iter()
+            .any(|change| matches!(change, PeerChange::AddOrUpdate(_)));
+        if adds_peers && self.key_pair.is_none() && update.private_key.is_none() {
+            return Err(Error::NoPrivateKey);
+        }
 
-        for AllowedIP { addr, cidr } in allowed_ips {
-            self.peers_by_ip
-                .insert(*addr, *cidr as _, Arc::clone(&peer));
+        let sockets = match update.listen_port {
+            Some(port) if port == 0 || port != self.listen_port => {
+                Some(self.bind_listen_sockets(port)?)
+            }
+            _ => None,
+        };
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let (Some(sockets), Some(mark)) = (&sockets, update.fwmark.or(self.fwmark)) {
+            // The connected sockets of peers are closed along with the current listen sockets
+            set_mark(&*sockets.sock4, mark)?;
+            set_mark(&*sockets.sock6, mark)?;
+        }
+        Ok(sockets)
+    }
+
+    fn apply(&mut self, update: DeviceUpdate) -> Result<(), Error> {
+        // What can fail is done first, the listen port is bound before the current sockets are
+        // closed, so that on error the device is left as it was
+        let sockets = self.prepare(&update)?;
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let (None, Some(mark)) = (&sockets, update.fwmark) {
+            self.set_fwmark(mark)?;
+        }
+
+        if let Some(sockets) = sockets {
+            self.use_listen_sockets(sockets)?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if update.fwmark.is_some() {
+            self.fwmark = update.fwmark;
+        }
+
+        if let Some(limit) = update.handshake_rate_limit {
+            self.set_handshake_rate_limit(limit);
+        }
+        if let Some(limit) = update.peer_handshake_rate_limit {
+            self.set_peer_handshake_rate_limit(limit);
+        }
+        if let Some(private_key) = update.private_key {
+            self.set_key(private_key);
+        }
+        if update.replace_peers {
+            self.clear_peers();
+        }
+        for change in update.peers {
+            match change {
+                PeerChange::AddOrUpdate(config) => self.add_or_update_peer(config)?,
+                PeerChange::Remove(public_key) => self.remove_peer(&public_key),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_handshake_rate_limit(&mut self, limit: u64) {
+        self.config.handshake_rate_limit = limit;
+        if let Some(rate_limiter) = self.rate_limiter.as_ref() {
+            rate_limiter.set_limit(limit);
+        }
+    }
+
+    fn set_peer_handshake_rate_limit(&mut self, limit: u64) {
+        self.config.peer_handshake_rate_limit = limit;
+        for peer in self.peers.values() {
+            peer.lock().tunnel.set_handshake_rate_limit(limit);
+        }
+    }
+
+    /// A snapshot of the configuration of the device and the state of its peers
+    fn state(&self) -> DeviceState {
+        let peers = self
+            .peers
+            .iter()
+            .map(|(public_key, peer)| {
+                let p = peer.lock();
+                let endpoint = p.endpoint().addr;
+                PeerState {
+                    public_key: *public_key,
+                    endpoint,
+                    endpoint_host: p
+                        .host_endpoint()
+                        .map(|(host, port)| format!("{}:{}", host, port)),
+                    protocol: p.protocol(),
+                    allowed_ips: p
+                        .allowed_ips()
+                        .map(|(addr, cidr)| AllowedIP { addr, cidr })
+                        .collect(),
+                    persistent_keepalive: p.persistent_keepalive(),
+                    preshared_key: p.preshared_key(),
+                    stats: p.tunnel.stats(),
+                }
+            })
+            .collect();
+
+        DeviceState {
+            public_key: self.key_pair.as_ref().map(ResponderKey::static_public),
+            listen_port: self.listen_port,
+            fwmark: self.fwmark,
+            handshake_rate_limit: self.config.handshake_rate_limit,
+            peer_handshake_rate_limit: self.config.peer_handshake_rate_limit,
+            rate_limiter: self.rate_limiter.as_ref().map(|r| RateLimiterState {
+                handshake_rate: r.handshake_rate(),
+                cookie_mode: r.cookie_mode(),
+                stats: r.stats(),
+            }),
+            peers,
         }
+    }
 
-        tracing::info!("Peer added");
+    /// Connect to the endpoint of a peer reached over TCP, if its connection is down and the
+    /// reconnection backoff allows
+    fn reconnect_tcp_endpoint(&self, peer: &Arc<Mutex<Peer>>, p: &mut Peer) {
+        if !p.tcp_reconnect_due() {
+            return;
+        }
+        let addr = p.endpoint().addr.expect("Reconnecting without an endpoint");
+        match p.connect_endpoint(&*self.transport, self.listen_port, self.fwmark) {
+            Ok(sock) => {
+                if let Err(e) = self.register_conn_handler(Arc::clone(peer), sock, addr.ip()) {
+                    tracing::error!(message = "Failed to register TCP endpoint", error = ?e);
+                    p.shutdown_endpoint();
+                }
+            }
+            Err(e) => tracing::warn!(message = "Failed to connect TCP endpoint", error = ?e),
+        }
     }
 
-    pub fn new(name: &str, config: DeviceConfig) -> Result<Device, Error> {
+    pub fn new(
+        iface: Arc<dyn VirtualInterface>,
+        config: DeviceConfig,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Device, Error> {
         let poll = EventPoll::<Handler>::new()?;
 
-        // Create a tunnel device
-        let iface = Arc::new(TunSocket::new(name)?.set_non_blocking()?);
+        // Before reading the MTU, which the setup may change
+        #[cfg(target_os = "linux")]
+        let network = match &config.network {
+            Some(network) => Some(NetworkSetup::new(&iface.name()?, network)?),
+            None => None,
+        };
+
         let mtu = iface.mtu()?;
 
         #[cfg(not(target_os = "linux"))]
@@ -362,10 +839,17 @@ impl Device {
         #[cfg(target_os = "linux")]
         let uapi_fd = config.uapi_fd;
 
+        let transport: Arc<dyn Transport> = match config.obfuscation {
+            Some(obfuscation) => {
+                obfuscation.validate().map_err(Error::Obfuscation)?;
+                Arc::new(ObfuscatedTransport::new(transport, obfuscation))
+            }
+            None => transport,
+        };
+
         let mut device = Device {
             queue: Arc::new(poll),
             iface,
-            config,
             exit_notice: Default::default(),
             yield_notice: Default::default(),
             fwmark: Default::default(),
@@ -375,77 +859,110 @@ impl Device {
             peers: Default::default(),
             peers_by_idx: Default::default(),
             peers_by_ip: AllowedIps::new(),
-            udp4: Default::default(),
-            udp6: Default::default(),
+            transport,
+            sock4: Default::default(),
+            sock6: Default::default(),
             cleanup_paths: Default::default(),
             mtu: AtomicUsize::new(mtu),
             rate_limiter: None,
+            handshakes: (config.handshake_threads > 0)
+                .then(|| Arc::new(HandshakeQueue::new(HANDSHAKE_QUEUE_SIZE))),
+            timers: Arc::new(Mutex::new(TimerWheel::new(TIMER_TICK, TIMER_SLOTS))),
+            thread_busy: (0..config.n_threads).map(|_| AtomicU64::new(0)).collect(),
+            events: Default::default(),
+            #[cfg(target_os = "linux")]
+            network,
+            config,
             #[cfg(target_os = "linux")]
             uapi_fd,
         };
 
+        // The policy rules of a dedicated routing table let packets with this mark bypass it
+        #[cfg(target_os = "linux")]
+        if let Some(table) = device.config.network.as_ref().and_then(|n| n.table) {
+            device.fwmark = Some(table);
+        }
+
         if uapi_fd >= 0 {
             device.register_api_fd(uapi_fd)?;
         } else {
             device.register_api_handler()?;
         }
+        if let Some(addr) = device.config.metrics.clone() {
+            device.register_metrics_handler(&addr)?;
+        }
+        if let Some(path) = device.config.config_file.clone() {
+            device.register_config_reload(path)?;
+        }
         device.register_iface_handler(Arc::clone(&device.iface))?;
         device.register_notifiers()?;
         device.register_timers()?;
+        device.register_resolve_timer()?;
 
-        #[cfg(target_os = "macos")]
-        {
-            // Only for macOS write the actual socket name into WG_TUN_NAME_FILE
-            if let Ok(name_file) = std::env::var("WG_TUN_NAME_FILE") {
-                if name == "utun" {
-                    std::fs::write(&name_file, device.iface.name().unwrap().as_bytes()).unwrap();
-                    device.cleanup_paths.push(name_file);
-                }
-            }
+        Ok(device)
+    }
+
+    fn open_listen_socket(&mut self, port: u16) -> Result<(), Error> {
+        if port != 0 && port == self.listen_port {
+            return Ok(()); // Already listening on it
         }
+        let sockets = self.bind_listen_sockets(port)?;
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(mark) = self.fwmark {
+            set_mark(&*sockets.sock4, mark)?;
+            set_mark(&*sockets.sock6, mark)?;
+        }
+        self.use_listen_sockets(sockets)
+    }
 
-        Ok(device)
+    /// Bind the network facing sockets on `port`, zero picks a random port, without using them
+    /// yet
+    fn bind_listen_sockets(&self, mut port: u16) -> Result<ListenSockets, Error> {
+        let sock4 = self.bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+
+        if port == 0 {
+            // Random port was assigned
+            port = sock4.local_addr()?.port();
+        }
+
+        let sock6 = self.bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
+
+        Ok(ListenSockets { sock4, sock6, port })
+    }
+
+    fn bind(&self, addr: SocketAddr) -> Result<Arc<dyn TransportSocket>, Error> {
+        self.transport
+            .bind(addr)
+            .map_err(|e| Error::Bind(format!("Failed to bind {}: {}", addr, e)))
     }
 
-    fn open_listen_socket(&mut self, mut port: u16) -> Result<(), Error> {
-        // Binds the network facing interfaces
-        // First close any existing open socket, and remove them from the event loop
-        if let Some(s) = self.udp4.take() {
+    /// Replace the listen sockets, along with the connected sockets of peers. The current
+    /// sockets are only closed once the new ones are on the event loop, on error the device keeps
+    /// listening on them.
+    fn use_listen_sockets(&mut self, sockets: ListenSockets) -> Result<(), Error> {
+        let ListenSockets { sock4, sock6, port } = sockets;
+        self.register_udp_handler(Arc::clone(&sock4))?;
+        if let Err(e) = self.register_udp_handler(Arc::clone(&sock6)) {
             unsafe {
                 // This is safe because the event loop is not running yet
-                self.queue.clear_event_by_fd(s.as_raw_fd())
+                self.queue.clear_event_by_fd(sock4.as_raw_fd())
             }
-        };
+            return Err(e);
+        }
 
-        if let Some(s) = self.udp6.take() {
+        // Then close the previous sockets, and remove them from the event loop
+        if let Some(s) = self.sock4.replace(sock4) {
             unsafe { self.queue.clear_event_by_fd(s.as_raw_fd()) };
         }
 
-        for peer in self.peers.values() {
-            peer.lock().shutdown_endpoint();
+        if let Some(s) = self.sock6.replace(sock6) {
+            unsafe { self.queue.clear_event_by_fd(s.as_raw_fd()) };
         }
 
-        // Then open new sockets and bind to the port
-        let udp_sock4 = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock4.set_reuse_address(true)?;
-        udp_sock4.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
-        udp_sock4.set_nonblocking(true)?;
-
-        if port == 0 {
-            // Random port was assigned
-            port = udp_sock4.local_addr()?.as_socket().unwrap().port();
+        for peer in self.peers.values() {
+            peer.lock().shutdown_endpoint();
         }
 
-        let udp_sock6 = socket2::Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
-        udp_sock6.set_reuse_address(true)?;
-        udp_sock6.bind(&SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into())?;
-        udp_sock6.set_nonblocking(true)?;
-
-        self.register_udp_handler(udp_sock4.try_clone().unwrap())?;
-        self.register_udp_handler(udp_sock6.try_clone().unwrap())?;
-        self.udp4 = Some(udp_sock4);
-        self.udp6 = Some(udp_sock6);
-
         self.listen_port = port;
 
         Ok(())
@@ -453,15 +970,17 @@ impl Device {
 
     fn set_key(&mut self, private_key: x25519::StaticSecret) {
         let public_key = x25519::PublicKey::from(&private_key);
-        let key_pair = Some((private_key.clone(), public_key));
 
         // x25519 (rightly) doesn't let us expose secret keys for comparison.
         // If the public keys are the same, then the private keys are the same.
-        if Some(&public_key) == self.key_pair.as_ref().map(|p| &p.1) {
+        if Some(public_key) == self.key_pair.as_ref().map(ResponderKey::static_public) {
             return;
         }
 
-        let rate_limiter = Arc::new(RateLimiter::new(&public_key, HANDSHAKE_RATE_LIMIT));
+        let rate_limiter = Arc::new(
+            RateLimiter::new(&public_key, self.config.handshake_rate_limit)
+                .with_source_limit(self.config.source_limit),
+        );
 
         for peer in self.peers.values_mut() {
             peer.lock().tunnel.set_static_private(
@@ -471,27 +990,36 @@ impl Device {
             )
         }
 
-        self.key_pair = key_pair;
+        self.key_pair = Some(ResponderKey::new(private_key));
         self.rate_limiter = Some(rate_limiter);
     }
 
     #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
     fn set_fwmark(&mut self, mark: u32) -> Result<(), Error> {
+        if let Err(e) = self.mark_sockets(mark) {
+            // Put the previous mark back on the sockets that already took the new one
+            let _ = self.mark_sockets(self.fwmark.unwrap_or(0));
+            return Err(e);
+        }
         self.fwmark = Some(mark);
+        Ok(())
+    }
 
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn mark_sockets(&self, mark: u32) -> Result<(), Error> {
         // First set fwmark on listeners
-        if let Some(ref sock) = self.udp4 {
-            sock.set_mark(mark)?;
+        if let Some(ref sock) = self.sock4 {
+            set_mark(&**sock, mark)?;
         }
 
-        if let Some(ref sock) = self.udp6 {
-            sock.set_mark(mark)?;
+        if let Some(ref sock) = self.sock6 {
+            set_mark(&**sock, mark)?;
         }
 
         // Then on all currently connected sockets
         for peer in self.peers.values() {
             if let Some(ref sock) = peer.lock().endpoint().conn {
-                sock.set_mark(mark)?
+                set_mark(&**sock, mark)?
             }
         }
 
@@ -502,6 +1030,7 @@ impl Device {
         self.peers.clear();
         self.peers_by_idx.clear();
         self.peers_by_ip.clear();
+        self.sync_routes();
     }
 
     fn register_notifiers(&mut self) -> Result<(), Error> {
@@ -532,45 +1061,69 @@ impl Device {
         )?;
 
         self.queue.new_periodic_event(
-            // Execute the timed function of every peer in the list
+            // Execute the timed function of the peers whose timers are due
             Box::new(|d, t| {
-                let peer_map = &d.peers;
-
-                let (udp4, udp6) = match (d.udp4.as_ref(), d.udp6.as_ref()) {
-                    (Some(udp4), Some(udp6)) => (udp4, udp6),
+                let (sock4, sock6) = match (d.sock4.as_ref(), d.sock6.as_ref()) {
+                    (Some(sock4), Some(sock6)) => (sock4, sock6),
                     _ => return Action::Continue,
                 };
 
-                // Go over each peer and invoke the timer function
-                for peer in peer_map.values() {
+                let mut due = vec![];
+                d.timers.lock().advance(Instant::now(), &mut due);
+
+                for (peer, deadline) in due {
+                    let peer = match peer.upgrade() {
+                        Some(peer) => peer,
+                        None => continue, // Removed
+                    };
                     let mut p = peer.lock();
+                    if p.timer_deadline != Some(deadline) {
+                        continue; // Rescheduled to an earlier time, and handled then
+                    }
+                    p.timer_deadline = None;
+
+                    // Peers without an endpoint are scheduled again once they get one
                     let endpoint_addr = match p.endpoint().addr {
                         Some(addr) => addr,
                         None => continue,
                     };
 
+                    if p.protocol() == EndpointProtocol::Tcp {
+                        d.reconnect_tcp_endpoint(&peer, &mut p);
+                        if let Some(conn) = p.endpoint().conn.as_ref() {
+                            let _: Result<_, _> = conn.flush();
+                        }
+                    }
+
                     match p.update_timers(&mut t.dst_buf[..]) {
                         TunnResult::Done => {}
                         TunnResult::Err(WireGuardError::ConnectionExpired) => {
                             p.shutdown_endpoint(); // close open udp socket
+                                                   // The hostname of the peer may point elsewhere by now
+                            if let Some((host, port)) = p.host_resolve_due(Instant::now(), true) {
+                                d.resolve_endpoint(&peer, host, port);
+                            }
                         }
                         TunnResult::Err(e) => tracing::error!(message = "Timer error", error = ?e),
                         TunnResult::WriteToNetwork(packet) => {
-                            match endpoint_addr {
-                                SocketAddr::V4(_) => {
-                                    udp4.send_to(packet, &endpoint_addr.into()).ok()
+                            if p.protocol() == EndpointProtocol::Tcp {
+                                if let Some(conn) = p.endpoint().conn.as_ref() {
+                                    let _: Result<_, _> = conn.send(packet);
                                 }
-                                SocketAddr::V6(_) => {
-                                    udp6.send_to(packet, &endpoint_addr.into()).ok()
-                                }
-                            };
+                            } else {
+                                match endpoint_addr {
+                                    SocketAddr::V4(_) => sock4.send_to(packet, endpoint_addr).ok(),
+                                    SocketAddr::V6(_) => sock6.send_to(packet, endpoint_addr).ok(),
+                                };
+                            }
                         }
                         _ => panic!("Unexpected result from update_timers"),
                     };
+                    schedule_timers(&d.timers, &peer, &mut p);
                 }
                 Action::Continue
             }),
-            std::time::Duration::from_millis(250),
+            TIMER_TICK,
         )?;
         Ok(())
     }
@@ -582,7 +1135,10 @@ impl Device {
 
     pub(crate) fn trigger_exit(&self) {
         self.queue
-            .trigger_notification(self.exit_notice.as_ref().unwrap())
+            .trigger_notification(self.exit_notice.as_ref().unwrap());
+        if let Some(handshakes) = &self.handshakes {
+            handshakes.shutdown();
+        }
     }
 
     pub(crate) fn cancel_yield(&self) {
@@ -590,105 +1146,161 @@ impl Device {
             .stop_notification(self.yield_notice.as_ref().unwrap())
     }
 
-    fn register_udp_handler(&self, udp: socket2::Socket) -> Result<(), Error> {
+    fn register_udp_handler(&self, udp: Arc<dyn TransportSocket>) -> Result<(), Error> {
         self.queue.new_event(
             udp.as_raw_fd(),
             Box::new(move |d, t| {
                 // Handler that handles anonymous packets over UDP
-                let mut iter = MAX_ITR;
-                let (private_key, public_key) = d.key_pair.as_ref().expect("Key not set");
-
+                let mut iter = 0;
                 let rate_limiter = d.rate_limiter.as_ref().unwrap();
 
                 // Loop while we have packets on the anonymous connection
-
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf =
-                    unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
-                while let Ok((packet_len, addr)) = udp.recv_from(src_buf) {
-                    let packet = &t.src_buf[..packet_len];
-                    // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
-                    let parsed_packet = match rate_limiter.verify_packet(
-                        Some(addr.as_socket().unwrap().ip()),
-                        packet,
-                        &mut t.dst_buf,
-                    ) {
-                        Ok(packet) => packet,
-                        Err(TunnResult::WriteToNetwork(cookie)) => {
-                            let _: Result<_, _> = udp.send_to(cookie, &addr);
+                while iter < MAX_ITR {
+                    t.datagrams.clear();
+                    let n_received =
+                        udp.recv_batch(&mut t.src_batch, MAX_ITR - iter, &mut t.datagrams);
+                    if n_received == 0 {
+                        break;
+                    }
+                    iter += n_received;
+
+                    let (src_batch, datagrams) = (&t.src_batch, &t.datagrams);
+                    let packets: Vec<&[u8]> = datagrams
+                        .iter()
+                        .map(|d| &src_batch[d.offset..d.offset + d.len])
+                        .collect();
+
+                    let mut i = 0;
+                    while i < n_received {
+                        let (packet, addr) = (packets[i], datagrams[i].addr);
+                        let mut end = i + 1;
+                        let mut stride = t.dst_batch.len();
+
+                        // The rate limiter initially checks mac1 and mac2, and optionally asks to send a cookie
+                        let (parsed_packet, valid_mac2) = match rate_limiter.verify_packet_cookie(
+                            Some(addr.ip()),
+                            packet,
+                            &mut t.dst_buf,
+                        ) {
+                            Ok(verified) => verified,
+                            Err(TunnResult::WriteToNetwork(cookie)) => {
+                                let _: Result<_, _> = udp.send_to(cookie, addr);
+                                d.events.emit(DeviceEvent::CookieSent { endpoint: addr });
+                                i = end;
+                                continue;
+                            }
+                            Err(_) => {
+                                i = end;
+                                continue;
+                            }
+                        };
+
+                        // Handshakes are left to the workers, so that data packets do not wait
+                        // behind them
+                        if let (
+                            Some(handshakes),
+                            Packet::HandshakeInit(_) | Packet::HandshakeResponse(_),
+                        ) = (&d.handshakes, &parsed_packet)
+                        {
+                            let job = HandshakeJob {
+                                datagram: packet.to_vec(),
+                                addr,
+                                udp: Arc::clone(&udp),
+                            };
+                            handshakes.push(job, valid_mac2);
+                            i = end;
                             continue;
                         }
-                        Err(_) => continue,
-                    };
 
-                    let peer = match &parsed_packet {
-                        Packet::HandshakeInit(p) => {
-                            parse_handshake_anon(private_key, public_key, p)
-                                .ok()
-                                .and_then(|hh| {
-                                    d.peers.get(&x25519::PublicKey::from(hh.peer_static_public))
-                                })
+                        let mut half = None;
+                        let peer = match d.peer_for_packet(&parsed_packet, &mut half) {
+                            None => {
+                                i = end;
+                                continue;
+                            }
+                            Some(peer) => peer,
+                        };
+
+                        // Data packets that follow from the same address for the same peer are
+                        // decapsulated together as a single batch
+                        if let Packet::PacketData(p) = &parsed_packet {
+                            let peer_idx = p.receiver_idx >> 8;
+                            (end, stride) =
+                                decapsulation_run(&packets, i, t.dst_batch.len(), |j| {
+                                    datagrams[j].addr == addr
+                                        && matches!(
+                                            Tunn::parse_incoming_packet(packets[j]),
+                                            Ok(Packet::PacketData(p)) if p.receiver_idx >> 8 == peer_idx
+                                        )
+                                });
                         }
-                        Packet::HandshakeResponse(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketCookieReply(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                        Packet::PacketData(p) => d.peers_by_idx.get(&(p.receiver_idx >> 8)),
-                    };
 
-                    let peer = match peer {
-                        None => continue,
-                        Some(peer) => peer,
-                    };
-
-                    let mut p = peer.lock();
-
-                    // We found a peer, use it to decapsulate the message+
-                    let mut flush = false; // Are there packets to send from the queue?
-                    match p
-                        .tunnel
-                        .handle_verified_packet(parsed_packet, &mut t.dst_buf[..])
-                    {
-                        TunnResult::Done => {}
-                        TunnResult::Err(_) => continue,
-                        TunnResult::WriteToNetwork(packet) => {
-                            flush = true;
-                            let _: Result<_, _> = udp.send_to(packet, &addr);
-                        }
-                        TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                t.iface.write4(packet);
-                            }
+                        let mut p = peer.lock();
+
+                        // We found a peer, use it to decapsulate the message+
+                        let mut results = Vec::with_capacity(end - i);
+                        if let Packet::PacketData(_) = parsed_packet {
+                            p.tunnel.decapsulate_batch(
+                                Some(addr.ip()),
+                                &packets[i..end],
+                                &mut t.dst_batch[..],
+                                stride,
+                                &mut results,
+                            );
+                        } else {
+                            let dst = &mut t.dst_batch[..];
+                            results.push(match (parsed_packet, half) {
+                                (Packet::HandshakeInit(init), Some(half)) => {
+                                    p.tunnel.resume_handshake_init(half, init, dst)
+                                }
+                                (parsed_packet, _) => {
+                                    p.tunnel.handle_verified_packet(parsed_packet, dst)
+                                }
+                            });
                         }
-                        TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                t.iface.write6(packet);
-                            }
+                        i = end;
+
+                        let mut flush = false; // Are there packets to send from the queue?
+                        let mut any_ok = false;
+                        let mut to_iface = Vec::with_capacity(results.len());
+                        for result in results {
+                            match result {
+                                TunnResult::Done => {}
+                                TunnResult::Err(_) => continue,
+                                TunnResult::WriteToNetwork(packet) => {
+                                    flush = true;
+                                    let _: Result<_, _> = udp.send_to(packet, addr);
+                                }
+                                TunnResult::WriteToTunnelV4(packet, addr) => {
+                                    if p.is_allowed_ip(addr) {
+                                        to_iface.push(packet);
+                                    }
+                                }
+                                TunnResult::WriteToTunnelV6(packet, addr) => {
+                                    if p.is_allowed_ip(addr) {
+                                        to_iface.push(packet);
+                                    }
+                                }
+                            };
+                            any_ok = true;
                         }
-                    };
+                        t.iface.write_packets(&mut to_iface);
+                        schedule_timers(&d.timers, peer, &mut p);
 
-                    if flush {
-                        // Flush pending queue
-                        while let TunnResult::WriteToNetwork(packet) =
-                            p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
-                        {
-                            let _: Result<_, _> = udp.send_to(packet, &addr);
+                        if !any_ok {
+                            continue;
                         }
-                    }
 
-                    // This packet was OK, that means we want to create a connected socket for this peer
-                    let addr = addr.as_socket().unwrap();
-                    let ip_addr = addr.ip();
-                    p.set_endpoint(addr);
-                    if d.config.use_connected_socket {
-                        if let Ok(sock) = p.connect_endpoint(d.listen_port, d.fwmark) {
-                            d.register_conn_handler(Arc::clone(peer), sock, ip_addr)
-                                .unwrap();
+                        if flush {
+                            // Flush pending queue
+                            while let TunnResult::WriteToNetwork(packet) =
+                                p.tunnel.decapsulate(None, &[], &mut t.dst_buf[..])
+                            {
+                                let _: Result<_, _> = udp.send_to(packet, addr);
+                            }
                         }
-                    }
 
-                    iter -= 1;
-                    if iter == 0 {
-                        break;
+                        d.peer_reached_at(peer, &mut p, addr);
                     }
                 }
                 Action::Continue
@@ -697,51 +1309,166 @@ impl Device {
         Ok(())
     }
 
+    /// The peer a verified packet is for. Initiations are decrypted only as far as needed to find
+    /// the peer, `half` is set to where its handshake continues from.
+    fn peer_for_packet(
+        &self,
+        packet: &Packet,
+        half: &mut Option<HalfHandshake>,
+    ) -> Option<&Arc<Mutex<Peer>>> {
+        match packet {
+            Packet::HandshakeInit(p) => {
+                let key_pair = self.key_pair.as_ref()?;
+                let hh = key_pair.parse_handshake_anon(p).ok()?;
+                let public_key = x25519::PublicKey::from(hh.peer_static_public);
+                *half = Some(hh);
+                self.peers.get(&public_key)
+            }
+            Packet::HandshakeResponse(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+            Packet::PacketCookieReply(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+            Packet::PacketData(p) => self.peers_by_idx.get(&(p.receiver_idx >> 8)),
+        }
+    }
+
+    /// Send to `addr` from now on, where a valid packet of the peer came from, and connect a
+    /// socket to it
+    fn peer_reached_at(&self, peer: &Arc<Mutex<Peer>>, p: &mut Peer, addr: SocketAddr) {
+        // A peer reached over TCP keeps its configured endpoint
+        if p.protocol() == EndpointProtocol::Tcp {
+            return;
+        }
+
+        if p.set_endpoint(addr) {
+            self.events.emit(DeviceEvent::EndpointChanged {
+                public_key: p.tunnel.peer_static_public(),
+                endpoint: addr,
+            });
+            // Timers that came due while the peer had no endpoint were dropped
+            schedule_timers(&self.timers, peer, p);
+        }
+        if self.config.use_connected_socket {
+            if let Ok(sock) = p.connect_endpoint(&*self.transport, self.listen_port, self.fwmark) {
+                self.register_conn_handler(Arc::clone(peer), sock, addr.ip())
+                    .unwrap();
+            }
+        }
+    }
+
+    /// Process a handshake message a worker took off the queue
+    pub(crate) fn process_handshake(&self, job: HandshakeJob, dst: &mut [u8]) {
+        let HandshakeJob {
+            datagram,
+            addr,
+            udp,
+        } = job;
+        // The rate limiter verified it before it was queued
+        let packet = match Tunn::parse_incoming_packet(&datagram) {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let mut half = None;
+        let peer = match self.peer_for_packet(&packet, &mut half) {
+            Some(peer) => peer,
+            None => return,
+        };
+
+        let mut p = peer.lock();
+        let result = match (packet, half) {
+            (Packet::HandshakeInit(init), Some(half)) => {
+                p.tunnel.resume_handshake_init(half, init, dst)
+            }
+            (packet, _) => p.tunnel.handle_verified_packet(packet, dst),
+        };
+        let ok = match result {
+            TunnResult::WriteToNetwork(packet) => {
+                let _: Result<_, _> = udp.send_to(packet, addr);
+                true
+            }
+            _ => false,
+        };
+        schedule_timers(&self.timers, peer, &mut p);
+        if !ok {
+            return;
+        }
+
+        // Flush pending queue
+        while let TunnResult::WriteToNetwork(packet) = p.tunnel.decapsulate(None, &[], dst) {
+            let _: Result<_, _> = udp.send_to(packet, addr);
+        }
+        self.peer_reached_at(peer, &mut p, addr);
+    }
+
     fn register_conn_handler(
         &self,
         peer: Arc<Mutex<Peer>>,
-        udp: socket2::Socket,
+        udp: Arc<dyn TransportSocket>,
         peer_addr: IpAddr,
     ) -> Result<(), Error> {
         self.queue.new_event(
             udp.as_raw_fd(),
-            Box::new(move |_, t| {
+            Box::new(move |d, t| {
                 // The conn_handler handles packet received from a connected UDP socket, associated
                 // with a known peer, this saves us the hustle of finding the right peer. If another
                 // peer gets the same ip, it will be ignored until the socket does not expire.
                 let iface = &t.iface;
-                let mut iter = MAX_ITR;
+                let mut iter = 0;
+
+                while iter < MAX_ITR {
+                    t.datagrams.clear();
+                    let n_received =
+                        udp.recv_batch(&mut t.src_batch, MAX_ITR - iter, &mut t.datagrams);
+                    if n_received == 0 {
+                        break;
+                    }
+                    iter += n_received;
 
-                // Safety: the `recv_from` implementation promises not to write uninitialised
-                // bytes to the buffer, so this casting is safe.
-                let src_buf =
-                    unsafe { &mut *(&mut t.src_buf[..] as *mut [u8] as *mut [MaybeUninit<u8>]) };
+                    let src_batch = &t.src_batch;
+                    let packets: Vec<&[u8]> = t
+                        .datagrams
+                        .iter()
+                        .map(|d| &src_batch[d.offset..d.offset + d.len])
+                        .collect();
 
-                while let Ok(read_bytes) = udp.recv(src_buf) {
                     let mut flush = false;
                     let mut p = peer.lock();
-                    match p.tunnel.decapsulate(
-                        Some(peer_addr),
-                        &t.src_buf[..read_bytes],
-                        &mut t.dst_buf[..],
-                    ) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => eprintln!("Decapsulate error {:?}", e),
-                        TunnResult::WriteToNetwork(packet) => {
-                            flush = true;
-                            let _: Result<_, _> = udp.send(packet);
-                        }
-                        TunnResult::WriteToTunnelV4(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                iface.write4(packet);
-                            }
-                        }
-                        TunnResult::WriteToTunnelV6(packet, addr) => {
-                            if p.is_allowed_ip(addr) {
-                                iface.write6(packet);
-                            }
+                    let mut i = 0;
+                    while i < n_received {
+                        let (end, stride) =
+                            decapsulation_run(&packets, i, t.dst_batch.len(), |_| true);
+
+                        let mut results = Vec::with_capacity(end - i);
+                        p.tunnel.decapsulate_batch(
+                            Some(peer_addr),
+                            &packets[i..end],
+                            &mut t.dst_batch[..],
+                            stride,
+                            &mut results,
+                        );
+                        i = end;
+
+                        let mut to_iface = Vec::with_capacity(results.len());
+                        for result in results {
+                            match result {
+                                TunnResult::Done => {}
+                                TunnResult::Err(e) => eprintln!("Decapsulate error {:?}", e),
+                                TunnResult::WriteToNetwork(packet) => {
+                                    flush = true;
+                                    let _: Result<_, _> = udp.send(packet);
+                                }
+                                TunnResult::WriteToTunnelV4(packet, addr) => {
+                                    if p.is_allowed_ip(addr) {
+                                        to_iface.push(packet);
+                                    }
+                                }
+                                TunnResult::WriteToTunnelV6(packet, addr) => {
+                                    if p.is_allowed_ip(addr) {
+                                        to_iface.push(packet);
+                                    }
+                                }
+                            };
                         }
-                    };
+                        iface.write_packets(&mut to_iface);
+                    }
 
                     if flush {
                         // Flush pending queue
@@ -751,11 +1478,11 @@ impl Device {
                             let _: Result<_, _> = udp.send(packet);
                         }
                     }
-
-                    iter -= 1;
-                    if iter == 0 {
-                        break;
-                    }
+                    schedule_timers(&d.timers, &peer, &mut p);
+                }
+                if udp.is_closed() {
+                    // Reconnected by the timers
+                    schedule_timers(&d.timers, &peer, &mut peer.lock());
                 }
                 Action::Continue
             }),
@@ -763,75 +1490,210 @@ impl Device {
         Ok(())
     }
 
-    fn register_iface_handler(&self, iface: Arc<TunSocket>) -> Result<(), Error> {
+    fn register_iface_handler(&self, iface: Arc<dyn VirtualInterface>) -> Result<(), Error> {
         self.queue.new_event(
             iface.as_raw_fd(),
             Box::new(move |d, t| {
                 // The iface_handler handles packets received from the WireGuard virtual network
                 // interface. The flow is as follows:
-                // * Read a packet
+                // * Read a batch of packets
                 // * Determine peer based on packet destination ip
-                // * Encapsulate the packet for the given peer
-                // * Send encapsulated packet to the peer's endpoint
+                // * Encapsulate consecutive packets for the same peer as a single batch
+                // * Send encapsulated packets to the peer's endpoint
                 let mtu = d.mtu.load(Ordering::Relaxed);
 
-                let udp4 = d.udp4.as_ref().expect("Not connected");
-                let udp6 = d.udp6.as_ref().expect("Not connected");
+                let sock4 = d.sock4.as_ref().expect("Not connected");
+                let sock6 = d.sock6.as_ref().expect("Not connected");
 
                 let peers = &d.peers_by_ip;
-                for _ in 0..MAX_ITR {
-                    let src = match iface.read(&mut t.src_buf[..mtu]) {
-                        Ok(src) => src,
-                        Err(Error::IfaceRead(e)) => {
-                            let ek = e.kind();
-                            if ek == io::ErrorKind::Interrupted || ek == io::ErrorKind::WouldBlock {
-                                break;
+                let find_peer =
+                    |packet: &[u8]| Tunn::dst_address(packet).and_then(|addr| peers.find(addr));
+
+                // Packets are read into slots of mtu bytes, and encapsulated into slots with room
+                // for the WireGuard overhead, or a handshake initiation if they have to be queued
+                let dst_stride = (mtu + DATA_OVERHEAD_SZ).max(HANDSHAKE_INIT_SZ);
+                let n_slots = (t.src_batch.len() / mtu).min(t.dst_batch.len() / dst_stride);
+                let max_read_packets = iface.max_read_packets(mtu);
+                let mut lens = Vec::with_capacity(n_slots);
+
+                let mut iter = 0;
+                while iter < MAX_ITR {
+                    lens.clear();
+                    let mut n_reads = 0;
+                    let mut drained = false;
+                    let batch_size = MAX_BATCH.min(MAX_ITR - iter);
+                    while n_reads < batch_size && lens.len() + max_read_packets <= n_slots {
+                        match iface.read_packets(
+                            &mut t.iface_scratch,
+                            &mut t.src_batch,
+                            mtu,
+                            &mut lens,
+                        ) {
+                            Ok(()) => n_reads += 1,
+                            Err(Error::IfaceRead(e)) => {
+                                let ek = e.kind();
+                                if ek == io::ErrorKind::Interrupted
+                                    || ek == io::ErrorKind::WouldBlock
+                                {
+                                    drained = true;
+                                    break;
+                                }
+                                eprintln!("Fatal read error on tun interface: {:?}", e);
+                                return Action::Exit;
+                            }
+                            Err(e) => {
+                                eprintln!("Unexpected error on tun interface: {:?}", e);
+                                return Action::Exit;
                             }
-                            eprintln!("Fatal read error on tun interface: {:?}", e);
-                            return Action::Exit;
-                        }
-                        Err(e) => {
-                            eprintln!("Unexpected error on tun interface: {:?}", e);
-                            return Action::Exit;
                         }
-                    };
-
-                    let dst_addr = match Tunn::dst_address(src) {
-                        Some(addr) => addr,
-                        None => continue,
-                    };
+                    }
+                    iter += n_reads.max(1);
+
+                    let src_batch = &t.src_batch;
+                    let packets: Vec<&[u8]> = lens
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &len)| &src_batch[i * mtu..][..len])
+                        .collect();
+                    let n_read = packets.len();
+
+                    let mut dst_batch = &mut t.dst_batch[..];
+                    let mut results = Vec::with_capacity(n_read);
+                    let mut outgoing = Vec::with_capacity(n_read);
+                    let mut i = 0;
+                    while i < n_read {
+                        let peer = match find_peer(packets[i]) {
+                            Some(peer) => peer,
+                            None => {
+                                i += 1;
+                                continue;
+                            }
+                        };
 
-                    let mut peer = match peers.find(dst_addr) {
-                        Some(peer) => peer.lock(),
-                        None => continue,
-                    };
+                        let mut end = i + 1;
+                        while end < n_read
+                            && find_peer(packets[end]).is_some_and(|p| Arc::ptr_eq(p, peer))
+                        {
+                            end += 1;
+                        }
 
-                    match peer.tunnel.encapsulate(src, &mut t.dst_buf[..]) {
-                        TunnResult::Done => {}
-                        TunnResult::Err(e) => {
-                            tracing::error!(message = "Encapsulate error", error = ?e)
+                        let (dst, rest) =
+                            std::mem::take(&mut dst_batch).split_at_mut((end - i) * dst_stride);
+                        dst_batch = rest;
+
+                        let mut p = peer.lock();
+                        p.tunnel
+                            .encapsulate_batch(&packets[i..end], dst, dst_stride, &mut results);
+                        schedule_timers(&d.timers, peer, &mut p);
+                        i = end;
+
+                        outgoing.clear();
+                        for result in results.drain(..) {
+                            match result {
+                                TunnResult::Done => {}
+                                TunnResult::Err(e) => {
+                                    tracing::error!(message = "Encapsulate error", error = ?e)
+                                }
+                                TunnResult::WriteToNetwork(packet) => outgoing.push(&packet[..]),
+                                _ => panic!("Unexpected result from encapsulate"),
+                            };
                         }
-                        TunnResult::WriteToNetwork(packet) => {
-                            let mut endpoint = peer.endpoint_mut();
-                            if let Some(conn) = endpoint.conn.as_mut() {
-                                // Prefer to send using the connected socket
-                                let _: Result<_, _> = conn.write(packet);
-                            } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp4.send_to(packet, &addr.into());
-                            } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
-                                let _: Result<_, _> = udp6.send_to(packet, &addr.into());
-                            } else {
-                                tracing::error!("No endpoint");
+                        if outgoing.is_empty() {
+                            continue;
+                        }
+
+                        let endpoint = p.endpoint();
+                        if let Some(conn) = endpoint.conn.as_ref() {
+                            // Prefer to send using the connected socket
+                            let _: Result<_, _> = conn.send_batch(&outgoing, None);
+                            if conn.has_pending() {
+                                // Flushed by the timers
+                                drop(endpoint);
+                                schedule_timers(&d.timers, peer, &mut p);
                             }
+                        } else if p.protocol() == EndpointProtocol::Tcp {
+                            // Dropped until the connection is reestablished
+                        } else if let Some(addr @ SocketAddr::V4(_)) = endpoint.addr {
+                            let _: Result<_, _> = sock4.send_batch(&outgoing, Some(addr));
+                        } else if let Some(addr @ SocketAddr::V6(_)) = endpoint.addr {
+                            let _: Result<_, _> = sock6.send_batch(&outgoing, Some(addr));
+                        } else {
+                            tracing::error!("No endpoint");
                         }
-                        _ => panic!("Unexpected result from encapsulate"),
-                    };
+                    }
+
+                    if drained {
+                        break;
+                    }
                 }
                 Action::Continue
             }),
         )?;
         Ok(())
     }
+
+    fn open_tun(name: &str, _config: &DeviceConfig) -> Result<TunSocket, Error> {
+        #[cfg(target_os = "linux")]
+        if _config.use_tun_offload {
+            return TunSocket::new_with_offload(name);
+        }
+        TunSocket::new(name)
+    }
+
+    fn default_transport(_config: &DeviceConfig) -> UdpTransport {
+        #[cfg(target_os = "linux")]
+        return UdpTransport::new(udp_batch::UdpBatching {
+            mmsg: _config.use_mmsg,
+            offload: _config.use_udp_offload,
+        });
+        #[cfg(not(target_os = "linux"))]
+        return UdpTransport::default();
+    }
+}
+
+/// Set the fwmark of a socket, for policy routing
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_mark(sock: &dyn TransportSocket, mark: u32) -> Result<(), Error> {
+    sock.set_mark(mark)
+        .map_err(|e| Error::SetSockOpt(format!("Failed to set fwmark {}: {}", mark, e)))
+}
+
+/// Schedule the timers of a peer for when its tunnel has something to do next, unless they are
+/// due earlier already
+fn schedule_timers(timers: &Mutex<TimerWheel<PeerTimer>>, peer: &Arc<Mutex<Peer>>, p: &mut Peer) {
+    let deadline = match p.time_to_next_event() {
+        Some(delay) => Instant::now() + delay,
+        None => return,
+    };
+    if matches!(p.timer_deadline, Some(scheduled) if scheduled <= deadline) {
+        return;
+    }
+    p.timer_deadline = Some(deadline);
+    timers
+        .lock()
+        .insert(deadline, (Arc::downgrade(peer), deadline));
+}
+
+/// Find the end of the longest run of packets from `start` for which `same_run` holds, and that
+/// fits into `dst_len` bytes when decapsulated with a common stride. Returns the end of the run and
+/// the stride to use, decapsulated packets are never larger than the datagrams they came in.
+fn decapsulation_run(
+    packets: &[&[u8]],
+    start: usize,
+    dst_len: usize,
+    same_run: impl Fn(usize) -> bool,
+) -> (usize, usize) {
+    let mut stride = packets[start].len();
+    let mut end = start + 1;
+    while end < packets.len() && same_run(end) {
+        let run_stride = stride.max(packets[end].len());
+        if (end + 1 - start) * run_stride > dst_len {
+            break;
+        }
+        stride = run_stride;
+        end += 1;
+    }
+    (end, stride)
 }
 
 /// A basic linear-feedback shift register implemented as xorshift, used to
@@ -0,0 +1,375 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Sets up the interface over rtnetlink: brings it up with its addresses and MTU, and routes the
+//! allowed IPs of peers to it, optionally in a dedicated table with the policy rules wg-quick
+//! installs. The addresses, routes and rules are removed again when the device is dropped.
+
+use super::peer::AllowedIP;
+use super::Error;
+use libc::*;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+
+const FRA_FWMARK: c_ushort = 10;
+const FRA_SUPPRESS_PREFIXLEN: c_ushort = 14;
+const FRA_TABLE: c_ushort = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+const NLMSG_HDR_LEN: usize = 16;
+
+/// How to set up the interface of the device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkConfig {
+    /// Assigned to the interface, with the prefix length of their subnet
+    pub addresses: Vec<AllowedIP>,
+    pub mtu: Option<u32>,
+    /// Route the allowed IPs of peers to the interface, as peers change
+    pub routes: bool,
+    /// Install the routes in this table rather than the main one, along with rules that send
+    /// packets without the fwmark of the device to it. The fwmark of the device is set to the
+    /// table, so that its own packets bypass it. Required for default routes.
+    pub table: Option<u32>,
+}
+
+/// What was set up on the interface, undone on drop
+pub(crate) struct NetworkSetup {
+    netlink: Netlink,
+    index: u32,
+    table: u32,
+    routes: bool,
+    /// The subnet of every installed route
+    installed: HashSet<AllowedIP>,
+    /// Messages that undo the setup, in the order it was done
+    undo: Vec<Message>,
+}
+
+impl NetworkSetup {
+    /// Set up the interface called `name` as `config` asks, apart from its routes
+    pub(crate) fn new(name: &str, config: &NetworkConfig) -> Result<NetworkSetup, Error> {
+        let mut setup = NetworkSetup {
+            netlink: Netlink::open().map_err(Error::Netlink)?,
+            index: if_index(name).map_err(Error::Netlink)?,
+            table: config.table.unwrap_or(RT_TABLE_MAIN.into()),
+            routes: config.routes,
+            installed: HashSet::new(),
+            undo: vec![],
+        };
+
+        // Whatever was set up before an error is undone when setup is dropped
+        setup
+            .netlink
+            .request(&link_message(setup.index, config.mtu))
+            .map_err(Error::Netlink)?;
+
+        for &address in &config.addresses {
+            let message = address_message(RTM_NEWADDR, setup.index, address);
+            setup.netlink.request(&message).map_err(Error::Netlink)?;
+            let undo = address_message(RTM_DELADDR, setup.index, address);
+            setup.undo.push(undo);
+        }
+
+        if let Some(table) = config.table {
+            for family in [AF_INET, AF_INET6] {
+                let undo = rule_messages(RTM_DELRULE, family, table);
+                for (message, undo) in rule_messages(RTM_NEWRULE, family, table).iter().zip(undo) {
+                    setup.netlink.request(message).map_err(Error::Netlink)?;
+                    setup.undo.push(undo);
+                }
+            }
+        }
+
+        Ok(setup)
+    }
+
+    /// Route `allowed_ips` to the interface, and remove the routes to those no longer among them.
+    /// Routes that fail to be added are tried again on the next call.
+    pub(crate) fn sync_routes(&mut self, allowed_ips: impl Iterator<Item = AllowedIP>) {
+        if !self.routes {
+            return;
+        }
+
+        let wanted: HashSet<_> = allowed_ips.map(subnet).collect();
+        let stale: Vec<_> = self.installed.difference(&wanted).copied().collect();
+        let new: Vec<_> = wanted.difference(&self.installed).copied().collect();
+
+        for ip in stale {
+            let message = route_message(RTM_DELROUTE, self.index, ip, self.table);
+            if let Err(e) = self.netlink.request(&message) {
+                tracing::warn!(message = "Failed to remove route", route = ?ip, error = ?e);
+            }
+            self.installed.remove(&ip);
+        }
+
+        for ip in new {
+            let message = route_message(RTM_NEWROUTE, self.index, ip, self.table);
+            match self.netlink.request(&message) {
+                Ok(()) => {
+                    self.installed.insert(ip);
+                }
+                Err(e) => tracing::warn!(message = "Failed to add route", route = ?ip, error = ?e),
+            }
+        }
+    }
+}
+
+impl Drop for NetworkSetup {
+    fn drop(&mut self) {
+        self.sync_routes(std::iter::empty());
+        while let Some(message) = self.undo.pop() {
+            if let Err(e) = self.netlink.request(&message) {
+                tracing::warn!(message = "Failed to undo interface setup", error = ?e);
+            }
+        }
+    }
+}
+
+fn if_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// The subnet of `ip`, with the host bits cleared as routes require
+fn subnet(ip: AllowedIP) -> AllowedIP {
+    let addr = match ip.addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(ip.cidr)).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(ip.cidr)).unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    };
+    AllowedIP {
+        addr,
+        cidr: ip.cidr,
+    }
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    }
+}
+
+fn addr_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// Bring the interface up, with `mtu`
+fn link_message(index: u32, mtu: Option<u32>) -> Message {
+    // struct ifinfomsg
+    let mut header = vec![AF_UNSPEC as u8, 0, 0, 0];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&(IFF_UP as u32).to_ne_bytes()); // flags
+    header.extend_from_slice(&(IFF_UP as u32).to_ne_bytes()); // change
+
+    let message = Message::new(RTM_NEWLINK, 0, &header);
+    match mtu {
+        Some(mtu) => message.attr(IFLA_MTU, &mtu.to_ne_bytes()),
+        None => message,
+    }
+}
+
+fn address_message(kind: u16, index: u32, address: AllowedIP) -> Message {
+    // struct ifaddrmsg
+    let mut header = vec![family(address.addr), address.cidr, 0, RT_SCOPE_UNIVERSE];
+    header.extend_from_slice(&index.to_ne_bytes());
+
+    let addr = addr_bytes(address.addr);
+    Message::new(kind, NLM_F_CREATE | NLM_F_REPLACE, &header)
+        .attr(IFA_LOCAL, &addr)
+        .attr(IFA_ADDRESS, &addr)
+}
+
+fn route_message(kind: u16, index: u32, ip: AllowedIP, table: u32) -> Message {
+    // struct rtmsg
+    let mut header = vec![
+        family(ip.addr),
+        ip.cidr,
+        0,
+        0,
+        RT_TABLE_UNSPEC,
+        RTPROT_BOOT,
+        RT_SCOPE_LINK,
+        RTN_UNICAST,
+    ];
+    header.extend_from_slice(&0u32.to_ne_bytes());
+
+    Message::new(kind, NLM_F_CREATE | NLM_F_REPLACE, &header)
+        .attr(RTA_DST, &addr_bytes(ip.addr))
+        .attr(RTA_OIF, &index.to_ne_bytes())
+        .attr(RTA_TABLE, &table.to_ne_bytes())
+}
+
+/// The rules of wg-quick, `not fwmark <table> table <table>` and then, taking precedence,
+/// `table main suppress_prefixlength 0` so that more specific routes of the main table still
+/// apply
+fn rule_messages(kind: u16, family: c_int, table: u32) -> [Message; 2] {
+    // struct fib_rule_hdr
+    let header = |table: u8, flags: u32| {
+        let mut header = vec![family as u8, 0, 0, 0, table, 0, 0, FR_ACT_TO_TBL];
+        header.extend_from_slice(&flags.to_ne_bytes());
+        header
+    };
+    let main = u32::from(RT_TABLE_MAIN);
+
+    [
+        Message::new(kind, NLM_F_CREATE, &header(0, FIB_RULE_INVERT))
+            .attr(FRA_FWMARK, &table.to_ne_bytes())
+            .attr(FRA_TABLE, &table.to_ne_bytes()),
+        Message::new(kind, NLM_F_CREATE, &header(RT_TABLE_MAIN, 0))
+            .attr(FRA_TABLE, &main.to_ne_bytes())
+            .attr(FRA_SUPPRESS_PREFIXLEN, &0u32.to_ne_bytes()),
+    ]
+}
+
+/// A netlink request, with room for its header
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    /// A request that is acknowledged, with the flags the request type needs on top. The
+    /// creation flags are dropped from deletions.
+    fn new(kind: u16, flags: c_int, header: &[u8]) -> Message {
+        let flags = match kind {
+            RTM_DELADDR | RTM_DELROUTE | RTM_DELRULE => NLM_F_REQUEST | NLM_F_ACK,
+            _ => NLM_F_REQUEST | NLM_F_ACK | flags,
+        };
+        let mut buf = vec![0u8; NLMSG_HDR_LEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags as u16).to_ne_bytes());
+        buf.extend_from_slice(header);
+        Message { buf }
+    }
+
+    fn attr(mut self, kind: c_ushort, data: &[u8]) -> Message {
+        let len = 4 + data.len() as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize((self.buf.len() + 3) & !3, 0); // Attributes are 4 byte aligned
+        self
+    }
+}
+
+struct Netlink {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+impl Netlink {
+    fn open() -> io::Result<Netlink> {
+        match unsafe { socket(AF_NETLINK, SOCK_RAW | SOCK_CLOEXEC, NETLINK_ROUTE) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Netlink { fd, seq: 0 }),
+        }
+    }
+
+    /// Send `message` to the kernel, and wait for it to be acknowledged
+    fn request(&mut self, message: &Message) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut buf = message.buf.clone();
+        let len = buf.len() as u32;
+        buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+
+        let mut kernel: sockaddr_nl = unsafe { std::mem::zeroed() };
+        kernel.nl_family = AF_NETLINK as _;
+        let sent = unsafe {
+            sendto(
+                self.fd,
+                buf.as_ptr() as _,
+                buf.len(),
+                0,
+                &kernel as *const sockaddr_nl as _,
+                std::mem::size_of::<sockaddr_nl>() as _,
+            )
+        };
+        if sent == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 4096];
+        loop {
+            let n = match unsafe { recv(self.fd, reply.as_mut_ptr() as _, reply.len(), 0) } {
+                -1 => return Err(io::Error::last_os_error()),
+                n => n as usize,
+            };
+
+            let mut rest = &reply[..n];
+            while rest.len() >= NLMSG_HDR_LEN {
+                let len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(rest[4..6].try_into().unwrap());
+                let seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+                if kind == NLMSG_ERROR as u16 && seq == self.seq && rest.len() >= NLMSG_HDR_LEN + 4
+                {
+                    // struct nlmsgerr, where zero acknowledges the request
+                    let error = i32::from_ne_bytes(rest[16..20].try_into().unwrap());
+                    return match error {
+                        0 => Ok(()),
+                        error => Err(io::Error::from_raw_os_error(-error)),
+                    };
+                }
+                if len < NLMSG_HDR_LEN {
+                    break;
+                }
+                rest = &rest[((len + 3) & !3).min(rest.len())..];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subnet_clears_host_bits() {
+        for (ip, expected) in [
+            ("10.1.2.3/24", "10.1.2.0/24"),
+            ("10.1.2.3/32", "10.1.2.3/32"),
+            ("10.1.2.3/0", "0.0.0.0/0"),
+            ("fd00::1:2/112", "fd00::1:0/112"),
+            ("fd00::1/0", "::/0"),
+        ] {
+            let ip = ip.parse::<AllowedIP>().unwrap();
+            assert_eq!(subnet(ip), expected.parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn route_message_layout() {
+        let ip = "10.0.0.0/8".parse().unwrap();
+        let message = route_message(RTM_DELROUTE, 7, ip, 51820);
+
+        let flags = u16::from_ne_bytes([message.buf[6], message.buf[7]]);
+        assert_eq!(flags, (NLM_F_REQUEST | NLM_F_ACK) as u16);
+        // The header, then three attributes of 8 bytes
+        assert_eq!(message.buf.len(), NLMSG_HDR_LEN + 12 + 3 * 8);
+        assert_eq!(&message.buf[16..18], &[AF_INET as u8, 8]);
+        assert_eq!(&message.buf[32..36], &[10, 0, 0, 0]);
+        assert_eq!(&message.buf[40..44], &7u32.to_ne_bytes());
+        assert_eq!(&message.buf[48..52], &51820u32.to_ne_bytes());
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An embedded userspace TCP/IP stack, to terminate the tunnel inside the application instead of
+//! in the kernel.
+//!
+//! A `Netstack` acts as the interface of a device. Applications dial and listen through the
+//! tunnel with the `TcpStream`, `TcpListener` and `UdpSocket` handles it hands out, much like
+//! their `std::net` counterparts, without a TUN device or any privileges.
+
+mod tcp;
+mod udp;
+
+use super::pollable_queue::PollableQueue;
+use super::transport::Transport;
+use super::virtual_interface::VirtualInterface;
+use super::{Device, DeviceConfig, DeviceHandle, Error};
+use parking_lot::{Condvar, Mutex, MutexGuard};
+use rand_core::{OsRng, RngCore};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp as tcp_socket, Socket};
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint};
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use tcp::{TcpListener, TcpStream};
+pub use udp::UdpSocket;
+
+pub const DEFAULT_MTU: usize = 1420;
+
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+/// How long the timer thread sleeps when no socket has a deadline
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An embedded network stack with the given tunnel addresses
+pub struct Netstack {
+    shared: Arc<Shared>,
+}
+
+/// The `VirtualInterface` a device exchanges packets with a `Netstack` over
+pub struct NetstackInterface {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    name: String,
+    mtu: usize,
+    stack: Mutex<Stack>,
+    /// Notified after every poll of the stack, for handles waiting on their sockets
+    changed: Condvar,
+    /// Notified when a handle used its socket, for the timer thread to recompute its deadline
+    timer: Condvar,
+    /// The packets the stack sent, for the device to encapsulate
+    egress: PollableQueue<Vec<u8>>,
+}
+
+struct Stack {
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    /// The packets the device decapsulated, for the stack to process
+    ingress: VecDeque<Vec<u8>>,
+    /// Sockets whose handles were dropped, removed once their connection is closed
+    closing: Vec<SocketHandle>,
+    /// The ports of the `TcpListener`s
+    listening: HashSet<u16>,
+    next_port: u16,
+    shutdown: bool,
+}
+
+impl Netstack {
+    /// Create a stack with the given addresses, at most one of each address family
+    pub fn new(name: &str, addresses: &[IpAddr], mtu: usize) -> io::Result<Netstack> {
+        let mut phy = Phy {
+            ingress: &mut VecDeque::new(),
+            egress: None,
+            mtu,
+        };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = OsRng.next_u64();
+        let mut iface = Interface::new(config, &mut phy, smoltcp::time::Instant::now());
+
+        for &addr in addresses {
+            let cidr = match addr {
+                IpAddr::V4(_) => IpCidr::new(addr.into(), 32),
+                IpAddr::V6(_) => IpCidr::new(addr.into(), 128),
+            };
+            let mut result = Ok(());
+            iface.update_ip_addrs(|addrs| result = addrs.push(cidr).map(|_| ()));
+            // Everything is reachable through the tunnel, smoltcp only needs to know that
+            let route = match addr {
+                IpAddr::V4(addr) => iface.routes_mut().add_default_ipv4_route(addr.into()),
+                IpAddr::V6(addr) => iface.routes_mut().add_default_ipv6_route(addr.into()),
+            };
+            if result.is_err() || matches!(route, Err(_) | Ok(Some(_))) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "At most one address of each family is supported",
+                ));
+            }
+        }
+
+        let shared = Arc::new(Shared {
+            name: name.to_owned(),
+            mtu,
+            stack: Mutex::new(Stack {
+                iface,
+                sockets: SocketSet::new(vec![]),
+                ingress: VecDeque::new(),
+                closing: vec![],
+                listening: HashSet::new(),
+                next_port: FIRST_EPHEMERAL_PORT,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+            timer: Condvar::new(),
+            egress: PollableQueue::new()?,
+        });
+
+        let timer_shared = Arc::clone(&shared);
+        thread::Builder::new()
+            .name(format!("{}-netstack", name))
+            .spawn(move || timer_shared.run_timers())?;
+
+        Ok(Netstack { shared })
+    }
+
+    /// The interface to create the device with
+    pub fn interface(&self) -> Arc<dyn VirtualInterface> {
+        Arc::new(NetstackInterface {
+            shared: Arc::clone(&self.shared),
+        })
+    }
+
+    /// Open a TCP connection through the tunnel
+    pub fn tcp_connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect(&self.shared, addr, None)
+    }
+
+    /// Open a TCP connection through the tunnel, giving up after `timeout`
+    pub fn tcp_connect_timeout(
+        &self,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> io::Result<TcpStream> {
+        TcpStream::connect(&self.shared, addr, Some(timeout))
+    }
+
+    /// Accept TCP connections on `port` of any of the stack's addresses, or on an ephemeral
+    /// port if `port` is 0
+    pub fn tcp_listen(&self, port: u16) -> io::Result<TcpListener> {
+        TcpListener::bind(&self.shared, port)
+    }
+
+    /// Bind a UDP socket to `port` of any of the stack's addresses, or to an ephemeral port if
+    /// `port` is 0
+    pub fn udp_bind(&self, port: u16) -> io::Result<UdpSocket> {
+        UdpSocket::bind(&self.shared, port)
+    }
+}
+
+impl Drop for Netstack {
+    fn drop(&mut self) {
+        self.shared.stack.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        self.shared.timer.notify_all();
+        self.shared.egress.shutdown();
+    }
+}
+
+impl DeviceHandle {
+    /// Create a device that terminates tunnel traffic in an embedded network stack with the given
+    /// addresses, rather than handing it to the system over a TUN device
+    pub fn new_with_netstack(
+        name: &str,
+        config: DeviceConfig,
+        addresses: &[IpAddr],
+    ) -> Result<(DeviceHandle, Netstack), Error> {
+        let transport = Arc::new(Device::default_transport(&config));
+        DeviceHandle::new_with_netstack_and_transport(name, config, addresses, transport)
+    }
+
+    /// Create a device that terminates tunnel traffic in an embedded network stack, and exchanges
+    /// datagrams with its peers over the given transport
+    pub fn new_with_netstack_and_transport(
+        name: &str,
+        config: DeviceConfig,
+        addresses: &[IpAddr],
+        transport: Arc<dyn Transport>,
+    ) -> Result<(DeviceHandle, Netstack), Error> {
+        let netstack = Netstack::new(name, addresses, DEFAULT_MTU)?;
+        let device = DeviceHandle::new_with_interface(netstack.interface(), config, transport)?;
+        Ok((device, netstack))
+    }
+}
+
+impl Shared {
+    /// Process queued packets and socket timers, and wake up the handles
+    fn poll(&self, stack: &mut Stack) {
+        let Stack {
+            iface,
+            sockets,
+            ingress,
+            closing,
+            ..
+        } = stack;
+        let mut phy = Phy {
+            ingress,
+            egress: Some(&self.egress),
+            mtu: self.mtu,
+        };
+        iface.poll(smoltcp::time::Instant::now(), &mut phy, sockets);
+
+        closing.retain(|&handle| {
+            let state = sockets.get::<tcp_socket::Socket>(handle).state();
+            let closed = matches!(
+                state,
+                tcp_socket::State::Closed | tcp_socket::State::TimeWait
+            );
+            if closed {
+                sockets.remove(handle);
+            }
+            !closed
+        });
+
+        self.changed.notify_all();
+    }
+
+    /// Poll after a handle used its socket, and let the timer thread know
+    fn poll_and_reschedule(&self, stack: &mut Stack) {
+        self.poll(stack);
+        self.timer.notify_one();
+    }
+
+    /// Block until `ready` returns a result, polling the stack whenever it may have changed
+    fn wait<T>(
+        &self,
+        stack: &mut MutexGuard<Stack>,
+        timeout: Option<Duration>,
+        mut ready: impl FnMut(&mut Stack) -> Option<io::Result<T>>,
+    ) -> io::Result<T> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if stack.shutdown {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "The network stack was shut down",
+                ));
+            }
+            if let Some(result) = ready(stack) {
+                return result;
+            }
+            match deadline {
+                Some(deadline) => {
+                    if self.changed.wait_until(stack, deadline).timed_out() {
+                        return ready(stack).unwrap_or_else(|| {
+                            Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"))
+                        });
+                    }
+                }
+                None => self.changed.wait(stack),
+            }
+        }
+    }
+
+    /// Drive retransmissions, delayed acks and keepalives until the `Netstack` is dropped
+    fn run_timers(&self) {
+        let mut stack = self.stack.lock();
+        while !stack.shutdown {
+            self.poll(&mut stack);
+            let Stack { iface, sockets, .. } = &mut *stack;
+            let delay = iface
+                .poll_delay(smoltcp::time::Instant::now(), sockets)
+                .map(Duration::from)
+                .unwrap_or(IDLE_POLL_INTERVAL);
+            self.timer.wait_for(&mut stack, delay);
+        }
+    }
+}
+
+impl Stack {
+    /// Pick a free port on the stack for a new socket
+    fn ephemeral_port(&mut self) -> io::Result<u16> {
+        let n_ports = u16::MAX - FIRST_EPHEMERAL_PORT + 1;
+        for _ in 0..n_ports {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+            if !self.port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "No free port"))
+    }
+
+    fn port_in_use(&self, port: u16) -> bool {
+        self.listening.contains(&port)
+            || self.sockets.iter().any(|(_, socket)| match socket {
+                Socket::Tcp(socket) => socket.local_endpoint().map(|e| e.port) == Some(port),
+                Socket::Udp(socket) => socket.endpoint().port == port,
+            })
+    }
+}
+
+fn socket_addr(endpoint: IpEndpoint) -> SocketAddr {
+    SocketAddr::new(endpoint.addr.into(), endpoint.port)
+}
+
+fn ip_endpoint(addr: SocketAddr) -> IpEndpoint {
+    IpEndpoint::new(IpAddress::from(addr.ip()), addr.port())
+}
+
+impl VirtualInterface for NetstackInterface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.shared.egress.as_raw_fd()
+    }
+
+    fn name(&self) -> Result<String, Error> {
+        Ok(self.shared.name.clone())
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        Ok(self.shared.mtu)
+    }
+
+    fn read_packets(
+        &self,
+        _scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        let packet = self
+            .shared
+            .egress
+            .pop()
+            .ok_or_else(|| Error::IfaceRead(io::ErrorKind::WouldBlock.into()))?;
+        // The stack never sends packets larger than the MTU
+        dst[lens.len() * stride..][..packet.len()].copy_from_slice(&packet);
+        lens.push(packet.len());
+        Ok(())
+    }
+
+    fn write_packets(&self, packets: &mut [&mut [u8]]) {
+        let mut stack = self.shared.stack.lock();
+        if stack.shutdown {
+            return;
+        }
+        stack
+            .ingress
+            .extend(packets.iter().map(|packet| packet.to_vec()));
+        self.shared.poll_and_reschedule(&mut stack);
+    }
+}
+
+/// The device smoltcp polls, backed by the packet queues between the stack and the tunnel
+struct Phy<'a> {
+    ingress: &'a mut VecDeque<Vec<u8>>,
+    /// None while the interface is being created
+    egress: Option<&'a PollableQueue<Vec<u8>>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(Option<&'a PollableQueue<Vec<u8>>>);
+
+impl phy::Device for Phy<'_> {
+    type RxToken<'a>
+        = RxToken
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.ingress.pop_front()?;
+        Some((RxToken(packet), TxToken(self.egress)))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(self.egress))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        if let Some(egress) = self.0 {
+            egress.push(packet);
+        }
+        result
+    }
+}
+
+// Two devices with embedded network stacks talking TCP and UDP over an in-memory network
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::device::transport::MemoryNetwork;
+    use crate::x25519::{PublicKey, StaticSecret};
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, Shutdown};
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixStream;
+
+    struct Node {
+        _device: DeviceHandle,
+        netstack: Netstack,
+        api: UnixStream,
+        key: StaticSecret,
+    }
+
+    impl Node {
+        fn new(network: &MemoryNetwork, endpoint: Ipv4Addr, addr: Ipv4Addr) -> Node {
+            let (api, uapi_fd) = UnixStream::pair().unwrap();
+            let config = DeviceConfig {
+                n_threads: 2,
+                uapi_fd: uapi_fd.into_raw_fd(),
+                ..Default::default()
+            };
+            let transport = network.transport(endpoint, endpoint.to_ipv6_mapped());
+            let (device, netstack) = DeviceHandle::new_with_netstack_and_transport(
+                "ns0",
+                config,
+                &[addr.into()],
+                Arc::new(transport),
+            )
+            .unwrap();
+
+            let node = Node {
+                _device: device,
+                netstack,
+                api,
+                key: StaticSecret::random_from_rng(OsRng),
+            };
+            node.set(&format!(
+                "private_key={}\nlisten_port=51820",
+                hex::encode(node.key.to_bytes())
+            ));
+            node
+        }
+
+        fn set(&self, settings: &str) {
+            write!(&self.api, "set=1\n{}\n\n", settings).unwrap();
+            let mut response = vec![];
+            while !response.ends_with(b"\n\n") {
+                let mut byte = [0];
+                (&self.api).read_exact(&mut byte).unwrap();
+                response.push(byte[0]);
+            }
+            assert_eq!(response, b"errno=0\n\n");
+        }
+
+        fn add_peer(&self, peer: &Node, endpoint: Ipv4Addr, allowed_ip: Ipv4Addr) {
+            self.set(&format!(
+                "public_key={}\nendpoint={}:51820\nallowed_ip={}/32",
+                hex::encode(PublicKey::from(&peer.key).as_bytes()),
+                endpoint,
+                allowed_ip
+            ));
+        }
+    }
+
+    #[test]
+    fn tcp_and_udp_through_tunnel() {
+        let network = MemoryNetwork::new();
+        let (endpoint_a, endpoint_b) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let (tunnel_a, tunnel_b) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2));
+
+        let a = Node::new(&network, endpoint_a, tunnel_a);
+        let b = Node::new(&network, endpoint_b, tunnel_b);
+        a.add_peer(&b, endpoint_b, tunnel_b);
+        b.add_peer(&a, endpoint_a, tunnel_a);
+
+        // An echo server on b
+        let listener = b.netstack.tcp_listen(7).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            assert_eq!(peer.ip(), tunnel_a);
+            let mut received = vec![];
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+        });
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut stream = a
+            .netstack
+            .tcp_connect_timeout((tunnel_b, 7).into(), Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), (tunnel_b, 7).into());
+        stream.set_read_timeout(Some(Duration::from_secs(10)));
+        stream.write_all(&data).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).unwrap();
+        assert!(echoed == data);
+        server.join().unwrap();
+
+        // Nobody listens on this port
+        let refused = a
+            .netstack
+            .tcp_connect_timeout((tunnel_b, 8).into(), Duration::from_secs(10));
+        assert_eq!(
+            refused.err().unwrap().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+
+        let mut socket_a = a.netstack.udp_bind(0).unwrap();
+        let mut socket_b = b.netstack.udp_bind(53).unwrap();
+        socket_a.set_read_timeout(Some(Duration::from_secs(10)));
+        socket_b.set_read_timeout(Some(Duration::from_secs(10)));
+        socket_a.send_to(b"ping", (tunnel_b, 53).into()).unwrap();
+        let mut buf = [0u8; 100];
+        let (len, from) = socket_b.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(from.ip(), tunnel_a);
+        assert_eq!(from.port(), socket_a.local_addr().unwrap().port());
+        socket_b.send_to(b"pong", from).unwrap();
+        let (len, from) = socket_a.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, (tunnel_b, 53).into());
+    }
+}
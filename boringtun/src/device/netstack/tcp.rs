@@ -0,0 +1,282 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::{ip_endpoint, socket_addr, Shared, Stack};
+use parking_lot::Mutex;
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::tcp::{ConnectError, RecvError, SendError, Socket, SocketBuffer, State};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BUFFER_SIZE: usize = 256 * 1024;
+/// The number of connections a listener can have in the middle of their handshake
+const BACKLOG: usize = 8;
+
+fn new_socket() -> Socket<'static> {
+    let mut socket = Socket::new(
+        SocketBuffer::new(vec![0; BUFFER_SIZE]),
+        SocketBuffer::new(vec![0; BUFFER_SIZE]),
+    );
+    socket.set_nagle_enabled(false);
+    socket
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Not connected")
+}
+
+/// A TCP connection through the tunnel
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl TcpStream {
+    pub(super) fn connect(
+        shared: &Arc<Shared>,
+        addr: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> io::Result<TcpStream> {
+        let mut stack = shared.stack.lock();
+        let port = stack.ephemeral_port()?;
+        let mut socket = new_socket();
+        let Stack { iface, sockets, .. } = &mut *stack;
+        socket
+            .connect(iface.context(), ip_endpoint(addr), port)
+            .map_err(|e| match e {
+                ConnectError::Unaddressable => io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "No local address to connect from",
+                ),
+                ConnectError::InvalidState => io::Error::from(io::ErrorKind::InvalidInput),
+            })?;
+        let handle = sockets.add(socket);
+        shared.poll_and_reschedule(&mut stack);
+
+        let connected = shared.wait(&mut stack, timeout, |stack| {
+            match stack.sockets.get::<Socket>(handle).state() {
+                State::SynSent | State::SynReceived => None,
+                State::Closed => Some(Err(io::Error::from(io::ErrorKind::ConnectionRefused))),
+                _ => Some(Ok(())),
+            }
+        });
+        if let Err(e) = connected {
+            if !stack.shutdown {
+                stack.sockets.get_mut::<Socket>(handle).abort();
+                stack.closing.push(handle);
+                shared.poll_and_reschedule(&mut stack);
+            }
+            return Err(e);
+        }
+
+        Ok(TcpStream::new(shared, handle))
+    }
+
+    fn new(shared: &Arc<Shared>, handle: SocketHandle) -> TcpStream {
+        TcpStream {
+            shared: Arc::clone(shared),
+            handle,
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let stack = self.shared.stack.lock();
+        let socket = stack.sockets.get::<Socket>(self.handle);
+        socket
+            .local_endpoint()
+            .map(socket_addr)
+            .ok_or_else(not_connected)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let stack = self.shared.stack.lock();
+        let socket = stack.sockets.get::<Socket>(self.handle);
+        socket
+            .remote_endpoint()
+            .map(socket_addr)
+            .ok_or_else(not_connected)
+    }
+
+    /// Close the sending half of the connection. The receiving half is closed by the peer.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            let mut stack = self.shared.stack.lock();
+            stack.sockets.get_mut::<Socket>(self.handle).close();
+            self.shared.poll_and_reschedule(&mut stack);
+        }
+        Ok(())
+    }
+
+    /// Fail reads that wait longer than `timeout` with `TimedOut`, `None` waits indefinitely
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Fail writes that wait longer than `timeout` with `TimedOut`, `None` waits indefinitely
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let handle = self.handle;
+        let mut stack = self.shared.stack.lock();
+        let n = self.shared.wait(&mut stack, self.read_timeout, |stack| {
+            let socket = stack.sockets.get_mut::<Socket>(handle);
+            if socket.can_recv() {
+                Some(socket.recv_slice(buf).map_err(|e| match e {
+                    RecvError::InvalidState => not_connected(),
+                    RecvError::Finished => io::Error::from(io::ErrorKind::UnexpectedEof),
+                }))
+            } else if !socket.may_recv() {
+                // The peer closed the connection
+                Some(Ok(0))
+            } else {
+                None
+            }
+        })?;
+        // Let the peer know the window opened
+        self.shared.poll_and_reschedule(&mut stack);
+        Ok(n)
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let handle = self.handle;
+        let mut stack = self.shared.stack.lock();
+        let n = self.shared.wait(&mut stack, self.write_timeout, |stack| {
+            let socket = stack.sockets.get_mut::<Socket>(handle);
+            if socket.can_send() {
+                Some(
+                    socket
+                        .send_slice(buf)
+                        .map_err(|SendError::InvalidState| not_connected()),
+                )
+            } else if !socket.may_send() {
+                Some(Err(io::Error::from(io::ErrorKind::BrokenPipe)))
+            } else {
+                None
+            }
+        })?;
+        self.shared.poll_and_reschedule(&mut stack);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Written data is already on its way, like with a kernel socket
+        Ok(())
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut stack = self.shared.stack.lock();
+        stack.sockets.get_mut::<Socket>(self.handle).close();
+        stack.closing.push(self.handle);
+        self.shared.poll_and_reschedule(&mut stack);
+    }
+}
+
+/// Accepts TCP connections through the tunnel
+pub struct TcpListener {
+    shared: Arc<Shared>,
+    port: u16,
+    /// The listening sockets, each is handed out when it accepted a connection and replaced
+    backlog: Mutex<Vec<SocketHandle>>,
+}
+
+impl TcpListener {
+    pub(super) fn bind(shared: &Arc<Shared>, port: u16) -> io::Result<TcpListener> {
+        let mut stack = shared.stack.lock();
+        let port = match port {
+            0 => stack.ephemeral_port()?,
+            port if stack.port_in_use(port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "Address already in use",
+                ))
+            }
+            port => port,
+        };
+
+        stack.listening.insert(port);
+        let mut backlog = vec![];
+        for _ in 0..BACKLOG {
+            backlog.push(listen(&mut stack, port)?);
+        }
+        shared.poll_and_reschedule(&mut stack);
+
+        Ok(TcpListener {
+            shared: Arc::clone(shared),
+            port,
+            backlog: Mutex::new(backlog),
+        })
+    }
+
+    /// Wait for the next connection
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut backlog = self.backlog.lock();
+        let mut stack = self.shared.stack.lock();
+        loop {
+            let (i, peer) = self.shared.wait(&mut stack, None, |stack| {
+                backlog.iter().enumerate().find_map(|(i, &handle)| {
+                    let socket = stack.sockets.get::<Socket>(handle);
+                    match socket.state() {
+                        State::Listen | State::SynReceived => None,
+                        _ => Some(Ok((i, socket.remote_endpoint().map(socket_addr)))),
+                    }
+                })
+            })?;
+
+            let handle = std::mem::replace(&mut backlog[i], listen(&mut stack, self.port)?);
+            match peer {
+                Some(peer) => {
+                    self.shared.poll_and_reschedule(&mut stack);
+                    return Ok((TcpStream::new(&self.shared, handle), peer));
+                }
+                // The connection was reset before it was accepted
+                None => stack.closing.push(handle),
+            }
+        }
+    }
+
+    /// The port the listener accepts connections on, for any of the stack's addresses
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut stack = self.shared.stack.lock();
+        for &handle in self.backlog.get_mut().iter() {
+            // Reset the connections that were not accepted yet
+            stack.sockets.get_mut::<Socket>(handle).abort();
+            stack.closing.push(handle);
+        }
+        stack.listening.remove(&self.port);
+        self.shared.poll_and_reschedule(&mut stack);
+    }
+}
+
+fn listen(stack: &mut Stack, port: u16) -> io::Result<SocketHandle> {
+    let mut socket = new_socket();
+    socket
+        .listen(port)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    Ok(stack.sockets.add(socket))
+}
@@ -0,0 +1,116 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::{ip_endpoint, socket_addr, Shared};
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::udp::{PacketBuffer, PacketMetadata, RecvError, SendError, Socket};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const BUFFER_SIZE: usize = 256 * 1024;
+const BUFFER_PACKETS: usize = 256;
+
+/// A UDP socket bound to a port of the network stack
+pub struct UdpSocket {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    port: u16,
+    read_timeout: Option<Duration>,
+}
+
+impl UdpSocket {
+    pub(super) fn bind(shared: &Arc<Shared>, port: u16) -> io::Result<UdpSocket> {
+        let mut stack = shared.stack.lock();
+        let port = match port {
+            0 => stack.ephemeral_port()?,
+            port if stack.port_in_use(port) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "Address already in use",
+                ))
+            }
+            port => port,
+        };
+
+        let mut socket = Socket::new(
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; BUFFER_PACKETS],
+                vec![0; BUFFER_SIZE],
+            ),
+            PacketBuffer::new(
+                vec![PacketMetadata::EMPTY; BUFFER_PACKETS],
+                vec![0; BUFFER_SIZE],
+            ),
+        );
+        socket
+            .bind(port)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let handle = stack.sockets.add(socket);
+
+        Ok(UdpSocket {
+            shared: Arc::clone(shared),
+            handle,
+            port,
+            read_timeout: None,
+        })
+    }
+
+    /// The port the socket is bound to, for any of the stack's addresses
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port))
+    }
+
+    /// Send a datagram through the tunnel, waiting for room in the send buffer
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let handle = self.handle;
+        let mut stack = self.shared.stack.lock();
+        self.shared.wait(&mut stack, None, |stack| {
+            let socket = stack.sockets.get_mut::<Socket>(handle);
+            match socket.send_slice(buf, ip_endpoint(addr)) {
+                Ok(()) => Some(Ok(buf.len())),
+                Err(SendError::BufferFull) if buf.len() <= socket.payload_send_capacity() => None,
+                Err(SendError::BufferFull) => Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Datagram too large",
+                ))),
+                Err(SendError::Unaddressable) => Some(Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "Unreachable address",
+                ))),
+            }
+        })?;
+        self.shared.poll_and_reschedule(&mut stack);
+        Ok(buf.len())
+    }
+
+    /// Wait for the next datagram, the part that does not fit in `buf` is discarded
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let handle = self.handle;
+        let mut stack = self.shared.stack.lock();
+        self.shared.wait(&mut stack, self.read_timeout, |stack| {
+            let socket = stack.sockets.get_mut::<Socket>(handle);
+            match socket.recv() {
+                Ok((datagram, meta)) => {
+                    let len = datagram.len().min(buf.len());
+                    buf[..len].copy_from_slice(&datagram[..len]);
+                    Some(Ok((len, socket_addr(meta.endpoint))))
+                }
+                Err(RecvError::Exhausted) => None,
+                Err(RecvError::Truncated) => unreachable!("Only returned by recv_slice"),
+            }
+        })
+    }
+
+    /// Fail receives that wait longer than `timeout` with `TimedOut`, `None` waits indefinitely
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.shared.stack.lock().sockets.remove(self.handle);
+    }
+}
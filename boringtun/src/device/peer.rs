@@ -2,18 +2,139 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use parking_lot::RwLock;
-use socket2::{Domain, Protocol, Type};
 
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::device::allowed_ips::AllowedIpsDiff;
+use crate::device::transport::{Transport, TransportSocket};
 use crate::device::{AllowedIps, Error};
 use crate::noise::{Tunn, TunnResult};
 
-#[derive(Default, Debug)]
+#[derive(Default)]
 pub struct Endpoint {
     pub addr: Option<SocketAddr>,
-    pub conn: Option<socket2::Socket>,
+    pub conn: Option<Arc<dyn TransportSocket>>,
+}
+
+/// How datagrams are carried to a peer's endpoint
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EndpointProtocol {
+    #[default]
+    Udp,
+    /// Length-prefixed datagrams over a TCP connection, to the peer or to a relay
+    Tcp,
+}
+
+impl FromStr for EndpointProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "udp" => Ok(EndpointProtocol::Udp),
+            "tcp" => Ok(EndpointProtocol::Tcp),
+            _ => Err("Invalid protocol".to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for EndpointProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndpointProtocol::Udp => write!(f, "udp"),
+            EndpointProtocol::Tcp => write!(f, "tcp"),
+        }
+    }
+}
+
+/// The endpoint of a peer as configured, an address or a hostname to resolve
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEndpoint {
+    Addr(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+impl From<SocketAddr> for PeerEndpoint {
+    fn from(addr: SocketAddr) -> Self {
+        PeerEndpoint::Addr(addr)
+    }
+}
+
+impl FromStr for PeerEndpoint {
+    type Err = String;
+
+    /// A socket address, or `host:port`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(PeerEndpoint::Addr(addr));
+        }
+        let (host, port) = s.rsplit_once(':').ok_or("Invalid endpoint format")?;
+        let valid_host = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+        if host.is_empty() || !host.chars().all(valid_host) {
+            return Err("Invalid endpoint host".to_owned());
+        }
+        let port = port.parse().map_err(|_| "Invalid endpoint port")?;
+        Ok(PeerEndpoint::Host {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for PeerEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerEndpoint::Addr(addr) => write!(f, "{}", addr),
+            PeerEndpoint::Host { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// How often a hostname endpoint is resolved again, in case its address changed
+const RERESOLVE_INTERVAL: Duration = Duration::from_secs(120);
+/// How soon a hostname endpoint is resolved again, when resolving it or reaching it failed
+const RESOLVE_RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An endpoint configured as a hostname, and when it was last resolved
+struct HostEndpoint {
+    host: String,
+    port: u16,
+    last_resolved: Option<Instant>,
+}
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Spaces out the attempts to connect to a TCP endpoint, doubling the delay after every attempt
+/// that did not lead to a handshake
+#[derive(Default)]
+struct Backoff {
+    attempts: u32,
+    last_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    fn due(&self, now: Instant) -> bool {
+        self.time_to_next(now).is_zero()
+    }
+
+    /// The time until the next attempt is due
+    fn time_to_next(&self, now: Instant) -> Duration {
+        let last_attempt = match self.last_attempt {
+            Some(last_attempt) => last_attempt,
+            None => return Duration::ZERO,
+        };
+        (last_attempt + self.delay()).saturating_duration_since(now)
+    }
+
+    fn delay(&self) -> Duration {
+        MIN_RECONNECT_DELAY
+            .saturating_mul(1 << self.attempts.saturating_sub(1).min(16))
+            .min(MAX_RECONNECT_DELAY)
+    }
 }
 
 pub struct Peer {
@@ -22,8 +143,12 @@ pub struct Peer {
     /// The index the tunnel uses
     index: u32,
     endpoint: RwLock<Endpoint>,
+    protocol: EndpointProtocol,
+    reconnect: Backoff,
     allowed_ips: AllowedIps<()>,
-    preshared_key: Option<[u8; 32]>,
+    host_endpoint: Option<HostEndpoint>,
+    /// When the device is due to update the peer's timers
+    pub(crate) timer_deadline: Option<Instant>,
 }
 
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
@@ -56,7 +181,6 @@ impl Peer {
         index: u32,
         endpoint: Option<SocketAddr>,
         allowed_ips: &[AllowedIP],
-        preshared_key: Option<[u8; 32]>,
     ) -> Peer {
         Peer {
             tunnel,
@@ -65,8 +189,11 @@ impl Peer {
                 addr: endpoint,
                 conn: None,
             }),
+            protocol: EndpointProtocol::Udp,
+            reconnect: Backoff::default(),
             allowed_ips: allowed_ips.iter().map(|ip| (ip, ())).collect(),
-            preshared_key,
+            host_endpoint: None,
+            timer_deadline: None,
         }
     }
 
@@ -74,38 +201,105 @@ impl Peer {
         self.tunnel.update_timers(dst)
     }
 
-    pub fn endpoint(&self) -> parking_lot::RwLockReadGuard<'_, Endpoint> {
-        self.endpoint.read()
+    /// The time until the peer's timers are due. Peers reached over TCP are also due when their
+    /// connection holds datagrams to flush, or is down and due to be reconnected.
+    pub fn time_to_next_event(&self) -> Option<Duration> {
+        let tunnel = self.tunnel.time_to_next_event();
+        if self.protocol != EndpointProtocol::Tcp {
+            return tunnel;
+        }
+
+        let endpoint = self.endpoint.read();
+        let stream = match endpoint.conn.as_ref() {
+            _ if endpoint.addr.is_none() => None,
+            Some(conn) if !conn.is_closed() => conn.has_pending().then_some(Duration::ZERO),
+            _ => Some(self.reconnect.time_to_next(Instant::now())),
+        };
+        match (tunnel, stream) {
+            (Some(tunnel), Some(stream)) => Some(tunnel.min(stream)),
+            (tunnel, stream) => tunnel.or(stream),
+        }
     }
 
-    pub(crate) fn endpoint_mut(&self) -> parking_lot::RwLockWriteGuard<'_, Endpoint> {
-        self.endpoint.write()
+    pub fn endpoint(&self) -> parking_lot::RwLockReadGuard<'_, Endpoint> {
+        self.endpoint.read()
     }
 
     pub fn shutdown_endpoint(&self) {
         if let Some(conn) = self.endpoint.write().conn.take() {
             tracing::info!("Disconnecting from endpoint");
-            conn.shutdown(Shutdown::Both).unwrap();
+            conn.shutdown();
         }
     }
 
-    pub fn set_endpoint(&self, addr: SocketAddr) {
+    /// Returns whether the endpoint changed
+    pub fn set_endpoint(&self, addr: SocketAddr) -> bool {
         let mut endpoint = self.endpoint.write();
-        if endpoint.addr != Some(addr) {
+        if endpoint.addr == Some(addr) {
             // We only need to update the endpoint if it differs from the current one
-            if let Some(conn) = endpoint.conn.take() {
-                conn.shutdown(Shutdown::Both).unwrap();
-            }
+            return false;
+        }
+        if let Some(conn) = endpoint.conn.take() {
+            conn.shutdown();
+        }
+
+        endpoint.addr = Some(addr);
+        true
+    }
+
+    /// Resolve the endpoint from `host` and `port` from now on, or stop when None. Returns
+    /// whether they changed.
+    pub(crate) fn set_host_endpoint(&mut self, host: Option<(String, u16)>) -> bool {
+        let current = self
+            .host_endpoint
+            .as_ref()
+            .map(|h| (h.host.as_str(), h.port));
+        if current == host.as_ref().map(|(host, port)| (host.as_str(), *port)) {
+            return false;
+        }
+        self.host_endpoint = host.map(|(host, port)| HostEndpoint {
+            host,
+            port,
+            last_resolved: None,
+        });
+        true
+    }
+
+    /// The hostname and port the endpoint is resolved from
+    pub fn host_endpoint(&self) -> Option<(&str, u16)> {
+        self.host_endpoint
+            .as_ref()
+            .map(|h| (h.host.as_str(), h.port))
+    }
 
-            endpoint.addr = Some(addr);
+    /// The hostname and port to resolve the endpoint from now, when that is due. It is every
+    /// RERESOLVE_INTERVAL, or sooner when `failing` to reach the peer.
+    pub(crate) fn host_resolve_due(
+        &mut self,
+        now: Instant,
+        failing: bool,
+    ) -> Option<(String, u16)> {
+        let host = self.host_endpoint.as_mut()?;
+        let interval = if failing {
+            RESOLVE_RETRY_INTERVAL
+        } else {
+            RERESOLVE_INTERVAL
+        };
+        match host.last_resolved {
+            Some(last) if now < last + interval => None,
+            _ => {
+                host.last_resolved = Some(now);
+                Some((host.host.clone(), host.port))
+            }
         }
     }
 
     pub fn connect_endpoint(
         &self,
+        transport: &dyn Transport,
         port: u16,
         fwmark: Option<u32>,
-    ) -> Result<socket2::Socket, Error> {
+    ) -> Result<Arc<dyn TransportSocket>, Error> {
         let mut endpoint = self.endpoint.write();
 
         if endpoint.conn.is_some() {
@@ -116,22 +310,10 @@ impl Peer {
             .addr
             .expect("Attempt to connect to undefined endpoint");
 
-        let udp_conn =
-            socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::UDP))?;
-        udp_conn.set_reuse_address(true)?;
-        let bind_addr = if addr.is_ipv4() {
-            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into()
-        } else {
-            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0).into()
+        let conn: Arc<dyn TransportSocket> = match self.protocol {
+            EndpointProtocol::Udp => transport.connect(port, addr, fwmark)?,
+            EndpointProtocol::Tcp => transport.connect_stream(addr, fwmark)?,
         };
-        udp_conn.bind(&bind_addr)?;
-        udp_conn.connect(&addr.into())?;
-        udp_conn.set_nonblocking(true)?;
-
-        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-        if let Some(fwmark) = fwmark {
-            udp_conn.set_mark(fwmark)?;
-        }
 
         tracing::info!(
             message="Connected endpoint",
@@ -139,15 +321,67 @@ impl Peer {
             endpoint=?endpoint.addr.unwrap()
         );
 
-        endpoint.conn = Some(udp_conn.try_clone().unwrap());
+        endpoint.conn = Some(Arc::clone(&conn));
+
+        Ok(conn)
+    }
 
-        Ok(udp_conn)
+    pub fn protocol(&self) -> EndpointProtocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: EndpointProtocol) {
+        if self.protocol != protocol {
+            self.shutdown_endpoint();
+            self.protocol = protocol;
+        }
+    }
+
+    /// Whether the peer is reached over TCP, and its connection is down and due to be
+    /// reestablished. A connection that failed is shut down here.
+    pub(crate) fn tcp_reconnect_due(&mut self) -> bool {
+        if self.protocol != EndpointProtocol::Tcp {
+            return false;
+        }
+
+        let now = Instant::now();
+        if let Some(conn) = self.endpoint.read().conn.as_ref() {
+            if !conn.is_closed() {
+                let last_attempt = self.reconnect.last_attempt;
+                let since_attempt = last_attempt.map(|t| now.duration_since(t));
+                // The connection is good once a handshake went through it
+                if matches!(
+                    (self.time_since_last_handshake(), since_attempt),
+                    (Some(since_handshake), Some(since_attempt)) if since_handshake < since_attempt
+                ) {
+                    self.reconnect.attempts = 0;
+                }
+                return false;
+            }
+        }
+
+        self.shutdown_endpoint();
+        if self.endpoint.read().addr.is_none() || !self.reconnect.due(now) {
+            return false;
+        }
+        self.reconnect.attempts += 1;
+        self.reconnect.last_attempt = Some(now);
+        true
     }
 
     pub fn is_allowed_ip<I: Into<IpAddr>>(&self, addr: I) -> bool {
         self.allowed_ips.find(addr.into()).is_some()
     }
 
+    /// What to add and remove for the allowed IPs of the peer to be exactly `wanted`
+    pub(crate) fn allowed_ips_diff(&self, wanted: &[AllowedIP]) -> AllowedIpsDiff {
+        self.allowed_ips.diff(&|_| true, wanted)
+    }
+
+    pub(crate) fn update_allowed_ips(&mut self, diff: &AllowedIpsDiff) {
+        self.allowed_ips.apply_diff(diff, &|_| true, ());
+    }
+
     pub fn allowed_ips(&self) -> impl Iterator<Item = (IpAddr, u8)> + '_ {
         self.allowed_ips.iter().map(|(_, ip, cidr)| (ip, cidr))
     }
@@ -160,8 +394,8 @@ impl Peer {
         self.tunnel.persistent_keepalive()
     }
 
-    pub fn preshared_key(&self) -> Option<&[u8; 32]> {
-        self.preshared_key.as_ref()
+    pub fn preshared_key(&self) -> Option<[u8; 32]> {
+        self.tunnel.preshared_key()
     }
 
     pub fn index(&self) -> u32 {
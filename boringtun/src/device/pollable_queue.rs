@@ -0,0 +1,58 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+/// A queue the event loop can poll like a socket. It is backed by a socket pair that is readable
+/// while items are queued, and reports end of file once the queue is shut down.
+pub(crate) struct PollableQueue<T> {
+    queue: Mutex<VecDeque<T>>,
+    /// Holds a single byte while the queue is not empty
+    readable: UnixStream,
+    notify: UnixStream,
+}
+
+impl<T> PollableQueue<T> {
+    pub(crate) fn new() -> io::Result<PollableQueue<T>> {
+        let (readable, notify) = UnixStream::pair()?;
+        readable.set_nonblocking(true)?;
+        notify.set_nonblocking(true)?;
+        Ok(PollableQueue {
+            queue: Mutex::new(VecDeque::new()),
+            readable,
+            notify,
+        })
+    }
+
+    pub(crate) fn push(&self, item: T) {
+        let mut queue = self.queue.lock();
+        if queue.is_empty() {
+            let _ = (&self.notify).write(&[1]);
+        }
+        queue.push_back(item);
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        let mut queue = self.queue.lock();
+        let item = queue.pop_front();
+        if item.is_some() && queue.is_empty() {
+            let _ = (&self.readable).read(&mut [0]);
+        }
+        item
+    }
+
+    pub(crate) fn shutdown(&self) {
+        let _ = self.readable.shutdown(Shutdown::Both);
+    }
+}
+
+impl<T> AsRawFd for PollableQueue<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.readable.as_raw_fd()
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Resolves the endpoints of peers configured as `host:port`, on threads of their own so that
+//! the event loop never waits for DNS. Endpoints are resolved again from time to time, and soon
+//! after the peer could not be reached.
+
+use super::events::DeviceEvent;
+use super::peer::Peer;
+use super::{schedule_timers, Action, Device, Error};
+use parking_lot::Mutex;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often peers are checked for endpoints due to be resolved
+const RESOLVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Resolves the hostnames of peer endpoints, see `DeviceConfig::resolver`
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves with the resolver of the system
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+impl Device {
+    /// Resolve `host` and `port` on a thread of its own, and use the first address as the
+    /// endpoint of `peer`
+    pub(super) fn resolve_endpoint(&self, peer: &Arc<Mutex<Peer>>, host: String, port: u16) {
+        let resolver = match &self.config.resolver {
+            Some(resolver) => Arc::clone(resolver),
+            None => Arc::new(SystemResolver),
+        };
+        let (timers, events) = (Arc::clone(&self.timers), Arc::clone(&self.events));
+        let peer = Arc::downgrade(peer);
+
+        let spawned = thread::Builder::new()
+            .name("resolver".to_owned())
+            .spawn(move || {
+                let addr = match resolver.resolve(&host, port).map(|addrs| addrs.first().copied()) {
+                    Ok(Some(addr)) => addr,
+                    Ok(None) => {
+                        tracing::warn!(message = "Endpoint resolved to no address", host = %host);
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!(message = "Failed to resolve endpoint", host = %host, error = ?e);
+                        return;
+                    }
+                };
+
+                let peer = match peer.upgrade() {
+                    Some(peer) => peer,
+                    None => return, // Removed
+                };
+                let mut p = peer.lock();
+                if p.host_endpoint() != Some((host.as_str(), port)) {
+                    return; // Given another endpoint meanwhile
+                }
+                if p.set_endpoint(addr) {
+                    let public_key = p.tunnel.peer_static_public();
+                    events.emit(DeviceEvent::EndpointChanged {
+                        public_key,
+                        endpoint: addr,
+                    });
+                }
+                schedule_timers(&timers, &peer, &mut p);
+            });
+        if let Err(e) = spawned {
+            tracing::error!(message = "Failed to spawn resolver thread", error = ?e);
+        }
+    }
+
+    /// Resolve the endpoints of peers again when due, and retry those not resolved yet
+    pub(super) fn register_resolve_timer(&self) -> Result<(), Error> {
+        self.queue.new_periodic_event(
+            Box::new(|d, _| {
+                let now = Instant::now();
+                for peer in d.peers.values() {
+                    let due = {
+                        let mut p = peer.lock();
+                        let failing = p.endpoint().addr.is_none();
+                        p.host_resolve_due(now, failing)
+                    };
+                    if let Some((host, port)) = due {
+                        d.resolve_endpoint(peer, host, port);
+                    }
+                }
+                Action::Continue
+            }),
+            RESOLVE_CHECK_INTERVAL,
+        )?;
+        Ok(())
+    }
+}
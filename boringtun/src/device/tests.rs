@@ -0,0 +1,624 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+// Devices running entirely in userspace, on a memory network and configured through UAPI socket
+// pairs
+
+use crate::device::config::PeerConfig;
+use crate::device::events::DeviceEvent;
+use crate::device::metrics::MetricsAddr;
+use crate::device::peer::AllowedIP;
+use crate::device::resolver::Resolver;
+use crate::device::transport::{Datagram, MemoryNetwork, Transport};
+use crate::device::udp_batch::MAX_DATAGRAM_SIZE;
+use crate::device::virtual_interface::{MemoryInterface, MemoryInterfaceHandle};
+use crate::device::{DeviceConfig, DeviceHandle};
+use crate::noise::obfuscation::Obfuscation;
+use crate::x25519::{PublicKey, StaticSecret};
+use parking_lot::Mutex;
+use rand_core::OsRng;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ENDPOINT_A: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const ENDPOINT_B: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const TUNNEL_A: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const TUNNEL_B: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+
+struct Node {
+    device: DeviceHandle,
+    iface: MemoryInterfaceHandle,
+    api: UnixStream,
+    key: StaticSecret,
+}
+
+impl Node {
+    fn new(network: &MemoryNetwork, addr: Ipv4Addr) -> Node {
+        Node::with_config(network, addr, DeviceConfig::default())
+    }
+
+    fn with_config(network: &MemoryNetwork, addr: Ipv4Addr, config: DeviceConfig) -> Node {
+        let (api, uapi_fd) = UnixStream::pair().unwrap();
+        let config = DeviceConfig {
+            n_threads: 2,
+            uapi_fd: uapi_fd.into_raw_fd(),
+            ..config
+        };
+        let (iface, handle) = MemoryInterface::new("mem0", 1420).unwrap();
+        let transport = network.transport(addr, addr.to_ipv6_mapped());
+        let device =
+            DeviceHandle::new_with_interface(Arc::new(iface), config, Arc::new(transport)).unwrap();
+
+        let node = Node {
+            device,
+            iface: handle,
+            api,
+            key: StaticSecret::random_from_rng(OsRng),
+        };
+        node.set(&format!(
+            "private_key={}\nlisten_port=51820",
+            hex::encode(node.key.to_bytes())
+        ));
+        node
+    }
+
+    fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.key)
+    }
+
+    fn set(&self, settings: &str) {
+        assert_eq!(self.try_set(settings), "errno=0\n\n");
+    }
+
+    /// The response to a set request
+    fn try_set(&self, settings: &str) -> String {
+        write!(&self.api, "set=1\n{}\n\n", settings).unwrap();
+        self.response()
+    }
+
+    /// The response to a get request
+    fn get(&self) -> String {
+        write!(&self.api, "get=1\n\n").unwrap();
+        self.response()
+    }
+
+    fn response(&self) -> String {
+        // The response is terminated by an empty line
+        let mut response = vec![];
+        while !response.ends_with(b"\n\n") {
+            let mut byte = [0];
+            (&self.api).read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    fn add_peer(&self, peer: &Node, endpoint: Ipv4Addr, allowed_ip: Ipv4Addr) {
+        self.set(&format!(
+            "public_key={}\nendpoint={}:51820\nallowed_ip={}/32",
+            hex::encode(peer.public_key().as_bytes()),
+            endpoint,
+            allowed_ip
+        ));
+    }
+
+    /// Add `peer` without an endpoint, to be learned from its packets
+    fn add_roaming_peer(&self, peer: &Node, allowed_ip: Ipv4Addr) {
+        self.set(&format!(
+            "public_key={}\nallowed_ip={}/32",
+            hex::encode(peer.public_key().as_bytes()),
+            allowed_ip
+        ));
+    }
+}
+
+/// Two devices on a memory network, `a` at `ENDPOINT_A` with `TUNNEL_A` inside the tunnel and `b`
+/// at `ENDPOINT_B` with `TUNNEL_B`
+struct Pair {
+    network: MemoryNetwork,
+    a: Node,
+    b: Node,
+}
+
+impl Pair {
+    /// Two devices that are peers of each other
+    fn new() -> Pair {
+        Pair::with_configs(DeviceConfig::default(), DeviceConfig::default())
+    }
+
+    /// Two devices with the given configs that are peers of each other
+    fn with_configs(config_a: DeviceConfig, config_b: DeviceConfig) -> Pair {
+        let pair = Pair::unpeered(config_a, config_b);
+        pair.peer();
+        pair
+    }
+
+    /// Two devices with the given configs, that are not peers yet
+    fn unpeered(config_a: DeviceConfig, config_b: DeviceConfig) -> Pair {
+        let network = MemoryNetwork::new();
+        let a = Node::with_config(&network, ENDPOINT_A, config_a);
+        let b = Node::with_config(&network, ENDPOINT_B, config_b);
+        Pair { network, a, b }
+    }
+
+    /// Make the devices peers of each other, at their endpoints
+    fn peer(&self) {
+        self.a.add_peer(&self.b, ENDPOINT_B, TUNNEL_B);
+        self.b.add_peer(&self.a, ENDPOINT_A, TUNNEL_A);
+    }
+
+    /// Send a packet from `a` to `b`, and a reply back, checking that both arrive unchanged
+    fn exchange(&self, i: u8) {
+        let packet = ipv4_packet(TUNNEL_A, TUNNEL_B, &[i; 100]);
+        self.a.iface.send(packet.clone());
+        let received = self.b.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received, packet);
+
+        let reply = ipv4_packet(TUNNEL_B, TUNNEL_A, &[i; 1000]);
+        self.b.iface.send(reply.clone());
+        let received = self.a.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(received, reply);
+    }
+}
+
+fn ipv4_packet(src: Ipv4Addr, dst: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; 20];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 17;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[test]
+fn devices_over_memory() {
+    let pair = Pair::new();
+    for i in 0..10u8 {
+        pair.exchange(i);
+    }
+
+    // Packets from addresses the peer is not allowed to use are dropped
+    let (a, b) = (&pair.a, &pair.b);
+    b.iface.send(ipv4_packet(
+        Ipv4Addr::new(192, 0, 2, 3),
+        TUNNEL_A,
+        &[0; 100],
+    ));
+    b.iface.send(ipv4_packet(TUNNEL_B, TUNNEL_A, &[1; 100]));
+    let received = a.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(received[20..], [1; 100]);
+}
+
+#[test]
+fn handshakes_on_a_worker_pool() {
+    let config = || DeviceConfig {
+        handshake_threads: 2,
+        ..Default::default()
+    };
+    let pair = Pair::with_configs(config(), config());
+    pair.exchange(0);
+}
+
+#[test]
+fn devices_with_obfuscation() {
+    let obfuscation: Obfuscation = "jc=4,jmin=40,jmax=70,s1=15,s2=40,h1=5,h2=6,h3=7,h4=8"
+        .parse()
+        .unwrap();
+    let config = DeviceConfig {
+        obfuscation: Some(obfuscation),
+        ..Default::default()
+    };
+    let pair = Pair::with_configs(config.clone(), config);
+    for i in 0..10u8 {
+        pair.exchange(i);
+    }
+
+    // A device without the same obfuscation can't talk to them
+    let endpoint_c = Ipv4Addr::new(10, 0, 0, 3);
+    let tunnel_c = Ipv4Addr::new(192, 0, 2, 3);
+    let (a, c) = (&pair.a, Node::new(&pair.network, endpoint_c));
+    c.add_peer(a, ENDPOINT_A, TUNNEL_A);
+    a.add_peer(&c, endpoint_c, tunnel_c);
+    c.iface.send(ipv4_packet(tunnel_c, TUNNEL_A, &[0; 100]));
+    assert!(a.iface.recv_timeout(Duration::from_millis(500)).is_err());
+}
+
+#[test]
+fn device_events() {
+    let Pair { a, b, .. } = &Pair::unpeered(DeviceConfig::default(), DeviceConfig::default());
+    let (events_a, events_b) = (a.device.subscribe(), b.device.subscribe());
+    a.add_peer(b, ENDPOINT_B, TUNNEL_B);
+    // b learns the endpoint of a from its handshake initiation
+    b.add_roaming_peer(a, TUNNEL_A);
+
+    a.iface.send(ipv4_packet(TUNNEL_A, TUNNEL_B, &[0; 100]));
+    b.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        events_a.recv_timeout(timeout),
+        Ok(DeviceEvent::HandshakeCompleted {
+            public_key: b.public_key()
+        })
+    );
+    assert_eq!(
+        events_b.recv_timeout(timeout),
+        Ok(DeviceEvent::HandshakeCompleted {
+            public_key: a.public_key()
+        })
+    );
+    assert_eq!(
+        events_b.recv_timeout(timeout),
+        Ok(DeviceEvent::EndpointChanged {
+            public_key: a.public_key(),
+            endpoint: SocketAddr::from((ENDPOINT_A, 51820)),
+        })
+    );
+}
+
+/// Resolves `b.example`, failing until it is given an address
+#[derive(Debug, Default)]
+struct StubResolver {
+    addr: std::sync::Mutex<Option<SocketAddr>>,
+}
+
+impl Resolver for StubResolver {
+    fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        assert_eq!((host, port), ("b.example", 51820));
+        let addr = *self.addr.lock().unwrap();
+        addr.map(|addr| vec![addr])
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No such host"))
+    }
+}
+
+#[test]
+fn hostname_endpoint() {
+    let resolver = Arc::new(StubResolver::default());
+    let config = DeviceConfig {
+        resolver: Some(Arc::clone(&resolver) as Arc<dyn Resolver>),
+        ..Default::default()
+    };
+    let Pair { a, b, .. } = &Pair::unpeered(config, DeviceConfig::default());
+    let events = a.device.subscribe();
+    a.set(&format!(
+        "public_key={}\nendpoint=b.example:51820\nallowed_ip={}/32",
+        hex::encode(b.public_key().as_bytes()),
+        TUNNEL_B
+    ));
+    b.add_peer(a, ENDPOINT_A, TUNNEL_A);
+
+    // The first resolution fails, and is retried
+    let endpoint = SocketAddr::from((ENDPOINT_B, 51820));
+    *resolver.addr.lock().unwrap() = Some(endpoint);
+    assert_eq!(
+        events.recv_timeout(Duration::from_secs(10)),
+        Ok(DeviceEvent::EndpointChanged {
+            public_key: b.public_key(),
+            endpoint,
+        })
+    );
+
+    a.iface.send(ipv4_packet(TUNNEL_A, TUNNEL_B, &[0; 100]));
+    b.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let peer = &a.device.get_config().peers[0];
+    assert_eq!(peer.endpoint, Some(endpoint));
+    assert_eq!(peer.endpoint_host.as_deref(), Some("b.example:51820"));
+}
+
+#[test]
+fn typed_config() {
+    let Pair { a, b, .. } = &Pair::unpeered(DeviceConfig::default(), DeviceConfig::default());
+    let key_b = b.public_key();
+    let allowed_ip = |addr: Ipv4Addr| AllowedIP {
+        addr: addr.into(),
+        cidr: 32,
+    };
+
+    a.device
+        .add_or_update_peer(PeerConfig {
+            endpoint: Some(SocketAddr::from((ENDPOINT_B, 51820)).into()),
+            allowed_ips: vec![allowed_ip(Ipv4Addr::new(192, 0, 2, 9))],
+            ..PeerConfig::new(key_b)
+        })
+        .unwrap();
+    b.add_peer(a, ENDPOINT_A, TUNNEL_A);
+
+    // Updating an existing peer replaces its allowed IPs
+    a.device
+        .add_or_update_peer(PeerConfig {
+            allowed_ips: vec![allowed_ip(TUNNEL_B)],
+            replace_allowed_ips: true,
+            persistent_keepalive: Some(25),
+            ..PeerConfig::new(key_b)
+        })
+        .unwrap();
+
+    a.iface.send(ipv4_packet(TUNNEL_A, TUNNEL_B, &[0; 100]));
+    b.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let state = a.device.get_config();
+    assert_eq!(state.public_key, Some(a.public_key()));
+    assert_eq!(state.listen_port, 51820);
+    assert_eq!(state.peers.len(), 1);
+    let peer = &state.peers[0];
+    assert_eq!(peer.public_key, key_b);
+    assert_eq!(peer.allowed_ips, [allowed_ip(TUNNEL_B)]);
+    assert_eq!(peer.persistent_keepalive, Some(25));
+    assert_eq!(peer.stats.handshakes_completed, 1);
+
+    a.device.remove_peer(&key_b);
+    assert!(a.device.get_config().peers.is_empty());
+}
+
+#[test]
+fn allowed_ip_removal_over_uapi() {
+    let a = Node::new(&MemoryNetwork::new(), ENDPOINT_A);
+    let (key_b, key_c) = (
+        PublicKey::from(&StaticSecret::random_from_rng(OsRng)),
+        PublicKey::from(&StaticSecret::random_from_rng(OsRng)),
+    );
+    let allowed_ips = |key: &PublicKey| -> Vec<String> {
+        let state = a.device.get_config();
+        let peer = state.peers.iter().find(|p| p.public_key == *key).unwrap();
+        let ips = peer.allowed_ips.iter();
+        ips.map(|ip| format!("{}/{}", ip.addr, ip.cidr)).collect()
+    };
+
+    a.set(&format!(
+        "public_key={}\nallowed_ip=192.0.2.0/24\nallowed_ip=192.0.2.9/32\nallowed_ip=fd00::/64",
+        hex::encode(key_b.as_bytes())
+    ));
+    // Only the exact prefix goes, a later line for the same prefix wins
+    a.set(&format!(
+        "public_key={}\nallowed_ip=-192.0.2.0/24\nallowed_ip=-fd00::/64\nallowed_ip=fd00::/64",
+        hex::encode(key_b.as_bytes())
+    ));
+    assert_eq!(allowed_ips(&key_b), ["192.0.2.9/32", "fd00::/64"]);
+    assert_eq!(
+        a.try_set(&format!(
+            "public_key={}\nallowed_ip=-192.0.2.0/33",
+            hex::encode(key_b.as_bytes())
+        )),
+        "errno=22\n\n"
+    );
+
+    // Another peer takes an allowed IP over, and removing it from the first does not affect the
+    // second
+    a.set(&format!(
+        "public_key={}\nallowed_ip=192.0.2.9/32\npublic_key={}\nallowed_ip=-192.0.2.9/32",
+        hex::encode(key_c.as_bytes()),
+        hex::encode(key_b.as_bytes())
+    ));
+    assert_eq!(allowed_ips(&key_b), ["fd00::/64"]);
+    assert_eq!(allowed_ips(&key_c), ["192.0.2.9/32"]);
+    let route = a
+        .device
+        .device
+        .read()
+        .peers_by_ip
+        .find("192.0.2.9".parse().unwrap())
+        .cloned();
+    let peer_c = a.device.device.read().peers.get(&key_c).cloned();
+    assert!(Arc::ptr_eq(&route.unwrap(), &peer_c.unwrap()));
+
+    // Replacing the allowed IPs keeps the unchanged ones
+    a.set(&format!(
+        "public_key={}\nreplace_allowed_ips=true\nallowed_ip=fd00::/64\nallowed_ip=198.51.100.0/24",
+        hex::encode(key_b.as_bytes())
+    ));
+    assert_eq!(allowed_ips(&key_b), ["198.51.100.0/24", "fd00::/64"]);
+}
+
+#[test]
+fn failed_set_changes_nothing() {
+    let network = MemoryNetwork::new();
+    let a = Node::new(&network, ENDPOINT_A);
+    let before = a.device.get_config();
+    let new_key = hex::encode(StaticSecret::random_from_rng(OsRng).to_bytes());
+    let peer_key = || {
+        let key = PublicKey::from(&StaticSecret::random_from_rng(OsRng));
+        hex::encode(key.as_bytes())
+    };
+
+    // The second peer section is invalid, after a new key, port and peer
+    let settings = format!(
+        "private_key={}\nlisten_port=51821\npublic_key={}\nallowed_ip=192.0.2.2/32\npublic_key={}\nallowed_ip=192.0.2.300/32",
+        new_key,
+        peer_key(),
+        peer_key()
+    );
+    assert_eq!(a.try_set(&settings), "errno=22\n\n");
+    assert_eq!(a.device.get_config(), before);
+
+    // A dry run validates without applying
+    let settings = format!(
+        "dry_run=true\nprivate_key={}\nlisten_port=51821\npublic_key={}",
+        new_key,
+        peer_key()
+    );
+    a.set(&settings);
+    assert_eq!(a.device.get_config(), before);
+    assert_eq!(a.try_set("dry_run=true\nlisten_port=x"), "errno=22\n\n");
+
+    a.set(&settings.replace("dry_run=true", "dry_run=false"));
+    let after = a.device.get_config();
+    assert_ne!(after.public_key, before.public_key);
+    assert_eq!(after.listen_port, 51821);
+    assert_eq!(after.peers.len(), 1);
+
+    // A port in use fails the set, and its dry run, and the device keeps listening where it was
+    let _taken = network
+        .transport(ENDPOINT_A, ENDPOINT_A.to_ipv6_mapped())
+        .bind("0.0.0.0:51822".parse().unwrap())
+        .unwrap();
+    assert_eq!(a.try_set("dry_run=true\nlisten_port=51822"), "errno=98\n\n");
+    assert_eq!(a.try_set("listen_port=51822"), "errno=98\n\n");
+    assert_eq!(a.device.get_config(), after);
+}
+
+#[test]
+fn handshake_rate_limit_over_uapi() {
+    let Pair { a, b, .. } = &Pair::new();
+
+    let response = b.get();
+    assert!(response.contains("handshake_rate_limit=100\n"));
+    assert!(response.contains("peer_handshake_rate_limit=10\n"));
+    assert!(response.contains("cookie_mode=false\n"));
+    assert_eq!(b.try_set("handshake_rate_limit=x"), "errno=22\n\n");
+
+    // Every handshake message is over the limit, the first initiation gets a cookie reply
+    b.set("handshake_rate_limit=0\npeer_handshake_rate_limit=5");
+    a.iface.send(ipv4_packet(TUNNEL_A, TUNNEL_B, &[0; 100]));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !b.get().contains("cookie_replies=1\n") {
+        assert!(Instant::now() < deadline, "No cookie reply");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let response = b.get();
+    assert!(response.contains("handshake_rate_limit=0\n"));
+    assert!(response.contains("peer_handshake_rate_limit=5\n"));
+    assert!(response.contains("cookie_mode=true\n"));
+    assert!(response.contains("invalid_mac1=0\n"));
+    assert!(response.contains("invalid_mac2=0\n"));
+    assert!(b.iface.try_recv().is_err());
+
+    let state = b.device.get_config();
+    assert_eq!(state.handshake_rate_limit, 0);
+    let rate_limiter = state.rate_limiter.unwrap();
+    assert!(rate_limiter.cookie_mode);
+    assert_eq!(rate_limiter.stats.under_load, 1);
+}
+
+#[test]
+fn device_metrics() {
+    let path = std::env::temp_dir().join(format!("boringtun-metrics-{}", std::process::id()));
+    let config = DeviceConfig {
+        metrics: Some(MetricsAddr::Unix(path.clone())),
+        handshake_threads: 2,
+        ..Default::default()
+    };
+    let Pair { a, b, .. } = &Pair::with_configs(config, DeviceConfig::default());
+    a.iface.send(ipv4_packet(TUNNEL_A, TUNNEL_B, &[0; 100]));
+    b.iface.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let mut conn = UnixStream::connect(&path).unwrap();
+    conn.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+
+    let key = base64::encode(b.public_key().as_bytes());
+    let labels = format!("{{interface=\"mem0\",public_key=\"{}\"}}", key);
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("boringtun_peers{interface=\"mem0\"} 1\n"));
+    assert!(response.contains(&format!(
+        "boringtun_peer_handshakes_completed_total{} 1\n",
+        labels
+    )));
+    assert!(response.contains(&format!("boringtun_peer_tx_bytes_total{} ", labels)));
+    // The handshake response was processed by a worker
+    assert!(response.contains("boringtun_handshake_queue_depth{interface=\"mem0\"} 0\n"));
+    assert!(response.contains("boringtun_handshake_queue_processed_total{interface=\"mem0\"} 1\n"));
+    assert!(
+        response.contains("boringtun_thread_busy_seconds_total{interface=\"mem0\",thread=\"1\"}")
+    );
+    assert!(response.ends_with("# EOF\n"));
+}
+
+/// Stands in for a UDP-over-TCP relay: forwards the frames of the latest TCP connection to
+/// `target` on the memory network, and the datagrams that come back as frames. The connection can
+/// be cut through the returned handle.
+fn relay(
+    network: &MemoryNetwork,
+    target: SocketAddr,
+) -> (SocketAddr, Arc<Mutex<Option<TcpStream>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let socket = network
+        .transport(Ipv4Addr::new(10, 0, 0, 3), Ipv6Addr::LOCALHOST)
+        .bind("0.0.0.0:0".parse().unwrap())
+        .unwrap();
+    let connection = Arc::new(Mutex::new(None::<TcpStream>));
+
+    let (udp, current) = (Arc::clone(&socket), Arc::clone(&connection));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            *current.lock() = Some(stream.try_clone().unwrap());
+            let udp = Arc::clone(&udp);
+            thread::spawn(move || loop {
+                let mut len = [0u8; 2];
+                if stream.read_exact(&mut len).is_err() {
+                    return;
+                }
+                let mut datagram = vec![0u8; u16::from_be_bytes(len) as usize];
+                if stream.read_exact(&mut datagram).is_err() {
+                    return;
+                }
+                udp.send_to(&datagram, target).unwrap();
+            });
+        }
+    });
+
+    let current = Arc::clone(&connection);
+    thread::spawn(move || {
+        let mut batch = vec![0u8; 4 * MAX_DATAGRAM_SIZE];
+        loop {
+            let mut datagrams: Vec<Datagram> = vec![];
+            if socket.recv_batch(&mut batch, 4, &mut datagrams) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            for d in datagrams {
+                if let Some(mut stream) = current.lock().as_ref() {
+                    let _ = stream.write_all(&(d.len as u16).to_be_bytes());
+                    let _ = stream.write_all(&batch[d.offset..d.offset + d.len]);
+                }
+            }
+        }
+    });
+
+    (addr, connection)
+}
+
+#[test]
+fn devices_over_tcp_relay() {
+    let pair = Pair::unpeered(DeviceConfig::default(), DeviceConfig::default());
+    let (a, b) = (&pair.a, &pair.b);
+    let (relay_addr, connection) = relay(&pair.network, (ENDPOINT_B, 51820).into());
+    a.set(&format!(
+        "public_key={}\nendpoint={}\nendpoint_protocol=tcp\nallowed_ip={}/32",
+        hex::encode(b.public_key().as_bytes()),
+        relay_addr,
+        TUNNEL_B
+    ));
+    // b only learns where a is from the packets the relay forwards
+    b.add_roaming_peer(a, TUNNEL_A);
+    for i in 0..10u8 {
+        pair.exchange(i);
+    }
+
+    // Packets are lost while the connection is down, until a reconnects
+    connection
+        .lock()
+        .take()
+        .unwrap()
+        .shutdown(Shutdown::Both)
+        .unwrap();
+    let packet = ipv4_packet(TUNNEL_A, TUNNEL_B, &[0xff; 100]);
+    let reconnected = (0..100).any(|_| {
+        a.iface.send(packet.clone());
+        b.iface.recv_timeout(Duration::from_millis(100)).is_ok()
+    });
+    assert!(reconnected);
+    pair.exchange(10);
+}
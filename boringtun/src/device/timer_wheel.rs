@@ -0,0 +1,88 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! A hashed timer wheel, so the device only visits the peers whose timers are due, rather than
+//! every peer on every tick.
+
+use std::time::{Duration, Instant};
+
+pub(crate) struct TimerWheel<T> {
+    tick: Duration,
+    start: Instant,
+    /// The last tick that was processed, counted from `start`
+    current: u64,
+    /// Each item with the tick it is due at, in the slot of that tick modulo the number of slots
+    slots: Vec<Vec<(u64, T)>>,
+}
+
+impl<T> TimerWheel<T> {
+    pub(crate) fn new(tick: Duration, n_slots: usize) -> TimerWheel<T> {
+        TimerWheel {
+            tick,
+            start: Instant::now(),
+            current: 0,
+            slots: (0..n_slots).map(|_| vec![]).collect(),
+        }
+    }
+
+    /// Schedule `item` for the first tick at or after `deadline`, and no earlier than the next
+    pub(crate) fn insert(&mut self, deadline: Instant, item: T) {
+        let since_start = deadline.saturating_duration_since(self.start).as_nanos();
+        let tick = self.tick.as_nanos();
+        let due = (since_start.div_ceil(tick) as u64).max(self.current + 1);
+        let n_slots = self.slots.len() as u64;
+        self.slots[(due % n_slots) as usize].push((due, item));
+    }
+
+    /// Process the ticks up to `now`, appending the items that are due to `due`. Items that
+    /// are due further than a full turn of the wheel stay in their slot.
+    pub(crate) fn advance(&mut self, now: Instant, due: &mut Vec<T>) {
+        let target =
+            (now.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos()) as u64;
+        let n_slots = self.slots.len() as u64;
+        // Every slot is visited at most once, however far behind the wheel is
+        let end = target.min(self.current + n_slots);
+        for tick in self.current + 1..=end {
+            let slot = &mut self.slots[(tick % n_slots) as usize];
+            let mut i = 0;
+            while i < slot.len() {
+                if slot[i].0 <= target {
+                    due.push(slot.swap_remove(i).1);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        self.current = self.current.max(target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_due_items() {
+        let tick = Duration::from_millis(100);
+        let mut wheel = TimerWheel::new(tick, 8);
+        let start = wheel.start;
+        wheel.insert(start + tick * 3, "soon");
+        wheel.insert(start + tick * 20, "after a turn");
+        wheel.insert(start, "overdue");
+
+        let mut due = vec![];
+        wheel.advance(start + tick, &mut due);
+        assert_eq!(due, ["overdue"]);
+
+        due.clear();
+        wheel.advance(start + tick * 12, &mut due);
+        assert_eq!(due, ["soon"]);
+
+        // Items further than a turn are left until their tick comes
+        due.clear();
+        wheel.advance(start + tick * 19, &mut due);
+        assert!(due.is_empty());
+        wheel.advance(start + tick * 100, &mut due);
+        assert_eq!(due, ["after a turn"]);
+    }
+}
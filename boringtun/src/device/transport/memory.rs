@@ -0,0 +1,298 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An in-memory network, to run devices against each other without touching the kernel's
+//! network stack. Datagrams sent to an address nobody is bound to are silently dropped, as with
+//! UDP.
+
+use super::{Datagram, Transport, TransportSocket};
+use crate::device::pollable_queue::PollableQueue;
+use crate::device::udp_batch::MAX_DATAGRAM_SIZE;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Weak};
+
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// A listening socket is keyed by its address alone, a connected socket also by its endpoint
+type SocketKey = (SocketAddr, Option<SocketAddr>);
+
+/// A network shared by any number of `MemoryTransport`s
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    sockets: Arc<Mutex<HashMap<SocketKey, Weak<Inbox>>>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// A transport for a host with the given addresses on this network
+    pub fn transport(&self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> MemoryTransport {
+        MemoryTransport {
+            network: self.clone(),
+            ipv4,
+            ipv6,
+        }
+    }
+
+    fn register(&self, key: SocketKey) -> io::Result<Arc<Inbox>> {
+        let mut sockets = self.sockets.lock();
+        if sockets.get(&key).and_then(Weak::upgrade).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "Address already in use",
+            ));
+        }
+        let inbox = Arc::new(Inbox::new()?);
+        sockets.insert(key, Arc::downgrade(&inbox));
+        Ok(inbox)
+    }
+
+    /// Find a free port on ip
+    fn ephemeral_port(&self, ip: IpAddr) -> io::Result<u16> {
+        let sockets = self.sockets.lock();
+        (FIRST_EPHEMERAL_PORT..=u16::MAX)
+            .find(|&port| {
+                sockets
+                    .get(&(SocketAddr::new(ip, port), None))
+                    .and_then(Weak::upgrade)
+                    .is_none()
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrInUse, "No free port"))
+    }
+
+    fn deliver(&self, from: SocketAddr, to: SocketAddr, packet: &[u8]) {
+        let inbox = {
+            let sockets = self.sockets.lock();
+            // A socket connected to the sender takes precedence over the listening socket
+            sockets
+                .get(&(to, Some(from)))
+                .or_else(|| sockets.get(&(to, None)))
+                .and_then(Weak::upgrade)
+        };
+        if let Some(inbox) = inbox {
+            inbox.push((from, packet.to_vec()));
+        }
+    }
+}
+
+/// Binds sockets on a `MemoryNetwork`
+#[derive(Clone)]
+pub struct MemoryTransport {
+    network: MemoryNetwork,
+    ipv4: Ipv4Addr,
+    ipv6: Ipv6Addr,
+}
+
+impl MemoryTransport {
+    fn local_ip(&self, ip: IpAddr) -> io::Result<IpAddr> {
+        match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() || ip == self.ipv4 => Ok(self.ipv4.into()),
+            IpAddr::V6(ip) if ip.is_unspecified() || ip == self.ipv6 => Ok(self.ipv6.into()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "Not a local address",
+            )),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn TransportSocket>> {
+        let ip = self.local_ip(addr.ip())?;
+        let port = match addr.port() {
+            0 => self.network.ephemeral_port(ip)?,
+            port => port,
+        };
+        let local = SocketAddr::new(ip, port);
+
+        Ok(Arc::new(MemorySocket {
+            inbox: self.network.register((local, None))?,
+            network: self.network.clone(),
+            local,
+            endpoint: None,
+        }))
+    }
+
+    fn connect(
+        &self,
+        local_port: u16,
+        endpoint: SocketAddr,
+        _fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>> {
+        let ip = match endpoint {
+            SocketAddr::V4(_) => self.ipv4.into(),
+            SocketAddr::V6(_) => self.ipv6.into(),
+        };
+        let local = SocketAddr::new(ip, local_port);
+
+        Ok(Arc::new(MemorySocket {
+            inbox: self.network.register((local, Some(endpoint)))?,
+            network: self.network.clone(),
+            local,
+            endpoint: Some(endpoint),
+        }))
+    }
+}
+
+/// The datagrams queued for a socket, with the address they were sent from
+type Inbox = PollableQueue<(SocketAddr, Vec<u8>)>;
+
+/// A socket on a `MemoryNetwork`, unbound when dropped
+pub struct MemorySocket {
+    network: MemoryNetwork,
+    local: SocketAddr,
+    endpoint: Option<SocketAddr>,
+    inbox: Arc<Inbox>,
+}
+
+impl TransportSocket for MemorySocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inbox.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn recv_batch(
+        &self,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        let mut n_received = 0;
+        for (i, buf) in batch
+            .chunks_mut(MAX_DATAGRAM_SIZE)
+            .take(max_buffers)
+            .enumerate()
+        {
+            let (addr, packet) = match self.inbox.pop() {
+                Some(datagram) => datagram,
+                None => break,
+            };
+            let len = packet.len().min(buf.len());
+            buf[..len].copy_from_slice(&packet[..len]);
+            datagrams.push(Datagram {
+                offset: i * MAX_DATAGRAM_SIZE,
+                len,
+                addr,
+            });
+            n_received += 1;
+        }
+        n_received
+    }
+
+    fn send_batch(&self, packets: &[&[u8]], addr: Option<SocketAddr>) -> io::Result<()> {
+        let to = addr
+            .or(self.endpoint)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No destination address"))?;
+        for packet in packets {
+            self.network.deliver(self.local, to, packet);
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        self.inbox.shutdown();
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        let key = (self.local, self.endpoint);
+        let mut sockets = self.network.sockets.lock();
+        if let Some(inbox) = sockets.get(&key) {
+            if inbox.ptr_eq(&Arc::downgrade(&self.inbox)) {
+                sockets.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recv_all(socket: &dyn TransportSocket) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut batch = vec![0u8; 4 * MAX_DATAGRAM_SIZE];
+        let mut received = vec![];
+        loop {
+            let mut datagrams = vec![];
+            if socket.recv_batch(&mut batch, 4, &mut datagrams) == 0 {
+                return received;
+            }
+            for d in datagrams {
+                received.push((d.addr, batch[d.offset..d.offset + d.len].to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let network = MemoryNetwork::new();
+        let a = network.transport(Ipv4Addr::new(10, 0, 0, 1), Ipv6Addr::LOCALHOST);
+        let b = network.transport(Ipv4Addr::new(10, 0, 0, 2), Ipv6Addr::LOCALHOST);
+
+        let sock_a = a.bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let sock_b = b.bind("0.0.0.0:51820".parse().unwrap()).unwrap();
+        let addr_a = sock_a.local_addr().unwrap();
+        let addr_b = sock_b.local_addr().unwrap();
+        assert_eq!(addr_a.ip(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(addr_b, "10.0.0.2:51820".parse().unwrap());
+
+        let packets: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let refs: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+        sock_a.send_batch(&refs, Some(addr_b)).unwrap();
+        // Nobody listens there
+        sock_a
+            .send_to(&[1, 2, 3], "10.0.0.3:1".parse().unwrap())
+            .unwrap();
+
+        let received = recv_all(&*sock_b);
+        assert_eq!(received.len(), packets.len());
+        for ((from, packet), sent) in received.iter().zip(&packets) {
+            assert_eq!(*from, addr_a);
+            assert_eq!(packet, sent);
+        }
+        assert!(recv_all(&*sock_a).is_empty());
+    }
+
+    #[test]
+    fn connected_socket_takes_precedence() {
+        let network = MemoryNetwork::new();
+        let a = network.transport(Ipv4Addr::new(10, 0, 0, 1), Ipv6Addr::LOCALHOST);
+        let b = network.transport(Ipv4Addr::new(10, 0, 0, 2), Ipv6Addr::LOCALHOST);
+        let c = network.transport(Ipv4Addr::new(10, 0, 0, 3), Ipv6Addr::LOCALHOST);
+
+        let listener = a.bind("0.0.0.0:1000".parse().unwrap()).unwrap();
+        let sock_b = b.bind("0.0.0.0:2000".parse().unwrap()).unwrap();
+        let sock_c = c.bind("0.0.0.0:3000".parse().unwrap()).unwrap();
+        let conn = a.connect(1000, sock_b.local_addr().unwrap(), None).unwrap();
+        assert!(a.bind("10.0.0.1:1000".parse().unwrap()).is_err());
+
+        sock_b
+            .send_to(b"from b", listener.local_addr().unwrap())
+            .unwrap();
+        sock_c
+            .send_to(b"from c", listener.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(recv_all(&*conn).len(), 1);
+        assert_eq!(recv_all(&*listener).len(), 1);
+
+        conn.send(b"reply").unwrap();
+        assert_eq!(recv_all(&*sock_b)[0].1, b"reply");
+
+        // Once the connected socket is gone the listener gets everything
+        drop(conn);
+        sock_b
+            .send_to(b"from b", listener.local_addr().unwrap())
+            .unwrap();
+        assert_eq!(recv_all(&*listener).len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The carriers WireGuard datagrams are exchanged over.
+//!
+//! A `Transport` binds the sockets a device listens on, and optionally connects sockets dedicated
+//! to a single peer endpoint. The event loop polls each `TransportSocket` for readability via its
+//! file descriptor, and moves datagrams through it in batches. Kernel UDP is the default, an
+//! in-memory network is provided for tests. Peers can be reached over TCP instead of UDP, see
+//! `tcp`, and any transport can disguise its datagrams, see `obfuscated`.
+
+pub mod memory;
+pub mod obfuscated;
+pub mod tcp;
+pub mod udp;
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+pub use super::udp_batch::Datagram;
+pub use memory::{MemoryNetwork, MemoryTransport};
+pub use obfuscated::ObfuscatedTransport;
+pub use tcp::TcpStreamSocket;
+pub use udp::UdpTransport;
+
+/// Creates the sockets a device exchanges datagrams with its peers over
+pub trait Transport: Send + Sync {
+    /// Bind a socket to listen for datagrams from any peer. Binding port 0 picks a free port.
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn TransportSocket>>;
+
+    /// Create a socket that only exchanges datagrams with `endpoint`, from `local_port`.
+    /// The event loop falls back to the listening socket if connecting fails.
+    fn connect(
+        &self,
+        local_port: u16,
+        endpoint: SocketAddr,
+        fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>>;
+
+    /// Connect a stream socket carrying datagrams to `endpoint`, for peers reached over TCP
+    fn connect_stream(
+        &self,
+        endpoint: SocketAddr,
+        fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>> {
+        Ok(Arc::new(TcpStreamSocket::connect(endpoint, fwmark)?))
+    }
+}
+
+/// A socket created by a `Transport`
+pub trait TransportSocket: Send + Sync {
+    /// A file descriptor that becomes readable when there are datagrams to receive, and reports
+    /// end of file once the socket is shut down
+    fn as_raw_fd(&self) -> RawFd;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Receive up to `max_buffers` buffers of MAX_DATAGRAM_SIZE bytes into `batch`, appending the
+    /// datagrams they hold to `datagrams`. Returns the number of datagrams received, which is zero
+    /// once there is nothing left to read.
+    fn recv_batch(
+        &self,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize;
+
+    /// Send `packets` in order to `addr`, or to the endpoint of a connected socket if `addr` is
+    /// None. Returns the first error encountered, packets after it are not sent.
+    fn send_batch(&self, packets: &[&[u8]], addr: Option<SocketAddr>) -> io::Result<()>;
+
+    fn send_to(&self, packet: &[u8], addr: SocketAddr) -> io::Result<()> {
+        self.send_batch(&[packet], Some(addr))
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<()> {
+        self.send_batch(&[packet], None)
+    }
+
+    /// Retry sending the datagrams a stream socket could not hand to the kernel yet
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Whether a stream socket holds datagrams that `flush` should retry sending
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    /// Whether the connection of a stream socket failed, and it should be replaced
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Mark outgoing packets for policy routing, where supported
+    fn set_mark(&self, _mark: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Stop receiving, waking up the event loop to drop the socket's handler
+    fn shutdown(&self);
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::{Datagram, Transport, TransportSocket};
+use crate::noise::obfuscation::Obfuscation;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+
+/// Disguises the datagrams of another transport, see `Obfuscation`
+pub struct ObfuscatedTransport {
+    inner: Arc<dyn Transport>,
+    obfuscation: Obfuscation,
+}
+
+impl ObfuscatedTransport {
+    pub fn new(inner: Arc<dyn Transport>, obfuscation: Obfuscation) -> ObfuscatedTransport {
+        ObfuscatedTransport { inner, obfuscation }
+    }
+}
+
+impl Transport for ObfuscatedTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn TransportSocket>> {
+        let inner = self.inner.bind(addr)?;
+        Ok(Arc::new(ObfuscatedSocket::new(inner, self.obfuscation)))
+    }
+
+    fn connect(
+        &self,
+        local_port: u16,
+        endpoint: SocketAddr,
+        fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>> {
+        let inner = self.inner.connect(local_port, endpoint, fwmark)?;
+        Ok(Arc::new(ObfuscatedSocket::new(inner, self.obfuscation)))
+    }
+
+    fn connect_stream(
+        &self,
+        endpoint: SocketAddr,
+        fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>> {
+        let inner = self.inner.connect_stream(endpoint, fwmark)?;
+        Ok(Arc::new(ObfuscatedSocket::new(inner, self.obfuscation)))
+    }
+}
+
+/// A socket that disguises the datagrams it sends, and drops received datagrams that are not
+/// disguised the same way
+pub struct ObfuscatedSocket {
+    inner: Arc<dyn TransportSocket>,
+    obfuscation: Obfuscation,
+}
+
+impl ObfuscatedSocket {
+    pub fn new(inner: Arc<dyn TransportSocket>, obfuscation: Obfuscation) -> ObfuscatedSocket {
+        ObfuscatedSocket { inner, obfuscation }
+    }
+}
+
+impl TransportSocket for ObfuscatedSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn recv_batch(
+        &self,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        let start = datagrams.len();
+        // Keep reading while only junk arrives, zero received means there is nothing left
+        loop {
+            let n_received = self.inner.recv_batch(batch, max_buffers, datagrams);
+            if n_received == 0 {
+                return 0;
+            }
+
+            let mut n_kept = 0;
+            for i in start..datagrams.len() {
+                let Datagram { offset, len, addr } = datagrams[i];
+                if let Some(message) = self
+                    .obfuscation
+                    .deobfuscate(&mut batch[offset..offset + len])
+                {
+                    datagrams[start + n_kept] = Datagram {
+                        offset: offset + message.start,
+                        len: message.len(),
+                        addr,
+                    };
+                    n_kept += 1;
+                }
+            }
+            datagrams.truncate(start + n_kept);
+            if n_kept > 0 {
+                return n_kept;
+            }
+        }
+    }
+
+    fn send_batch(&self, packets: &[&[u8]], addr: Option<SocketAddr>) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(packets.iter().map(|p| p.len()).sum());
+        let mut ranges = Vec::with_capacity(packets.len());
+        for packet in packets {
+            self.obfuscation.obfuscate(packet, &mut buf, &mut ranges);
+        }
+        let datagrams: Vec<&[u8]> = ranges.into_iter().map(|range| &buf[range]).collect();
+        self.inner.send_batch(&datagrams, addr)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn has_pending(&self) -> bool {
+        self.inner.has_pending()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn set_mark(&self, mark: u32) -> io::Result<()> {
+        self.inner.set_mark(mark)
+    }
+
+    fn shutdown(&self) {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,268 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! WireGuard over TCP, for networks that only let TCP through.
+//!
+//! Every datagram is sent as a frame with a two byte big-endian length prefix, over a TCP
+//! connection to the peer, or to a relay that forwards the datagrams to the peer over UDP.
+
+use super::{Datagram, TransportSocket};
+use crate::device::udp_batch::MAX_DATAGRAM_SIZE;
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Type};
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How many bytes of frames to queue while the kernel's send buffer is full, further datagrams
+/// are dropped as they would be by a congested UDP socket
+const MAX_PENDING: usize = 1 << 20;
+const READ_SIZE: usize = 1 << 16;
+const LEN_PREFIX: usize = 2;
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+const SEND_FLAGS: libc::c_int = 0;
+
+/// A TCP connection to a single endpoint, carrying length-prefixed datagrams
+pub struct TcpStreamSocket {
+    socket: socket2::Socket,
+    endpoint: SocketAddr,
+    /// Received bytes that do not make up a complete frame yet
+    received: Mutex<Vec<u8>>,
+    /// Frames the kernel did not accept yet
+    pending: Mutex<Vec<u8>>,
+    closed: AtomicBool,
+}
+
+impl TcpStreamSocket {
+    /// Start connecting to `endpoint`. Datagrams sent before the connection is established are
+    /// queued, and a connection that fails is reported by `is_closed`.
+    pub fn connect(endpoint: SocketAddr, _fwmark: Option<u32>) -> io::Result<TcpStreamSocket> {
+        let socket = socket2::Socket::new(
+            Domain::for_address(endpoint),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(true)?;
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(fwmark) = _fwmark {
+            socket.set_mark(fwmark)?;
+        }
+
+        #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos"))]
+        socket.set_nosigpipe(true)?;
+
+        match socket.connect(&endpoint.into()) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(TcpStreamSocket {
+            socket,
+            endpoint,
+            received: Mutex::new(vec![]),
+            pending: Mutex::new(vec![]),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Mark the connection as failed, and wake up the event loop to drop its handler
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    /// Write as much of `pending` as the kernel accepts
+    fn write_pending(&self, pending: &mut Vec<u8>) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == pending.len() {
+                break Ok(());
+            }
+            match self.socket.send_with_flags(&pending[written..], SEND_FLAGS) {
+                Ok(0) => break Ok(()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.close();
+                    break Err(e);
+                }
+            }
+        };
+        pending.drain(..written);
+        result
+    }
+}
+
+impl TransportSocket for TcpStreamSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not an IP socket address"))
+    }
+
+    fn recv_batch(
+        &self,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        let max_buffers = max_buffers.min(batch.len() / MAX_DATAGRAM_SIZE);
+        let mut received = self.received.lock();
+        let mut n_received = 0;
+        let mut consumed = 0;
+
+        while n_received < max_buffers {
+            // Hand out the complete frames first
+            let frame = &received[consumed..];
+            if frame.len() >= LEN_PREFIX {
+                let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+                if frame.len() >= LEN_PREFIX + len {
+                    let offset = n_received * MAX_DATAGRAM_SIZE;
+                    batch[offset..offset + len].copy_from_slice(&frame[LEN_PREFIX..][..len]);
+                    datagrams.push(Datagram {
+                        offset,
+                        len,
+                        addr: self.endpoint,
+                    });
+                    consumed += LEN_PREFIX + len;
+                    n_received += 1;
+                    continue;
+                }
+            }
+
+            // Then read more of the stream
+            received.drain(..consumed);
+            consumed = 0;
+            let old_len = received.len();
+            received.resize(old_len + READ_SIZE, 0);
+            match (&self.socket).read(&mut received[old_len..]) {
+                Ok(0) => {
+                    received.truncate(old_len);
+                    self.close();
+                    break;
+                }
+                Ok(n) => received.truncate(old_len + n),
+                Err(e) => {
+                    received.truncate(old_len);
+                    match e.kind() {
+                        io::ErrorKind::WouldBlock => break,
+                        io::ErrorKind::Interrupted => {}
+                        _ => {
+                            self.close();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        received.drain(..consumed);
+        n_received
+    }
+
+    fn send_batch(&self, packets: &[&[u8]], _addr: Option<SocketAddr>) -> io::Result<()> {
+        if self.is_closed() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        let mut pending = self.pending.lock();
+        for packet in packets {
+            if pending.len() + LEN_PREFIX + packet.len() > MAX_PENDING {
+                break;
+            }
+            pending.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            pending.extend_from_slice(packet);
+        }
+        self.write_pending(&mut pending)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock();
+        if pending.is_empty() || self.is_closed() {
+            return Ok(());
+        }
+        self.write_pending(&mut pending)
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.is_closed() && !self.pending.lock().is_empty()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn set_mark(&self, mark: u32) -> io::Result<()> {
+        self.socket.set_mark(mark)
+    }
+
+    fn shutdown(&self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    /// Receive until `n` datagrams arrived or the connection is closed
+    fn recv_until(socket: &TcpStreamSocket, n: usize) -> Vec<Vec<u8>> {
+        let mut batch = vec![0u8; 4 * MAX_DATAGRAM_SIZE];
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < n && !socket.is_closed() && Instant::now() < deadline {
+            let mut datagrams = vec![];
+            socket.recv_batch(&mut batch, 4, &mut datagrams);
+            for d in datagrams {
+                received.push(batch[d.offset..d.offset + d.len].to_vec());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        received
+    }
+
+    #[test]
+    fn frames_datagrams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = TcpStreamSocket::connect(listener.local_addr().unwrap(), None).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        socket
+            .send_batch(&[b"hello", b"", &[7; 3000]], None)
+            .unwrap();
+        let mut frames = vec![0u8; 2 + 5 + 2 + 2 + 3000];
+        stream.read_exact(&mut frames).unwrap();
+        assert_eq!(&frames[..9], b"\x00\x05hello\x00\x00");
+        assert_eq!(&frames[9..11], &3000u16.to_be_bytes());
+
+        // Frames that arrive split up are reassembled
+        stream.write_all(&[0, 4, b'p']).unwrap();
+        stream.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.write_all(b"ing\x00\x04pong").unwrap();
+        let received = recv_until(&socket, 2);
+        assert_eq!(received, [b"ping", b"pong"]);
+
+        drop(stream);
+        assert!(recv_until(&socket, 1).is_empty());
+        assert!(socket.is_closed());
+        assert!(socket.send(b"late").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::{Datagram, Transport, TransportSocket};
+use crate::device::udp_batch::UdpBatching;
+use socket2::{Domain, Protocol, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+/// WireGuard over kernel UDP sockets
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpTransport {
+    pub batching: UdpBatching,
+}
+
+impl UdpTransport {
+    pub fn new(batching: UdpBatching) -> UdpTransport {
+        UdpTransport { batching }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn bind(&self, addr: SocketAddr) -> io::Result<Arc<dyn TransportSocket>> {
+        let socket =
+            socket2::Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        self.batching.configure(&socket);
+
+        Ok(Arc::new(UdpSocket {
+            socket,
+            batching: self.batching,
+        }))
+    }
+
+    fn connect(
+        &self,
+        local_port: u16,
+        endpoint: SocketAddr,
+        _fwmark: Option<u32>,
+    ) -> io::Result<Arc<dyn TransportSocket>> {
+        let socket = socket2::Socket::new(
+            Domain::for_address(endpoint),
+            Type::STREAM,
+            Some(Protocol::UDP),
+        )?;
+        socket.set_reuse_address(true)?;
+        let bind_addr = if endpoint.is_ipv4() {
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, local_port).into()
+        } else {
+            SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, local_port, 0, 0).into()
+        };
+        socket.bind(&bind_addr)?;
+        socket.connect(&endpoint.into())?;
+        socket.set_nonblocking(true)?;
+
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(fwmark) = _fwmark {
+            socket.set_mark(fwmark)?;
+        }
+
+        self.batching.configure(&socket);
+
+        Ok(Arc::new(UdpSocket {
+            socket,
+            batching: self.batching,
+        }))
+    }
+}
+
+/// A listening or connected UDP socket
+#[derive(Debug)]
+pub struct UdpSocket {
+    socket: socket2::Socket,
+    batching: UdpBatching,
+}
+
+impl TransportSocket for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not an IP socket address"))
+    }
+
+    fn recv_batch(
+        &self,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        self.batching
+            .recv(&self.socket, batch, max_buffers, datagrams)
+    }
+
+    fn send_batch(&self, packets: &[&[u8]], addr: Option<SocketAddr>) -> io::Result<()> {
+        self.batching.send(&self.socket, packets, addr)
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    fn set_mark(&self, mark: u32) -> io::Result<()> {
+        self.socket.set_mark(mark)
+    }
+
+    fn shutdown(&self) {
+        self.socket.shutdown(Shutdown::Both).unwrap();
+    }
+}
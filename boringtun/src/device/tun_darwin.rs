@@ -223,6 +223,37 @@ impl TunSocket {
         self.write(src, AF_INET6 as u8)
     }
 
+    /// Write a batch of IP packets
+    pub fn write_packets(&self, packets: &mut [&mut [u8]]) {
+        for packet in packets {
+            match packet.first().map(|b| b >> 4) {
+                Some(4) => self.write4(packet),
+                Some(6) => self.write6(packet),
+                _ => 0,
+            };
+        }
+    }
+
+    /// The most packets a single call to `read_packets` may yield
+    pub fn max_read_packets(&self, _mtu: usize) -> usize {
+        1
+    }
+
+    /// Read the next packet from the device into slot `lens.len()` of the `stride` sized slots of
+    /// `dst`, and append its length to `lens`
+    pub fn read_packets(
+        &self,
+        _scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        let slot = &mut dst[lens.len() * stride..][..stride];
+        let n = self.read(slot)?.len();
+        lens.push(n);
+        Ok(())
+    }
+
     pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
         let mut hdr = [0u8; 4];
 
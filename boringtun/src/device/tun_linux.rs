@@ -1,12 +1,20 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use super::tun_offload::{self, SegmentError, VIRTIO_NET_HDR_LEN};
 use super::Error;
 use libc::*;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
+
+const IFF_VNET_HDR: c_int = 0x4000;
+const TUN_F_CSUM: c_uint = 0x01;
+const TUN_F_TSO4: c_uint = 0x02;
+const TUN_F_TSO6: c_uint = 0x04;
 
 #[repr(C)]
 union IfrIfru {
@@ -40,6 +48,8 @@ pub struct ifreq {
 pub struct TunSocket {
     fd: RawFd,
     name: String,
+    /// Every packet read or written is prefixed with a virtio_net_hdr
+    vnet_hdr: bool,
 }
 
 impl Drop for TunSocket {
@@ -56,19 +66,57 @@ impl AsRawFd for TunSocket {
 
 impl TunSocket {
     fn write(&self, buf: &[u8]) -> usize {
+        if self.vnet_hdr {
+            let hdr = tun_offload::VirtioNetHdr::default().encode();
+            return self.write_parts(&hdr, &[buf]).saturating_sub(hdr.len());
+        }
+
         match unsafe { write(self.fd, buf.as_ptr() as _, buf.len() as _) } {
             -1 => 0,
             n => n as usize,
         }
     }
 
+    /// Write a single packet made of a virtio_net_hdr followed by parts
+    fn write_parts(&self, hdr: &[u8], parts: &[&[u8]]) -> usize {
+        let iovecs: Vec<iovec> = std::iter::once(hdr)
+            .chain(parts.iter().copied())
+            .map(|part| iovec {
+                iov_base: part.as_ptr() as *mut _,
+                iov_len: part.len(),
+            })
+            .collect();
+
+        match unsafe { writev(self.fd, iovecs.as_ptr(), iovecs.len() as _) } {
+            -1 => 0,
+            n => n as usize,
+        }
+    }
+
     pub fn new(name: &str) -> Result<TunSocket, Error> {
+        TunSocket::open(name, false)
+    }
+
+    /// Like `new`, but enables checksum and TCP segmentation offloads. The kernel then hands us
+    /// TSO super-packets that `read_packets` splits, and `write_packets` coalesces TCP segments
+    /// into GRO super-packets. A provided FD is used as is, without offloads.
+    pub fn new_with_offload(name: &str) -> Result<TunSocket, Error> {
+        TunSocket::open(name, true)
+    }
+
+    /// Open another queue of the same multi-queue interface, with the same offloads
+    pub fn new_queue(&self) -> Result<TunSocket, Error> {
+        TunSocket::open(&self.name, self.vnet_hdr)
+    }
+
+    fn open(name: &str, offload: bool) -> Result<TunSocket, Error> {
         // If the provided name appears to be a FD, use that.
         let provided_fd = name.parse::<i32>();
         if let Ok(fd) = provided_fd {
             return Ok(TunSocket {
                 fd,
                 name: name.to_string(),
+                vnet_hdr: false,
             });
         }
 
@@ -77,10 +125,14 @@ impl TunSocket {
             fd => fd,
         };
         let iface_name = name.as_bytes();
+        let mut flags = IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE;
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE) as _,
+                ifru_flags: flags as _,
             },
         };
 
@@ -94,8 +146,23 @@ impl TunSocket {
             return Err(Error::IOCtl(io::Error::last_os_error()));
         }
 
+        if offload {
+            let hdr_len = VIRTIO_NET_HDR_LEN as c_int;
+            if unsafe { ioctl(fd, TUNSETVNETHDRSZ as _, &hdr_len) } < 0 {
+                return Err(Error::IOCtl(io::Error::last_os_error()));
+            }
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+            if unsafe { ioctl(fd, TUNSETOFFLOAD as _, offloads as c_ulong) } < 0 {
+                return Err(Error::IOCtl(io::Error::last_os_error()));
+            }
+        }
+
         let name = name.to_string();
-        Ok(TunSocket { fd, name })
+        Ok(TunSocket {
+            fd,
+            name,
+            vnet_hdr: offload,
+        })
     }
 
     pub fn set_non_blocking(self) -> Result<TunSocket, Error> {
@@ -150,10 +217,62 @@ impl TunSocket {
         self.write(src)
     }
 
+    /// Write a batch of IP packets, coalescing TCP segments if offloads are enabled. The packets
+    /// may be modified in the process.
+    pub fn write_packets(&self, packets: &mut [&mut [u8]]) {
+        if self.vnet_hdr {
+            tun_offload::coalesce(packets, |hdr, parts| {
+                self.write_parts(hdr, parts);
+            });
+        } else {
+            for packet in packets {
+                self.write(packet);
+            }
+        }
+    }
+
     pub fn read<'a>(&self, dst: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
         match unsafe { read(self.fd, dst.as_mut_ptr() as _, dst.len()) } {
             -1 => Err(Error::IfaceRead(io::Error::last_os_error())),
             n => Ok(&mut dst[..n as usize]),
         }
     }
+
+    /// The most packets a single call to `read_packets` may yield
+    pub fn max_read_packets(&self, mtu: usize) -> usize {
+        if self.vnet_hdr {
+            tun_offload::max_segments(mtu)
+        } else {
+            1
+        }
+    }
+
+    /// Read the next packet from the device into the `stride` sized slots of `dst`, starting with
+    /// slot `lens.len()`, and append the packet lengths to `lens`. With offloads enabled a single
+    /// read may yield many packets, `scratch` must then be large enough for a whole super-packet.
+    /// Packets that can't be segmented are dropped.
+    pub fn read_packets(
+        &self,
+        scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        if !self.vnet_hdr {
+            let slot = &mut dst[lens.len() * stride..][..stride];
+            let n = self.read(slot)?.len();
+            lens.push(n);
+            return Ok(());
+        }
+
+        let packet = self.read(scratch)?;
+        match tun_offload::segment(packet, dst, stride, lens) {
+            Ok(()) => {}
+            Err(SegmentError::NoSpace) => {
+                tracing::warn!(message = "No room to segment packet", len = packet.len())
+            }
+            Err(e) => tracing::debug!(message = "Dropping packet from tun", error = ?e),
+        }
+        Ok(())
+    }
 }
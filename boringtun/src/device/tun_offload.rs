@@ -0,0 +1,584 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Segmentation and coalescing of TCP packets for TUN devices opened with `IFF_VNET_HDR`.
+//!
+//! With offloads enabled the kernel hands us TSO super-packets of up to 64KiB, prefixed with a
+//! virtio_net_hdr that describes how to split them, and with checksums left for us to compute.
+//! In the other direction consecutive segments of a TCP flow are merged into a single GSO
+//! super-packet, so the kernel can process them in one go, as it does for GRO.
+
+use std::convert::TryInto;
+
+pub const VIRTIO_NET_HDR_LEN: usize = 10;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPPROTO_TCP: u8 = 6;
+const IPV4_MIN_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const TCP_MIN_HEADER_SIZE: usize = 20;
+/// Offset of the checksum field in the TCP header
+const TCP_CSUM_OFF: usize = 16;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_FLAG_CWR: u8 = 0x80;
+
+/// The most segments merged into a single super-packet
+const MAX_COALESCED_SEGMENTS: usize = 64;
+const MAX_IP_PACKET_SIZE: usize = (1 << 16) - 1;
+
+/// struct virtio_net_hdr, in native byte order as TUN devices use unless told otherwise
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    fn decode(buf: &[u8]) -> Option<VirtioNetHdr> {
+        let buf = buf.get(..VIRTIO_NET_HDR_LEN)?;
+        let u16_at = |off: usize| u16::from_ne_bytes(buf[off..off + 2].try_into().unwrap());
+        Some(VirtioNetHdr {
+            flags: buf[0],
+            gso_type: buf[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; VIRTIO_NET_HDR_LEN] {
+        let mut buf = [0u8; VIRTIO_NET_HDR_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        buf
+    }
+}
+
+/// The most packets a single super-packet is split into, for an interface with the given MTU.
+/// The TCP segment size leaves room for the largest IP and TCP headers.
+pub fn max_segments(mtu: usize) -> usize {
+    let min_gso_size = mtu.saturating_sub(IPV6_HEADER_SIZE + 60).max(1);
+    MAX_IP_PACKET_SIZE.div_ceil(min_gso_size)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SegmentError {
+    /// The virtio_net_hdr or the packet headers are malformed
+    InvalidPacket,
+    /// The virtio_net_hdr requests an offload that was never enabled
+    UnsupportedGso,
+    /// The segments don't fit in the destination buffer
+    NoSpace,
+}
+
+/// Split a packet read from the TUN device, starting with its virtio_net_hdr, into IP packets with
+/// complete checksums. Each packet is written into the next `stride` sized slot of `dst`, the first
+/// slot being at `lens.len()`, and its length is appended to `lens`.
+pub fn segment(
+    src: &[u8],
+    dst: &mut [u8],
+    stride: usize,
+    lens: &mut Vec<usize>,
+) -> Result<(), SegmentError> {
+    let hdr = VirtioNetHdr::decode(src).ok_or(SegmentError::InvalidPacket)?;
+    let packet = &src[VIRTIO_NET_HDR_LEN..];
+    let free_slots = (dst.len() / stride).saturating_sub(lens.len());
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            if free_slots == 0 || packet.len() > stride {
+                return Err(SegmentError::NoSpace);
+            }
+            let slot = &mut dst[lens.len() * stride..][..packet.len()];
+            slot.copy_from_slice(packet);
+            if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                // The checksum field already holds the pseudo header sum
+                let start = hdr.csum_start as usize;
+                let field = start + hdr.csum_offset as usize;
+                if field + 2 > slot.len() {
+                    return Err(SegmentError::InvalidPacket);
+                }
+                let csum = !fold(sum(&slot[start..], 0));
+                slot[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+            }
+            lens.push(packet.len());
+            Ok(())
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+            segment_tcp(&hdr, packet, dst, stride, free_slots, lens)
+        }
+        _ => Err(SegmentError::UnsupportedGso),
+    }
+}
+
+fn segment_tcp(
+    hdr: &VirtioNetHdr,
+    packet: &[u8],
+    dst: &mut [u8],
+    stride: usize,
+    free_slots: usize,
+    lens: &mut Vec<usize>,
+) -> Result<(), SegmentError> {
+    let is_v4 = hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN == VIRTIO_NET_HDR_GSO_TCPV4;
+    let ip_hdr_len = hdr.csum_start as usize;
+    if packet.len() < ip_hdr_len + TCP_MIN_HEADER_SIZE
+        || (is_v4 && ip_hdr_len < IPV4_MIN_HEADER_SIZE)
+        || (!is_v4 && ip_hdr_len < IPV6_HEADER_SIZE)
+    {
+        return Err(SegmentError::InvalidPacket);
+    }
+    let tcp_hdr_len = ((packet[ip_hdr_len + 12] >> 4) as usize) * 4;
+    let hdr_len = ip_hdr_len + tcp_hdr_len;
+    let gso_size = hdr.gso_size as usize;
+    if tcp_hdr_len < TCP_MIN_HEADER_SIZE || hdr_len > packet.len() || gso_size == 0 {
+        return Err(SegmentError::InvalidPacket);
+    }
+
+    let (headers, payload) = packet.split_at(hdr_len);
+    let n_segments = payload.len().div_ceil(gso_size);
+    if n_segments > free_slots || hdr_len + gso_size.min(payload.len()) > stride {
+        return Err(SegmentError::NoSpace);
+    }
+
+    let first_seq = u32::from_be_bytes(headers[ip_hdr_len + 4..ip_hdr_len + 8].try_into().unwrap());
+    let first_id = u16::from_be_bytes(headers[4..6].try_into().unwrap());
+    let flags = headers[ip_hdr_len + 13];
+
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        let len = hdr_len + chunk.len();
+        let slot = &mut dst[lens.len() * stride..][..len];
+        slot[..hdr_len].copy_from_slice(headers);
+        slot[hdr_len..].copy_from_slice(chunk);
+
+        if is_v4 {
+            slot[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            slot[4..6].copy_from_slice(&first_id.wrapping_add(i as u16).to_be_bytes());
+            set_ipv4_checksum(slot, ip_hdr_len);
+        } else {
+            slot[4..6].copy_from_slice(&((len - IPV6_HEADER_SIZE) as u16).to_be_bytes());
+        }
+
+        let (ip, tcp) = slot.split_at_mut(ip_hdr_len);
+        let seq = first_seq.wrapping_add((i * gso_size) as u32);
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        // FIN and PSH only belong on the last segment, CWR only on the first
+        let mut segment_flags = flags;
+        if i + 1 < n_segments {
+            segment_flags &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+        }
+        if i > 0 {
+            segment_flags &= !TCP_FLAG_CWR;
+        }
+        tcp[13] = segment_flags;
+
+        tcp[TCP_CSUM_OFF..TCP_CSUM_OFF + 2].copy_from_slice(&[0, 0]);
+        let csum = !fold(sum(tcp, pseudo_header_sum(ip, tcp.len())));
+        tcp[TCP_CSUM_OFF..TCP_CSUM_OFF + 2].copy_from_slice(&csum.to_be_bytes());
+
+        lens.push(len);
+    }
+
+    Ok(())
+}
+
+/// Header fields of a TCP packet that may be merged with the segments that follow it
+#[derive(Clone, Copy)]
+struct TcpSegment {
+    ip_hdr_len: usize,
+    hdr_len: usize,
+    seq: u32,
+    flags: u8,
+}
+
+impl TcpSegment {
+    fn parse(packet: &[u8]) -> Option<TcpSegment> {
+        let ip_hdr_len = match packet.first()? >> 4 {
+            // No IP options, and not fragmented
+            4 if packet.len() >= IPV4_MIN_HEADER_SIZE
+                && packet[0] & 0x0f == 5
+                && packet[9] == IPPROTO_TCP
+                && u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff == 0 =>
+            {
+                IPV4_MIN_HEADER_SIZE
+            }
+            // No extension headers
+            6 if packet.len() >= IPV6_HEADER_SIZE && packet[6] == IPPROTO_TCP => IPV6_HEADER_SIZE,
+            _ => return None,
+        };
+        if packet.len() < ip_hdr_len + TCP_MIN_HEADER_SIZE || ip_len(packet)? != packet.len() {
+            return None;
+        }
+
+        let tcp = &packet[ip_hdr_len..];
+        let hdr_len = ip_hdr_len + ((tcp[12] >> 4) as usize) * 4;
+        let flags = tcp[13];
+        // Only plain data segments, that is with no SYN, FIN, RST or URG, are merged
+        if hdr_len >= packet.len()
+            || flags & !(TCP_FLAG_ACK | TCP_FLAG_PSH) != 0
+            || flags & TCP_FLAG_ACK == 0
+        {
+            return None;
+        }
+
+        Some(TcpSegment {
+            ip_hdr_len,
+            hdr_len,
+            seq: u32::from_be_bytes(tcp[4..8].try_into().unwrap()),
+            flags,
+        })
+    }
+
+    /// True if next can be appended to a super-packet ending with this segment
+    fn can_merge(
+        &self,
+        packet: &[u8],
+        first: &[u8],
+        next: &TcpSegment,
+        next_packet: &[u8],
+    ) -> bool {
+        let payload_len = packet.len() - self.hdr_len;
+        let first_payload_len = first.len() - self.hdr_len;
+        let next_payload_len = next_packet.len() - next.hdr_len;
+
+        // The headers must be identical except for the lengths, IDs, checksums and sequence numbers
+        let same_ip_hdr = if self.ip_hdr_len == IPV4_MIN_HEADER_SIZE {
+            first[0..2] == next_packet[0..2] && first[6..10] == next_packet[6..10]
+        } else {
+            first[0..4] == next_packet[0..4] && first[6..8] == next_packet[6..8]
+        };
+        let same_addrs = ip_addrs(first) == ip_addrs(next_packet);
+        let (tcp, next_tcp) = (&first[self.ip_hdr_len..], &next_packet[next.ip_hdr_len..]);
+        let same_tcp_hdr = tcp[0..4] == next_tcp[0..4]
+            && tcp[8..13] == next_tcp[8..13]
+            && tcp[14..16] == next_tcp[14..16]
+            && first[self.ip_hdr_len + TCP_MIN_HEADER_SIZE..self.hdr_len]
+                == next_packet[next.ip_hdr_len + TCP_MIN_HEADER_SIZE..next.hdr_len];
+
+        self.hdr_len == next.hdr_len
+            && same_ip_hdr
+            && same_addrs
+            && same_tcp_hdr
+            // A pushed segment ends the super-packet, and all segments but the last are full sized
+            && self.flags & TCP_FLAG_PSH == 0
+            && payload_len == first_payload_len
+            && next_payload_len <= first_payload_len
+            && next.seq == self.seq.wrapping_add(payload_len as u32)
+    }
+}
+
+/// Merge consecutive segments of the same TCP flow in `packets` into GSO super-packets. Calls
+/// `write` for every packet to hand to the TUN device, with its virtio_net_hdr and the parts it
+/// is made of. The headers of the first packet of a super-packet are updated in place.
+pub fn coalesce(packets: &mut [&mut [u8]], mut write: impl FnMut(&[u8], &[&[u8]])) {
+    let mut i = 0;
+    while i < packets.len() {
+        let first = match TcpSegment::parse(packets[i]) {
+            Some(first) => first,
+            None => {
+                write(&VirtioNetHdr::default().encode(), &[&packets[i][..]]);
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut last = first;
+        let mut total_len = packets[i].len();
+        let mut end = i + 1;
+        while end < packets.len() && end - i < MAX_COALESCED_SEGMENTS {
+            let next = match TcpSegment::parse(packets[end]) {
+                Some(next) => next,
+                None => break,
+            };
+            let next_payload_len = packets[end].len() - next.hdr_len;
+            if total_len + next_payload_len > MAX_IP_PACKET_SIZE
+                || !last.can_merge(packets[end - 1], packets[i], &next, packets[end])
+            {
+                break;
+            }
+            total_len += next_payload_len;
+            last = next;
+            end += 1;
+        }
+
+        if end == i + 1 {
+            write(&VirtioNetHdr::default().encode(), &[&packets[i][..]]);
+            i += 1;
+            continue;
+        }
+
+        let (head, rest) = packets[i..end].split_first_mut().unwrap();
+        let gso_size = head.len() - first.hdr_len;
+        merge_headers(head, &first, total_len, last.flags & TCP_FLAG_PSH);
+
+        let mut parts: Vec<&[u8]> = Vec::with_capacity(end - i);
+        parts.push(&head[..]);
+        parts.extend(rest.iter().map(|p| &p[first.hdr_len..]));
+        write(
+            &VirtioNetHdr {
+                flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+                gso_type: if first.ip_hdr_len == IPV4_MIN_HEADER_SIZE {
+                    VIRTIO_NET_HDR_GSO_TCPV4
+                } else {
+                    VIRTIO_NET_HDR_GSO_TCPV6
+                },
+                hdr_len: first.hdr_len as u16,
+                gso_size: gso_size as u16,
+                csum_start: first.ip_hdr_len as u16,
+                csum_offset: TCP_CSUM_OFF as u16,
+            }
+            .encode(),
+            &parts,
+        );
+        i = end;
+    }
+}
+
+/// Rewrite the headers of the first segment to describe a super-packet of total_len bytes. The TCP
+/// checksum is left partial, holding only the pseudo header sum, for the kernel to complete.
+fn merge_headers(head: &mut [u8], first: &TcpSegment, total_len: usize, psh: u8) {
+    if first.ip_hdr_len == IPV4_MIN_HEADER_SIZE {
+        head[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        set_ipv4_checksum(head, first.ip_hdr_len);
+    } else {
+        head[4..6].copy_from_slice(&((total_len - IPV6_HEADER_SIZE) as u16).to_be_bytes());
+    }
+
+    let (ip, tcp) = head.split_at_mut(first.ip_hdr_len);
+    tcp[13] |= psh;
+    let csum = fold(pseudo_header_sum(ip, total_len - first.ip_hdr_len));
+    tcp[TCP_CSUM_OFF..TCP_CSUM_OFF + 2].copy_from_slice(&csum.to_be_bytes());
+}
+
+/// The length of the IP packet according to its header
+fn ip_len(packet: &[u8]) -> Option<usize> {
+    match packet[0] >> 4 {
+        4 => Some(u16::from_be_bytes([packet[2], packet[3]]) as usize),
+        6 => Some(u16::from_be_bytes([packet[4], packet[5]]) as usize + IPV6_HEADER_SIZE),
+        _ => None,
+    }
+}
+
+fn set_ipv4_checksum(packet: &mut [u8], ip_hdr_len: usize) {
+    packet[10..12].copy_from_slice(&[0, 0]);
+    let csum = !fold(sum(&packet[..ip_hdr_len], 0));
+    packet[10..12].copy_from_slice(&csum.to_be_bytes());
+}
+
+/// The sum of the TCP pseudo header, for a TCP header and payload of tcp_len bytes
+fn pseudo_header_sum(ip: &[u8], tcp_len: usize) -> u64 {
+    sum(ip_addrs(ip), IPPROTO_TCP as u64 + tcp_len as u64)
+}
+
+/// The source and destination addresses of the IP packet
+fn ip_addrs(packet: &[u8]) -> &[u8] {
+    if packet[0] >> 4 == 4 {
+        &packet[12..20]
+    } else {
+        &packet[8..40]
+    }
+}
+
+/// The internet checksum sum of data, before folding
+fn sum(data: &[u8], initial: u64) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    let mut acc = initial;
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u64;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u64) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u64) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    acc as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_packet(v6: bool, id: u16, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let ip_hdr_len = if v6 {
+            IPV6_HEADER_SIZE
+        } else {
+            IPV4_MIN_HEADER_SIZE
+        };
+        let len = ip_hdr_len + TCP_MIN_HEADER_SIZE + payload.len();
+        let mut packet = vec![0u8; len];
+        if v6 {
+            packet[0] = 0x60;
+            packet[4..6].copy_from_slice(&((len - IPV6_HEADER_SIZE) as u16).to_be_bytes());
+            packet[6] = IPPROTO_TCP;
+            packet[7] = 64;
+            packet[8..24].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+            packet[24..40].copy_from_slice(&[0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        } else {
+            packet[0] = 0x45;
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            packet[4..6].copy_from_slice(&id.to_be_bytes());
+            packet[6] = 0x40;
+            packet[8] = 64;
+            packet[9] = IPPROTO_TCP;
+            packet[12..20].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+            set_ipv4_checksum(&mut packet, ip_hdr_len);
+        }
+        let (ip, tcp) = packet.split_at_mut(ip_hdr_len);
+        tcp[0..4].copy_from_slice(&[0x30, 0x39, 0x01, 0xbb]);
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+        tcp[8..12].copy_from_slice(&7u32.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        tcp[TCP_MIN_HEADER_SIZE..].copy_from_slice(payload);
+        let csum = !fold(sum(tcp, pseudo_header_sum(ip, tcp.len())));
+        tcp[TCP_CSUM_OFF..TCP_CSUM_OFF + 2].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+
+    fn segments(v6: bool, payload_lens: &[usize]) -> Vec<Vec<u8>> {
+        let mut seq = 1000;
+        payload_lens
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| {
+                let flags = if i + 1 == payload_lens.len() {
+                    TCP_FLAG_ACK | TCP_FLAG_PSH
+                } else {
+                    TCP_FLAG_ACK
+                };
+                let payload: Vec<u8> = (0..len).map(|b| (b + i) as u8).collect();
+                let packet = tcp_packet(v6, 100 + i as u16, seq, flags, &payload);
+                seq += len as u32;
+                packet
+            })
+            .collect()
+    }
+
+    fn coalesce_all(packets: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut copies = packets.to_vec();
+        let mut refs: Vec<&mut [u8]> = copies.iter_mut().map(|p| &mut p[..]).collect();
+        let mut written = vec![];
+        coalesce(&mut refs, |hdr, parts| {
+            let mut packet = hdr.to_vec();
+            parts.iter().for_each(|p| packet.extend_from_slice(p));
+            written.push(packet);
+        });
+        written
+    }
+
+    fn segment_all(written: &[Vec<u8>], stride: usize) -> Vec<Vec<u8>> {
+        let mut dst = vec![0u8; stride * 128];
+        let mut lens = vec![];
+        for packet in written {
+            segment(packet, &mut dst, stride, &mut lens).unwrap();
+        }
+        lens.iter()
+            .enumerate()
+            .map(|(i, &len)| dst[i * stride..][..len].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn coalesce_and_segment_v4() {
+        let packets = segments(false, &[1400, 1400, 1400, 1400, 600]);
+        let written = coalesce_all(&packets);
+        assert_eq!(written.len(), 1);
+        let hdr = VirtioNetHdr::decode(&written[0]).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 1400);
+        assert_eq!(written[0].len(), VIRTIO_NET_HDR_LEN + 40 + 4 * 1400 + 600);
+
+        assert_eq!(segment_all(&written, 1500), packets);
+    }
+
+    #[test]
+    fn coalesce_and_segment_v6() {
+        let packets = segments(true, &[1200, 1200, 1200]);
+        let written = coalesce_all(&packets);
+        assert_eq!(written.len(), 1);
+        let hdr = VirtioNetHdr::decode(&written[0]).unwrap();
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV6);
+        assert_eq!(hdr.csum_start as usize, IPV6_HEADER_SIZE);
+
+        assert_eq!(segment_all(&written, 1500), packets);
+    }
+
+    #[test]
+    fn coalesce_stops_at_gaps_and_flows() {
+        let mut packets = segments(false, &[1000, 1000, 1000, 1000]);
+        // A sequence gap after the second segment
+        packets[2] = tcp_packet(false, 102, 5000, TCP_FLAG_ACK, &[1; 1000]);
+        packets[3] = tcp_packet(false, 103, 6000, TCP_FLAG_ACK, &[2; 1000]);
+        // A different flow, and a SYN that is never merged
+        packets.push(tcp_packet(true, 0, 1, TCP_FLAG_ACK, &[3; 1000]));
+        packets.push(tcp_packet(true, 0, 1, 0x02, &[]));
+
+        let written = coalesce_all(&packets);
+        assert_eq!(written.len(), 4);
+        assert_eq!(segment_all(&written, 1500), packets);
+    }
+
+    #[test]
+    fn coalesce_smaller_segment_ends_packet() {
+        let packets = segments(false, &[1000, 500, 1000]);
+        let written = coalesce_all(&packets);
+        assert_eq!(written.len(), 2);
+        assert_eq!(segment_all(&written, 1500), packets);
+    }
+
+    #[test]
+    fn segment_needs_csum() {
+        let mut packet = tcp_packet(false, 1, 1, TCP_FLAG_ACK, &[9; 333]);
+        let expected = packet.clone();
+        // Replace the checksum with the partial sum the kernel leaves us
+        let (ip, tcp) = packet.split_at_mut(IPV4_MIN_HEADER_SIZE);
+        let partial = fold(pseudo_header_sum(ip, tcp.len()));
+        tcp[TCP_CSUM_OFF..TCP_CSUM_OFF + 2].copy_from_slice(&partial.to_be_bytes());
+        let mut with_hdr = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            csum_start: IPV4_MIN_HEADER_SIZE as u16,
+            csum_offset: TCP_CSUM_OFF as u16,
+            ..Default::default()
+        }
+        .encode()
+        .to_vec();
+        with_hdr.extend_from_slice(&packet);
+
+        assert_eq!(segment_all(&[with_hdr], 1500), vec![expected]);
+    }
+
+    #[test]
+    fn segment_no_space() {
+        let packets = segments(false, &[1000, 1000, 1000]);
+        let written = coalesce_all(&packets);
+        let mut dst = vec![0u8; 1500 * 2];
+        let mut lens = vec![];
+        assert_eq!(
+            segment(&written[0], &mut dst, 1500, &mut lens),
+            Err(SegmentError::NoSpace)
+        );
+        assert!(lens.is_empty());
+    }
+}
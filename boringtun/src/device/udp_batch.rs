@@ -0,0 +1,415 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Moves batches of datagrams through a UDP socket.
+//!
+//! On Linux a whole batch is received with a single `recvmmsg` and sent with a single `sendmmsg`.
+//! Additionally, with UDP GRO the kernel coalesces consecutive datagrams of a flow into a single
+//! buffer, and with UDP GSO (`UDP_SEGMENT`) a run of equally sized datagrams to the same
+//! destination is handed to the kernel as one. On other platforms one datagram is moved per
+//! syscall.
+
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+
+/// The largest UDP datagram we can receive
+pub const MAX_DATAGRAM_SIZE: usize = (1 << 16) - 1;
+/// The largest number of buffers received, or datagrams sent, with a single syscall
+pub const MAX_BATCH: usize = 32;
+
+/// A datagram received as part of a batch, stored at `batch[offset..offset + len]`
+#[derive(Debug, Clone, Copy)]
+pub struct Datagram {
+    pub offset: usize,
+    pub len: usize,
+    pub addr: SocketAddr,
+}
+
+/// How batches of datagrams are moved through a socket
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpBatching {
+    /// Use `recvmmsg`/`sendmmsg`, ignored on platforms other than Linux
+    pub mmsg: bool,
+    /// Use UDP GRO and GSO, ignored on platforms other than Linux or if mmsg is not set
+    pub offload: bool,
+}
+
+impl UdpBatching {
+    /// Prepare a socket for receiving. Enables UDP GRO when offload is requested and supported by
+    /// the kernel, otherwise datagrams are simply received one per buffer.
+    pub fn configure(&self, _udp: &socket2::Socket) {
+        #[cfg(target_os = "linux")]
+        if self.mmsg && self.offload {
+            if let Err(e) = linux::set_gro(_udp) {
+                tracing::debug!(message = "UDP GRO not available", error = ?e);
+            }
+        }
+    }
+
+    /// Receive up to `max_buffers` buffers of MAX_DATAGRAM_SIZE bytes into `batch`, appending the
+    /// datagrams they hold to `datagrams`. A single buffer holds several datagrams when they were
+    /// coalesced with GRO. Returns the number of datagrams received, which is zero once the socket
+    /// has nothing left to read.
+    pub fn recv(
+        &self,
+        udp: &socket2::Socket,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        let max_buffers = max_buffers
+            .min(MAX_BATCH)
+            .min(batch.len() / MAX_DATAGRAM_SIZE);
+
+        #[cfg(target_os = "linux")]
+        if self.mmsg {
+            return linux::recv_mmsg(udp, batch, max_buffers, datagrams);
+        }
+
+        let n_received = datagrams.len();
+        for (offset, buf) in batch
+            .chunks_mut(MAX_DATAGRAM_SIZE)
+            .take(max_buffers)
+            .enumerate()
+            .map(|(i, buf)| (i * MAX_DATAGRAM_SIZE, buf))
+        {
+            // Safety: the `recv_from` implementation promises not to write uninitialised
+            // bytes to the buffer, so this casting is safe.
+            let buf = unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) };
+            match udp.recv_from(buf) {
+                Ok((len, addr)) => datagrams.push(Datagram {
+                    offset,
+                    len,
+                    addr: addr.as_socket().unwrap(),
+                }),
+                Err(_) => break,
+            }
+        }
+        datagrams.len() - n_received
+    }
+
+    /// Send `packets` in order to `addr`, or to the peer of a connected socket if `addr` is None.
+    /// Returns the first error encountered, packets after it are not sent.
+    pub fn send(
+        &self,
+        udp: &socket2::Socket,
+        packets: &[&[u8]],
+        addr: Option<SocketAddr>,
+    ) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.mmsg {
+            return linux::send_mmsg(udp, packets, addr, self.offload);
+        }
+
+        let addr = addr.map(socket2::SockAddr::from);
+        for packet in packets {
+            match &addr {
+                Some(addr) => udp.send_to(packet, addr)?,
+                None => udp.send(packet)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Datagram, MAX_BATCH, MAX_DATAGRAM_SIZE};
+    use std::io;
+    use std::mem::{size_of, size_of_val};
+    use std::net::SocketAddr;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    // Not exposed by libc for every Linux target, the values are part of the kernel ABI
+    const UDP_SEGMENT: libc::c_int = 103;
+    const UDP_GRO: libc::c_int = 104;
+
+    /// The kernel refuses to segment more datagrams than this at once
+    const MAX_GSO_SEGMENTS: usize = 64;
+    /// Room for the largest IP and UDP headers in a segmented send
+    const MAX_GSO_PAYLOAD: usize = MAX_DATAGRAM_SIZE - 40 - 8;
+
+    /// Space for a single control message with an int payload, aligned for cmsghdr
+    type CmsgBuf = [u64; 4];
+
+    pub(super) fn set_gro(udp: &socket2::Socket) -> io::Result<()> {
+        let enable: libc::c_int = 1;
+        match unsafe {
+            libc::setsockopt(
+                udp.as_raw_fd(),
+                libc::SOL_UDP,
+                UDP_GRO,
+                &enable as *const _ as _,
+                size_of_val(&enable) as _,
+            )
+        } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn recv_mmsg(
+        udp: &socket2::Socket,
+        batch: &mut [u8],
+        max_buffers: usize,
+        datagrams: &mut Vec<Datagram>,
+    ) -> usize {
+        let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; MAX_BATCH] = unsafe { std::mem::zeroed() };
+        let mut cmsgs: [CmsgBuf; MAX_BATCH] = [CmsgBuf::default(); MAX_BATCH];
+        let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { std::mem::zeroed() };
+
+        for (i, buf) in batch
+            .chunks_mut(MAX_DATAGRAM_SIZE)
+            .take(max_buffers)
+            .enumerate()
+        {
+            iovecs[i] = libc::iovec {
+                iov_base: buf.as_mut_ptr() as _,
+                iov_len: buf.len(),
+            };
+            let hdr = &mut msgs[i].msg_hdr;
+            hdr.msg_name = &mut names[i] as *mut _ as _;
+            hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_iov = &mut iovecs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = cmsgs[i].as_mut_ptr() as _;
+            hdr.msg_controllen = size_of::<CmsgBuf>() as _;
+        }
+
+        let n_msgs = unsafe {
+            libc::recvmmsg(
+                udp.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                max_buffers as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n_msgs <= 0 {
+            return 0;
+        }
+
+        let n_received = datagrams.len();
+        for (i, msg) in msgs[..n_msgs as usize].iter().enumerate() {
+            let addr = match sockaddr_to_socket_addr(&names[i], msg.msg_hdr.msg_namelen) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let len = msg.msg_len as usize;
+            // Without GRO, or if nothing was coalesced, the buffer holds a single datagram
+            let segment_size = gro_segment_size(&msg.msg_hdr).unwrap_or(len).max(1);
+
+            let mut offset = 0;
+            while offset < len {
+                let segment_len = segment_size.min(len - offset);
+                datagrams.push(Datagram {
+                    offset: i * MAX_DATAGRAM_SIZE + offset,
+                    len: segment_len,
+                    addr,
+                });
+                offset += segment_len;
+            }
+        }
+        datagrams.len() - n_received
+    }
+
+    pub(super) fn send_mmsg(
+        udp: &socket2::Socket,
+        packets: &[&[u8]],
+        addr: Option<SocketAddr>,
+        offload: bool,
+    ) -> io::Result<()> {
+        let sock_addr = addr.map(socket2::SockAddr::from);
+        let mut sent = 0;
+
+        while sent < packets.len() {
+            let mut iovecs: [libc::iovec; MAX_BATCH] = unsafe { std::mem::zeroed() };
+            let mut cmsgs: [CmsgBuf; MAX_BATCH] = [CmsgBuf::default(); MAX_BATCH];
+            let mut msgs: [libc::mmsghdr; MAX_BATCH] = unsafe { std::mem::zeroed() };
+            // The index in chunk of the first packet of each message
+            let mut firsts = [0; MAX_BATCH + 1];
+
+            let chunk = &packets[sent..packets.len().min(sent + MAX_BATCH)];
+            for (iovec, packet) in iovecs.iter_mut().zip(chunk) {
+                *iovec = libc::iovec {
+                    iov_base: packet.as_ptr() as *mut _,
+                    iov_len: packet.len(),
+                };
+            }
+
+            let mut n_msgs = 0;
+            let mut first = 0;
+            while first < chunk.len() {
+                let n_segments = if offload {
+                    gso_run_len(&chunk[first..])
+                } else {
+                    1
+                };
+
+                let hdr = &mut msgs[n_msgs].msg_hdr;
+                if let Some(addr) = &sock_addr {
+                    hdr.msg_name = addr.as_ptr() as *mut _;
+                    hdr.msg_namelen = addr.len();
+                }
+                hdr.msg_iov = &mut iovecs[first];
+                hdr.msg_iovlen = n_segments as _;
+                if n_segments > 1 {
+                    set_gso_segment_size(hdr, &mut cmsgs[n_msgs], chunk[first].len() as u16);
+                }
+
+                firsts[n_msgs] = first;
+                n_msgs += 1;
+                first += n_segments;
+            }
+            firsts[n_msgs] = chunk.len();
+
+            let n_sent =
+                unsafe { libc::sendmmsg(udp.as_raw_fd(), msgs.as_mut_ptr(), n_msgs as _, 0) };
+            if n_sent < 0 {
+                let err = io::Error::last_os_error();
+                if offload && n_msgs < chunk.len() {
+                    // The kernel or the outgoing interface may not support segmentation, retry
+                    // the remaining packets individually
+                    tracing::debug!(message = "UDP GSO send failed", error = ?err);
+                    return send_mmsg(udp, &packets[sent..], addr, false);
+                }
+                return Err(err);
+            }
+            sent += firsts[n_sent as usize];
+        }
+
+        Ok(())
+    }
+
+    /// The number of packets at the start of `packets` that can be sent as a single GSO message:
+    /// all of the same size, except for the last one that may be smaller
+    fn gso_run_len(packets: &[&[u8]]) -> usize {
+        let segment_size = packets[0].len();
+        let mut total = segment_size;
+        let mut n = 1;
+        for packet in &packets[1..] {
+            if n == MAX_GSO_SEGMENTS
+                || packet.len() > segment_size
+                || total + packet.len() > MAX_GSO_PAYLOAD
+            {
+                break;
+            }
+            total += packet.len();
+            n += 1;
+            if packet.len() < segment_size {
+                break;
+            }
+        }
+        n
+    }
+
+    fn set_gso_segment_size(hdr: &mut libc::msghdr, buf: &mut CmsgBuf, segment_size: u16) {
+        hdr.msg_control = buf.as_mut_ptr() as _;
+        hdr.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<u16>() as _) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(hdr);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+        }
+    }
+
+    fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                    let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    return Some(size as usize);
+                }
+                cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+            }
+        }
+        None
+    }
+
+    fn sockaddr_to_socket_addr(
+        storage: &libc::sockaddr_storage,
+        len: libc::socklen_t,
+    ) -> Option<SocketAddr> {
+        // Safety: the storage was filled in by the kernel, and is large enough for any address
+        unsafe { socket2::SockAddr::new(*storage, len) }.as_socket()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket2::{Domain, Protocol, Type};
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    fn loopback_socket(batching: &UdpBatching) -> socket2::Socket {
+        let udp = socket2::Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        udp.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        udp.set_nonblocking(true).unwrap();
+        batching.configure(&udp);
+        udp
+    }
+
+    fn roundtrip(batching: UdpBatching) {
+        let sender = loopback_socket(&batching);
+        let receiver = loopback_socket(&batching);
+        let sender_addr = sender.local_addr().unwrap().as_socket().unwrap();
+        let receiver_addr = receiver.local_addr().unwrap().as_socket().unwrap();
+
+        // A run of equally sized packets with a shorter one at the end, followed by a longer one
+        let mut sent: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 1200]).collect();
+        sent.push(vec![20; 100]);
+        sent.push(vec![21; 1400]);
+        let packets: Vec<&[u8]> = sent.iter().map(|p| &p[..]).collect();
+        batching
+            .send(&sender, &packets, Some(receiver_addr))
+            .unwrap();
+
+        let mut batch = vec![0u8; MAX_BATCH * MAX_DATAGRAM_SIZE];
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.len() < sent.len() && Instant::now() < deadline {
+            let mut datagrams = vec![];
+            if batching.recv(&receiver, &mut batch, MAX_BATCH, &mut datagrams) == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            for datagram in datagrams {
+                assert_eq!(datagram.addr, sender_addr);
+                received.push(batch[datagram.offset..datagram.offset + datagram.len].to_vec());
+            }
+        }
+
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn roundtrip_one_by_one() {
+        roundtrip(UdpBatching {
+            mmsg: false,
+            offload: false,
+        });
+    }
+
+    #[test]
+    fn roundtrip_mmsg() {
+        roundtrip(UdpBatching {
+            mmsg: true,
+            offload: false,
+        });
+    }
+
+    #[test]
+    fn roundtrip_mmsg_offload() {
+        roundtrip(UdpBatching {
+            mmsg: true,
+            offload: true,
+        });
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use super::VirtualInterface;
+use crate::device::pollable_queue::PollableQueue;
+use crate::device::Error;
+use parking_lot::Mutex;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An interface that exchanges packets with the application over channels, instead of with the
+/// kernel. Packets sent with the `MemoryInterfaceHandle` are read by the device as if they were
+/// routed into a TUN device, and the packets the device writes are received from the handle.
+pub struct MemoryInterface {
+    name: String,
+    mtu: AtomicUsize,
+    to_device: Arc<PollableQueue<Vec<u8>>>,
+    from_device: Mutex<Sender<Vec<u8>>>,
+}
+
+/// The application's end of a `MemoryInterface`
+pub struct MemoryInterfaceHandle {
+    to_device: Arc<PollableQueue<Vec<u8>>>,
+    from_device: Receiver<Vec<u8>>,
+}
+
+impl MemoryInterface {
+    pub fn new(name: &str, mtu: usize) -> io::Result<(MemoryInterface, MemoryInterfaceHandle)> {
+        let to_device = Arc::new(PollableQueue::new()?);
+        let (sender, receiver) = mpsc::channel();
+
+        let iface = MemoryInterface {
+            name: name.to_owned(),
+            mtu: AtomicUsize::new(mtu),
+            to_device: Arc::clone(&to_device),
+            from_device: Mutex::new(sender),
+        };
+        let handle = MemoryInterfaceHandle {
+            to_device,
+            from_device: receiver,
+        };
+        Ok((iface, handle))
+    }
+
+    pub fn set_mtu(&self, mtu: usize) {
+        self.mtu.store(mtu, Ordering::Relaxed);
+    }
+}
+
+impl VirtualInterface for MemoryInterface {
+    fn as_raw_fd(&self) -> RawFd {
+        self.to_device.as_raw_fd()
+    }
+
+    fn name(&self) -> Result<String, Error> {
+        Ok(self.name.clone())
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        Ok(self.mtu.load(Ordering::Relaxed))
+    }
+
+    fn read_packets(
+        &self,
+        _scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        let packet = self
+            .to_device
+            .pop()
+            .ok_or_else(|| Error::IfaceRead(io::ErrorKind::WouldBlock.into()))?;
+        if packet.len() > stride {
+            tracing::debug!(
+                message = "Dropping packet larger than MTU",
+                len = packet.len()
+            );
+            return Ok(());
+        }
+        dst[lens.len() * stride..][..packet.len()].copy_from_slice(&packet);
+        lens.push(packet.len());
+        Ok(())
+    }
+
+    fn write_packets(&self, packets: &mut [&mut [u8]]) {
+        let from_device = self.from_device.lock();
+        for packet in packets {
+            // The application may have dropped its handle, the packets are lost then
+            let _ = from_device.send(packet.to_vec());
+        }
+    }
+}
+
+impl MemoryInterfaceHandle {
+    /// Hand an IP packet to the device, to be sent to the peer it is routed to
+    pub fn send(&self, packet: Vec<u8>) {
+        self.to_device.push(packet);
+    }
+
+    /// Wait for the next IP packet the device received from a peer
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        self.from_device.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        self.from_device.try_recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_pass_through_the_handle() {
+        let (iface, handle) = MemoryInterface::new("mem0", 100).unwrap();
+        assert_eq!(iface.name().unwrap(), "mem0");
+        iface.set_mtu(40);
+        assert_eq!(iface.mtu().unwrap(), 40);
+
+        handle.send(vec![1; 20]);
+        handle.send(vec![2; 50]);
+        handle.send(vec![3; 40]);
+        let (mut dst, mut lens) = ([0u8; 120], vec![]);
+        for _ in 0..3 {
+            iface
+                .read_packets(&mut [], &mut dst, 40, &mut lens)
+                .unwrap();
+        }
+        // Packets larger than the stride are dropped
+        assert_eq!(lens, [20, 40]);
+        assert_eq!(dst[..20], [1; 20]);
+        assert_eq!(dst[40..80], [3; 40]);
+        assert!(iface
+            .read_packets(&mut [], &mut dst, 40, &mut lens)
+            .is_err());
+
+        iface.write_packets(&mut [&mut [4; 10], &mut [5; 20]]);
+        assert_eq!(handle.recv_timeout(Duration::ZERO), Ok(vec![4; 10]));
+        assert_eq!(handle.try_recv(), Ok(vec![5; 20]));
+        assert_eq!(handle.try_recv(), Err(TryRecvError::Empty));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The network interface a device exchanges IP packets with the rest of the system over.
+//!
+//! A `TunSocket` is used by default, but a device can run entirely in userspace, without the
+//! privileges to create a TUN device, with a `MemoryInterface`.
+
+pub mod memory;
+
+use super::tun::TunSocket;
+use super::Error;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+
+pub use memory::{MemoryInterface, MemoryInterfaceHandle};
+
+pub trait VirtualInterface: Send + Sync {
+    /// A file descriptor that becomes readable when there are packets to read
+    fn as_raw_fd(&self) -> RawFd;
+
+    fn name(&self) -> Result<String, Error>;
+
+    /// Get the current MTU value
+    fn mtu(&self) -> Result<usize, Error>;
+
+    /// Read the next packet from the interface into the `stride` sized slots of `dst`, starting
+    /// with slot `lens.len()`, and append the packet lengths to `lens`. A single read may yield up
+    /// to `max_read_packets` packets. Fails with `Error::IfaceRead` of kind `WouldBlock` once
+    /// there is nothing left to read.
+    fn read_packets(
+        &self,
+        scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error>;
+
+    /// The most packets a single call to `read_packets` may yield
+    fn max_read_packets(&self, _mtu: usize) -> usize {
+        1
+    }
+
+    /// Write a batch of IP packets. The packets may be modified in the process.
+    fn write_packets(&self, packets: &mut [&mut [u8]]);
+
+    /// Open another queue of the same interface, so event loop threads can read from it in
+    /// parallel. Returns None if the interface only has a single queue.
+    fn open_queue(&self) -> Result<Option<Arc<dyn VirtualInterface>>, Error> {
+        Ok(None)
+    }
+}
+
+impl VirtualInterface for TunSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        AsRawFd::as_raw_fd(self)
+    }
+
+    fn name(&self) -> Result<String, Error> {
+        TunSocket::name(self)
+    }
+
+    fn mtu(&self) -> Result<usize, Error> {
+        TunSocket::mtu(self)
+    }
+
+    fn read_packets(
+        &self,
+        scratch: &mut [u8],
+        dst: &mut [u8],
+        stride: usize,
+        lens: &mut Vec<usize>,
+    ) -> Result<(), Error> {
+        TunSocket::read_packets(self, scratch, dst, stride, lens)
+    }
+
+    fn max_read_packets(&self, mtu: usize) -> usize {
+        TunSocket::max_read_packets(self, mtu)
+    }
+
+    fn write_packets(&self, packets: &mut [&mut [u8]]) {
+        TunSocket::write_packets(self, packets)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_queue(&self) -> Result<Option<Arc<dyn VirtualInterface>>, Error> {
+        Ok(Some(Arc::new(self.new_queue()?.set_non_blocking()?)))
+    }
+}
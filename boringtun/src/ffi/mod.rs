@@ -6,7 +6,7 @@
 #![allow(clippy::missing_safety_doc)]
 
 //! C bindings for the BoringTun library
-use super::noise::{Tunn, TunnResult};
+use super::noise::{Tunn, TunnEvent, TunnResult};
 use crate::x25519::{PublicKey, StaticSecret};
 use base64::{decode, encode};
 use hex::encode as encode_hex;
@@ -18,8 +18,8 @@ use tracing_subscriber::fmt;
 
 use crate::serialization::KeyBytes;
 use std::ffi::{CStr, CString};
-use std::io::{Error, ErrorKind, Write};
-use std::os::raw::c_char;
+use std::io::{Error, Write};
+use std::os::raw::{c_char, c_void};
 use std::panic;
 use std::ptr;
 use std::ptr::null_mut;
@@ -53,6 +53,40 @@ pub struct wireguard_result {
     pub size: usize,
 }
 
+#[allow(non_camel_case_types)]
+#[repr(C)]
+/// Something that happened to a tunnel, see wireguard_set_event_callback
+pub enum tunnel_event {
+    /// A handshake established a new session
+    HANDSHAKE_COMPLETED = 0,
+    /// No session could be established in time, or the last one grew too old
+    CONNECTION_EXPIRED = 1,
+    /// The peer is under load, and demanded a cookie with our next handshake message
+    COOKIE_RECEIVED = 2,
+    /// We are under load, and demanded a cookie from the peer
+    COOKIE_SENT = 3,
+}
+
+impl From<TunnEvent> for tunnel_event {
+    fn from(event: TunnEvent) -> tunnel_event {
+        match event {
+            TunnEvent::HandshakeCompleted => tunnel_event::HANDSHAKE_COMPLETED,
+            TunnEvent::ConnectionExpired => tunnel_event::CONNECTION_EXPIRED,
+            TunnEvent::CookieReceived => tunnel_event::COOKIE_RECEIVED,
+            TunnEvent::CookieSent => tunnel_event::COOKIE_SENT,
+        }
+    }
+}
+
+type EventCallback = unsafe extern "C" fn(tunnel_event, *mut c_void);
+
+/// The callback's context pointer, owned by the caller
+struct EventContext(*mut c_void);
+
+// The caller promises the context can be used from whichever thread uses the tunnel
+unsafe impl Send for EventContext {}
+unsafe impl Sync for EventContext {}
+
 #[repr(C)]
 pub struct stats {
     pub time_since_last_handshake: i64,
@@ -60,9 +94,22 @@ pub struct stats {
     pub rx_bytes: usize,
     pub estimated_loss: f32,
     pub estimated_rtt: i32,
-    reserved: [u8; 56], // Make sure to add new fields in this space, keeping total size constant
+    pub session_age: i64,
+    pub session_index: i64,
+    pub handshakes_initiated: u32,
+    pub handshakes_completed: u32,
+    pub handshakes_failed: u32,
+    pub cookies_sent: u32,
+    pub cookies_received: u32,
+    pub dropped_packets: u32,
+    pub replayed_packets: u32,
+    pub queued_packets: u32,
+    reserved: [u8; 8], // Make sure to add new fields in this space, keeping total size constant
 }
 
+// The size of stats is part of the ABI
+const _: () = assert!(std::mem::size_of::<stats>() == 88);
+
 impl<'a> From<TunnResult<'a>> for wireguard_result {
     fn from(res: TunnResult<'a>) -> wireguard_result {
         match res {
@@ -176,10 +223,7 @@ impl Write for FFIFunctionPointerWriter {
             unsafe { (self.log_func)(c_string.as_ptr()) }
             Ok(buf.len())
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "Failed to create CString from buffer.",
-            ))
+            Err(Error::other("Failed to create CString from buffer."))
         }
     }
 
@@ -231,11 +275,7 @@ pub unsafe extern "C" fn set_logging_function(
             .try_init()
             .is_ok()
     });
-    if let Ok(value) = result {
-        value
-    } else {
-        false
-    }
+    result.unwrap_or_default()
 }
 
 /// Allocate a new tunnel, return NULL on failure.
@@ -352,7 +392,7 @@ pub unsafe extern "C" fn wireguard_read(
 }
 
 /// This is a state keeping function, that need to be called periodically.
-/// Recommended interval: 100ms.
+/// Recommended interval: 100ms, or as given by `wireguard_time_to_next_event`.
 #[no_mangle]
 pub unsafe extern "C" fn wireguard_tick(
     tunnel: *const Mutex<Tunn>,
@@ -365,6 +405,36 @@ pub unsafe extern "C" fn wireguard_tick(
     wireguard_result::from(tunnel.update_timers(dst))
 }
 
+/// Returns the number of milliseconds until `wireguard_tick` has something to do, or -1 if
+/// nothing is due until packets are written or read. The deadline can only move closer after
+/// `wireguard_write`, `wireguard_read` or `wireguard_force_handshake`.
+#[no_mangle]
+pub unsafe extern "C" fn wireguard_time_to_next_event(tunnel: *const Mutex<Tunn>) -> i64 {
+    let tunnel = tunnel.as_ref().unwrap().lock();
+    match tunnel.time_to_next_event() {
+        Some(delay) => delay.as_millis().min(i64::MAX as u128) as i64,
+        None => -1,
+    }
+}
+
+/// Call `callback` with `ctx` on every tunnel event, from the thread calling into the tunnel at
+/// the time. A NULL callback removes it. The callback runs while the tunnel is locked, so it
+/// must not call any wireguard_ function on the same tunnel, and `ctx` must stay valid until
+/// the callback is removed or the tunnel is freed.
+#[no_mangle]
+pub unsafe extern "C" fn wireguard_set_event_callback(
+    tunnel: *const Mutex<Tunn>,
+    callback: Option<EventCallback>,
+    ctx: *mut c_void,
+) {
+    let mut tunnel = tunnel.as_ref().unwrap().lock();
+    let handler = callback.map(|callback| {
+        let ctx = EventContext(ctx);
+        Box::new(move |event: TunnEvent| unsafe { callback(event.into(), ctx.0) }) as Box<_>
+    });
+    tunnel.set_event_handler(handler);
+}
+
 /// Force the tunnel to initiate a new handshake, dst buffer must be at least 148 byte long.
 #[no_mangle]
 pub unsafe extern "C" fn wireguard_force_handshake(
@@ -382,16 +452,44 @@ pub unsafe extern "C" fn wireguard_force_handshake(
 /// Time of last handshake in seconds (or -1 if no handshake occurred)
 /// Number of data bytes encapsulated
 /// Number of data bytes decapsulated
+/// Age of the current session in seconds, and its index (or -1 without a current session)
+/// Handshake, cookie and dropped packet counters, the counters wrap around
 #[no_mangle]
 pub unsafe extern "C" fn wireguard_stats(tunnel: *const Mutex<Tunn>) -> stats {
     let tunnel = tunnel.as_ref().unwrap().lock();
-    let (time, tx_bytes, rx_bytes, estimated_loss, estimated_rtt) = tunnel.stats();
+    let s = tunnel.stats();
     stats {
-        time_since_last_handshake: time.map(|t| t.as_secs() as i64).unwrap_or(-1),
-        tx_bytes,
-        rx_bytes,
-        estimated_loss,
-        estimated_rtt: estimated_rtt.map(|r| r as i32).unwrap_or(-1),
-        reserved: [0u8; 56],
+        time_since_last_handshake: s
+            .time_since_last_handshake
+            .map(|t| t.as_secs() as i64)
+            .unwrap_or(-1),
+        tx_bytes: s.tx_bytes,
+        rx_bytes: s.rx_bytes,
+        estimated_loss: s.estimated_loss,
+        estimated_rtt: s.estimated_rtt.map(|r| r as i32).unwrap_or(-1),
+        session_age: s.session_age.map(|t| t.as_secs() as i64).unwrap_or(-1),
+        session_index: s.session_index.map(i64::from).unwrap_or(-1),
+        handshakes_initiated: s.handshakes_initiated as u32,
+        handshakes_completed: s.handshakes_completed as u32,
+        handshakes_failed: s.handshakes_failed as u32,
+        cookies_sent: s.cookies_sent as u32,
+        cookies_received: s.cookies_received as u32,
+        dropped_packets: s.total_dropped() as u32,
+        replayed_packets: s.replayed_packets as u32,
+        queued_packets: s.queued_packets as u32,
+        reserved: [0u8; 8],
     }
 }
+
+/// Returns the number of received packets dropped because of `reason`, the error code a
+/// WIREGUARD_ERROR result carries in its size. Unknown reasons count zero.
+#[no_mangle]
+pub unsafe extern "C" fn wireguard_dropped_packets(tunnel: *const Mutex<Tunn>, reason: u32) -> u64 {
+    let tunnel = tunnel.as_ref().unwrap().lock();
+    let stats = tunnel.stats();
+    stats
+        .dropped_packets
+        .get(reason as usize)
+        .copied()
+        .unwrap_or(0)
+}
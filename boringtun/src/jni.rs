@@ -10,14 +10,17 @@ use std::ptr;
 
 use jni::objects::{JByteBuffer, JClass, JString};
 use jni::strings::JNIStr;
-use jni::sys::{jbyteArray, jint, jlong, jshort, jstring};
+use jni::sys::{jbyteArray, jint, jlong, jlongArray, jshort, jstring};
 use jni::JNIEnv;
 use parking_lot::Mutex;
 
 use crate::ffi::new_tunnel;
+use crate::ffi::wireguard_dropped_packets;
 use crate::ffi::wireguard_read;
 use crate::ffi::wireguard_result;
+use crate::ffi::wireguard_stats;
 use crate::ffi::wireguard_tick;
+use crate::ffi::wireguard_time_to_next_event;
 use crate::ffi::wireguard_write;
 use crate::ffi::x25519_key;
 use crate::ffi::x25519_key_to_base64;
@@ -35,7 +38,6 @@ pub extern "C" fn log_print(_log_string: *const c_char) {
 }
 
 /// Generates new x25519 secret key and converts into java byte array.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_x25519_1secret_1key"]
 pub extern "C" fn generate_secret_key(env: JNIEnv, _class: JClass) -> jbyteArray {
     match env.byte_array_from_slice(&x25519_secret_key().key) {
@@ -45,7 +47,6 @@ pub extern "C" fn generate_secret_key(env: JNIEnv, _class: JClass) -> jbyteArray
 }
 
 /// Computes public x25519 key from secret key and converts into java byte array.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_x25519_1public_1key"]
 pub unsafe extern "C" fn generate_public_key1(
     env: JNIEnv,
@@ -72,7 +73,6 @@ pub unsafe extern "C" fn generate_public_key1(
 }
 
 /// Converts x25519 key to hex string.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_x25519_1key_1to_1hex"]
 pub unsafe extern "C" fn convert_x25519_key_to_hex(
     env: JNIEnv,
@@ -98,7 +98,6 @@ pub unsafe extern "C" fn convert_x25519_key_to_hex(
 }
 
 /// Converts x25519 key to base64 string.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_x25519_1key_1to_1base64"]
 pub unsafe extern "C" fn convert_x25519_key_to_base64(
     env: JNIEnv,
@@ -125,7 +124,6 @@ pub unsafe extern "C" fn convert_x25519_key_to_base64(
 }
 
 /// Creates new tunnel
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_new_1tunnel"]
 pub unsafe extern "C" fn create_new_tunnel(
     env: JNIEnv,
@@ -171,7 +169,6 @@ pub unsafe extern "C" fn create_new_tunnel(
 }
 
 /// Encrypts raw IP packets into WG formatted packets.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1write"]
 pub unsafe extern "C" fn encrypt_raw_packet(
     env: JNIEnv,
@@ -206,7 +203,6 @@ pub unsafe extern "C" fn encrypt_raw_packet(
 }
 
 /// Decrypts WG formatted packets into raw IP packets.
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1read"]
 pub unsafe extern "C" fn decrypt_to_raw_packet(
     env: JNIEnv,
@@ -242,7 +238,6 @@ pub unsafe extern "C" fn decrypt_to_raw_packet(
 }
 
 /// Periodic function that writes WG formatted packets into destination buffer
-#[no_mangle]
 #[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1tick"]
 pub unsafe extern "C" fn run_periodic_task(
     env: JNIEnv,
@@ -269,3 +264,55 @@ pub unsafe extern "C" fn run_periodic_task(
 
     output.size as i32
 }
+
+/// Milliseconds until the periodic function has something to do, -1 if nothing is due
+#[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1time_1to_1next_1event"]
+pub unsafe extern "C" fn time_to_next_event(_env: JNIEnv, _class: JClass, tunnel: jlong) -> jlong {
+    wireguard_time_to_next_event(tunnel as *const Mutex<Tunn>)
+}
+
+/// Returns the tunnel stats as a java long array, in the order of the stats struct:
+/// time since last handshake, tx bytes, rx bytes, estimated loss in parts per million,
+/// estimated rtt, session age, session index, handshakes initiated, completed and failed,
+/// cookies sent and received, dropped, replayed and queued packets
+#[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1stats"]
+pub unsafe extern "C" fn stats(env: JNIEnv, _class: JClass, tunnel: jlong) -> jlongArray {
+    let s = wireguard_stats(tunnel as *const Mutex<Tunn>);
+    let values = [
+        s.time_since_last_handshake,
+        s.tx_bytes as jlong,
+        s.rx_bytes as jlong,
+        (s.estimated_loss * 1_000_000.0) as jlong,
+        s.estimated_rtt as jlong,
+        s.session_age,
+        s.session_index,
+        s.handshakes_initiated as jlong,
+        s.handshakes_completed as jlong,
+        s.handshakes_failed as jlong,
+        s.cookies_sent as jlong,
+        s.cookies_received as jlong,
+        s.dropped_packets as jlong,
+        s.replayed_packets as jlong,
+        s.queued_packets as jlong,
+    ];
+
+    let array = match env.new_long_array(values.len() as i32) {
+        Ok(array) => array,
+        Err(_) => return ptr::null_mut(),
+    };
+    match env.set_long_array_region(array, 0, &values) {
+        Ok(_) => array,
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Received packets dropped because of `reason`, the error code of a WIREGUARD_ERROR result
+#[export_name = "Java_com_cloudflare_app_boringtun_BoringTunJNI_wireguard_1dropped_1packets"]
+pub unsafe extern "C" fn dropped_packets(
+    _env: JNIEnv,
+    _class: JClass,
+    tunnel: jlong,
+    reason: jint,
+) -> jlong {
+    wireguard_dropped_packets(tunnel as *const Mutex<Tunn>, reason as u32) as jlong
+}
@@ -1,7 +1,7 @@
 // Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum WireGuardError {
     DestinationBufferTooSmall,
     IncorrectPacketLength,
@@ -21,3 +21,50 @@ pub enum WireGuardError {
     ConnectionExpired,
     UnderLoad,
 }
+
+impl WireGuardError {
+    /// Every error, in declaration order, so `ALL[e as usize] == e`
+    pub const ALL: [WireGuardError; 17] = [
+        WireGuardError::DestinationBufferTooSmall,
+        WireGuardError::IncorrectPacketLength,
+        WireGuardError::UnexpectedPacket,
+        WireGuardError::WrongPacketType,
+        WireGuardError::WrongIndex,
+        WireGuardError::WrongKey,
+        WireGuardError::InvalidTai64nTimestamp,
+        WireGuardError::WrongTai64nTimestamp,
+        WireGuardError::InvalidMac,
+        WireGuardError::InvalidAeadTag,
+        WireGuardError::InvalidCounter,
+        WireGuardError::DuplicateCounter,
+        WireGuardError::InvalidPacket,
+        WireGuardError::NoCurrentSession,
+        WireGuardError::LockFailed,
+        WireGuardError::ConnectionExpired,
+        WireGuardError::UnderLoad,
+    ];
+
+    /// A stable snake_case name for the error, as reported for dropped packets over the UAPI and
+    /// in metrics, for example `invalid_aead_tag` for `InvalidAeadTag`
+    pub fn name(self) -> &'static str {
+        match self {
+            WireGuardError::DestinationBufferTooSmall => "destination_buffer_too_small",
+            WireGuardError::IncorrectPacketLength => "incorrect_packet_length",
+            WireGuardError::UnexpectedPacket => "unexpected_packet",
+            WireGuardError::WrongPacketType => "wrong_packet_type",
+            WireGuardError::WrongIndex => "wrong_index",
+            WireGuardError::WrongKey => "wrong_key",
+            WireGuardError::InvalidTai64nTimestamp => "invalid_tai64n_timestamp",
+            WireGuardError::WrongTai64nTimestamp => "wrong_tai64n_timestamp",
+            WireGuardError::InvalidMac => "invalid_mac",
+            WireGuardError::InvalidAeadTag => "invalid_aead_tag",
+            WireGuardError::InvalidCounter => "invalid_counter",
+            WireGuardError::DuplicateCounter => "duplicate_counter",
+            WireGuardError::InvalidPacket => "invalid_packet",
+            WireGuardError::NoCurrentSession => "no_current_session",
+            WireGuardError::LockFailed => "lock_failed",
+            WireGuardError::ConnectionExpired => "connection_expired",
+            WireGuardError::UnderLoad => "under_load",
+        }
+    }
+}
@@ -234,6 +234,8 @@ struct NoiseParams {
     static_public: x25519::PublicKey,
     /// Our static private key
     static_private: x25519::StaticSecret,
+    /// A pre-computation of the hash initiations to our static public key start from
+    initial_hash: [u8; KEY_LEN],
     /// Static public key of the other party
     peer_static_public: x25519::PublicKey,
     /// A shared key = DH(static_private, peer_static_public)
@@ -249,6 +251,7 @@ impl std::fmt::Debug for NoiseParams {
         f.debug_struct("NoiseParams")
             .field("static_public", &self.static_public)
             .field("static_private", &"<redacted>")
+            .field("initial_hash", &self.initial_hash)
             .field("peer_static_public", &self.peer_static_public)
             .field("static_shared", &"<redacted>")
             .field("sending_mac1_key", &self.sending_mac1_key)
@@ -317,27 +320,113 @@ struct Cookies {
     write_cookie: Option<[u8; 16]>,
 }
 
-#[derive(Debug)]
+/// A handshake initiation with the static public key of the initiator decrypted. The handshake
+/// of the peer with that key continues from it, see `Tunn::resume_handshake_init`.
 pub struct HalfHandshake {
     pub peer_index: u32,
     pub peer_static_public: [u8; 32],
+    /// Our static public key the initiation was decrypted with
+    static_public: x25519::PublicKey,
+    peer_ephemeral_public: x25519::PublicKey,
+    hash: [u8; KEY_LEN],
+    chaining_key: [u8; KEY_LEN],
+}
+
+impl std::fmt::Debug for HalfHandshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HalfHandshake")
+            .field("peer_index", &self.peer_index)
+            .field("peer_static_public", &self.peer_static_public)
+            .field("static_public", &self.static_public)
+            .field("peer_ephemeral_public", &self.peer_ephemeral_public)
+            .field("hash", &self.hash)
+            .field("chaining_key", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Our static key pair, with the part of the handshake that does not depend on the initiator
+/// computed once instead of for every initiation
+#[derive(Clone)]
+pub struct ResponderKey {
+    static_private: x25519::StaticSecret,
+    static_public: x25519::PublicKey,
+    /// HASH(HASH(HASH(CONSTRUCTION) || IDENTIFIER) || static_public)
+    initial_hash: [u8; KEY_LEN],
+}
+
+impl std::fmt::Debug for ResponderKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponderKey")
+            .field("static_private", &"<redacted>")
+            .field("static_public", &self.static_public)
+            .finish()
+    }
+}
+
+impl ResponderKey {
+    pub fn new(static_private: x25519::StaticSecret) -> ResponderKey {
+        let static_public = x25519::PublicKey::from(&static_private);
+        ResponderKey {
+            initial_hash: responder_initial_hash(&static_public),
+            static_private,
+            static_public,
+        }
+    }
+
+    pub fn static_private(&self) -> &x25519::StaticSecret {
+        &self.static_private
+    }
+
+    pub fn static_public(&self) -> x25519::PublicKey {
+        self.static_public
+    }
+
+    /// Like `parse_handshake_anon`, without hashing our static public key again
+    pub fn parse_handshake_anon(
+        &self,
+        packet: &HandshakeInit,
+    ) -> Result<HalfHandshake, WireGuardError> {
+        decrypt_initiator_static(
+            &self.static_private,
+            &self.static_public,
+            &self.initial_hash,
+            packet,
+        )
+    }
+}
+
+// initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
+fn responder_initial_hash(static_public: &x25519::PublicKey) -> [u8; KEY_LEN] {
+    b2s_hash(&INITIAL_CHAIN_HASH, static_public.as_bytes())
 }
 
+/// Decrypt the static public key of the initiator of `packet`, which is all the handshake
+/// needs to find the peer it is with. `ResponderKey::parse_handshake_anon` saves a hash when
+/// parsing many initiations.
 pub fn parse_handshake_anon(
     static_private: &x25519::StaticSecret,
     static_public: &x25519::PublicKey,
     packet: &HandshakeInit,
 ) -> Result<HalfHandshake, WireGuardError> {
+    let initial_hash = responder_initial_hash(static_public);
+    decrypt_initiator_static(static_private, static_public, &initial_hash, packet)
+}
+
+fn decrypt_initiator_static(
+    static_private: &x25519::StaticSecret,
+    static_public: &x25519::PublicKey,
+    initial_hash: &[u8; KEY_LEN],
+    packet: &HandshakeInit,
+) -> Result<HalfHandshake, WireGuardError> {
+    // msg.sender_index = little_endian(initiator.sender_index)
     let peer_index = packet.sender_idx;
     // initiator.chaining_key = HASH(CONSTRUCTION)
     let mut chaining_key = INITIAL_CHAIN_KEY;
-    // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
-    let mut hash = INITIAL_CHAIN_HASH;
-    hash = b2s_hash(&hash, static_public.as_bytes());
     // msg.unencrypted_ephemeral = DH_PUBKEY(initiator.ephemeral_private)
     let peer_ephemeral_public = x25519::PublicKey::from(*packet.unencrypted_ephemeral);
     // initiator.hash = HASH(initiator.hash || msg.unencrypted_ephemeral)
-    hash = b2s_hash(&hash, peer_ephemeral_public.as_bytes());
+    let hash = b2s_hash(initial_hash, peer_ephemeral_public.as_bytes());
     // temp = HMAC(initiator.chaining_key, msg.unencrypted_ephemeral)
     // initiator.chaining_key = HMAC(temp, 0x1)
     chaining_key = b2s_hmac(
@@ -365,6 +454,10 @@ pub fn parse_handshake_anon(
     Ok(HalfHandshake {
         peer_index,
         peer_static_public,
+        static_public: *static_public,
+        peer_ephemeral_public,
+        hash,
+        chaining_key,
     })
 }
 
@@ -381,6 +474,7 @@ impl NoiseParams {
         let initial_sending_mac_key = b2s_hash(LABEL_MAC1, peer_static_public.as_bytes());
 
         NoiseParams {
+            initial_hash: responder_initial_hash(&static_public),
             static_public,
             static_private,
             peer_static_public,
@@ -402,6 +496,7 @@ impl NoiseParams {
 
         self.static_private = static_private;
         self.static_public = static_public;
+        self.initial_hash = responder_initial_hash(&static_public);
 
         self.static_shared = self.static_private.diffie_hellman(&self.peer_static_public);
     }
@@ -478,55 +573,58 @@ impl Handshake {
         self.params.set_static_private(private_key, public_key)
     }
 
+    pub(crate) fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.params.preshared_key = preshared_key;
+    }
+
+    pub(crate) fn preshared_key(&self) -> Option<[u8; 32]> {
+        self.params.preshared_key
+    }
+
+    pub(crate) fn peer_static_public(&self) -> x25519::PublicKey {
+        self.params.peer_static_public
+    }
+
     pub(super) fn receive_handshake_initialization<'a>(
         &mut self,
         packet: HandshakeInit,
         dst: &'a mut [u8],
     ) -> Result<(&'a mut [u8], Session), WireGuardError> {
-        // initiator.chaining_key = HASH(CONSTRUCTION)
-        let mut chaining_key = INITIAL_CHAIN_KEY;
-        // initiator.hash = HASH(HASH(initiator.chaining_key || IDENTIFIER) || responder.static_public)
-        let mut hash = INITIAL_CHAIN_HASH;
-        hash = b2s_hash(&hash, self.params.static_public.as_bytes());
-        // msg.sender_index = little_endian(initiator.sender_index)
-        let peer_index = packet.sender_idx;
-        // msg.unencrypted_ephemeral = DH_PUBKEY(initiator.ephemeral_private)
-        let peer_ephemeral_public = x25519::PublicKey::from(*packet.unencrypted_ephemeral);
-        // initiator.hash = HASH(initiator.hash || msg.unencrypted_ephemeral)
-        hash = b2s_hash(&hash, peer_ephemeral_public.as_bytes());
-        // temp = HMAC(initiator.chaining_key, msg.unencrypted_ephemeral)
-        // initiator.chaining_key = HMAC(temp, 0x1)
-        chaining_key = b2s_hmac(
-            &b2s_hmac(&chaining_key, peer_ephemeral_public.as_bytes()),
-            &[0x01],
-        );
-        // temp = HMAC(initiator.chaining_key, DH(initiator.ephemeral_private, responder.static_public))
-        let ephemeral_shared = self
-            .params
-            .static_private
-            .diffie_hellman(&peer_ephemeral_public);
-        let temp = b2s_hmac(&chaining_key, &ephemeral_shared.to_bytes());
-        // initiator.chaining_key = HMAC(temp, 0x1)
-        chaining_key = b2s_hmac(&temp, &[0x01]);
-        // key = HMAC(temp, initiator.chaining_key || 0x2)
-        let key = b2s_hmac2(&temp, &chaining_key, &[0x02]);
-
-        let mut peer_static_public_decrypted = [0u8; KEY_LEN];
-        // msg.encrypted_static = AEAD(key, 0, initiator.static_public, initiator.hash)
-        aead_chacha20_open(
-            &mut peer_static_public_decrypted,
-            &key,
-            0,
-            packet.encrypted_static,
-            &hash,
+        let half = decrypt_initiator_static(
+            &self.params.static_private,
+            &self.params.static_public,
+            &self.params.initial_hash,
+            &packet,
         )?;
+        self.resume_handshake_initialization(half, packet, dst)
+    }
+
+    /// Continue a handshake initiation from the state `parse_handshake_anon` left it in
+    pub(super) fn resume_handshake_initialization<'a>(
+        &mut self,
+        half: HalfHandshake,
+        packet: HandshakeInit,
+        dst: &'a mut [u8],
+    ) -> Result<(&'a mut [u8], Session), WireGuardError> {
+        if half.static_public != self.params.static_public {
+            // Decrypted with a key we no longer have
+            return Err(WireGuardError::WrongKey);
+        }
 
         ring::constant_time::verify_slices_are_equal(
             self.params.peer_static_public.as_bytes(),
-            &peer_static_public_decrypted,
+            &half.peer_static_public,
         )
         .map_err(|_| WireGuardError::WrongKey)?;
 
+        let HalfHandshake {
+            peer_index,
+            peer_ephemeral_public,
+            mut hash,
+            mut chaining_key,
+            ..
+        } = half;
+
         // initiator.hash = HASH(initiator.hash || msg.encrypted_static)
         hash = b2s_hash(&hash, packet.encrypted_static);
         // temp = HMAC(initiator.chaining_key, DH(initiator.static_private, responder.static_public))
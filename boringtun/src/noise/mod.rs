@@ -3,17 +3,21 @@
 
 pub mod errors;
 pub mod handshake;
+pub mod obfuscation;
 pub mod rate_limiter;
+pub mod shaping;
 
 mod session;
 mod timers;
 
 use crate::noise::errors::WireGuardError;
-use crate::noise::handshake::Handshake;
+use crate::noise::handshake::{HalfHandshake, Handshake};
 use crate::noise::rate_limiter::RateLimiter;
+use crate::noise::shaping::TrafficShaping;
 use crate::noise::timers::{TimerName, Timers};
 use crate::x25519;
 
+use parking_lot::Mutex;
 use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
@@ -21,7 +25,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 /// The default value to use for rate limiting, when no other rate limiter is defined
-const PEER_HANDSHAKE_RATE_LIMIT: u64 = 10;
+pub(crate) const PEER_HANDSHAKE_RATE_LIMIT: u64 = 10;
 
 const IPV4_MIN_HEADER_SIZE: usize = 20;
 const IPV4_LEN_OFF: usize = 2;
@@ -71,6 +75,122 @@ pub struct Tunn {
     tx_bytes: usize,
     rx_bytes: usize,
     rate_limiter: Arc<RateLimiter>,
+    /// The limit of the rate limiter the tunnel creates when not given one
+    handshake_rate_limit: u64,
+    /// A preshared key delivered through a `PresharedKeyHandle`, not installed yet
+    pending_preshared_key: Arc<Mutex<Option<PendingPresharedKey>>>,
+    /// How to pad packets and when to send decoys, packets are sent as they are if None
+    traffic_shaping: Option<TrafficShaping>,
+    counters: Counters,
+    /// Called on every `TunnEvent`
+    event_handler: Option<Box<dyn Fn(TunnEvent) + Send + Sync>>,
+}
+
+/// Something that happened to a tunnel, reported to its event handler as it happens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnEvent {
+    /// A handshake established a new session
+    HandshakeCompleted,
+    /// No session could be established in time, or the last one grew too old, and the tunnel
+    /// dropped its sessions and queued packets
+    ConnectionExpired,
+    /// The peer is under load, and demanded a cookie with our next handshake message
+    CookieReceived,
+    /// We are under load, and demanded a cookie from the peer
+    CookieSent,
+}
+
+/// The event counters behind `TunnStats`
+#[derive(Default)]
+struct Counters {
+    handshakes_initiated: u64,
+    handshakes_completed: u64,
+    handshakes_failed: u64,
+    cookies_sent: u64,
+    cookies_received: u64,
+    dropped_packets: [u64; WireGuardError::ALL.len()],
+}
+
+/// A snapshot of a tunnel's counters and estimates, see `Tunn::stats`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TunnStats {
+    /// Time since the most recent session was established, None without a current session
+    pub time_since_last_handshake: Option<Duration>,
+    /// Data bytes sent
+    pub tx_bytes: usize,
+    /// Data bytes received
+    pub rx_bytes: usize,
+    /// The estimated fraction of packets lost, weighted towards the most recent sessions
+    pub estimated_loss: f32,
+    /// Time it took to complete the latest handshake we initiated, in milliseconds
+    pub estimated_rtt: Option<u32>,
+    /// Handshake initiations sent, retransmissions included
+    pub handshakes_initiated: u64,
+    /// Handshakes that established a session, whichever side initiated them
+    pub handshakes_completed: u64,
+    /// Handshake initiations that got no response in time, and were retransmitted or given up on
+    pub handshakes_failed: u64,
+    /// Cookie replies sent by this tunnel while under load. A device answers with cookies
+    /// before it knows the peer, those are not counted here.
+    pub cookies_sent: u64,
+    pub cookies_received: u64,
+    /// Received packets that were dropped, indexed by the error they caused, `e as usize`
+    pub dropped_packets: [u64; WireGuardError::ALL.len()],
+    /// Received data packets dropped as replays, they count as dropped packets as well
+    pub replayed_packets: u64,
+    /// The local index of the current session
+    pub session_index: Option<u32>,
+    /// Time since the current session was established
+    pub session_age: Option<Duration>,
+    /// Packets waiting for a session to be sent
+    pub queued_packets: usize,
+}
+
+impl TunnStats {
+    /// The number of received packets dropped because of `reason`
+    pub fn dropped(&self, reason: WireGuardError) -> u64 {
+        self.dropped_packets[reason as usize]
+    }
+
+    /// The number of received packets dropped, for any reason
+    pub fn total_dropped(&self) -> u64 {
+        self.dropped_packets.iter().sum()
+    }
+}
+
+struct PendingPresharedKey {
+    key: Option<[u8; 32]>,
+    rekey: bool,
+}
+
+/// Hands a tunnel new preshared keys from outside, for example from a post-quantum key
+/// encapsulation exchange run through the tunnel, to mix its secret into the Noise IKpsk2
+/// handshake. The key is installed between handshakes, the next time the tunnel's timers are
+/// updated or a handshake is initiated or answered, whichever comes first. A handshake in
+/// progress completes with the key it started with.
+#[derive(Clone)]
+pub struct PresharedKeyHandle {
+    pending: Arc<Mutex<Option<PendingPresharedKey>>>,
+    notify: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl PresharedKeyHandle {
+    /// Use `key` from the next handshake on. With `rekey`, that handshake is initiated on the
+    /// next timer update, rather than when the current session is due for renewal. A key that
+    /// was not installed yet is replaced.
+    pub fn deliver(&self, key: Option<[u8; 32]>, rekey: bool) {
+        *self.pending.lock() = Some(PendingPresharedKey { key, rekey });
+        if let Some(notify) = self.notify.as_ref() {
+            notify();
+        }
+    }
+
+    /// Call `notify` after every delivery, for example to update the tunnel's timers without
+    /// waiting for `time_to_next_event` to elapse
+    pub fn on_deliver(mut self, notify: impl Fn() + Send + Sync + 'static) -> Self {
+        self.notify = Some(Arc::new(notify));
+        self
+    }
 }
 
 type MessageType = u32;
@@ -79,10 +199,10 @@ const HANDSHAKE_RESP: MessageType = 2;
 const COOKIE_REPLY: MessageType = 3;
 const DATA: MessageType = 4;
 
-const HANDSHAKE_INIT_SZ: usize = 148;
+pub(crate) const HANDSHAKE_INIT_SZ: usize = 148;
 const HANDSHAKE_RESP_SZ: usize = 92;
 const COOKIE_REPLY_SZ: usize = 64;
-const DATA_OVERHEAD_SZ: usize = 32;
+pub(crate) const DATA_OVERHEAD_SZ: usize = 32;
 
 #[derive(Debug)]
 pub struct HandshakeInit<'a> {
@@ -125,7 +245,7 @@ pub enum Packet<'a> {
 
 impl Tunn {
     #[inline(always)]
-    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet, WireGuardError> {
+    pub fn parse_incoming_packet(src: &[u8]) -> Result<Packet<'_>, WireGuardError> {
         if src.len() < 4 {
             return Err(WireGuardError::InvalidPacket);
         }
@@ -220,9 +340,108 @@ impl Tunn {
             rate_limiter: rate_limiter.unwrap_or_else(|| {
                 Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
             }),
+            handshake_rate_limit: PEER_HANDSHAKE_RATE_LIMIT,
+            pending_preshared_key: Default::default(),
+            traffic_shaping: None,
+            counters: Default::default(),
+            event_handler: None,
+        }
+    }
+
+    /// Install a new preshared key, used from the next handshake on. Established sessions keep
+    /// their keys until they are replaced, call `force_handshake` to replace them right away.
+    pub fn set_preshared_key(&mut self, preshared_key: Option<[u8; 32]>) {
+        self.handshake.set_preshared_key(preshared_key);
+    }
+
+    pub fn preshared_key(&self) -> Option<[u8; 32]> {
+        self.handshake.preshared_key()
+    }
+
+    /// The static public key of the peer at the other end of the tunnel
+    pub fn peer_static_public(&self) -> x25519::PublicKey {
+        self.handshake.peer_static_public()
+    }
+
+    /// Call `handler` on every `TunnEvent`, from whichever thread is using the tunnel at the
+    /// time. None removes the handler.
+    pub fn set_event_handler(&mut self, handler: Option<Box<dyn Fn(TunnEvent) + Send + Sync>>) {
+        self.event_handler = handler;
+    }
+
+    fn emit(&self, event: TunnEvent) {
+        if let Some(handler) = self.event_handler.as_ref() {
+            handler(event);
+        }
+    }
+
+    /// A handle to deliver preshared keys from another thread
+    pub fn preshared_key_handle(&self) -> PresharedKeyHandle {
+        PresharedKeyHandle {
+            pending: Arc::clone(&self.pending_preshared_key),
+            notify: None,
+        }
+    }
+
+    /// Install the preshared key delivered through a `PresharedKeyHandle`, if any. Only to be
+    /// called between handshakes, as the key is mixed in when a response is processed. A request
+    /// for a new handshake stays in `want_rekey` until one is initiated.
+    fn install_pending_preshared_key(&mut self) {
+        if let Some(PendingPresharedKey { key, rekey }) = self.pending_preshared_key.lock().take() {
+            tracing::debug!("Installing delivered preshared key");
+            self.handshake.set_preshared_key(key);
+            self.timers.want_rekey |= rekey;
+        }
+    }
+
+    /// Was a preshared key delivered that asks for a new handshake?
+    fn preshared_key_wants_rekey(&self) -> bool {
+        matches!(&*self.pending_preshared_key.lock(), Some(pending) if pending.rekey)
+    }
+
+    /// Initiate a new handshake now, even if one is in progress. Useful to put a new preshared
+    /// key to use.
+    pub fn force_handshake<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+        self.format_handshake_initiation(dst, true)
+    }
+
+    /// Pad packets and send decoys as described by `shaping`, or send packets as they are with
+    /// `None`. Decoys are only understood by peers that also use traffic shaping.
+    pub fn set_traffic_shaping(&mut self, shaping: Option<TrafficShaping>) {
+        self.traffic_shaping = shaping;
+        self.timers.next_decoy = None;
+    }
+
+    pub fn traffic_shaping(&self) -> Option<&TrafficShaping> {
+        self.traffic_shaping.as_ref()
+    }
+
+    /// The size to pad a plaintext of `len` bytes to, so it still fits in `dst_len` bytes once
+    /// encapsulated
+    fn padded_len(&self, len: usize, dst_len: usize) -> usize {
+        match self.traffic_shaping {
+            Some(ref shaping) => shaping
+                .padded_len(len)
+                .min(dst_len.saturating_sub(DATA_OVERHEAD_SZ))
+                .max(len),
+            None => len,
+        }
+    }
+
+    /// Change the number of handshake messages per second above which the tunnel asks for
+    /// cookies. Only applies while the tunnel uses a rate limiter of its own, not one it was
+    /// given.
+    pub fn set_handshake_rate_limit(&mut self, limit: u64) {
+        self.handshake_rate_limit = limit;
+        if self.timers.should_reset_rr {
+            self.rate_limiter.set_limit(limit);
         }
     }
 
+    pub fn handshake_rate_limit(&self) -> u64 {
+        self.handshake_rate_limit
+    }
+
     /// Update the private key and clear existing sessions
     pub fn set_static_private(
         &mut self,
@@ -232,7 +451,7 @@ impl Tunn {
     ) {
         self.timers.should_reset_rr = rate_limiter.is_none();
         self.rate_limiter = rate_limiter.unwrap_or_else(|| {
-            Arc::new(RateLimiter::new(&static_public, PEER_HANDSHAKE_RATE_LIMIT))
+            Arc::new(RateLimiter::new(&static_public, self.handshake_rate_limit))
         });
         self.handshake
             .set_static_private(static_private, static_public);
@@ -248,10 +467,19 @@ impl Tunn {
     /// Panics if dst buffer is too small.
     /// Size of dst should be at least src.len() + 32, and no less than 148 bytes.
     pub fn encapsulate<'a>(&mut self, src: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
+        self.update_time_current();
         let current = self.current;
         if let Some(ref session) = self.sessions[current % N_SESSIONS] {
+            if session.is_sending_exhausted() {
+                // REJECT_AFTER_MESSAGES reached, this session can't be used anymore
+                return self.queue_and_handshake(src, dst);
+            }
             // Send the packet using an established session
-            let packet = session.format_packet_data(src, dst);
+            let padded_len = self.padded_len(src.len(), dst.len());
+            let packet = match session.format_packet_data(src, padded_len, dst) {
+                Ok(packet) => packet,
+                Err(e) => return TunnResult::Err(e),
+            };
             self.timer_tick(TimerName::TimeLastPacketSent);
             // Exclude Keepalive packets from timer update.
             if !src.is_empty() {
@@ -262,8 +490,65 @@ impl Tunn {
         }
 
         // If there is no session, queue the packet for future retry
+        self.queue_and_handshake(src, dst)
+    }
+
+    /// Encapsulate a batch of packets from the tunnel interface, all destined to this peer.
+    /// `dst` is split into consecutive chunks of `dst_stride` bytes, the packet `src[i]` is
+    /// encapsulated into the i-th chunk, and its TunnResult is appended to `results`.
+    ///
+    /// Nonces and timers are handled once for the whole batch, instead of once per packet.
+    ///
+    /// # Panics
+    /// Panics if dst holds less than src.len() chunks.
+    /// Each chunk must be large enough for the packet it holds, as with encapsulate.
+    pub fn encapsulate_batch<'a, P: AsRef<[u8]>>(
+        &mut self,
+        src: &[P],
+        dst: &'a mut [u8],
+        dst_stride: usize,
+        results: &mut Vec<TunnResult<'a>>,
+    ) {
+        self.update_time_current();
+        let mut packets = src.iter().map(AsRef::as_ref);
+        let mut chunks = dst.chunks_mut(dst_stride);
+        let mut next_chunk = || chunks.next().expect("The destination buffer is too small");
+
+        let current = self.current;
+        if let Some(ref session) = self.sessions[current % N_SESSIONS] {
+            // Send as many packets as the session has nonces left for
+            let counters = session.reserve_sending_counters(src.len() as u64);
+            let mut n_sent = 0;
+            let mut sent_data = false;
+            for counter in counters {
+                let packet = packets.next().unwrap();
+                let padded_len = self.padded_len(packet.len(), dst_stride);
+                let dst = session.seal_packet_data(counter, packet, padded_len, next_chunk());
+                results.push(TunnResult::WriteToNetwork(dst));
+                self.tx_bytes += packet.len();
+                n_sent += 1;
+                // Exclude Keepalive packets from timer update.
+                sent_data |= !packet.is_empty();
+            }
+
+            if n_sent > 0 {
+                self.timer_tick(TimerName::TimeLastPacketSent);
+            }
+            if sent_data {
+                self.timer_tick(TimerName::TimeLastDataPacketSent);
+            }
+        }
+
+        // Queue whatever could not be sent for future retry
+        for packet in packets {
+            results.push(self.queue_and_handshake(packet, next_chunk()));
+        }
+    }
+
+    /// Queue the packet until a usable session is established, and initiate a new handshake if
+    /// none is in progress
+    fn queue_and_handshake<'a>(&mut self, src: &[u8], dst: &'a mut [u8]) -> TunnResult<'a> {
         self.queue_packet(src);
-        // Initiate a new handshake if none is in progress
         self.format_handshake_initiation(dst, false)
     }
 
@@ -291,32 +576,106 @@ impl Tunn {
         {
             Ok(packet) => packet,
             Err(TunnResult::WriteToNetwork(cookie)) => {
+                self.counters.cookies_sent += 1;
+                self.emit(TunnEvent::CookieSent);
                 dst[..cookie.len()].copy_from_slice(cookie);
                 return TunnResult::WriteToNetwork(&mut dst[..cookie.len()]);
             }
-            Err(TunnResult::Err(e)) => return TunnResult::Err(e),
+            Err(TunnResult::Err(e)) => return self.drop_packet(e),
             _ => unreachable!(),
         };
 
         self.handle_verified_packet(packet, dst)
     }
 
+    /// Receives a batch of UDP datagrams from the network, all from the same source.
+    /// `dst` is split into consecutive chunks of `dst_stride` bytes, the datagram `src[i]` is
+    /// decapsulated into the i-th chunk, and its TunnResult is appended to `results`.
+    ///
+    /// Data packets are decrypted without the per packet session bookkeeping, which is done once
+    /// for the whole batch. As with decapsulate, if any of the results is of type
+    /// TunnResult::WriteToNetwork, should call decapsulate with an empty datagram until
+    /// TunnResult::Done is returned.
+    ///
+    /// # Panics
+    /// Panics if dst holds less than src.len() chunks.
+    pub fn decapsulate_batch<'a, P: AsRef<[u8]>>(
+        &mut self,
+        src_addr: Option<IpAddr>,
+        src: &[P],
+        dst: &'a mut [u8],
+        dst_stride: usize,
+        results: &mut Vec<TunnResult<'a>>,
+    ) {
+        self.update_time_current();
+        let mut chunks = dst.chunks_mut(dst_stride);
+        let mut received_data = false;
+
+        for datagram in src.iter().map(AsRef::as_ref) {
+            let dst = chunks.next().expect("The destination buffer is too small");
+
+            let result = match Tunn::parse_incoming_packet(datagram) {
+                // Data packets are not subject to rate limiting, skip straight to decryption
+                Ok(Packet::PacketData(packet)) => {
+                    let r_idx = packet.receiver_idx as usize;
+                    match self.sessions[r_idx % N_SESSIONS].as_ref() {
+                        Some(session) => match session.receive_packet_data(packet, dst) {
+                            Ok(decapsulated_packet) => {
+                                // Confirm each session the peer sends with, as `decapsulate` does
+                                self.set_current_session(r_idx);
+                                received_data = true;
+                                self.validate_decapsulated_packet(decapsulated_packet)
+                            }
+                            Err(e) => self.drop_packet(e),
+                        },
+                        None => self.drop_packet(WireGuardError::NoCurrentSession),
+                    }
+                }
+                _ => self.decapsulate(src_addr, datagram, dst),
+            };
+            results.push(result);
+        }
+
+        if received_data {
+            self.timer_tick(TimerName::TimeLastPacketReceived);
+        }
+    }
+
     pub(crate) fn handle_verified_packet<'a>(
         &mut self,
         packet: Packet,
         dst: &'a mut [u8],
     ) -> TunnResult<'a> {
         match packet {
-            Packet::HandshakeInit(p) => self.handle_handshake_init(p, dst),
+            Packet::HandshakeInit(p) => self.handle_handshake_init(None, p, dst),
             Packet::HandshakeResponse(p) => self.handle_handshake_response(p, dst),
             Packet::PacketCookieReply(p) => self.handle_cookie_reply(p),
             Packet::PacketData(p) => self.handle_data(p, dst),
         }
-        .unwrap_or_else(TunnResult::from)
+        .unwrap_or_else(|e| self.drop_packet(e))
+    }
+
+    /// Handle a handshake initiation from the state `parse_handshake_anon` decrypted it to, rather
+    /// than decrypting it again. The packet must have been verified by `RateLimiter::verify_packet`.
+    pub fn resume_handshake_init<'a>(
+        &mut self,
+        half: HalfHandshake,
+        packet: HandshakeInit,
+        dst: &'a mut [u8],
+    ) -> TunnResult<'a> {
+        self.handle_handshake_init(Some(half), packet, dst)
+            .unwrap_or_else(|e| self.drop_packet(e))
+    }
+
+    /// Count a received packet that was dropped because of `e`
+    fn drop_packet<'a>(&mut self, e: WireGuardError) -> TunnResult<'a> {
+        self.counters.dropped_packets[e as usize] += 1;
+        TunnResult::Err(e)
     }
 
     fn handle_handshake_init<'a>(
         &mut self,
+        half: Option<HalfHandshake>,
         p: HandshakeInit,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
@@ -324,12 +683,27 @@ impl Tunn {
             message = "Received handshake_initiation",
             remote_idx = p.sender_idx
         );
+        self.update_time_current();
 
-        let (packet, session) = self.handshake.receive_handshake_initialization(p, dst)?;
+        // The initiation starts a new handshake with the latest key, unless an initiation of ours
+        // still awaits its response
+        if self.handshake.timer().is_none() {
+            self.install_pending_preshared_key();
+        }
+        let (packet, session) = match half {
+            Some(half) => self
+                .handshake
+                .resume_handshake_initialization(half, p, dst)?,
+            None => self.handshake.receive_handshake_initialization(p, dst)?,
+        };
 
         // Store new session in ring buffer
         let index = session.local_index();
         self.sessions[index % N_SESSIONS] = Some(session);
+        self.counters.handshakes_completed += 1;
+        // The new session uses the latest key, there is no need for another handshake
+        self.timers.want_rekey = false;
+        self.emit(TunnEvent::HandshakeCompleted);
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeLastPacketSent);
@@ -350,14 +724,20 @@ impl Tunn {
             local_idx = p.receiver_idx,
             remote_idx = p.sender_idx
         );
+        self.update_time_current();
 
+        // Checked with the key the initiation was sent with, a delivered key waits for the next
+        // handshake
         let session = self.handshake.receive_handshake_response(p)?;
 
-        let keepalive_packet = session.format_packet_data(&[], dst);
+        let padded_len = self.padded_len(0, dst.len());
+        let keepalive_packet = session.format_packet_data(&[], padded_len, dst)?;
         // Store new session in ring buffer
         let l_idx = session.local_index();
         let index = l_idx % N_SESSIONS;
         self.sessions[index] = Some(session);
+        self.counters.handshakes_completed += 1;
+        self.emit(TunnEvent::HandshakeCompleted);
 
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick_session_established(true, index); // New session established, we are the initiator
@@ -376,8 +756,11 @@ impl Tunn {
             message = "Received cookie_reply",
             local_idx = p.receiver_idx
         );
+        self.update_time_current();
 
         self.handshake.receive_cookie_reply(p)?;
+        self.counters.cookies_received += 1;
+        self.emit(TunnEvent::CookieReceived);
         self.timer_tick(TimerName::TimeLastPacketReceived);
         self.timer_tick(TimerName::TimeCookieReceived);
 
@@ -408,6 +791,7 @@ impl Tunn {
         packet: PacketData,
         dst: &'a mut [u8],
     ) -> Result<TunnResult<'a>, WireGuardError> {
+        self.update_time_current();
         let r_idx = packet.receiver_idx as usize;
         let idx = r_idx % N_SESSIONS;
 
@@ -438,6 +822,8 @@ impl Tunn {
         if self.handshake.is_in_progress() && !force_resend {
             return TunnResult::Done;
         }
+        self.update_time_current();
+        self.install_pending_preshared_key();
 
         if self.handshake.is_expired() {
             self.timers.clear();
@@ -448,6 +834,8 @@ impl Tunn {
         match self.handshake.format_handshake_initiation(dst) {
             Ok(packet) => {
                 tracing::debug!("Sending handshake_initiation");
+                self.counters.handshakes_initiated += 1;
+                self.timers.want_rekey = false;
 
                 if starting_new_handshake {
                     self.timer_tick(TimerName::TimeLastHandshakeStarted);
@@ -459,11 +847,42 @@ impl Tunn {
         }
     }
 
+    /// Send a decoy using the current session, its plaintext is all zeros, which the receiver
+    /// discards like a keepalive
+    pub(super) fn format_decoy<'a>(&mut self, dst: &'a mut [u8]) -> TunnResult<'a> {
+        let size = match self
+            .traffic_shaping
+            .as_ref()
+            .and_then(|s| s.decoys.as_ref())
+        {
+            Some(schedule) => schedule.size,
+            None => return TunnResult::Done,
+        };
+        let padded_len = self
+            .padded_len(size, dst.len())
+            .min(dst.len().saturating_sub(DATA_OVERHEAD_SZ));
+
+        let current = self.current;
+        let session = match self.sessions[current % N_SESSIONS] {
+            Some(ref session) if !session.is_sending_exhausted() => session,
+            // Decoys never start a handshake
+            _ => return TunnResult::Done,
+        };
+        match session.format_packet_data(&[], padded_len, dst) {
+            Ok(packet) => {
+                self.timer_tick(TimerName::TimeLastPacketSent);
+                TunnResult::WriteToNetwork(packet)
+            }
+            Err(e) => TunnResult::Err(e),
+        }
+    }
+
     /// Check if an IP packet is v4 or v6, truncate to the length indicated by the length field
     /// Returns the truncated packet and the source IP as TunnResult
     fn validate_decapsulated_packet<'a>(&mut self, packet: &'a mut [u8]) -> TunnResult<'a> {
         let (computed_len, src_ip_address) = match packet.len() {
             0 => return TunnResult::Done, // This is keepalive, and not an error
+            _ if packet[0] == 0 => return TunnResult::Done, // A padded keepalive, or a decoy
             _ if packet[0] >> 4 == 4 && packet.len() >= IPV4_MIN_HEADER_SIZE => {
                 let len_bytes: [u8; IP_LEN_SZ] = packet[IPV4_LEN_OFF..IPV4_LEN_OFF + IP_LEN_SZ]
                     .try_into()
@@ -540,6 +959,13 @@ impl Tunn {
         self.packet_queue.pop_front()
     }
 
+    /// Number of packets sent using the current session, zero if there is no current session
+    fn current_session_sending_counter(&self) -> u64 {
+        self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .map_or(0, |session| session.sending_counter())
+    }
+
     fn estimate_loss(&self) -> f32 {
         let session_idx = self.current;
 
@@ -570,27 +996,43 @@ impl Tunn {
         }
     }
 
-    /// Return stats from the tunnel:
-    /// * Time since last handshake in seconds
-    /// * Data bytes sent
-    /// * Data bytes received
-    pub fn stats(&self) -> (Option<Duration>, usize, usize, f32, Option<u32>) {
-        let time = self.time_since_last_handshake();
-        let tx_bytes = self.tx_bytes;
-        let rx_bytes = self.rx_bytes;
-        let loss = self.estimate_loss();
-        let rtt = self.handshake.last_rtt;
-
-        (time, tx_bytes, rx_bytes, loss, rtt)
+    /// Return stats from the tunnel
+    pub fn stats(&self) -> TunnStats {
+        let counters = &self.counters;
+        let session_index = self.sessions[self.current % N_SESSIONS]
+            .as_ref()
+            .map(|_| self.current as u32);
+
+        TunnStats {
+            time_since_last_handshake: self.time_since_last_handshake(),
+            tx_bytes: self.tx_bytes,
+            rx_bytes: self.rx_bytes,
+            estimated_loss: self.estimate_loss(),
+            estimated_rtt: self.handshake.last_rtt,
+            handshakes_initiated: counters.handshakes_initiated,
+            handshakes_completed: counters.handshakes_completed,
+            handshakes_failed: counters.handshakes_failed,
+            cookies_sent: counters.cookies_sent,
+            cookies_received: counters.cookies_received,
+            dropped_packets: counters.dropped_packets,
+            replayed_packets: counters.dropped_packets[WireGuardError::DuplicateCounter as usize],
+            session_index,
+            session_age: self.current_session_age(),
+            queued_packets: self.packet_queue.len(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::noise::timers::REJECT_AFTER_MESSAGES;
     #[cfg(feature = "mock-instant")]
-    use crate::noise::timers::{REKEY_AFTER_TIME, REKEY_TIMEOUT};
+    use crate::noise::timers::{
+        KEEPALIVE_TIMEOUT, REKEY_AFTER_MESSAGES, REKEY_AFTER_TIME, REKEY_TIMEOUT,
+    };
 
     use super::*;
+    use crate::noise::handshake::ResponderKey;
     use rand_core::{OsRng, RngCore};
 
     fn create_two_tuns() -> (Tunn, Tunn) {
@@ -721,6 +1163,56 @@ mod tests {
         assert!(matches!(packet, Packet::PacketData(_)));
     }
 
+    #[test]
+    fn resume_handshake_init() {
+        let my_secret_key = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let my_public_key = x25519_dalek::PublicKey::from(&my_secret_key);
+        let their_key = ResponderKey::new(x25519_dalek::StaticSecret::random_from_rng(OsRng));
+        let mut my_tun = Tunn::new(
+            my_secret_key,
+            their_key.static_public(),
+            None,
+            None,
+            1,
+            None,
+        );
+        let their_secret_key = their_key.static_private().clone();
+        let mut their_tun = Tunn::new(their_secret_key.clone(), my_public_key, None, None, 2, None);
+
+        let init = create_handshake_init(&mut my_tun);
+        let parse_init = || match Tunn::parse_incoming_packet(&init) {
+            Ok(Packet::HandshakeInit(packet)) => packet,
+            _ => unreachable!(),
+        };
+        let half = their_key.parse_handshake_anon(&parse_init()).unwrap();
+        assert_eq!(half.peer_static_public, my_public_key.to_bytes());
+
+        // The handshake of another peer does not continue from it
+        let other_public_key =
+            x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        let mut other_tun = Tunn::new(their_secret_key, other_public_key, None, None, 3, None);
+        let mut dst = vec![0u8; 2048];
+        assert!(matches!(
+            other_tun.resume_handshake_init(half, parse_init(), &mut dst),
+            TunnResult::Err(WireGuardError::WrongKey)
+        ));
+
+        let half = their_key.parse_handshake_anon(&parse_init()).unwrap();
+        let resp = match their_tun.resume_handshake_init(half, parse_init(), &mut dst) {
+            TunnResult::WriteToNetwork(resp) => resp.to_vec(),
+            _ => unreachable!(),
+        };
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+
+        // Replays are caught as when the initiation is decrypted in full
+        let half = their_key.parse_handshake_anon(&parse_init()).unwrap();
+        assert!(matches!(
+            their_tun.resume_handshake_init(half, parse_init(), &mut dst),
+            TunnResult::Err(WireGuardError::WrongTai64nTimestamp)
+        ));
+    }
+
     #[test]
     fn full_handshake_plus_timers() {
         let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
@@ -729,6 +1221,86 @@ mod tests {
         assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
     }
 
+    #[test]
+    fn rotate_preshared_key() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let preshared_key = Some([7u8; 32]);
+        let mut dst = vec![0u8; 2048];
+        // Handshake initiations are only accepted with a newer timestamp
+        #[cfg(feature = "mock-instant")]
+        mock_instant::MockClock::advance(Duration::from_millis(1));
+
+        // The handshake fails until both ends use the new key
+        my_tun.set_preshared_key(preshared_key);
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        assert!(matches!(
+            my_tun.decapsulate(None, &resp, &mut dst),
+            TunnResult::Err(_)
+        ));
+
+        their_tun.set_preshared_key(preshared_key);
+        #[cfg(feature = "mock-instant")]
+        mock_instant::MockClock::advance(Duration::from_millis(1));
+        let init = match my_tun.force_handshake(&mut dst) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.preshared_key(), preshared_key);
+    }
+
+    #[test]
+    fn delivered_preshared_key_starts_handshake() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let preshared_key = Some([9u8; 32]);
+        let mut dst = vec![0u8; 2048];
+        #[cfg(feature = "mock-instant")]
+        mock_instant::MockClock::advance(Duration::from_millis(1));
+
+        their_tun
+            .preshared_key_handle()
+            .deliver(preshared_key, false);
+        my_tun.preshared_key_handle().deliver(preshared_key, true);
+        let init = match my_tun.update_timers(&mut dst) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => panic!("Expected a handshake initiation"),
+        };
+        assert!(matches!(
+            Tunn::parse_incoming_packet(&init),
+            Ok(Packet::HandshakeInit(_))
+        ));
+        assert_eq!(my_tun.preshared_key(), preshared_key);
+        // A delivery asks for a single handshake
+        assert!(matches!(my_tun.update_timers(&mut dst), TunnResult::Done));
+
+        let resp = create_handshake_response(&mut their_tun, &init);
+        assert_eq!(their_tun.preshared_key(), preshared_key);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+    }
+
+    #[test]
+    fn delivered_preshared_key_waits_for_handshake_in_progress() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+
+        // The response is checked with the key the initiation was sent with
+        my_tun
+            .preshared_key_handle()
+            .deliver(Some([9u8; 32]), false);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+        assert_eq!(my_tun.preshared_key(), None);
+
+        let mut dst = vec![0u8; 2048];
+        assert!(matches!(my_tun.update_timers(&mut dst), TunnResult::Done));
+        assert_eq!(my_tun.preshared_key(), Some([9u8; 32]));
+    }
+
     #[test]
     #[cfg(feature = "mock-instant")]
     fn new_handshake_after_two_mins() {
@@ -753,6 +1325,108 @@ mod tests {
         update_timer_results_in_handshake(&mut my_tun);
     }
 
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn time_to_next_event_follows_timers() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        // The handshake is counted by the rate limiter, which is reset a second later
+        assert_eq!(my_tun.time_to_next_event(), Some(Duration::from_secs(1)));
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+        assert!(matches!(
+            their_tun.update_timers(&mut their_dst),
+            TunnResult::Done
+        ));
+        assert!(my_tun.time_to_next_event().unwrap() > REKEY_AFTER_TIME - Duration::from_secs(1));
+
+        // Data that goes unanswered calls for a handshake after 15 seconds
+        let data = match my_tun.encapsulate(&create_ipv4_udp_packet(), &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data,
+            _ => unreachable!(),
+        };
+        assert_eq!(my_tun.time_to_next_event(), Some(Duration::from_secs(14)));
+
+        // Received data is answered with a keepalive 10 seconds after the last packet sent
+        assert!(matches!(
+            their_tun.decapsulate(None, data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert_eq!(their_tun.time_to_next_event(), Some(Duration::from_secs(9)));
+        mock_instant::MockClock::advance(Duration::from_secs(8));
+        assert!(matches!(
+            their_tun.update_timers(&mut their_dst),
+            TunnResult::Done
+        ));
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        let keepalive = match their_tun.update_timers(&mut their_dst) {
+            TunnResult::WriteToNetwork(keepalive) => keepalive,
+            _ => unreachable!(),
+        };
+
+        // Once answered, only the session renewal is left
+        assert!(matches!(
+            my_tun.decapsulate(None, keepalive, &mut my_dst),
+            TunnResult::Done
+        ));
+        assert_eq!(
+            my_tun.time_to_next_event(),
+            Some(REKEY_AFTER_TIME - Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn packets_after_idling_are_timed_when_handled() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        let send_data = |from: &mut Tunn, to: &mut Tunn| {
+            let mut dst = [0u8; 1024];
+            let data = match from.encapsulate(&create_ipv4_udp_packet(), &mut dst) {
+                TunnResult::WriteToNetwork(data) => data,
+                _ => unreachable!(),
+            };
+            let mut to_dst = [0u8; 1024];
+            assert!(matches!(
+                to.decapsulate(None, data, &mut to_dst),
+                TunnResult::WriteToTunnelV4(..)
+            ));
+        };
+        let mut update_timers = |my_tun: &mut Tunn, their_tun: &mut Tunn| {
+            assert!(matches!(
+                my_tun.update_timers(&mut my_dst),
+                TunnResult::Done
+            ));
+            assert!(matches!(
+                their_tun.update_timers(&mut their_dst),
+                TunnResult::Done
+            ));
+        };
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        update_timers(&mut my_tun, &mut their_tun);
+
+        // Idle without updating the timers, then exchange data
+        mock_instant::MockClock::advance(
+            KEEPALIVE_TIMEOUT + REKEY_TIMEOUT + Duration::from_secs(5),
+        );
+        send_data(&mut their_tun, &mut my_tun);
+        send_data(&mut my_tun, &mut their_tun);
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        update_timers(&mut my_tun, &mut their_tun);
+
+        // Both ends heard from each other two seconds before, more data calls for neither a
+        // keepalive nor a handshake
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        send_data(&mut my_tun, &mut their_tun);
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        update_timers(&mut my_tun, &mut their_tun);
+    }
+
     #[test]
     #[cfg(feature = "mock-instant")]
     fn handshake_no_resp_rekey_timeout() {
@@ -766,6 +1440,205 @@ mod tests {
         update_timer_results_in_handshake(&mut my_tun)
     }
 
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn new_handshake_after_rekey_after_messages() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let session = my_tun.sessions[my_tun.current % N_SESSIONS]
+            .as_ref()
+            .unwrap();
+        session.set_sending_counter(REKEY_AFTER_MESSAGES - 1);
+
+        mock_instant::MockClock::advance(REKEY_TIMEOUT);
+        assert!(matches!(my_tun.update_timers(&mut []), TunnResult::Done));
+        assert!(matches!(their_tun.update_timers(&mut []), TunnResult::Done));
+
+        // Sending one more packet reaches the limit, the next timer update initiates a handshake
+        let mut my_dst = [0u8; 1024];
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let data = my_tun.encapsulate(&sent_packet_buf, &mut my_dst);
+        assert!(matches!(data, TunnResult::WriteToNetwork(_)));
+
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        update_timer_results_in_handshake(&mut my_tun);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn responder_rekeys_after_messages() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let session = their_tun.sessions[their_tun.current % N_SESSIONS]
+            .as_ref()
+            .unwrap();
+        session.set_sending_counter(REKEY_AFTER_MESSAGES);
+
+        // Unlike REKEY_AFTER_TIME, the message limit applies to the responder as well
+        mock_instant::MockClock::advance(REKEY_TIMEOUT);
+        assert!(matches!(my_tun.update_timers(&mut []), TunnResult::Done));
+        update_timer_results_in_handshake(&mut their_tun);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn no_data_after_reject_after_messages() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let session = my_tun.sessions[my_tun.current % N_SESSIONS]
+            .as_ref()
+            .unwrap();
+        session.set_sending_counter(REJECT_AFTER_MESSAGES - 1);
+
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+        let sent_packet_buf = create_ipv4_udp_packet();
+
+        // The last packet allowed by the limit still goes through
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+
+        // The next packet is queued and a handshake is initiated instead
+        mock_instant::MockClock::advance(Duration::from_secs(1));
+        let init = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            Tunn::parse_incoming_packet(&init),
+            Ok(Packet::HandshakeInit(_))
+        ));
+
+        // Once the handshake completes, the queued packet is sent with the new session
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+
+        let data = match my_tun.decapsulate(None, &[], &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        let recv_packet_buf = match their_tun.decapsulate(None, &data, &mut their_dst) {
+            TunnResult::WriteToTunnelV4(recv, _) => recv.to_vec(),
+            _ => unreachable!(),
+        };
+        assert_eq!(sent_packet_buf, recv_packet_buf);
+    }
+
+    #[test]
+    fn batch_ip_packets() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        let sent_packets = [
+            create_ipv4_udp_packet(),
+            create_ipv4_udp_packet(),
+            create_ipv4_udp_packet(),
+        ];
+
+        let mut my_dst = vec![0u8; 3 * 2048];
+        let mut encapsulated = vec![];
+        my_tun.encapsulate_batch(&sent_packets, &mut my_dst, 2048, &mut encapsulated);
+        let datagrams: Vec<Vec<u8>> = encapsulated
+            .into_iter()
+            .map(|result| match result {
+                TunnResult::WriteToNetwork(data) => data.to_vec(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(datagrams.len(), sent_packets.len());
+
+        let mut their_dst = vec![0u8; 3 * 2048];
+        let mut decapsulated = vec![];
+        their_tun.decapsulate_batch(None, &datagrams, &mut their_dst, 2048, &mut decapsulated);
+        assert_eq!(decapsulated.len(), sent_packets.len());
+        for (result, sent) in decapsulated.into_iter().zip(&sent_packets) {
+            match result {
+                TunnResult::WriteToTunnelV4(recv, _) => assert_eq!(recv, &sent[..]),
+                _ => unreachable!(),
+            }
+        }
+
+        // Replayed packets are rejected individually
+        let mut decapsulated = vec![];
+        their_tun.decapsulate_batch(
+            None,
+            &datagrams[1..],
+            &mut their_dst,
+            2048,
+            &mut decapsulated,
+        );
+        assert!(decapsulated
+            .iter()
+            .all(|result| matches!(result, TunnResult::Err(WireGuardError::DuplicateCounter))));
+    }
+
+    #[test]
+    fn batch_before_handshake() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let sent_packets = [create_ipv4_udp_packet(), create_ipv4_udp_packet()];
+
+        // Without a session only a single handshake is initiated, and all packets are queued
+        let mut my_dst = vec![0u8; 2 * 2048];
+        let mut results = vec![];
+        my_tun.encapsulate_batch(&sent_packets, &mut my_dst, 2048, &mut results);
+        let init = match &results[..] {
+            [TunnResult::WriteToNetwork(init), TunnResult::Done] => init.to_vec(),
+            _ => unreachable!(),
+        };
+
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+
+        let mut their_dst = [0u8; 2048];
+        for sent in &sent_packets {
+            let data = match my_tun.decapsulate(None, &[], &mut my_dst) {
+                TunnResult::WriteToNetwork(data) => data.to_vec(),
+                _ => unreachable!(),
+            };
+            match their_tun.decapsulate(None, &data, &mut their_dst) {
+                TunnResult::WriteToTunnelV4(recv, _) => assert_eq!(recv, &sent[..]),
+                _ => unreachable!(),
+            }
+        }
+        assert!(matches!(
+            my_tun.decapsulate(None, &[], &mut my_dst),
+            TunnResult::Done
+        ));
+    }
+
+    #[test]
+    fn batch_reaching_reject_after_messages() {
+        let (mut my_tun, _their_tun) = create_two_tuns_and_handshake();
+        let session = my_tun.sessions[my_tun.current % N_SESSIONS]
+            .as_ref()
+            .unwrap();
+        session.set_sending_counter(REJECT_AFTER_MESSAGES - 2);
+
+        // Only the packets that fit under the limit are sent, the rest wait for a new session
+        let sent_packets = [
+            create_ipv4_udp_packet(),
+            create_ipv4_udp_packet(),
+            create_ipv4_udp_packet(),
+        ];
+        let mut my_dst = vec![0u8; 3 * 2048];
+        let mut results = vec![];
+        my_tun.encapsulate_batch(&sent_packets, &mut my_dst, 2048, &mut results);
+        let init = match &results[..] {
+            [TunnResult::WriteToNetwork(_), TunnResult::WriteToNetwork(_), TunnResult::WriteToNetwork(init)] => {
+                init
+            }
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            Tunn::parse_incoming_packet(init),
+            Ok(Packet::HandshakeInit(_))
+        ));
+        assert_eq!(my_tun.packet_queue.len(), 1);
+    }
+
     #[test]
     fn one_ip_packet() {
         let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
@@ -791,4 +1664,131 @@ mod tests {
         };
         assert_eq!(sent_packet_buf, recv_packet_buf);
     }
+
+    #[test]
+    fn stats_count_handshakes_and_drops() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let mut my_dst = [0u8; 1024];
+        let mut their_dst = [0u8; 1024];
+
+        // Packets wait in the queue until a session is established
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let init = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(init) => init.to_vec(),
+            _ => unreachable!(),
+        };
+        let stats = my_tun.stats();
+        assert_eq!(stats.queued_packets, 1);
+        assert_eq!(stats.handshakes_initiated, 1);
+        assert_eq!(stats.session_index, None);
+
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+
+        let stats = my_tun.stats();
+        assert_eq!(stats.handshakes_initiated, 1);
+        assert_eq!(stats.handshakes_completed, 1);
+        assert!(stats.session_index.is_some());
+        assert!(stats.session_age.is_some());
+        assert_eq!(their_tun.stats().handshakes_completed, 1);
+
+        // A replayed data packet is dropped and counted
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data.to_vec(),
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::WriteToTunnelV4(..)
+        ));
+        assert!(matches!(
+            their_tun.decapsulate(None, &data, &mut their_dst),
+            TunnResult::Err(WireGuardError::DuplicateCounter)
+        ));
+        let stats = their_tun.stats();
+        assert_eq!(stats.replayed_packets, 1);
+        assert_eq!(stats.dropped(WireGuardError::DuplicateCounter), 1);
+        assert_eq!(stats.total_dropped(), 1);
+    }
+
+    #[test]
+    fn handshake_emits_events() {
+        let (mut my_tun, mut their_tun) = create_two_tuns();
+        let events = Arc::new(Mutex::new(vec![]));
+        for (tun, side) in [(&mut my_tun, "mine"), (&mut their_tun, "theirs")] {
+            let events = Arc::clone(&events);
+            tun.set_event_handler(Some(Box::new(move |e| events.lock().push((side, e)))));
+        }
+
+        let init = create_handshake_init(&mut my_tun);
+        let resp = create_handshake_response(&mut their_tun, &init);
+        let keepalive = parse_handshake_resp(&mut my_tun, &resp);
+        parse_keepalive(&mut their_tun, &keepalive);
+
+        assert_eq!(
+            *events.lock(),
+            [
+                ("theirs", TunnEvent::HandshakeCompleted),
+                ("mine", TunnEvent::HandshakeCompleted)
+            ]
+        );
+    }
+
+    #[test]
+    fn padded_packets_and_decoys() {
+        let (mut my_tun, mut their_tun) = create_two_tuns_and_handshake();
+        my_tun.set_traffic_shaping(Some(shaping::TrafficShaping {
+            buckets: vec![256],
+            mtu: 1420,
+            decoys: Some(shaping::DecoySchedule {
+                interval: Duration::ZERO,
+                jitter: Duration::ZERO,
+                size: 0,
+            }),
+        }));
+        let mut my_dst = [0u8; 2048];
+        let mut their_dst = [0u8; 2048];
+
+        // Data packets are padded to the bucket, and arrive as they were sent
+        let sent_packet_buf = create_ipv4_udp_packet();
+        let data = match my_tun.encapsulate(&sent_packet_buf, &mut my_dst) {
+            TunnResult::WriteToNetwork(data) => data,
+            _ => unreachable!(),
+        };
+        assert_eq!(data.len(), 256 + DATA_OVERHEAD_SZ);
+        match their_tun.decapsulate(None, data, &mut their_dst) {
+            TunnResult::WriteToTunnelV4(recv, _) => assert_eq!(recv, &sent_packet_buf[..]),
+            _ => unreachable!(),
+        }
+
+        // The first timer update schedules a decoy, the next sends it
+        assert!(matches!(
+            my_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+        let decoy = match my_tun.update_timers(&mut my_dst) {
+            TunnResult::WriteToNetwork(decoy) => decoy,
+            _ => unreachable!(),
+        };
+        assert_eq!(decoy.len(), 256 + DATA_OVERHEAD_SZ);
+        let rx_bytes = their_tun.stats().rx_bytes;
+        assert!(matches!(
+            their_tun.decapsulate(None, decoy, &mut their_dst),
+            TunnResult::Done
+        ));
+        assert_eq!(their_tun.stats().rx_bytes, rx_bytes);
+
+        // Decoys never start a handshake
+        let (mut idle_tun, _) = create_two_tuns();
+        idle_tun.set_traffic_shaping(my_tun.traffic_shaping().cloned());
+        assert!(matches!(
+            idle_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+        assert!(matches!(
+            idle_tun.update_timers(&mut my_dst),
+            TunnResult::Done
+        ));
+    }
 }
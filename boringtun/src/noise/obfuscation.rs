@@ -0,0 +1,269 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Optional obfuscation of the WireGuard message format, against censors that recognise it by
+//! its fixed message types and sizes.
+//!
+//! Obfuscation wraps messages on their way to and from the network and leaves the protocol
+//! itself untouched. The message type field is replaced by a configured magic header, handshake
+//! messages are preceded by random padding, and each handshake initiation is preceded by random
+//! junk datagrams. Both ends need the same configuration, and an all-default configuration keeps
+//! the standard wire format.
+
+use super::{COOKIE_REPLY, COOKIE_REPLY_SZ, DATA, DATA_OVERHEAD_SZ, HANDSHAKE_INIT};
+use super::{HANDSHAKE_INIT_SZ, HANDSHAKE_RESP, HANDSHAKE_RESP_SZ};
+use rand_core::{OsRng, RngCore};
+use std::convert::TryInto;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+const MAX_JUNK_PACKETS: usize = 128;
+const MAX_JUNK_SIZE: usize = 1280;
+const MAX_PADDING: usize = 1024;
+
+/// How messages are disguised. Its text form is a comma separated list of `key=value` pairs,
+/// `jc`, `jmin` and `jmax` for the junk datagrams, `s1` and `s2` for the handshake padding and
+/// `h1` to `h4` for the headers. Omitted keys keep their default value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Obfuscation {
+    /// The number of junk datagrams sent ahead of each handshake initiation
+    pub junk_packets: usize,
+    pub junk_min_size: usize,
+    pub junk_max_size: usize,
+    /// The number of random bytes in front of handshake initiations
+    pub init_padding: usize,
+    /// The number of random bytes in front of handshake responses
+    pub response_padding: usize,
+    /// The values replacing the message type of handshake initiations, handshake responses,
+    /// cookie replies and data packets
+    pub headers: [u32; 4],
+}
+
+impl Default for Obfuscation {
+    fn default() -> Self {
+        Obfuscation {
+            junk_packets: 0,
+            junk_min_size: 0,
+            junk_max_size: 0,
+            init_padding: 0,
+            response_padding: 0,
+            headers: [HANDSHAKE_INIT, HANDSHAKE_RESP, COOKIE_REPLY, DATA],
+        }
+    }
+}
+
+impl Obfuscation {
+    /// Check that received datagrams can be told apart, and that sizes are within limits
+    pub fn validate(&self) -> Result<(), String> {
+        if self.junk_packets > MAX_JUNK_PACKETS {
+            return Err(format!("At most {} junk packets", MAX_JUNK_PACKETS));
+        }
+        if self.junk_min_size > self.junk_max_size || self.junk_max_size > MAX_JUNK_SIZE {
+            return Err(format!(
+                "Junk packet sizes must be ordered, and at most {}",
+                MAX_JUNK_SIZE
+            ));
+        }
+        if self.init_padding > MAX_PADDING || self.response_padding > MAX_PADDING {
+            return Err(format!("Handshake padding must be at most {}", MAX_PADDING));
+        }
+        if self.init_padding + HANDSHAKE_INIT_SZ == self.response_padding + HANDSHAKE_RESP_SZ {
+            return Err("Padded handshake messages must differ in size".to_owned());
+        }
+        for (i, header) in self.headers.iter().enumerate() {
+            if self.headers[i + 1..].contains(header) {
+                return Err("Headers must be distinct".to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    /// Append the datagrams that carry `message` to `buf`, and their ranges in `buf` to
+    /// `datagrams`. Handshake initiations are preceded by junk datagrams.
+    pub fn obfuscate(&self, message: &[u8], buf: &mut Vec<u8>, datagrams: &mut Vec<Range<usize>>) {
+        let message_type = match message.get(..4) {
+            Some(header) => u32::from_le_bytes(header.try_into().unwrap()),
+            None => 0,
+        };
+        let (header, padding) = match message_type {
+            HANDSHAKE_INIT => {
+                for _ in 0..self.junk_packets {
+                    let size = self.junk_min_size
+                        + (OsRng.next_u32() as usize)
+                            % (self.junk_max_size - self.junk_min_size + 1);
+                    let start = buf.len();
+                    buf.resize(start + size, 0);
+                    OsRng.fill_bytes(&mut buf[start..]);
+                    datagrams.push(start..buf.len());
+                }
+                (self.headers[0], self.init_padding)
+            }
+            HANDSHAKE_RESP => (self.headers[1], self.response_padding),
+            COOKIE_REPLY => (self.headers[2], 0),
+            DATA => (self.headers[3], 0),
+            // Not a WireGuard message, leave it alone
+            _ => {
+                let start = buf.len();
+                buf.extend_from_slice(message);
+                datagrams.push(start..buf.len());
+                return;
+            }
+        };
+
+        let start = buf.len();
+        buf.resize(start + padding, 0);
+        OsRng.fill_bytes(&mut buf[start..]);
+        buf.extend_from_slice(&header.to_le_bytes());
+        buf.extend_from_slice(&message[4..]);
+        datagrams.push(start..buf.len());
+    }
+
+    /// Restore the message carried by a received datagram in place, and return its range in
+    /// `datagram`. Returns None for junk, and for anything else that is not a disguised message.
+    pub fn deobfuscate(&self, datagram: &mut [u8]) -> Option<Range<usize>> {
+        let len = datagram.len();
+        let header_at = |offset: usize| {
+            datagram
+                .get(offset..offset + 4)
+                .map(|h| u32::from_le_bytes(h.try_into().unwrap()))
+        };
+
+        let (offset, message_type) = if len == self.init_padding + HANDSHAKE_INIT_SZ
+            && header_at(self.init_padding) == Some(self.headers[0])
+        {
+            (self.init_padding, HANDSHAKE_INIT)
+        } else if len == self.response_padding + HANDSHAKE_RESP_SZ
+            && header_at(self.response_padding) == Some(self.headers[1])
+        {
+            (self.response_padding, HANDSHAKE_RESP)
+        } else if len == COOKIE_REPLY_SZ && header_at(0) == Some(self.headers[2]) {
+            (0, COOKIE_REPLY)
+        } else if len >= DATA_OVERHEAD_SZ && header_at(0) == Some(self.headers[3]) {
+            (0, DATA)
+        } else {
+            return None;
+        };
+
+        datagram[offset..offset + 4].copy_from_slice(&message_type.to_le_bytes());
+        Some(offset..len)
+    }
+}
+
+impl FromStr for Obfuscation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut obfuscation = Obfuscation::default();
+        for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got {}", pair))?;
+            let invalid = |_| format!("Invalid value for {}: {}", key, value);
+            match key.trim() {
+                "jc" => obfuscation.junk_packets = value.trim().parse().map_err(invalid)?,
+                "jmin" => obfuscation.junk_min_size = value.trim().parse().map_err(invalid)?,
+                "jmax" => obfuscation.junk_max_size = value.trim().parse().map_err(invalid)?,
+                "s1" => obfuscation.init_padding = value.trim().parse().map_err(invalid)?,
+                "s2" => obfuscation.response_padding = value.trim().parse().map_err(invalid)?,
+                "h1" => obfuscation.headers[0] = value.trim().parse().map_err(invalid)?,
+                "h2" => obfuscation.headers[1] = value.trim().parse().map_err(invalid)?,
+                "h3" => obfuscation.headers[2] = value.trim().parse().map_err(invalid)?,
+                "h4" => obfuscation.headers[3] = value.trim().parse().map_err(invalid)?,
+                key => return Err(format!("Unknown obfuscation parameter {}", key)),
+            }
+        }
+        obfuscation.validate()?;
+        Ok(obfuscation)
+    }
+}
+
+impl fmt::Display for Obfuscation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [h1, h2, h3, h4] = self.headers;
+        write!(
+            f,
+            "jc={},jmin={},jmax={},s1={},s2={},h1={},h2={},h3={},h4={}",
+            self.junk_packets,
+            self.junk_min_size,
+            self.junk_max_size,
+            self.init_padding,
+            self.response_padding,
+            h1,
+            h2,
+            h3,
+            h4
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(message_type: u32, len: usize) -> Vec<u8> {
+        let mut message = vec![0xAB; len];
+        message[..4].copy_from_slice(&message_type.to_le_bytes());
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let obfuscation: Obfuscation =
+            "jc=3,jmin=10,jmax=50,s1=15,s2=40,h1=1111,h2=2222,h3=3333,h4=4444"
+                .parse()
+                .unwrap();
+        assert_eq!(obfuscation.to_string().parse(), Ok(obfuscation));
+
+        for (message_type, len, junk, sent_len) in [
+            (HANDSHAKE_INIT, HANDSHAKE_INIT_SZ, 3, HANDSHAKE_INIT_SZ + 15),
+            (HANDSHAKE_RESP, HANDSHAKE_RESP_SZ, 0, HANDSHAKE_RESP_SZ + 40),
+            (COOKIE_REPLY, COOKIE_REPLY_SZ, 0, COOKIE_REPLY_SZ),
+            (DATA, 1000, 0, 1000),
+        ] {
+            let sent = message(message_type, len);
+            let (mut buf, mut datagrams) = (vec![], vec![]);
+            obfuscation.obfuscate(&sent, &mut buf, &mut datagrams);
+            assert_eq!(datagrams.len(), junk + 1);
+
+            let mut received = vec![];
+            for range in datagrams {
+                let datagram = &mut buf[range];
+                let disguised =
+                    datagram.len() == sent_len && datagram[sent_len - len..][..4] != sent[..4];
+                if let Some(message) = obfuscation.deobfuscate(datagram) {
+                    assert!(disguised);
+                    received.push(datagram[message].to_vec());
+                } else {
+                    assert!((10..=50).contains(&datagram.len()));
+                }
+            }
+            assert_eq!(received, [sent]);
+        }
+
+        // Standard messages are not recognised
+        assert_eq!(obfuscation.deobfuscate(&mut message(DATA, 100)), None);
+    }
+
+    #[test]
+    fn default_is_standard_wire_format() {
+        let obfuscation: Obfuscation = "".parse().unwrap();
+        let sent = message(HANDSHAKE_INIT, HANDSHAKE_INIT_SZ);
+        let (mut buf, mut datagrams) = (vec![], vec![]);
+        obfuscation.obfuscate(&sent, &mut buf, &mut datagrams);
+        assert_eq!(buf, sent);
+        assert_eq!(
+            obfuscation.deobfuscate(&mut buf),
+            Some(0..HANDSHAKE_INIT_SZ)
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_config() {
+        assert!("h1=5,h2=5".parse::<Obfuscation>().is_err());
+        assert!("s1=0,s2=56".parse::<Obfuscation>().is_err());
+        assert!("jmin=100,jmax=50".parse::<Obfuscation>().is_err());
+        assert!("jc=1,jmax=70000".parse::<Obfuscation>().is_err());
+        assert!("x=1".parse::<Obfuscation>().is_err());
+    }
+}
@@ -4,8 +4,11 @@ use crate::noise::{HandshakeInit, HandshakeResponse, Packet, Tunn, TunnResult, W
 
 #[cfg(feature = "mock-instant")]
 use mock_instant::Instant;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
 use std::net::IpAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
 #[cfg(not(feature = "mock-instant"))]
 use crate::sleepyinstant::Instant;
@@ -24,11 +27,16 @@ const COOKIE_NONCE_SIZE: usize = 24;
 /// How often should reset count in seconds
 const RESET_PERIOD: u64 = 1;
 
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// Marks the ends of the list of sources
+const NIL: usize = usize::MAX;
+
 type Cookie = [u8; COOKIE_SIZE];
 
 /// There are two places where WireGuard requires "randomness" for cookies
 /// * The 24 byte nonce in the cookie massage - here the only goal is to avoid nonce reuse
 /// * A secret value that changes every two minutes
+///
 /// Because the main goal of the cookie is simply for a party to prove ownership of an IP address
 /// we can relax the randomness definition a bit, in order to avoid locking, because using less
 /// resources is the main goal of any DoS prevention mechanism.
@@ -44,11 +52,222 @@ pub struct RateLimiter {
     nonce_ctr: AtomicU64,
     mac1_key: [u8; 32],
     cookie_key: Key,
-    limit: u64,
+    /// Handshake messages per second above which cookies are asked for
+    limit: AtomicU64,
     /// The counter since last reset
     count: AtomicU64,
     /// The time last reset was performed on this rate limiter
     last_reset: Mutex<Instant>,
+    /// Buckets by source address, None to only apply the global limit
+    sources: Option<Mutex<SourceBuckets>>,
+    handshakes: AtomicU64,
+    under_load: AtomicU64,
+    cookie_replies: AtomicU64,
+    invalid_mac: AtomicU64,
+    invalid_mac2: AtomicU64,
+    rate_limited: AtomicU64,
+}
+
+/// Totals since the rate limiter was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimiterStats {
+    /// Handshake messages with a valid mac1
+    pub handshakes: u64,
+    /// Handshake messages that arrived while over the limit
+    pub under_load: u64,
+    /// Cookie replies sent to senders over the limit without a valid mac2
+    pub cookie_replies: u64,
+    /// Handshake messages dropped for an invalid mac1
+    pub invalid_mac: u64,
+    /// Handshake messages with a mac2 that is not the cookie of their sender
+    pub invalid_mac2: u64,
+    /// Handshake messages dropped under load because their source was over its rate
+    pub rate_limited: u64,
+}
+
+/// How many handshake messages a single source address, or /64 for IPv6, may send while the
+/// rate limiter is under load. As in the kernel implementation, messages with a valid cookie
+/// beyond that are dropped. The cookie replies sent to a source are limited the same way on
+/// their own, so that spoofed sources can neither use up the rate of the address owner nor
+/// have replies reflected at it without bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLimit {
+    /// Messages per second
+    pub rate: u64,
+    /// Messages a source may send at once after a quiet period
+    pub burst: u64,
+    /// The number of sources remembered, the least recently seen is forgotten beyond that
+    pub max_sources: usize,
+}
+
+impl Default for SourceLimit {
+    fn default() -> Self {
+        SourceLimit {
+            rate: 20,
+            burst: 5,
+            max_sources: 8192,
+        }
+    }
+}
+
+impl SourceLimit {
+    /// The tokens, in nanoseconds, a message costs
+    fn cost(&self) -> u64 {
+        NANOS_PER_SEC / self.rate.max(1)
+    }
+
+    fn max_tokens(&self) -> u64 {
+        self.cost().saturating_mul(self.burst.max(1))
+    }
+}
+
+/// A token bucket, the tokens being nanoseconds
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: u64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &SourceLimit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.max_tokens(),
+            last: now,
+        }
+    }
+
+    /// Take the tokens of one message, false if there are not enough
+    fn take(&mut self, limit: &SourceLimit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_nanos();
+        self.last = now;
+        self.tokens = u64::try_from(elapsed)
+            .unwrap_or(u64::MAX)
+            .saturating_add(self.tokens)
+            .min(limit.max_tokens());
+        match self.tokens.checked_sub(limit.cost()) {
+            Some(tokens) => {
+                self.tokens = tokens;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The address a bucket is kept for, IPv6 sources share one per /64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SourceKey {
+    V4([u8; 4]),
+    V6([u8; 8]),
+}
+
+impl From<IpAddr> for SourceKey {
+    fn from(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(a) => SourceKey::V4(a.octets()),
+            IpAddr::V6(a) => match a.to_ipv4_mapped() {
+                Some(a) => SourceKey::V4(a.octets()),
+                None => SourceKey::V6(a.octets()[..8].try_into().unwrap()),
+            },
+        }
+    }
+}
+
+struct Source {
+    key: SourceKey,
+    /// For the cookie replies to messages without a valid mac2
+    plain: Bucket,
+    /// For messages with a valid mac2, so that senders spoofing the address cannot use it up
+    cookie: Bucket,
+    /// The next more and less recently seen sources
+    prev: usize,
+    next: usize,
+}
+
+/// The buckets of up to `limit.max_sources` sources, in a list from the most to the least
+/// recently seen
+struct SourceBuckets {
+    limit: SourceLimit,
+    index: HashMap<SourceKey, usize>,
+    sources: Vec<Source>,
+    head: usize,
+    tail: usize,
+}
+
+impl SourceBuckets {
+    fn new(limit: SourceLimit) -> SourceBuckets {
+        SourceBuckets {
+            limit,
+            index: HashMap::new(),
+            sources: Vec::new(),
+            head: NIL,
+            tail: NIL,
+        }
+    }
+
+    /// Take the tokens of one message from `addr`, false if it is over its rate
+    fn take(&mut self, addr: IpAddr, valid_mac2: bool) -> bool {
+        let (limit, now) = (self.limit, Instant::now());
+        let i = self.touch(SourceKey::from(addr), now);
+        let source = &mut self.sources[i];
+        match valid_mac2 {
+            true => source.cookie.take(&limit, now),
+            false => source.plain.take(&limit, now),
+        }
+    }
+
+    /// The index of the source with `key`, made the most recently seen. A new source takes the
+    /// place of the least recently seen once full.
+    fn touch(&mut self, key: SourceKey, now: Instant) -> usize {
+        if let Some(&i) = self.index.get(&key) {
+            self.unlink(i);
+            self.push_front(i);
+            return i;
+        }
+
+        let source = Source {
+            key,
+            plain: Bucket::full(&self.limit, now),
+            cookie: Bucket::full(&self.limit, now),
+            prev: NIL,
+            next: NIL,
+        };
+        let i = if self.sources.len() < self.limit.max_sources.max(1) {
+            self.sources.push(source);
+            self.sources.len() - 1
+        } else {
+            let i = self.tail;
+            self.unlink(i);
+            self.index.remove(&self.sources[i].key);
+            self.sources[i] = source;
+            i
+        };
+        self.index.insert(key, i);
+        self.push_front(i);
+        i
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.sources[i].prev, self.sources[i].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.sources[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.sources[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.sources[i].prev = NIL;
+        self.sources[i].next = self.head;
+        match self.head {
+            NIL => self.tail = i,
+            head => self.sources[head].prev = i,
+        }
+        self.head = i;
+    }
 }
 
 impl RateLimiter {
@@ -62,12 +281,46 @@ impl RateLimiter {
             nonce_ctr: AtomicU64::new(0),
             mac1_key: b2s_hash(LABEL_MAC1, public_key.as_bytes()),
             cookie_key: b2s_hash(LABEL_COOKIE, public_key.as_bytes()).into(),
-            limit,
+            limit: AtomicU64::new(limit),
             count: AtomicU64::new(0),
             last_reset: Mutex::new(Instant::now()),
+            handshakes: AtomicU64::new(0),
+            under_load: AtomicU64::new(0),
+            cookie_replies: AtomicU64::new(0),
+            invalid_mac: AtomicU64::new(0),
+            invalid_mac2: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            sources: None,
         }
     }
 
+    /// Limit each source address on its own while under load, with `limit`, or only apply the
+    /// global limit with None, the default
+    pub fn with_source_limit(mut self, limit: Option<SourceLimit>) -> Self {
+        self.sources = limit.map(|limit| Mutex::new(SourceBuckets::new(limit)));
+        self
+    }
+
+    /// Change the number of handshake messages per second above which cookies are asked for
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    /// Handshake messages counted since the last reset, about the current rate per second
+    pub fn handshake_rate(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Whether handshake messages without a valid mac2 currently get a cookie reply instead of
+    /// being processed
+    pub fn cookie_mode(&self) -> bool {
+        self.handshake_rate() >= self.limit()
+    }
+
     fn rand_bytes() -> [u8; 32] {
         let mut key = [0u8; 32];
         OsRng.fill_bytes(&mut key);
@@ -85,6 +338,26 @@ impl RateLimiter {
         }
     }
 
+    /// The time until the count is due to be reset, None if there is nothing to reset
+    pub(crate) fn time_to_reset(&self) -> Option<Duration> {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let since_reset = self.last_reset.lock().elapsed();
+        Some(Duration::from_secs(RESET_PERIOD).saturating_sub(since_reset))
+    }
+
+    pub fn stats(&self) -> RateLimiterStats {
+        RateLimiterStats {
+            handshakes: self.handshakes.load(Ordering::Relaxed),
+            under_load: self.under_load.load(Ordering::Relaxed),
+            cookie_replies: self.cookie_replies.load(Ordering::Relaxed),
+            invalid_mac: self.invalid_mac.load(Ordering::Relaxed),
+            invalid_mac2: self.invalid_mac2.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+        }
+    }
+
     /// Compute the correct cookie value based on the current secret value and the source IP
     fn current_cookie(&self, addr: IpAddr) -> Cookie {
         let mut addr_bytes = [0u8; 16];
@@ -109,7 +382,7 @@ impl RateLimiter {
     }
 
     fn is_under_load(&self) -> bool {
-        self.count.fetch_add(1, Ordering::SeqCst) >= self.limit
+        self.count.fetch_add(1, Ordering::SeqCst) >= self.limit()
     }
 
     pub(crate) fn format_cookie_reply<'a>(
@@ -156,6 +429,18 @@ impl RateLimiter {
         src: &'a [u8],
         dst: &'b mut [u8],
     ) -> Result<Packet<'a>, TunnResult<'b>> {
+        self.verify_packet_cookie(src_addr, src, dst)
+            .map(|(packet, _)| packet)
+    }
+
+    /// Like `verify_packet`, also telling whether a handshake message carries a valid mac2, which
+    /// proves its sender got a cookie for the address it sends from. Only checked under load
+    pub fn verify_packet_cookie<'a, 'b>(
+        &self,
+        src_addr: Option<IpAddr>,
+        src: &'a [u8],
+        dst: &'b mut [u8],
+    ) -> Result<(Packet<'a>, bool), TunnResult<'b>> {
         let packet = Tunn::parse_incoming_packet(src)?;
 
         // Verify and rate limit handshake messages only
@@ -166,28 +451,272 @@ impl RateLimiter {
             let (mac1, mac2) = macs.split_at(16);
 
             let computed_mac1 = b2s_keyed_mac_16(&self.mac1_key, msg);
-            verify_slices_are_equal(&computed_mac1[..16], mac1)
-                .map_err(|_| TunnResult::Err(WireGuardError::InvalidMac))?;
+            verify_slices_are_equal(&computed_mac1[..16], mac1).map_err(|_| {
+                self.invalid_mac.fetch_add(1, Ordering::Relaxed);
+                TunnResult::Err(WireGuardError::InvalidMac)
+            })?;
+            self.handshakes.fetch_add(1, Ordering::Relaxed);
+
+            if !self.is_under_load() {
+                return Ok((packet, false));
+            }
+            self.under_load.fetch_add(1, Ordering::Relaxed);
+
+            // Only given an address can we validate mac2, which is zero until the sender got a
+            // cookie
+            let valid_mac2 = match src_addr {
+                Some(addr) if mac2 != [0u8; COOKIE_SIZE] => {
+                    let computed_mac2 = b2s_keyed_mac_16_2(&self.current_cookie(addr), msg, mac1);
+                    let valid = verify_slices_are_equal(&computed_mac2[..16], mac2).is_ok();
+                    if !valid {
+                        self.invalid_mac2.fetch_add(1, Ordering::Relaxed);
+                    }
+                    valid
+                }
+                _ => false,
+            };
+
+            // Under load, sources are held to their own rate as well
+            let over_rate = match (src_addr, &self.sources) {
+                (Some(addr), Some(sources)) => !sources.lock().take(addr, valid_mac2),
+                _ => false,
+            };
+            if over_rate {
+                self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                return Err(TunnResult::Err(WireGuardError::UnderLoad));
+            }
 
-            if self.is_under_load() {
+            if !valid_mac2 {
                 let addr = match src_addr {
                     None => return Err(TunnResult::Err(WireGuardError::UnderLoad)),
                     Some(addr) => addr,
                 };
+                let cookie_packet = self
+                    .format_cookie_reply(sender_idx, self.current_cookie(addr), mac1, dst)
+                    .map_err(TunnResult::Err)?;
+                self.cookie_replies.fetch_add(1, Ordering::Relaxed);
+                return Err(TunnResult::WriteToNetwork(cookie_packet));
+            }
 
-                // Only given an address can we validate mac2
-                let cookie = self.current_cookie(addr);
-                let computed_mac2 = b2s_keyed_mac_16_2(&cookie, msg, mac1);
+            return Ok((packet, valid_mac2));
+        }
 
-                if verify_slices_are_equal(&computed_mac2[..16], mac2).is_err() {
-                    let cookie_packet = self
-                        .format_cookie_reply(sender_idx, cookie, mac1, dst)
-                        .map_err(TunnResult::Err)?;
-                    return Err(TunnResult::WriteToNetwork(cookie_packet));
-                }
+        Ok((packet, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::HANDSHAKE_INIT_SZ;
+    use crate::x25519;
+    #[cfg(feature = "mock-instant")]
+    use mock_instant::MockClock;
+
+    const LIMIT: SourceLimit = SourceLimit {
+        rate: 10,
+        burst: 3,
+        max_sources: 2,
+    };
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Verdict {
+        Pass,
+        Cookie,
+        Drop,
+    }
+
+    fn rate_limiter(limit: u64, source_limit: SourceLimit) -> RateLimiter {
+        let public_key = x25519::PublicKey::from(&x25519::StaticSecret::random_from_rng(OsRng));
+        RateLimiter::new(&public_key, limit).with_source_limit(Some(source_limit))
+    }
+
+    /// A handshake initiation with a valid mac1, and a valid mac2 if it has a cookie for
+    /// `cookie_addr`
+    fn initiation(limiter: &RateLimiter, cookie_addr: Option<IpAddr>) -> Vec<u8> {
+        let mut packet = vec![0u8; HANDSHAKE_INIT_SZ];
+        packet[0] = 1;
+        let mac1_off = HANDSHAKE_INIT_SZ - 32;
+        let mac1 = b2s_keyed_mac_16(&limiter.mac1_key, &packet[..mac1_off]);
+        packet[mac1_off..mac1_off + 16].copy_from_slice(&mac1);
+        if let Some(addr) = cookie_addr {
+            let cookie = limiter.current_cookie(addr);
+            let mac2 = b2s_keyed_mac_16_2(&cookie, &packet[..mac1_off], &mac1);
+            packet[mac1_off + 16..].copy_from_slice(&mac2);
+        }
+        packet
+    }
+
+    fn verify(limiter: &RateLimiter, addr: Option<IpAddr>, packet: &[u8]) -> Verdict {
+        let mut dst = [0u8; 64];
+        match limiter.verify_packet(addr, packet, &mut dst) {
+            Ok(_) => Verdict::Pass,
+            Err(TunnResult::WriteToNetwork(_)) => Verdict::Cookie,
+            Err(_) => Verdict::Drop,
+        }
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn sources_are_not_limited_when_idle() {
+        let limiter = rate_limiter(u64::MAX, LIMIT);
+        let packet = initiation(&limiter, None);
+
+        for _ in 0..10 {
+            assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Pass);
+        }
+        assert_eq!(limiter.stats().rate_limited, 0);
+    }
+
+    #[test]
+    fn mac2_is_not_checked_when_idle() {
+        let limiter = rate_limiter(u64::MAX, LIMIT);
+        // A cookie for another address is only rejected under load
+        let with_cookie = initiation(&limiter, ip("192.0.2.1"));
+
+        assert_eq!(
+            verify(&limiter, ip("192.0.2.2"), &with_cookie),
+            Verdict::Pass
+        );
+        assert_eq!(limiter.stats().invalid_mac2, 0);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn cookie_replies_are_limited_per_source() {
+        // With a limit of zero the rate limiter is always under load
+        let limiter = rate_limiter(0, LIMIT);
+        let packet = initiation(&limiter, None);
+
+        for _ in 0..3 {
+            assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Cookie);
+        }
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Drop);
+        // Other sources are not affected
+        assert_eq!(verify(&limiter, ip("192.0.2.2"), &packet), Verdict::Cookie);
+
+        // The source gets a reply every 1/rate seconds
+        MockClock::advance(Duration::from_millis(100));
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Cookie);
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Drop);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn valid_cookies_have_a_rate_of_their_own() {
+        let limiter = rate_limiter(0, LIMIT);
+        let (packet, with_cookie) = (
+            initiation(&limiter, None),
+            initiation(&limiter, ip("192.0.2.1")),
+        );
+
+        for _ in 0..3 {
+            assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Cookie);
+        }
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Drop);
+
+        // Those spoofing the address do not use up the rate of its actual owner
+        for _ in 0..3 {
+            assert_eq!(
+                verify(&limiter, ip("192.0.2.1"), &with_cookie),
+                Verdict::Pass
+            );
+        }
+        assert_eq!(
+            verify(&limiter, ip("192.0.2.1"), &with_cookie),
+            Verdict::Drop
+        );
+
+        let stats = limiter.stats();
+        assert_eq!((stats.cookie_replies, stats.rate_limited), (3, 2));
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn least_recently_seen_source_is_forgotten() {
+        let limiter = rate_limiter(0, LIMIT);
+        let packet = initiation(&limiter, None);
+        for source in ["192.0.2.1", "192.0.2.2"] {
+            for _ in 0..3 {
+                assert_eq!(verify(&limiter, ip(source), &packet), Verdict::Cookie);
             }
         }
 
-        Ok(packet)
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Drop);
+        // Takes the place of 192.0.2.2, the least recently seen
+        assert_eq!(verify(&limiter, ip("192.0.2.3"), &packet), Verdict::Cookie);
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Drop);
+        assert_eq!(verify(&limiter, ip("192.0.2.2"), &packet), Verdict::Cookie);
+    }
+
+    #[test]
+    #[cfg(feature = "mock-instant")]
+    fn ipv6_sources_share_a_bucket_per_64() {
+        let limiter = rate_limiter(0, LIMIT);
+        let packet = initiation(&limiter, None);
+
+        for source in ["2001:db8::1", "2001:db8::2", "2001:db8::3"] {
+            assert_eq!(verify(&limiter, ip(source), &packet), Verdict::Cookie);
+        }
+        assert_eq!(verify(&limiter, ip("2001:db8::4"), &packet), Verdict::Drop);
+        assert_eq!(
+            verify(&limiter, ip("2001:db8:0:1::1"), &packet),
+            Verdict::Cookie
+        );
+
+        // IPv4 sources are the same over IPv6
+        for _ in 0..3 {
+            assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Cookie);
+        }
+        assert_eq!(
+            verify(&limiter, ip("::ffff:192.0.2.1"), &packet),
+            Verdict::Drop
+        );
+    }
+
+    #[test]
+    fn global_limit_applies_to_all_sources() {
+        let limiter = rate_limiter(2, SourceLimit::default());
+        let packet = initiation(&limiter, None);
+
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Pass);
+        assert_eq!(verify(&limiter, ip("192.0.2.2"), &packet), Verdict::Pass);
+        assert_eq!(verify(&limiter, ip("192.0.2.3"), &packet), Verdict::Cookie);
+        let with_cookie = initiation(&limiter, ip("192.0.2.3"));
+        assert_eq!(
+            verify(&limiter, ip("192.0.2.3"), &with_cookie),
+            Verdict::Pass
+        );
+        // Without an address there is no cookie to ask for
+        assert_eq!(verify(&limiter, None, &packet), Verdict::Drop);
+        assert_eq!(limiter.stats().under_load, 3);
+    }
+
+    #[test]
+    fn limit_changes_at_runtime() {
+        let limiter = rate_limiter(10, SourceLimit::default());
+        let packet = initiation(&limiter, None);
+
+        assert_eq!(verify(&limiter, ip("192.0.2.1"), &packet), Verdict::Pass);
+        assert_eq!(verify(&limiter, ip("192.0.2.2"), &packet), Verdict::Pass);
+        assert!(!limiter.cookie_mode());
+
+        limiter.set_limit(2);
+        assert_eq!(limiter.limit(), 2);
+        assert_eq!(limiter.handshake_rate(), 2);
+        assert!(limiter.cookie_mode());
+        assert_eq!(verify(&limiter, ip("192.0.2.3"), &packet), Verdict::Cookie);
+
+        // A cookie for another address is no cookie at all
+        let with_cookie = initiation(&limiter, ip("192.0.2.1"));
+        assert_eq!(
+            verify(&limiter, ip("192.0.2.4"), &with_cookie),
+            Verdict::Cookie
+        );
+        let stats = limiter.stats();
+        assert_eq!((stats.cookie_replies, stats.invalid_mac2), (2, 1));
     }
 }
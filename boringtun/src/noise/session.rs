@@ -3,16 +3,17 @@
 
 use super::PacketData;
 use crate::noise::errors::WireGuardError;
+use crate::noise::timers::REJECT_AFTER_MESSAGES;
 use parking_lot::Mutex;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub struct Session {
     pub(crate) receiving_index: u32,
     sending_index: u32,
     receiver: LessSafeKey,
     sender: LessSafeKey,
-    sending_key_counter: AtomicUsize,
+    sending_key_counter: AtomicU64,
     receiving_key_counter: Mutex<ReceivingKeyCounterValidator>,
 }
 
@@ -129,7 +130,7 @@ impl ReceivingKeyCounterValidator {
             }
         } else {
             let mut i = self.next;
-            while i % WORD_SIZE != 0 && i < counter {
+            while !i.is_multiple_of(WORD_SIZE) && i < counter {
                 // Clear until i aligned to word size
                 self.clear_bit(i);
                 i += 1;
@@ -165,7 +166,7 @@ impl Session {
                 UnboundKey::new(&CHACHA20_POLY1305, &receiving_key).unwrap(),
             ),
             sender: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &sending_key).unwrap()),
-            sending_key_counter: AtomicUsize::new(0),
+            sending_key_counter: AtomicU64::new(0),
             receiving_key_counter: Mutex::new(Default::default()),
         }
     }
@@ -174,6 +175,22 @@ impl Session {
         self.receiving_index as usize
     }
 
+    /// Returns the number of packets sent so far using this session
+    pub(super) fn sending_counter(&self) -> u64 {
+        self.sending_key_counter.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if this session may no longer be used to send packets, because
+    /// REJECT_AFTER_MESSAGES were already sent with it
+    pub(super) fn is_sending_exhausted(&self) -> bool {
+        self.sending_counter() >= REJECT_AFTER_MESSAGES
+    }
+
+    #[cfg(test)]
+    pub(super) fn set_sending_counter(&self, counter: u64) {
+        self.sending_key_counter.store(counter, Ordering::Relaxed)
+    }
+
     /// Returns true if receiving counter is good to use
     fn receiving_counter_quick_check(&self, counter: u64) -> Result<(), WireGuardError> {
         let counter_validator = self.receiving_key_counter.lock();
@@ -191,15 +208,53 @@ impl Session {
     }
 
     /// src - an IP packet from the interface
+    /// padded_len - the plaintext is padded with zeros up to this size, if src is shorter
     /// dst - pre-allocated space to hold the encapsulating UDP packet to send over the network
-    /// returns the size of the formatted packet
-    pub(super) fn format_packet_data<'a>(&self, src: &[u8], dst: &'a mut [u8]) -> &'a mut [u8] {
-        if dst.len() < src.len() + super::DATA_OVERHEAD_SZ {
+    /// returns the size of the formatted packet, or an error if the session already sent
+    /// REJECT_AFTER_MESSAGES packets
+    pub(super) fn format_packet_data<'a>(
+        &self,
+        src: &[u8],
+        padded_len: usize,
+        dst: &'a mut [u8],
+    ) -> Result<&'a mut [u8], WireGuardError> {
+        let counter = self
+            .reserve_sending_counters(1)
+            .next()
+            .ok_or(WireGuardError::InvalidCounter)?;
+
+        Ok(self.seal_packet_data(counter, src, padded_len, dst))
+    }
+
+    /// Reserve up to n consecutive sending counters, so a batch of packets can be formatted with a
+    /// single atomic operation. The returned range is shorter than n if REJECT_AFTER_MESSAGES would
+    /// be reached, and empty if the session can't be used for sending anymore.
+    pub(super) fn reserve_sending_counters(&self, n: u64) -> std::ops::Range<u64> {
+        // Never let the counter reach REJECT_AFTER_MESSAGES, the nonce must not be reused
+        let start = self
+            .sending_key_counter
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ctr| {
+                (ctr < REJECT_AFTER_MESSAGES).then(|| ctr + n.min(REJECT_AFTER_MESSAGES - ctr))
+            })
+            .unwrap_or(REJECT_AFTER_MESSAGES);
+
+        start..start + n.min(REJECT_AFTER_MESSAGES - start)
+    }
+
+    /// Encrypt src into dst as a data packet, using a counter previously obtained from
+    /// reserve_sending_counters, padding it with zeros to padded_len
+    pub(super) fn seal_packet_data<'a>(
+        &self,
+        sending_key_counter: u64,
+        src: &[u8],
+        padded_len: usize,
+        dst: &'a mut [u8],
+    ) -> &'a mut [u8] {
+        let len = padded_len.max(src.len());
+        if dst.len() < len + super::DATA_OVERHEAD_SZ {
             panic!("The destination buffer is too small");
         }
 
-        let sending_key_counter = self.sending_key_counter.fetch_add(1, Ordering::Relaxed) as u64;
-
         let (message_type, rest) = dst.split_at_mut(4);
         let (receiver_index, rest) = rest.split_at_mut(4);
         let (counter, data) = rest.split_at_mut(8);
@@ -213,15 +268,16 @@ impl Session {
             let mut nonce = [0u8; 12];
             nonce[4..12].copy_from_slice(&sending_key_counter.to_le_bytes());
             data[..src.len()].copy_from_slice(src);
+            data[src.len()..len].fill(0);
             self.sender
                 .seal_in_place_separate_tag(
                     Nonce::assume_unique_for_key(nonce),
                     Aad::from(&[]),
-                    &mut data[..src.len()],
+                    &mut data[..len],
                 )
                 .map(|tag| {
-                    data[src.len()..src.len() + AEAD_SIZE].copy_from_slice(tag.as_ref());
-                    src.len() + AEAD_SIZE
+                    data[len..len + AEAD_SIZE].copy_from_slice(tag.as_ref());
+                    len + AEAD_SIZE
                 })
                 .unwrap()
         };
@@ -246,6 +302,10 @@ impl Session {
         if packet.receiver_idx != self.receiving_index {
             return Err(WireGuardError::WrongIndex);
         }
+        if packet.counter >= REJECT_AFTER_MESSAGES {
+            // The peer must never send this many messages with the same key
+            return Err(WireGuardError::InvalidCounter);
+        }
         // Don't reuse counters, in case this is a replay attack we want to quickly check the counter without running expensive decryption
         self.receiving_counter_quick_check(packet.counter)?;
 
@@ -326,4 +386,53 @@ mod tests {
         assert!(c.mark_did_receive(N_BITS * 3 + 71).is_err());
         assert!(c.mark_did_receive(N_BITS * 3 + 72).is_err());
     }
+
+    #[test]
+    fn test_reject_after_messages() {
+        let sender = Session::new(1, 2, [0u8; 32], [1u8; 32]);
+        let receiver = Session::new(2, 1, [1u8; 32], [0u8; 32]);
+        let mut sent = [0u8; 64];
+        let mut received = [0u8; 64];
+
+        sender.set_sending_counter(REJECT_AFTER_MESSAGES - 1);
+        assert!(!sender.is_sending_exhausted());
+        let packet = sender
+            .format_packet_data(&[], 0, &mut sent)
+            .unwrap()
+            .to_vec();
+        assert!(sender.is_sending_exhausted());
+
+        // The last allowed counter is still accepted
+        let parsed = match crate::noise::Tunn::parse_incoming_packet(&packet).unwrap() {
+            crate::noise::Packet::PacketData(p) => p,
+            _ => unreachable!(),
+        };
+        assert!(receiver.receive_packet_data(parsed, &mut received).is_ok());
+
+        // No more packets can be sent with this session
+        assert!(matches!(
+            sender.format_packet_data(&[], 0, &mut sent),
+            Err(WireGuardError::InvalidCounter)
+        ));
+        assert_eq!(sender.sending_counter(), REJECT_AFTER_MESSAGES);
+
+        // Reservations are clamped to the counters that are left
+        sender.set_sending_counter(REJECT_AFTER_MESSAGES - 2);
+        assert_eq!(
+            sender.reserve_sending_counters(3),
+            REJECT_AFTER_MESSAGES - 2..REJECT_AFTER_MESSAGES
+        );
+        assert!(sender.reserve_sending_counters(3).is_empty());
+
+        // And none can be received, even before attempting to decrypt
+        let packet = PacketData {
+            receiver_idx: 2,
+            counter: REJECT_AFTER_MESSAGES,
+            encrypted_encapsulated_packet: &[0u8; 16],
+        };
+        assert!(matches!(
+            receiver.receive_packet_data(packet, &mut received),
+            Err(WireGuardError::InvalidCounter)
+        ));
+    }
 }
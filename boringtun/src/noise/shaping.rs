@@ -0,0 +1,111 @@
+// Copyright (c) 2019 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Optional traffic shaping, to make the size and timing of a tunnel's packets say less about the
+//! traffic it carries.
+//!
+//! Data packets are padded with zeros up to a size bucket, receivers learn the real size from the
+//! IP header, so padded packets are understood by every WireGuard implementation. Keepalives are
+//! padded as well, and decoy packets made of zeros are sent on a schedule. Receivers silently
+//! discard those when they implement this module, other implementations drop them as invalid.
+
+use rand_core::{OsRng, RngCore};
+use std::time::Duration;
+
+/// How a tunnel pads its packets, and when it sends decoys
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficShaping {
+    /// Plaintext sizes to pad packets up to, in increasing order. A packet is padded to the
+    /// smallest bucket it fits in, and to `mtu` if it fits in none.
+    pub buckets: Vec<usize>,
+    /// Packets are never padded beyond this size, usually the MTU of the tunnel interface
+    pub mtu: usize,
+    /// Send decoy packets on this schedule while a session is established, `None` sends none
+    pub decoys: Option<DecoySchedule>,
+}
+
+/// When to send decoy packets, and how large they are
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecoySchedule {
+    /// The average time between two decoys
+    pub interval: Duration,
+    /// Each interval is randomly shortened or lengthened by up to this much
+    pub jitter: Duration,
+    /// The plaintext size of decoys before padding, zero makes them look like keepalives
+    pub size: usize,
+}
+
+impl TrafficShaping {
+    /// Pad every packet to the MTU
+    pub fn constant_size(mtu: usize) -> TrafficShaping {
+        TrafficShaping {
+            buckets: vec![],
+            mtu,
+            decoys: None,
+        }
+    }
+
+    /// The size to pad a plaintext of `len` bytes to
+    pub(super) fn padded_len(&self, len: usize) -> usize {
+        let bucket = self
+            .buckets
+            .iter()
+            .copied()
+            .find(|&bucket| bucket >= len)
+            .unwrap_or(self.mtu);
+        bucket.min(self.mtu).max(len)
+    }
+
+    /// The time to wait before sending the next decoy, if any
+    pub(super) fn next_decoy_delay(&self) -> Option<Duration> {
+        let schedule = self.decoys.as_ref()?;
+        let jitter = schedule.jitter.min(schedule.interval).as_micros() as u64;
+        if jitter == 0 {
+            return Some(schedule.interval);
+        }
+        let offset = Duration::from_micros(OsRng.next_u64() % (2 * jitter + 1));
+        Some(schedule.interval + offset - Duration::from_micros(jitter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_to_buckets() {
+        let shaping = TrafficShaping {
+            buckets: vec![128, 512],
+            mtu: 1420,
+            decoys: None,
+        };
+        assert_eq!(shaping.padded_len(0), 128);
+        assert_eq!(shaping.padded_len(128), 128);
+        assert_eq!(shaping.padded_len(129), 512);
+        assert_eq!(shaping.padded_len(513), 1420);
+        // Oversized packets are left alone
+        assert_eq!(shaping.padded_len(1500), 1500);
+
+        let shaping = TrafficShaping {
+            buckets: vec![2000],
+            ..TrafficShaping::constant_size(1280)
+        };
+        assert_eq!(shaping.padded_len(40), 1280);
+    }
+
+    #[test]
+    fn decoy_delay_within_jitter() {
+        let mut shaping = TrafficShaping::constant_size(1420);
+        assert_eq!(shaping.next_decoy_delay(), None);
+
+        shaping.decoys = Some(DecoySchedule {
+            interval: Duration::from_millis(500),
+            jitter: Duration::from_millis(100),
+            size: 0,
+        });
+        for _ in 0..100 {
+            let delay = shaping.next_decoy_delay().unwrap();
+            assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(600));
+        }
+    }
+}
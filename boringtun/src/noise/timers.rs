@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use super::errors::WireGuardError;
-use crate::noise::{Tunn, TunnResult};
+use crate::noise::{Tunn, TunnEvent, TunnResult};
 use std::mem;
 use std::ops::{Index, IndexMut};
 
@@ -20,12 +20,17 @@ pub(crate) const REKEY_AFTER_TIME: Duration = Duration::from_secs(120);
 const REJECT_AFTER_TIME: Duration = Duration::from_secs(180);
 const REKEY_ATTEMPT_TIME: Duration = Duration::from_secs(90);
 pub(crate) const REKEY_TIMEOUT: Duration = Duration::from_secs(5);
-const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
+pub(crate) const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(10);
 const COOKIE_EXPIRATION_TIME: Duration = Duration::from_secs(120);
 
+// Message count limits for a single session
+// https://www.wireguard.com/papers/wireguard.pdf#page=14
+pub(crate) const REKEY_AFTER_MESSAGES: u64 = 1 << 60;
+pub(crate) const REJECT_AFTER_MESSAGES: u64 = u64::MAX - (1 << 13);
+
 #[derive(Debug)]
 pub enum TimerName {
-    /// Current time, updated each call to `update_timers`
+    /// Current time, updated each call to `update_timers` and as packets are handled
     TimeCurrent,
     /// Time when last handshake was completed
     TimeSessionEstablished,
@@ -60,9 +65,13 @@ pub struct Timers {
     want_keepalive: bool,
     /// Did we send data without hearing back?
     want_handshake: bool,
+    /// Did a delivered preshared key ask for a handshake that was not initiated yet?
+    pub(super) want_rekey: bool,
     persistent_keepalive: usize,
     /// Should this timer call reset rr function (if not a shared rr instance)
     pub(super) should_reset_rr: bool,
+    /// When to send the next decoy, if traffic shaping asks for decoys
+    pub(super) next_decoy: Option<Duration>,
 }
 
 impl Timers {
@@ -74,8 +83,10 @@ impl Timers {
             session_timers: Default::default(),
             want_keepalive: Default::default(),
             want_handshake: Default::default(),
+            want_rekey: false,
             persistent_keepalive: usize::from(persistent_keepalive.unwrap_or(0)),
             should_reset_rr: reset_rr,
+            next_decoy: None,
         }
     }
 
@@ -126,6 +137,13 @@ impl Tunn {
         self.timers[timer_name] = time;
     }
 
+    /// Read the clock into `TimeCurrent`. Idle tunnels may go a long time between calls to
+    /// `update_timers`, so this is done for every packet sent or received on its own, once per batch
+    /// of packets, and for handshake messages.
+    pub(super) fn update_time_current(&mut self) {
+        self.timers[TimeCurrent] = Instant::now().duration_since(self.timers.time_started);
+    }
+
     pub(super) fn timer_tick_session_established(
         &mut self,
         is_initiator: bool,
@@ -182,6 +200,12 @@ impl Tunn {
 
         self.update_session_timers(now);
 
+        // A handshake in progress completes with the preshared key it started with, unless the
+        // delivered key asks for a new one
+        if self.handshake.timer().is_none() || self.preshared_key_wants_rekey() {
+            self.install_pending_preshared_key();
+        }
+
         // Load timers only once:
         let session_established = self.timers[TimeSessionEstablished];
         let handshake_started = self.timers[TimeLastHandshakeStarted];
@@ -209,9 +233,15 @@ impl Tunn {
                 tracing::error!("CONNECTION_EXPIRED(REJECT_AFTER_TIME * 3)");
                 self.handshake.set_expired();
                 self.clear_all();
+                self.emit(TunnEvent::ConnectionExpired);
                 return TunnResult::Err(WireGuardError::ConnectionExpired);
             }
 
+            if self.timers.want_rekey {
+                tracing::debug!("HANDSHAKE(PRESHARED_KEY_DELIVERED)");
+                handshake_initiation_required = true;
+            }
+
             if let Some(time_init_sent) = self.handshake.timer() {
                 // Handshake Initiation Retransmission
                 if now - handshake_started >= REKEY_ATTEMPT_TIME {
@@ -222,6 +252,7 @@ impl Tunn {
                     tracing::error!("CONNECTION_EXPIRED(REKEY_ATTEMPT_TIME)");
                     self.handshake.set_expired();
                     self.clear_all();
+                    self.emit(TunnEvent::ConnectionExpired);
                     return TunnResult::Err(WireGuardError::ConnectionExpired);
                 }
 
@@ -232,6 +263,7 @@ impl Tunn {
                     // if a response has not been received, where jitter is some random
                     // value between 0 and 333 ms.
                     tracing::warn!("HANDSHAKE(REKEY_TIMEOUT)");
+                    self.counters.handshakes_failed += 1;
                     handshake_initiation_required = true;
                 }
             } else {
@@ -265,6 +297,16 @@ impl Tunn {
                     }
                 }
 
+                // After sending REKEY_AFTER_MESSAGES using the current session, either party
+                // initiates a new handshake, regardless of who was the initiator of the
+                // current session.
+                if self.current_session_sending_counter() >= REKEY_AFTER_MESSAGES
+                    && now - handshake_started >= REKEY_TIMEOUT
+                {
+                    tracing::debug!("HANDSHAKE(REKEY_AFTER_MESSAGES)");
+                    handshake_initiation_required = true;
+                }
+
                 // If we have sent a packet to a given peer but have not received a
                 // packet after from that peer for (KEEPALIVE + REKEY_TIMEOUT) ms,
                 // we initiate a new handshake.
@@ -308,9 +350,111 @@ impl Tunn {
             return self.encapsulate(&[], dst);
         }
 
+        if self.decoy_due(now) {
+            tracing::trace!("DECOY");
+            return self.format_decoy(dst);
+        }
+
         TunnResult::Done
     }
 
+    /// Is a decoy due to be sent? Schedules the following one when it is, or when none is
+    /// scheduled yet.
+    fn decoy_due(&mut self, now: Duration) -> bool {
+        let shaping = match self.traffic_shaping {
+            Some(ref shaping) => shaping,
+            None => return false,
+        };
+        match self.timers.next_decoy {
+            Some(next) if now < next => false,
+            next => {
+                self.timers.next_decoy = shaping.next_decoy_delay().map(|delay| now + delay);
+                next.is_some()
+            }
+        }
+    }
+
+    /// The time until `update_timers` has something to do, None once the connection expired and
+    /// nothing is due until packets are sent or received. Calling `update_timers` earlier is
+    /// harmless, calling it later delays handshakes and keepalives. The deadline only moves
+    /// closer when the tunnel handles packets, or when a preshared key is delivered.
+    pub fn time_to_next_event(&self) -> Option<Duration> {
+        if self.handshake.is_expired() {
+            return None;
+        }
+        if self.timers.want_rekey || self.preshared_key_wants_rekey() {
+            return Some(Duration::ZERO);
+        }
+
+        let now = Instant::now().duration_since(self.timers.time_started);
+        let timers = &self.timers;
+        let session_established = timers[TimeSessionEstablished];
+        let handshake_started = timers[TimeLastHandshakeStarted];
+        let aut_packet_received = timers[TimeLastPacketReceived];
+        let aut_packet_sent = timers[TimeLastPacketSent];
+        let data_packet_received = timers[TimeLastDataPacketReceived];
+        let data_packet_sent = timers[TimeLastDataPacketSent];
+        let persistent_keepalive = timers.persistent_keepalive;
+
+        // Deadlines counted from tunnel initiation, like the timers
+        let mut next = session_established + REJECT_AFTER_TIME * 3;
+        let mut at = |deadline: Duration| next = next.min(deadline);
+
+        for (session, t) in self.sessions.iter().zip(timers.session_timers.iter()) {
+            if session.is_some() {
+                at(*t + REJECT_AFTER_TIME);
+            }
+        }
+
+        if self.handshake.has_cookie() {
+            at(timers[TimeCookieReceived] + COOKIE_EXPIRATION_TIME);
+        }
+
+        if let Some(time_init_sent) = self.handshake.timer() {
+            at(handshake_started + REKEY_ATTEMPT_TIME);
+            at(now + REKEY_TIMEOUT.saturating_sub(time_init_sent.elapsed()));
+        } else {
+            if timers.is_initiator() {
+                if session_established < data_packet_sent {
+                    at(session_established + REKEY_AFTER_TIME);
+                }
+                if session_established < data_packet_received {
+                    at(session_established + REJECT_AFTER_TIME - KEEPALIVE_TIMEOUT - REKEY_TIMEOUT);
+                }
+            }
+            if self.current_session_sending_counter() >= REKEY_AFTER_MESSAGES {
+                at(handshake_started + REKEY_TIMEOUT);
+            }
+            if data_packet_sent > aut_packet_received && timers.want_handshake {
+                at(aut_packet_received + KEEPALIVE_TIMEOUT + REKEY_TIMEOUT);
+            }
+            if data_packet_received > aut_packet_sent && timers.want_keepalive {
+                at(aut_packet_sent + KEEPALIVE_TIMEOUT);
+            }
+            if persistent_keepalive > 0 {
+                at(timers[TimePersistentKeepalive]
+                    + Duration::from_secs(persistent_keepalive as _));
+            }
+        }
+
+        let decoys = self
+            .traffic_shaping
+            .as_ref()
+            .and_then(|s| s.decoys.as_ref());
+        if decoys.is_some() {
+            // The first decoy is scheduled by the next update
+            at(timers.next_decoy.unwrap_or(now));
+        }
+
+        let rate_limiter_reset = match timers.should_reset_rr {
+            true => self.rate_limiter.time_to_reset(),
+            false => None,
+        };
+
+        let next = next.saturating_sub(now);
+        Some(rate_limiter_reset.map_or(next, |reset| reset.min(next)))
+    }
+
     pub fn time_since_last_handshake(&self) -> Option<Duration> {
         let current_session = self.current;
         if self.sessions[current_session % super::N_SESSIONS].is_some() {
@@ -323,6 +467,20 @@ impl Tunn {
         }
     }
 
+    /// Time since the current session was established, which may be older than the last
+    /// handshake until the peer starts using the new session
+    pub(super) fn current_session_age(&self) -> Option<Duration> {
+        let idx = self.current % super::N_SESSIONS;
+        self.sessions[idx].as_ref()?;
+        let now = Instant::now().duration_since(self.timers.time_started);
+        Some(now.saturating_sub(self.timers.session_timers[idx]))
+    }
+
+    /// Zero or None disables the persistent keepalive
+    pub fn set_persistent_keepalive(&mut self, keepalive: Option<u16>) {
+        self.timers.persistent_keepalive = usize::from(keepalive.unwrap_or(0));
+    }
+
     pub fn persistent_keepalive(&self) -> Option<u16> {
         let keepalive = self.timers.persistent_keepalive;
 
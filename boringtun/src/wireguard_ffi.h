@@ -22,6 +22,14 @@ enum result_type
     WRITE_TO_TUNNEL_IPV6 = 6,
 };
 
+enum tunnel_event
+{
+    HANDSHAKE_COMPLETED = 0,
+    CONNECTION_EXPIRED = 1,
+    COOKIE_RECEIVED = 2, // the peer is under load and demanded a cookie
+    COOKIE_SENT = 3,     // we are under load and demanded a cookie from the peer
+};
+
 struct wireguard_result
 {
     enum result_type op;
@@ -35,7 +43,17 @@ struct stats
     size_t rx_bytes;
     float estimated_loss;
     int32_t estimated_rtt; // rtt estimated on time it took to complete latest initiated handshake in ms
-    uint8_t reserved[56];  // decrement appropriately when adding new fields
+    int64_t session_age;   // seconds since the current session was established, -1 if none
+    int64_t session_index; // local index of the current session, -1 if none
+    uint32_t handshakes_initiated;
+    uint32_t handshakes_completed;
+    uint32_t handshakes_failed;
+    uint32_t cookies_sent;
+    uint32_t cookies_received;
+    uint32_t dropped_packets;  // received packets dropped for any reason
+    uint32_t replayed_packets; // received data packets dropped as replays
+    uint32_t queued_packets;   // packets waiting for a session to be sent
+    uint8_t reserved[8];       // decrement appropriately when adding new fields
 };
 
 struct x25519_key
@@ -99,8 +117,20 @@ struct wireguard_result wireguard_tick(const struct wireguard_tunnel *tunnel,
                                        uint8_t *dst,
                                        uint32_t dst_size);
 
+// Milliseconds until wireguard_tick has something to do, -1 if nothing is due
+int64_t wireguard_time_to_next_event(const struct wireguard_tunnel *tunnel);
+
+// Calls callback with ctx on every tunnel event, NULL removes the callback. The callback runs
+// with the tunnel locked and must not call back into the same tunnel.
+void wireguard_set_event_callback(const struct wireguard_tunnel *tunnel,
+                                  void (*callback)(enum tunnel_event event, void *ctx),
+                                  void *ctx);
+
 struct wireguard_result wireguard_force_handshake(const struct wireguard_tunnel *tunnel,
                                                   uint8_t *dst,
                                                   uint32_t dst_size);
 
 struct stats wireguard_stats(const struct wireguard_tunnel *tunnel);
+
+// Received packets dropped because of reason, the error code of a WIREGUARD_ERROR result
+uint64_t wireguard_dropped_packets(const struct wireguard_tunnel *tunnel, uint32_t reason);